@@ -0,0 +1,10 @@
+//! Build script embedding build information
+
+use vergen::{vergen, Config};
+
+fn main() {
+    // Source archives and sandboxed builds have no git repository, the version is still known
+    if let Err(e) = vergen(Config::default()) {
+        println!("cargo:warning=Build information unavailable: {}", e);
+    }
+}
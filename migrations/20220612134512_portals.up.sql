@@ -0,0 +1,5 @@
+CREATE TABLE portals(
+  discord_channel_id BIGINT PRIMARY KEY NOT NULL,
+  matrix_room_id TEXT NOT NULL UNIQUE,
+  room_alias TEXT UNIQUE
+);
@@ -0,0 +1,2 @@
+DROP TABLE discord_stickers;
+DROP TABLE discord_emojis;
@@ -0,0 +1,15 @@
+CREATE TABLE discord_emojis(
+  emoji_id BIGINT PRIMARY KEY NOT NULL,
+  guild_id BIGINT,
+  name TEXT NOT NULL,
+  animated BOOLEAN NOT NULL,
+  mxc_url TEXT NOT NULL
+);
+CREATE INDEX discord_emojis_guild_id ON discord_emojis(guild_id);
+CREATE TABLE discord_stickers(
+  sticker_id BIGINT PRIMARY KEY NOT NULL,
+  guild_id BIGINT,
+  name TEXT NOT NULL,
+  mxc_url TEXT NOT NULL
+);
+CREATE INDEX discord_stickers_guild_id ON discord_stickers(guild_id);
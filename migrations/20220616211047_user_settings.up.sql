@@ -0,0 +1,6 @@
+CREATE TABLE user_settings(
+  user_id TEXT PRIMARY KEY NOT NULL,
+  privacy_mode BOOLEAN NOT NULL DEFAULT FALSE,
+  pseudonym TEXT,
+  pseudonym_avatar TEXT
+);
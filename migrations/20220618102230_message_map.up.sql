@@ -0,0 +1,10 @@
+CREATE TABLE message_map(
+  matrix_event_id TEXT NOT NULL,
+  matrix_room_id TEXT NOT NULL,
+  discord_message_id BIGINT NOT NULL,
+  discord_channel_id BIGINT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (matrix_event_id, discord_message_id)
+);
+CREATE INDEX message_map_discord_message_id ON message_map(discord_message_id);
+CREATE INDEX message_map_matrix_room_id ON message_map(matrix_room_id, created_at);
@@ -0,0 +1,7 @@
+CREATE TABLE reserved_names(
+  kind TEXT NOT NULL,
+  name TEXT NOT NULL,
+  owner TEXT NOT NULL,
+  PRIMARY KEY (kind, name),
+  UNIQUE (kind, owner)
+);
@@ -0,0 +1,2 @@
+DROP INDEX portals_guild_id;
+ALTER TABLE portals DROP COLUMN guild_id;
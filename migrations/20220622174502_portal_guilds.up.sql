@@ -0,0 +1,2 @@
+ALTER TABLE portals ADD COLUMN guild_id BIGINT;
+CREATE INDEX portals_guild_id ON portals(guild_id);
@@ -0,0 +1,21 @@
+DROP FUNCTION message_map_prune(TIMESTAMPTZ);
+DROP FUNCTION message_map_ensure_partitions(INT);
+ALTER TABLE message_map RENAME TO message_map_partitioned;
+ALTER INDEX message_map_discord_message_id RENAME TO message_map_partitioned_discord_message_id;
+ALTER INDEX message_map_matrix_room_id RENAME TO message_map_partitioned_matrix_room_id;
+DROP INDEX message_map_discord_channel_id;
+ALTER TABLE message_map_partitioned RENAME CONSTRAINT message_map_pkey TO message_map_partitioned_pkey;
+CREATE TABLE message_map(
+  matrix_event_id TEXT NOT NULL,
+  matrix_room_id TEXT NOT NULL,
+  discord_message_id BIGINT NOT NULL,
+  discord_channel_id BIGINT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (matrix_event_id, discord_message_id)
+);
+INSERT INTO message_map (matrix_event_id, matrix_room_id, discord_message_id, discord_channel_id, created_at)
+  SELECT matrix_event_id, matrix_room_id, discord_message_id, discord_channel_id, created_at FROM message_map_partitioned
+  ON CONFLICT DO NOTHING;
+DROP TABLE message_map_partitioned;
+CREATE INDEX message_map_discord_message_id ON message_map(discord_message_id);
+CREATE INDEX message_map_matrix_room_id ON message_map(matrix_room_id, created_at);
@@ -0,0 +1,65 @@
+ALTER TABLE message_map RENAME TO message_map_old;
+ALTER TABLE message_map_old RENAME CONSTRAINT message_map_pkey TO message_map_old_pkey;
+ALTER INDEX message_map_discord_message_id RENAME TO message_map_old_discord_message_id;
+ALTER INDEX message_map_matrix_room_id RENAME TO message_map_old_matrix_room_id;
+CREATE TABLE message_map(
+  matrix_event_id TEXT NOT NULL,
+  matrix_room_id TEXT NOT NULL,
+  discord_message_id BIGINT NOT NULL,
+  discord_channel_id BIGINT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  pinned BOOLEAN NOT NULL DEFAULT FALSE,
+  PRIMARY KEY (matrix_event_id, discord_message_id, created_at)
+) PARTITION BY RANGE (created_at);
+CREATE TABLE message_map_default PARTITION OF message_map DEFAULT;
+INSERT INTO message_map (matrix_event_id, matrix_room_id, discord_message_id, discord_channel_id, created_at)
+  SELECT matrix_event_id, matrix_room_id, discord_message_id, discord_channel_id, created_at FROM message_map_old;
+DROP TABLE message_map_old;
+CREATE INDEX message_map_discord_message_id ON message_map(discord_message_id);
+CREATE INDEX message_map_discord_channel_id ON message_map(discord_channel_id, discord_message_id);
+CREATE INDEX message_map_matrix_room_id ON message_map(matrix_room_id, created_at);
+
+-- Creates the monthly partitions from the current month up to `months_ahead` months ahead.
+-- Months that already have rows in the default partition are skipped.
+CREATE FUNCTION message_map_ensure_partitions(months_ahead INT) RETURNS INT AS $$
+DECLARE
+  month_start TIMESTAMPTZ;
+  month_end TIMESTAMPTZ;
+  partition_name TEXT;
+  created INT := 0;
+BEGIN
+  FOR i IN 0..months_ahead LOOP
+    month_start := date_trunc('month', NOW() AT TIME ZONE 'UTC') AT TIME ZONE 'UTC' + make_interval(months => i);
+    month_end := month_start + INTERVAL '1 month';
+    partition_name := 'message_map_' || to_char(month_start AT TIME ZONE 'UTC', 'YYYY_MM');
+    CONTINUE WHEN to_regclass(partition_name) IS NOT NULL;
+    CONTINUE WHEN EXISTS (SELECT 1 FROM message_map_default WHERE created_at >= month_start AND created_at < month_end);
+    EXECUTE format('CREATE TABLE %I PARTITION OF message_map FOR VALUES FROM (%L) TO (%L)', partition_name, month_start, month_end);
+    created := created + 1;
+  END LOOP;
+  RETURN created;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Deletes unpinned mappings older than `cutoff` and drops monthly partitions that became empty.
+CREATE FUNCTION message_map_prune(cutoff TIMESTAMPTZ) RETURNS BIGINT AS $$
+DECLARE
+  deleted BIGINT;
+  part RECORD;
+  is_empty BOOLEAN;
+BEGIN
+  DELETE FROM message_map WHERE created_at < cutoff AND NOT pinned;
+  GET DIAGNOSTICS deleted = ROW_COUNT;
+  FOR part IN
+    SELECT c.relname FROM pg_inherits i JOIN pg_class c ON c.oid = i.inhrelid
+    WHERE i.inhparent = 'message_map'::regclass AND c.relname <> 'message_map_default'
+  LOOP
+    CONTINUE WHEN to_timestamp(substring(part.relname FROM 13), 'YYYY_MM') + INTERVAL '1 month' > cutoff;
+    EXECUTE format('SELECT NOT EXISTS (SELECT 1 FROM %I)', part.relname) INTO is_empty;
+    IF is_empty THEN
+      EXECUTE format('DROP TABLE %I', part.relname);
+    END IF;
+  END LOOP;
+  RETURN deleted;
+END;
+$$ LANGUAGE plpgsql;
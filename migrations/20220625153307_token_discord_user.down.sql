@@ -0,0 +1,2 @@
+DROP INDEX discord_tokens_discord_user_id;
+ALTER TABLE discord_tokens DROP COLUMN discord_user_id;
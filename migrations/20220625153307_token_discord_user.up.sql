@@ -0,0 +1,2 @@
+ALTER TABLE discord_tokens ADD COLUMN discord_user_id BIGINT;
+CREATE INDEX discord_tokens_discord_user_id ON discord_tokens(discord_user_id);
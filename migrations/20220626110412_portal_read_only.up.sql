@@ -0,0 +1 @@
+ALTER TABLE portals ADD COLUMN read_only BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1 @@
+ALTER TABLE portals DROP COLUMN locale;
@@ -0,0 +1 @@
+ALTER TABLE portals ADD COLUMN locale TEXT;
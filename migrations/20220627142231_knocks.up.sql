@@ -0,0 +1,8 @@
+CREATE TABLE knocks (
+    discord_message_id BIGINT PRIMARY KEY,
+    discord_channel_id BIGINT NOT NULL,
+    matrix_room_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE UNIQUE INDEX knocks_room_user ON knocks (matrix_room_id, user_id);
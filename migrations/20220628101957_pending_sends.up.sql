@@ -0,0 +1,9 @@
+CREATE TABLE pending_sends (
+    txn_id TEXT PRIMARY KEY,
+    matrix_room_id TEXT NOT NULL,
+    discord_channel_id BIGINT NOT NULL,
+    discord_message_id BIGINT NOT NULL,
+    content TEXT NOT NULL,
+    attempts INT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
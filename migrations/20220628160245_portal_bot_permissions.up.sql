@@ -0,0 +1 @@
+ALTER TABLE portals ADD COLUMN bot_permissions BIGINT;
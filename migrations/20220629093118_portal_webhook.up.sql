@@ -0,0 +1 @@
+ALTER TABLE portals ADD COLUMN webhook_url TEXT;
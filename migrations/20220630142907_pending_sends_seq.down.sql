@@ -0,0 +1 @@
+ALTER TABLE pending_sends DROP COLUMN seq;
@@ -0,0 +1 @@
+ALTER TABLE pending_sends ADD COLUMN seq BIGSERIAL NOT NULL;
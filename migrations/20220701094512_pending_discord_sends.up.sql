@@ -0,0 +1,9 @@
+CREATE TABLE pending_discord_sends (
+    seq BIGSERIAL PRIMARY KEY,
+    discord_channel_id BIGINT NOT NULL,
+    matrix_user_id TEXT,
+    content TEXT NOT NULL,
+    dropped INT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX pending_discord_sends_channel ON pending_discord_sends (discord_channel_id, seq);
@@ -0,0 +1,4 @@
+CREATE TABLE catalog_entries (
+    matrix_room_id TEXT PRIMARY KEY,
+    entry TEXT NOT NULL
+);
@@ -0,0 +1,4 @@
+ALTER TABLE pending_discord_sends DROP COLUMN matrix_event_id;
+ALTER TABLE pending_discord_sends DROP COLUMN matrix_room_id;
+ALTER TABLE message_map DROP COLUMN relayed;
+ALTER TABLE message_map DROP COLUMN webhook_id;
@@ -0,0 +1,4 @@
+ALTER TABLE message_map ADD COLUMN webhook_id BIGINT;
+ALTER TABLE message_map ADD COLUMN relayed BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE pending_discord_sends ADD COLUMN matrix_room_id TEXT;
+ALTER TABLE pending_discord_sends ADD COLUMN matrix_event_id TEXT;
@@ -0,0 +1,6 @@
+CREATE TABLE member_roles (
+    guild_id INT8 NOT NULL,
+    discord_user_id INT8 NOT NULL,
+    role_ids INT8[] NOT NULL,
+    PRIMARY KEY (guild_id, discord_user_id)
+);
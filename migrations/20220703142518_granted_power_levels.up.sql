@@ -0,0 +1,6 @@
+CREATE TABLE granted_power_levels (
+    matrix_room_id TEXT NOT NULL,
+    matrix_user_id TEXT NOT NULL,
+    power_level INT8 NOT NULL,
+    PRIMARY KEY (matrix_room_id, matrix_user_id)
+);
@@ -0,0 +1 @@
+ALTER TABLE user_settings ADD COLUMN mention_dm BOOLEAN NOT NULL DEFAULT FALSE;
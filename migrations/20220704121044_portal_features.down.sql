@@ -0,0 +1,2 @@
+ALTER TABLE portals DROP COLUMN features_disabled;
+ALTER TABLE portals DROP COLUMN features_enabled;
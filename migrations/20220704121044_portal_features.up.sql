@@ -0,0 +1,2 @@
+ALTER TABLE portals ADD COLUMN features_enabled TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE portals ADD COLUMN features_disabled TEXT[] NOT NULL DEFAULT '{}';
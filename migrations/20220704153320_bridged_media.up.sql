@@ -0,0 +1,8 @@
+CREATE TABLE bridged_media(
+  mxc_uri TEXT PRIMARY KEY NOT NULL,
+  discord_channel_id INT8,
+  transient BOOLEAN NOT NULL DEFAULT FALSE,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX bridged_media_channel ON bridged_media (discord_channel_id);
+CREATE INDEX bridged_media_transient ON bridged_media (created_at) WHERE transient;
@@ -0,0 +1 @@
+ALTER TABLE discord_tokens DROP COLUMN invalid_since;
@@ -0,0 +1 @@
+ALTER TABLE discord_tokens ADD COLUMN invalid_since TIMESTAMPTZ;
@@ -0,0 +1 @@
+DROP TABLE bridge_versions;
@@ -0,0 +1,5 @@
+CREATE TABLE bridge_versions (
+    version TEXT NOT NULL,
+    started_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX bridge_versions_started_at ON bridge_versions (started_at);
@@ -0,0 +1,6 @@
+CREATE TABLE guilds (
+    guild_id BIGINT PRIMARY KEY,
+    space_room_id TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    icon TEXT
+);
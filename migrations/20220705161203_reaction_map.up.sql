@@ -0,0 +1,10 @@
+CREATE TABLE reaction_map (
+    matrix_event_id TEXT PRIMARY KEY,
+    matrix_room_id TEXT NOT NULL,
+    discord_channel_id BIGINT NOT NULL,
+    discord_message_id BIGINT NOT NULL,
+    discord_user_id BIGINT NOT NULL,
+    emoji TEXT NOT NULL,
+    relayed BOOLEAN NOT NULL
+);
+CREATE INDEX reaction_map_discord_message_id ON reaction_map(discord_message_id, emoji);
@@ -0,0 +1 @@
+ALTER TABLE pending_discord_sends DROP COLUMN reply_to;
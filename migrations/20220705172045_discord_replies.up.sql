@@ -0,0 +1 @@
+ALTER TABLE pending_discord_sends ADD COLUMN reply_to BIGINT;
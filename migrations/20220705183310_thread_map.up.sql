@@ -0,0 +1,8 @@
+CREATE TABLE thread_map (
+    discord_thread_id BIGINT PRIMARY KEY,
+    discord_parent_id BIGINT NOT NULL,
+    matrix_room_id TEXT NOT NULL,
+    root_event_id TEXT NOT NULL,
+    latest_event_id TEXT NOT NULL,
+    UNIQUE (matrix_room_id, root_event_id)
+);
@@ -0,0 +1,5 @@
+CREATE TABLE media_hashes (
+    sha256 TEXT PRIMARY KEY,
+    mxc_uri TEXT NOT NULL REFERENCES bridged_media (mxc_uri) ON DELETE CASCADE
+);
+CREATE INDEX media_hashes_mxc_uri ON media_hashes (mxc_uri);
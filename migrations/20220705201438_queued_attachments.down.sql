@@ -0,0 +1,2 @@
+ALTER TABLE pending_discord_sends DROP COLUMN attachment_name;
+ALTER TABLE pending_discord_sends DROP COLUMN attachment_mxc;
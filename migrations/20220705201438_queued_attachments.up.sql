@@ -0,0 +1,2 @@
+ALTER TABLE pending_discord_sends ADD COLUMN attachment_mxc TEXT;
+ALTER TABLE pending_discord_sends ADD COLUMN attachment_name TEXT;
@@ -0,0 +1,3 @@
+ALTER TABLE discord_tokens DROP COLUMN sealed;
+ALTER TABLE discord_tokens DROP COLUMN expires_at;
+ALTER TABLE discord_tokens DROP COLUMN refresh_token;
@@ -0,0 +1,3 @@
+ALTER TABLE discord_tokens ADD COLUMN refresh_token TEXT;
+ALTER TABLE discord_tokens ADD COLUMN expires_at TIMESTAMPTZ;
+ALTER TABLE discord_tokens ADD COLUMN sealed BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,2 @@
+DROP TABLE puppet_nicks;
+DROP TABLE puppet_profiles;
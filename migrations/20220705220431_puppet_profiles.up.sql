@@ -0,0 +1,12 @@
+CREATE TABLE puppet_profiles(
+  discord_user_id BIGINT PRIMARY KEY NOT NULL,
+  displayname TEXT NOT NULL,
+  avatar_hash TEXT,
+  avatar_mxc TEXT
+);
+CREATE TABLE puppet_nicks(
+  guild_id BIGINT NOT NULL,
+  discord_user_id BIGINT NOT NULL,
+  displayname TEXT NOT NULL,
+  PRIMARY KEY (guild_id, discord_user_id)
+);
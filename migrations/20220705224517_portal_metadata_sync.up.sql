@@ -0,0 +1 @@
+ALTER TABLE portals ADD COLUMN metadata_sync TEXT NOT NULL DEFAULT 'from_discord';
@@ -0,0 +1,9 @@
+CREATE TABLE event_queue(
+  id BIGSERIAL PRIMARY KEY NOT NULL,
+  payload TEXT NOT NULL,
+  dry_run BOOLEAN NOT NULL DEFAULT FALSE,
+  attempts INTEGER NOT NULL DEFAULT 0,
+  next_retry_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  last_error TEXT
+);
+CREATE INDEX event_queue_next_retry_at ON event_queue(dry_run, next_retry_at);
@@ -0,0 +1 @@
+ALTER TABLE event_queue DROP COLUMN ordering_key;
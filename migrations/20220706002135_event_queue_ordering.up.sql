@@ -0,0 +1,2 @@
+ALTER TABLE event_queue ADD COLUMN ordering_key TEXT NOT NULL DEFAULT '';
+CREATE INDEX event_queue_ordering_key ON event_queue(dry_run, ordering_key, id);
@@ -0,0 +1,6 @@
+CREATE TABLE gateway_sessions(
+  shard_id BIGINT PRIMARY KEY NOT NULL,
+  session_id TEXT NOT NULL,
+  sequence BIGINT NOT NULL,
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
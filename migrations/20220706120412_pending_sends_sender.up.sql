@@ -0,0 +1 @@
+ALTER TABLE pending_sends ADD COLUMN matrix_user_id TEXT;
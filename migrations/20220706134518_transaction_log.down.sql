@@ -0,0 +1,2 @@
+DROP TABLE queued_matrix_events;
+DROP TABLE transaction_log;
@@ -0,0 +1,14 @@
+CREATE TABLE transaction_log(
+  txn_id TEXT NOT NULL,
+  dry_run BOOLEAN NOT NULL DEFAULT FALSE,
+  completed_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (txn_id, dry_run)
+);
+CREATE INDEX transaction_log_completed_at ON transaction_log(completed_at);
+CREATE TABLE queued_matrix_events(
+  event_id TEXT NOT NULL,
+  dry_run BOOLEAN NOT NULL DEFAULT FALSE,
+  queued_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (event_id, dry_run)
+);
+CREATE INDEX queued_matrix_events_queued_at ON queued_matrix_events(queued_at);
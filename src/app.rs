@@ -1,6 +1,7 @@
 //! App
 
 use std::{
+    collections::HashMap,
     str::FromStr,
     sync::{
         atomic::{AtomicBool, Ordering},
@@ -9,12 +10,18 @@ use std::{
     time::Duration,
 };
 
-use crate::{Args, ConfigFile};
+use crate::{
+    features::Feature,
+    retry::{retry, Backoff},
+    secrets::SecretBox,
+    time::{self, Clock, ClockSkew, SkewChange},
+    Args, Command, ConfigFile,
+};
 use anyhow::Result;
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use matrix_sdk::{
-    config::{RequestConfig, StoreConfig, SyncSettings},
-    event_handler::Ctx,
+    config::{RequestConfig, StoreConfig},
+    event_handler::{Ctx, RawEvent},
     room::Room,
     ruma::{
         api::client::{
@@ -25,42 +32,158 @@ use matrix_sdk::{
             uiaa::UserIdentifier,
         },
         events::{
+            reaction::SyncReactionEvent,
+            receipt::ReceiptEventContent,
             room::{
-                member::StrippedRoomMemberEvent,
-                message::{RoomMessageEventContent, SyncRoomMessageEvent},
+                canonical_alias::SyncRoomCanonicalAliasEvent,
+                member::{MembershipState, StrippedRoomMemberEvent, SyncRoomMemberEvent},
+                message::{MessageType, Relation, RoomMessageEventContent, SyncRoomMessageEvent},
+                name::SyncRoomNameEvent,
+                redaction::SyncRoomRedactionEvent,
+                tombstone::SyncRoomTombstoneEvent,
+                topic::SyncRoomTopicEvent,
             },
-            MessageLikeEvent,
+            typing::TypingEventContent,
+            MessageLikeEvent, SyncEphemeralRoomEvent, SyncStateEvent,
         },
-        DeviceId, OwnedDeviceId, OwnedUserId, ServerName, UserId,
+        presence::PresenceState,
+        DeviceId, OwnedDeviceId, OwnedUserId, RoomId, ServerName, UserId,
     },
-    Client, LoopCtrl, Session,
+    Client, Session,
 };
 use matrix_sdk_appservice::{AppService, AppServiceRegistration};
 use sqlx::{
     postgres::{PgConnectOptions, PgSslMode},
     ConnectOptions, PgPool,
 };
-use tokio::{
-    sync::mpsc::{self, UnboundedSender},
-    time::sleep,
-};
+use tokio::sync::{mpsc, Mutex, Notify, OnceCell, Semaphore};
 use tracing::{debug, error, info, log::LevelFilter, warn};
-use twilight_model::id::{marker::UserMarker, Id};
+use twilight_gateway::Event;
+use twilight_model::id::{
+    marker::{ApplicationMarker, ChannelMarker, GuildMarker, MessageMarker, UserMarker},
+    Id,
+};
 
-use self::client::VirtualClient;
+use self::{
+    client::VirtualClient,
+    discord::DiscordBot,
+    event_queue::{queue_matrix_event, MatrixEventKind},
+    instance_lock::InstanceLock,
+    pipeline::Pipeline,
+    portal_settings::PortalSettingsEventContent,
+};
 
+pub mod activities;
+pub mod announce;
+pub mod api_tokens;
+pub mod archival;
+pub mod archive;
+pub mod attachments;
+pub mod audit;
+pub mod banner;
+pub mod bans;
+pub mod capabilities;
+pub mod catalog;
 pub mod client;
+pub mod commands;
+pub mod discord;
+pub mod discord_outbox;
+pub mod double_puppet;
+pub mod echo;
+pub mod edits;
+pub mod emoji;
+pub mod event_queue;
+pub mod event_webhooks;
+pub mod forum_tags;
+pub mod gateway;
+pub mod guild_identity;
+pub mod health;
+pub mod http;
+pub mod instance_lock;
+pub mod integrity;
+pub mod knocks;
+pub mod load_shedding;
+pub mod logout;
+pub mod mappings;
+pub mod media;
+pub mod member_roles;
+pub mod members;
+pub mod mention_dm;
+pub mod mentions;
+pub mod message_map;
 pub mod messages;
+pub mod mscs;
+pub mod names;
+pub mod oauth;
+pub mod outbox;
+pub mod permissions;
+pub mod pipeline;
+pub mod portal_features;
+pub mod portal_settings;
+pub mod portals;
+pub mod power;
+pub mod profiles;
+pub mod provisioning;
+pub mod reactions;
+pub mod receipts;
+pub mod redactions;
+pub mod replies;
+pub mod room_metadata;
+pub mod settings;
+pub mod slash_commands;
+pub mod spaces;
+pub mod threads;
+pub mod token_watchdog;
+pub mod topic;
+pub mod transactions;
+pub mod typing;
+pub mod upgrade;
+pub mod uploads;
+pub mod webhook_puppets;
+pub mod webhooks;
+pub mod whois;
+
+/// Interval in which the main task checks whether the bridge should shut down
+const QUIT_POLL_INTERVAL: Duration = Duration::from_millis(250);
 
 /// Queue events that need to be handled
 #[derive(Clone, Debug)]
 enum QueueEvent {
-    /// Close request sent
-    Close,
     /// Matrix room member event
     RoomMemberEvent(Box<(StrippedRoomMemberEvent, Room)>),
+    /// Matrix membership change in a joined room
+    RoomMembershipEvent(Box<(SyncRoomMemberEvent, Room)>),
     /// Matrix message event
     RoomMessageEvent(Box<(SyncRoomMessageEvent, Room)>),
+    /// Matrix room upgrade event
+    RoomTombstoneEvent(Box<(SyncRoomTombstoneEvent, Room)>),
+    /// Matrix canonical alias change
+    RoomCanonicalAliasEvent(Box<(SyncRoomCanonicalAliasEvent, Room)>),
+    /// Matrix room name change
+    RoomNameEvent(Box<(SyncRoomNameEvent, Room)>),
+    /// Matrix room topic change
+    RoomTopicEvent(Box<(SyncRoomTopicEvent, Room)>),
+    /// Matrix reaction event
+    ReactionEvent(Box<(SyncReactionEvent, Room)>),
+    /// Matrix read receipts
+    ReceiptEvent(
+        Box<(
+            SyncEphemeralRoomEvent<ReceiptEventContent>,
+            Room,
+            receipts::ReceiptThreads,
+        )>,
+    ),
+    /// Matrix typing notifications
+    TypingEvent(Box<(SyncEphemeralRoomEvent<TypingEventContent>, Room)>),
+    /// Matrix redaction event
+    RedactionEvent(Box<(SyncRoomRedactionEvent, Room)>),
+    /// Matrix portal settings change
+    PortalSettingsEvent(Box<(SyncStateEvent<PortalSettingsEventContent>, Room)>),
+    /// Discord gateway event
+    DiscordEvent(Box<Event>),
+    /// End of the events of a batch from the homeserver received at the given time in
+    /// milliseconds since the unix epoch
+    TransactionEnd(u64),
 }
 
 /// Application entrypoint
@@ -72,17 +195,96 @@ pub struct App {
     appservice: AppService,
     /// Database
     db: Arc<PgPool>,
-    /// Event queue
-    queue: UnboundedSender<QueueEvent>,
+    /// Wakes up the event queue worker when an event was stored
+    queue_notify: Notify,
+    /// Whether the event queue stopped accepting events
+    queue_closed: AtomicBool,
+    /// Signals that the event queue worker stopped after the queue was closed
+    queue_stopped: Notify,
+    /// Limits the number of queued events handled concurrently
+    queue_permits: Arc<Semaphore>,
+    /// Queued events that are being handled
+    queue_claimed: DashSet<i64>,
+    /// Events of transactions in progress that the event handlers failed to queue
+    failed_events: DashSet<String>,
+    /// Pressure on the event queue
+    load: load_shedding::LoadShedder,
+    /// Events and messages recently sent by the bridge
+    echoes: echo::EchoGuard,
+    /// Reconnects of the discord gateway
+    gateway: gateway::GatewayMonitor,
     /// discordbot client
     client: Arc<VirtualClient>,
     /// Client for discord users
     discord_clients: DashMap<Id<UserMarker>, Arc<VirtualClient>>,
+    /// Discord clients of matrix users logged in with their own account
+    discord_user_http: DashMap<OwnedUserId, Arc<twilight_http::Client>>,
+    /// OAuth2 logins waiting for discord's redirect, by state
+    oauth_logins: DashMap<String, oauth::PendingLogin>,
+    /// Latest message each matrix user read in a discord channel
+    read_markers: DashMap<(OwnedUserId, Id<ChannelMarker>), Id<MessageMarker>>,
+    /// Presence of matrix users from the EDUs of appservice transactions
+    presence: DashMap<OwnedUserId, PresenceState>,
+    /// Clients of webhook puppets by localpart
+    webhook_clients: DashMap<String, Arc<VirtualClient>>,
     /// discordbot user id
     user_id: OwnedUserId,
+    /// Whether outgoing messages are only logged instead of sent
+    dry_run: bool,
+    /// Discord bridge bot
+    discord: Option<DiscordBot>,
+    /// HTTP client for media downloads
+    http: reqwest::Client,
+    /// Application id of the discord bot
+    application_id: OnceCell<Id<ApplicationMarker>>,
+    /// MSCs whose code paths are active, known after negotiation with the homeserver
+    mscs: OnceCell<Vec<mscs::Msc>>,
+    /// Pipeline for homeserver requests
+    pipeline: Pipeline,
+    /// Encryption of secrets stored in the database, if a secret key is configured
+    secrets: Option<SecretBox>,
+    /// Offsets of the homeserver and discord clocks
+    clock_skew: ClockSkew,
+    /// Whether sends are queued because the homeserver is unreachable
+    outbox_paused: AtomicBool,
+    /// Whether messages to discord are queued because discord is unreachable
+    discord_outbox_paused: AtomicBool,
+    /// Whether discord rejected the bot token at the last validation
+    bot_token_invalid: AtomicBool,
+    /// Serializes the delivery of each channel's queue of messages to discord
+    discord_outbox_locks: DashMap<Id<ChannelMarker>, Arc<Mutex<()>>>,
+    /// Serializes the creation of each channel's portal
+    portal_creation_locks: DashMap<Id<ChannelMarker>, Arc<Mutex<()>>>,
+    /// Serializes the creation of each guild's space
+    space_creation_locks: DashMap<Id<GuildMarker>, Arc<Mutex<()>>>,
+    /// Lock preventing a second instance from running, not taken in dry-run mode
+    _instance_lock: Option<InstanceLock>,
 }
 
 impl App {
+    /// Returns the encryption of secrets stored in the database
+    ///
+    /// # Errors
+    /// This function will return an error if no secret key is configured
+    pub fn secrets(&self) -> Result<&SecretBox> {
+        self.secrets
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No secret_key configured"))
+    }
+
+    /// Records a timestamp of the homeserver or discord and warns when their clocks drift apart
+    fn observe_clock(&self, clock: Clock, timestamp: u64) {
+        match self.clock_skew.observe(clock, timestamp, time::now_ms()) {
+            Some(SkewChange::Exceeded(skew)) => warn!(
+                "The homeserver clock is {} ms {} discord's, message order and time-based deduplication may be off",
+                skew.saturating_abs(),
+                if skew > 0 { "ahead of" } else { "behind" }
+            ),
+            Some(SkewChange::Recovered) => info!("The homeserver and discord clocks agree again"),
+            None => {}
+        }
+    }
+
     /// Returns the device id or creates a new one
     async fn device_id(self: &Arc<Self>) -> Result<OwnedDeviceId> {
         let device_id = self.client.store().get_custom_value(b"device_id").await?;
@@ -131,7 +333,7 @@ impl App {
         }
     }
     /// Retrieve connection options from a config file
-    fn get_connect_options(config: &ConfigFile) -> PgConnectOptions {
+    pub(crate) fn get_connect_options(config: &ConfigFile) -> PgConnectOptions {
         let mut conn_opt = PgConnectOptions::new();
 
         if let Some(ref host) = config.bridge.db.host {
@@ -174,7 +376,18 @@ impl App {
             conn_opt = conn_opt.extra_float_digits(Some(extra_float_digits));
         }
         conn_opt = conn_opt.options(config.bridge.db.options.clone());
-        conn_opt.log_statements(LevelFilter::Debug);
+        conn_opt
+            .log_statements(LevelFilter::Debug)
+            .log_slow_statements(
+                LevelFilter::Warn,
+                Duration::from_millis(
+                    config
+                        .bridge
+                        .db
+                        .slow_query_ms
+                        .unwrap_or(crate::db_trace::DEFAULT_SLOW_QUERY_MS),
+                ),
+            );
         conn_opt
     }
 
@@ -188,7 +401,20 @@ impl App {
         let registration = AppServiceRegistration::try_from_yaml_file(&args.registration)?;
 
         debug!("Connecting to database");
-        let db = Arc::new(PgPool::connect_with(Self::get_connect_options(config)).await?);
+        let connect_options = Self::get_connect_options(config);
+        let db = Arc::new(
+            retry("database", Backoff::default(), || {
+                PgPool::connect_with(connect_options.clone())
+            })
+            .await?,
+        );
+
+        let dry_run = matches!(args.subcommand, Command::Start { dry_run: true });
+        let instance_lock = if matches!(args.subcommand, Command::Start { dry_run: false }) {
+            Some(InstanceLock::acquire(&db, &registration.id).await?)
+        } else {
+            None
+        };
 
         sqlx::migrate!().set_ignore_missing(true).run(&*db).await?;
 
@@ -206,10 +432,11 @@ impl App {
             .assert_identity();
 
         debug!("Creating appservice instance");
-        let appservice = AppService::new(
+        let appservice = AppService::new_with_config(
             config.homeserver.address.as_str(),
             config.homeserver.domain.clone(),
             registration,
+            client_builder,
         )
         .await?;
 
@@ -221,20 +448,95 @@ impl App {
             <&ServerName>::try_from(config.homeserver.domain.as_str())?,
         )?;
 
-        let client = client_builder.build().await?;
-
-        let (sender, mut receiver) = mpsc::unbounded_channel();
+        // The main client of the appservice is the client of the bridge bot
+        let client = appservice.get_cached_client(None)?;
+
+        let (alert_sender, alert_receiver) = mpsc::unbounded_channel();
+
+        let (discord, discord_events) = if let Some(ref token) = config.discord.bot_token {
+            debug!("Connecting to discord");
+            // Only the instance holding the lock resumes the sessions, dry runs and one-off
+            // subcommands must not take them from the instance they run alongside
+            let sessions = if instance_lock.is_none() {
+                HashMap::new()
+            } else {
+                gateway::take_gateway_sessions(&db)
+                    .await
+                    .unwrap_or_else(|e| {
+                        warn!("Failed to load the gateway sessions to resume: {:?}", e);
+                        HashMap::new()
+                    })
+            };
+            if !sessions.is_empty() {
+                info!("Resuming {} gateway sessions", sessions.len());
+            }
+            let (discord, events) = retry("discord", gateway::CONNECT_BACKOFF, || {
+                DiscordBot::new(token.clone(), sessions.clone())
+            })
+            .await?;
+            (Some(discord), Some(events))
+        } else {
+            warn!("No discord bot token configured");
+            (None, None)
+        };
+
+        banner::log_startup_summary(
+            config,
+            appservice.registration(),
+            discord
+                .as_ref()
+                .map(|discord: &DiscordBot| discord.cluster.shards().count()),
+            dry_run,
+        );
 
         let arc = Arc::new(Self {
             config: config.clone(),
             appservice,
             db,
-            queue: sender,
+            queue_notify: Notify::new(),
+            queue_closed: AtomicBool::new(false),
+            queue_stopped: Notify::new(),
+            queue_permits: Arc::new(Semaphore::new(config.bridge.event_parallelism.max(1))),
+            queue_claimed: DashSet::new(),
+            failed_events: DashSet::new(),
+            load: load_shedding::LoadShedder::default(),
+            echoes: echo::EchoGuard::default(),
+            gateway: gateway::GatewayMonitor::default(),
             client: Arc::new(VirtualClient::new(client)),
             discord_clients: DashMap::new(),
+            discord_user_http: DashMap::new(),
+            oauth_logins: DashMap::new(),
+            read_markers: DashMap::new(),
+            presence: DashMap::new(),
+            webhook_clients: DashMap::new(),
             user_id,
+            dry_run,
+            discord,
+            http: reqwest::Client::new(),
+            application_id: OnceCell::new(),
+            mscs: OnceCell::new(),
+            pipeline: Pipeline::new(config.bridge.homeserver_parallelism, alert_sender),
+            secrets: config
+                .bridge
+                .secret_key
+                .as_deref()
+                .map(SecretBox::new)
+                .transpose()?,
+            clock_skew: ClockSkew::default(),
+            outbox_paused: AtomicBool::new(false),
+            discord_outbox_paused: AtomicBool::new(false),
+            bot_token_invalid: AtomicBool::new(false),
+            discord_outbox_locks: DashMap::new(),
+            portal_creation_locks: DashMap::new(),
+            space_creation_locks: DashMap::new(),
+            _instance_lock: instance_lock,
         });
 
+        if arc.dry_run {
+            warn!("Running in dry-run mode, no messages will be sent");
+        }
+        arc.spawn_pipeline_alerts(alert_receiver);
+
         arc.try_register_user(&discordbot_name).await?;
 
         arc.client(None)
@@ -242,39 +544,102 @@ impl App {
             .restore_login(arc.client_session().await?)
             .await?;
 
-        let arc2 = Arc::clone(&arc);
-        tokio::spawn(async move {
-            while let Some(event) = receiver.recv().await {
-                let arc = Arc::clone(&arc2);
-                if let QueueEvent::Close = event {
-                    debug!("Closing queue");
-                    receiver.close();
-                }
-                let err = match tokio::spawn(async move { arc.handle_event(event).await }).await {
-                    Ok(Ok(())) => continue,
-                    Ok(Err(e)) => e,
-                    Err(e) => e.into(),
-                };
-                sentry::integrations::anyhow::capture_anyhow(&err);
-                eprintln!("{:?}", err);
-            }
-            info!("Shutting down queue runner");
-        });
+        // One-off subcommands run next to the bridge without its instance lock, so they must leave
+        // its event queue alone
+        if !matches!(args.subcommand, Command::Start { .. }) {
+            return Ok(arc);
+        }
+
+        arc.spawn_event_queue_worker();
+
+        if let Some(events) = discord_events {
+            arc.spawn_discord_event_loop(events);
+        }
 
         arc.client(None)
             .await?
             .register_event_handler_context(Arc::downgrade(&arc))
             .register_event_handler(
-                |event: StrippedRoomMemberEvent, room: Room, Ctx(this): Ctx<Weak<Self>>| async move {
-                    this.queue(QueueEvent::RoomMemberEvent(Box::new((event, room))))
+                |_: StrippedRoomMemberEvent,
+                 room: Room,
+                 raw: RawEvent,
+                 Ctx(this): Ctx<Weak<Self>>| {
+                    queue_matrix_event(this, MatrixEventKind::StrippedMember, room, raw)
                 },
             )
             .await
             .register_event_handler(
-                |event: SyncRoomMessageEvent,
+                |_: SyncRoomMemberEvent, room: Room, raw: RawEvent, Ctx(this): Ctx<Weak<Self>>| {
+                    queue_matrix_event(this, MatrixEventKind::Member, room, raw)
+                },
+            )
+            .await
+            .register_event_handler(
+                |_: SyncRoomMessageEvent, room: Room, raw: RawEvent, Ctx(this): Ctx<Weak<Self>>| {
+                    queue_matrix_event(this, MatrixEventKind::Message, room, raw)
+                },
+            )
+            .await
+            .register_event_handler(
+                |_: SyncRoomTombstoneEvent,
+                 room: Room,
+                 raw: RawEvent,
+                 Ctx(this): Ctx<Weak<Self>>| {
+                    queue_matrix_event(this, MatrixEventKind::Tombstone, room, raw)
+                },
+            )
+            .await
+            .register_event_handler(
+                |_: SyncRoomCanonicalAliasEvent,
+                 room: Room,
+                 raw: RawEvent,
+                 Ctx(this): Ctx<Weak<Self>>| {
+                    queue_matrix_event(this, MatrixEventKind::CanonicalAlias, room, raw)
+                },
+            )
+            .await
+            .register_event_handler(
+                |_: SyncRoomNameEvent, room: Room, raw: RawEvent, Ctx(this): Ctx<Weak<Self>>| {
+                    queue_matrix_event(this, MatrixEventKind::Name, room, raw)
+                },
+            )
+            .await
+            .register_event_handler(
+                |_: SyncRoomTopicEvent, room: Room, raw: RawEvent, Ctx(this): Ctx<Weak<Self>>| {
+                    queue_matrix_event(this, MatrixEventKind::Topic, room, raw)
+                },
+            )
+            .await
+            .register_event_handler(
+                |_: SyncReactionEvent, room: Room, raw: RawEvent, Ctx(this): Ctx<Weak<Self>>| {
+                    queue_matrix_event(this, MatrixEventKind::Reaction, room, raw)
+                },
+            )
+            .await
+            .register_event_handler(
+                |_: SyncEphemeralRoomEvent<ReceiptEventContent>,
+                 room: Room,
+                 raw: RawEvent,
+                 Ctx(this): Ctx<Weak<Self>>| {
+                    queue_matrix_event(this, MatrixEventKind::Receipt, room, raw)
+                },
+            )
+            .await
+            .register_event_handler(
+                |_: SyncRoomRedactionEvent,
                  room: Room,
-                 Ctx(this): Ctx<Weak<Self>>| async move {
-                     this.queue(QueueEvent::RoomMessageEvent(Box::new((event, room))))
+                 raw: RawEvent,
+                 Ctx(this): Ctx<Weak<Self>>| {
+                    queue_matrix_event(this, MatrixEventKind::Redaction, room, raw)
+                },
+            )
+            .await
+            .register_event_handler(
+                |_: SyncStateEvent<PortalSettingsEventContent>,
+                 room: Room,
+                 raw: RawEvent,
+                 Ctx(this): Ctx<Weak<Self>>| {
+                    queue_matrix_event(this, MatrixEventKind::PortalSettings, room, raw)
                 },
             )
             .await;
@@ -284,13 +649,56 @@ impl App {
     /// Internal queue event handler
     async fn handle_event(self: &Arc<Self>, event: QueueEvent) -> Result<()> {
         match event {
-            QueueEvent::Close => {}
             QueueEvent::RoomMemberEvent(content) => {
                 self.handle_room_member_event(content.1, content.0).await?;
             }
+            QueueEvent::RoomMembershipEvent(content) => {
+                self.handle_transaction_invite(&content.0, &content.1).await;
+                self.emit_matrix_ban(content.1.room_id(), &content.0)
+                    .await?;
+                self.handle_matrix_ban(content.1.room_id(), &content.0)
+                    .await?;
+                self.handle_room_membership_event(content.0, content.1)
+                    .await?;
+            }
             QueueEvent::RoomMessageEvent(content) => {
                 self.handle_room_message_event(content.0, content.1).await?;
             }
+            QueueEvent::RoomTombstoneEvent(content) => {
+                self.handle_room_tombstone_event(content.0, content.1)
+                    .await?;
+            }
+            QueueEvent::RoomCanonicalAliasEvent(content) => {
+                self.handle_room_canonical_alias_event(content.0, content.1)
+                    .await?;
+            }
+            QueueEvent::RoomNameEvent(content) => {
+                self.handle_room_name_event(content.0, content.1).await?;
+            }
+            QueueEvent::RoomTopicEvent(content) => {
+                self.handle_room_topic_event(content.0, content.1).await?;
+            }
+            QueueEvent::ReactionEvent(content) => {
+                self.handle_reaction_event(content.0, content.1).await?;
+            }
+            QueueEvent::ReceiptEvent(content) => {
+                self.handle_receipt_event(content.0, content.1, content.2)
+                    .await?;
+            }
+            QueueEvent::TypingEvent(content) => {
+                self.handle_typing_event(content.0, content.1).await?;
+            }
+            QueueEvent::RedactionEvent(content) => {
+                self.handle_redaction_event(content.0, content.1).await?;
+            }
+            QueueEvent::PortalSettingsEvent(content) => {
+                self.handle_portal_settings_event(content.0, content.1)
+                    .await?;
+            }
+            QueueEvent::DiscordEvent(event) => {
+                self.handle_discord_event(*event).await?;
+            }
+            QueueEvent::TransactionEnd(received) => self.finish_transaction(received),
         }
         Ok(())
     }
@@ -302,22 +710,61 @@ impl App {
     pub async fn run(self: &Arc<Self>) -> Result<()> {
         let quit = Arc::new(AtomicBool::new(false));
         signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&quit))?;
-        self.client(None)
-            .await?
-            .sync_with_callback(SyncSettings::default(), |_| {
-                let quit = Arc::clone(&quit);
-                async move {
-                    if quit.load(Ordering::Relaxed) {
-                        LoopCtrl::Break
-                    } else {
-                        LoopCtrl::Continue
-                    }
-                }
-            })
-            .await;
+        signal_hook::flag::register(signal_hook::consts::SIGTERM, Arc::clone(&quit))?;
+        self.negotiate_mscs().await;
+        if let Err(e) = self.announce_upgrade().await {
+            error!("Failed to announce the upgrade: {:?}", e);
+        }
+        self.spawn_message_map_maintenance();
+        self.spawn_media_cleanup();
+        self.spawn_transaction_log_pruning();
+        self.spawn_token_watchdog();
+        self.spawn_http_listener();
+        self.spawn_integrity_check();
+        self.spawn_catalog_refresh();
+        if let Err(e) = self.accept_pending_invites().await {
+            error!("Failed to process pending invites: {:?}", e);
+        }
+        if let Err(e) = self.restore_portal_settings().await {
+            error!("Failed to restore portal settings: {:?}", e);
+        }
+        match self.replay_pending_sends().await {
+            Ok(true) => {}
+            Ok(false) => self.pause_outbox(),
+            Err(e) => error!("Failed to replay interrupted sends: {:?}", e),
+        }
+        if let Some(ref discord) = self.discord {
+            discord.cluster.up().await;
+            if let Err(e) = self.apply_guild_identities().await {
+                error!("Failed to apply guild identities: {:?}", e);
+            }
+            if let Err(e) = self.sync_global_commands().await {
+                error!("Failed to register slash commands: {:?}", e);
+            }
+            match self.deliver_discord_queues().await {
+                Ok(true) => {}
+                Ok(false) => self.pause_discord_outbox(),
+                Err(e) => error!("Failed to deliver queued messages to discord: {:?}", e),
+            }
+        }
+        while !quit.load(Ordering::Relaxed) {
+            tokio::time::sleep(QUIT_POLL_INTERVAL).await;
+        }
 
         info!("Shutting down");
-        self.queue.send(QueueEvent::Close)?;
+        if let Some(ref discord) = self.discord {
+            // Closing the sessions as resumable lets discord replay missed events after a restart
+            let sessions = discord.cluster.down_resumable();
+            debug!("Closed {} resumable gateway sessions", sessions.len());
+            if let Err(e) = self.save_gateway_sessions(sessions).await {
+                error!("Failed to store the gateway sessions: {:?}", e);
+            }
+        }
+        self.close_queue();
+        self.drain_queue(Duration::from_secs(
+            self.config.bridge.shutdown_timeout_secs,
+        ))
+        .await;
 
         Ok(())
     }
@@ -337,29 +784,57 @@ impl App {
             return Ok(());
         }
         if let Room::Invited(room) = room {
-            info!("Autojoining room {}", room.room_id());
-            let mut delay = 2;
-
-            while let Err(err) = room.accept_invitation().await {
-                // retry autojoin due to synapse sending invites, before the
-                // invited user can join for more information see
-                // https://github.com/matrix-org/synapse/issues/4345
-                warn!(
-                    "Failed to join room {} ({:?}), retrying in {}s",
-                    room.room_id(),
-                    err,
-                    delay
-                );
-
-                sleep(Duration::from_secs(delay)).await;
-                delay *= 2;
+            self.accept_invite(room.room_id()).await;
+        }
+        Ok(())
+    }
 
-                if delay > 8 {
-                    error!("Can't join room {} ({:?})", room.room_id(), err);
-                    break;
-                }
+    /// Handles an invite of the bridge bot delivered in an appservice transaction
+    ///
+    /// Transactions carry invites as regular member events instead of stripped state.
+    async fn handle_transaction_invite(self: &Arc<Self>, event: &SyncRoomMemberEvent, room: &Room) {
+        if let SyncStateEvent::Original(event) = event {
+            if event.state_key == self.user_id
+                && event.content.membership == MembershipState::Invite
+            {
+                self.accept_invite(room.room_id()).await;
             }
-            info!("Successfully joined room {}", room.room_id());
+        }
+    }
+
+    /// Joins a room the bridge bot was invited to
+    async fn accept_invite(self: &Arc<Self>, room_id: &RoomId) {
+        if self.dry_run {
+            info!("[dry-run] Would autojoin room {}", room_id);
+            return;
+        }
+        info!("Autojoining room {}", room_id);
+        // retry autojoin due to synapse sending invites, before the
+        // invited user can join for more information see
+        // https://github.com/matrix-org/synapse/issues/4345
+        let backoff =
+            Backoff::new(Duration::from_secs(2), Duration::from_secs(8)).with_max_attempts(4);
+        match retry("matrix", backoff, || {
+            Client::join_room_by_id(&self.client, room_id)
+        })
+        .await
+        {
+            Ok(_) => info!("Successfully joined room {}", room_id),
+            Err(err) => error!("Can't join room {} ({:?})", room_id, err),
+        }
+    }
+
+    /// Processes invites that are pending from before the bridge was started
+    ///
+    /// Invites are only handled as live events otherwise, so invites that arrived while the
+    /// bridge was offline or that were interrupted by a restart would be left pending.
+    async fn accept_pending_invites(self: &Arc<Self>) -> Result<()> {
+        let invited = self.client(None).await?.invited_rooms();
+        if !invited.is_empty() {
+            info!("Processing {} pending invites", invited.len());
+        }
+        for room in invited {
+            self.accept_invite(room.room_id()).await;
         }
         Ok(())
     }
@@ -371,30 +846,99 @@ impl App {
         sender: &UserId,
         args: Vec<&str>,
         room: Room,
+        management: bool,
     ) -> Result<()> {
-        #[allow(clippy::single_match)]
         match args.first() {
-            Some(&"unregister") => {
-                self.unregister_user(sender).await?;
+            Some(&"unregister" | &"logout") => {
+                // The logout is confirmed in the management room
+                if !self.logout_user(sender, "unregistered").await? {
+                    let content =
+                        RoomMessageEventContent::text_plain("No discord account is registered");
+                    self.send_message(&room, content).await?;
+                }
+            }
+            Some(&"register" | &"login") if !management => {
                 let content = RoomMessageEventContent::text_plain(
-                    "Successfully unregistered discord account",
+                    "Log in from a direct message with the bridge bot, your token is visible to everyone in this room",
                 );
-                if let Room::Joined(room) = room {
-                    room.send(content, None).await?;
-                }
+                self.send_message(&room, content).await?;
             }
-            Some(&"register") => {
-                if args.len() >= 2 {
+            Some(&"register" | &"login") => {
+                if args.len() < 2 {
+                    let content = match self.start_oauth_login(sender, room.room_id()).await? {
+                        Some(url) => RoomMessageEventContent::text_plain(format!(
+                            "Log in with discord at {} within 10 minutes",
+                            url
+                        )),
+                        None => RoomMessageEventContent::text_plain("Usage: login <token>"),
+                    };
+                    self.send_message(&room, content).await?;
+                } else {
                     self.register_user(sender, room.room_id(), args[1]).await?;
                     let content = RoomMessageEventContent::text_plain(
-                        "Successfully registered discord account",
+                        "Successfully registered discord account, your messages are now sent from it",
+                    );
+                    self.send_message(&room, content).await?;
+                }
+            }
+            Some(&"audit") => {
+                self.handle_audit_command(sender, &args, &room).await?;
+            }
+            Some(&"bans") => {
+                self.handle_bans_command(sender, &args, &room).await?;
+            }
+            Some(&"announce") => {
+                self.handle_announce_command(sender, &args, &room).await?;
+            }
+            Some(&"api-token") if !management => {
+                let content = RoomMessageEventContent::text_plain(
+                    "Manage API tokens from a direct message with the bridge bot, new tokens are visible to everyone in this room",
+                );
+                self.send_message(&room, content).await?;
+            }
+            Some(&"api-token") => {
+                self.handle_api_token_command(sender, &args, &room).await?;
+            }
+            Some(&"webhook") => {
+                self.handle_webhook_command(sender, &args, &room).await?;
+            }
+            Some(&"whois") => {
+                self.handle_whois_command(sender, &args, &room).await?;
+            }
+            Some(&"integrity") => {
+                self.handle_integrity_command(sender, &room).await?;
+            }
+            Some(&"purge-media") => {
+                self.handle_purge_media_command(sender, &args, &room)
+                    .await?;
+            }
+            Some(&"feature") => {
+                self.handle_feature_command(sender, &args, &room).await?;
+            }
+            Some(&"locale") => {
+                self.handle_locale_command(sender, &args, &room).await?;
+            }
+            Some(&"metadata-sync") => {
+                self.handle_metadata_sync_command(sender, &args, &room)
+                    .await?;
+            }
+            Some(&"privacy" | &"pseudonym" | &"pseudonym-avatar" | &"mention-dm") => {
+                self.handle_privacy_command(sender, &args, &room).await?;
+            }
+            _ => {
+                if self.handle_management_command(sender, &args, &room).await? {
+                    return Ok(());
+                }
+                if let Some(reply) = self.shared_command_reply(&args).await? {
+                    self.send_message(&room, RoomMessageEventContent::text_plain(reply))
+                        .await?;
+                } else if management {
+                    let content = RoomMessageEventContent::text_plain(
+                        "Unknown command, send `help` for a list of commands",
                     );
-                    if let Room::Joined(room) = room {
-                        room.send(content, None).await?;
-                    }
+                    self.send_message(&room, content).await?;
                 }
             }
-            _ => {}
         }
         Ok(())
     }
@@ -407,32 +951,51 @@ impl App {
         room: Room,
     ) -> Result<()> {
         let event = event.into_full_event(room.room_id().to_owned());
+        self.observe_clock(Clock::Homeserver, event.origin_server_ts().get().into());
         if let MessageLikeEvent::Original(o) = event {
-            if o.content.body().starts_with("!discord") {
-                let content = o.content.body();
-                let mut parts = content.split_whitespace();
-                parts.next();
-                let args = parts.collect::<Vec<_>>();
-                return self.handle_command(&o.sender, args, room).await;
+            if self.is_matrix_echo(&o.sender, &o.event_id).await? {
+                debug!("Not bridging {}, the bridge sent it", o.event_id);
+                return Ok(());
             }
+            if self
+                .handle_command_message(&o.sender, o.content.body(), room.clone())
+                .await?
+            {
+                return Ok(());
+            }
+            let formatted = match &o.content.msgtype {
+                MessageType::Text(text) => text.formatted.as_ref(),
+                MessageType::Notice(notice) => notice.formatted.as_ref(),
+                MessageType::Emote(emote) => emote.formatted.as_ref(),
+                _ => None,
+            };
+            if let Err(e) = self
+                .handle_mentions(
+                    &o.room_id,
+                    &o.event_id,
+                    &o.sender,
+                    o.content.body(),
+                    formatted.map(|formatted| formatted.body.as_str()),
+                )
+                .await
+            {
+                warn!("Failed to handle mentions in {}: {:?}", o.event_id, e);
+            }
+            if let Some(Relation::Replacement(ref replacement)) = o.content.relates_to {
+                if !self.room_feature(&o.room_id, Feature::Edits).await? {
+                    return Ok(());
+                }
+                self.edit_on_discord(
+                    &replacement.event_id,
+                    &o.sender,
+                    &self.discord_markdown(&replacement.new_content).await?,
+                )
+                .await?;
+                return Ok(());
+            }
+            self.relay_matrix_message(&o.room_id, &o.event_id, &o.sender, &o.content)
+                .await?;
         }
         Ok(())
     }
 }
-
-/// Helper trait used for enqueueing events
-trait EnqueueEvent {
-    /// Queue an event
-    fn queue(&self, event: QueueEvent) -> Result<()>;
-}
-
-impl EnqueueEvent for Weak<App> {
-    fn queue(&self, event: QueueEvent) -> Result<()> {
-        self.upgrade()
-            .ok_or_else(|| anyhow::anyhow!("Application is shutting down"))?
-            .queue
-            .send(event)?;
-
-        Ok(())
-    }
-}
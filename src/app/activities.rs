@@ -0,0 +1,132 @@
+//! Discord activity invites
+//!
+//! Activities like watch-together or embedded apps can't be joined from matrix, so they are
+//! bridged as notices that say what was started and link to the message on discord.
+
+use std::sync::Arc;
+
+use super::{portals::Portal, replies::message_link, App};
+use crate::html;
+use anyhow::Result;
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use tracing::debug;
+use twilight_model::channel::{message::MessageActivityType, Message};
+
+/// Returns the verb describing an activity invite
+const fn activity_verb(kind: MessageActivityType) -> &'static str {
+    match kind {
+        MessageActivityType::Join => "invited everyone to join",
+        MessageActivityType::Spectate => "invited everyone to watch",
+        MessageActivityType::Listen => "invited everyone to listen along to",
+        MessageActivityType::JoinRequest => "asked to join",
+    }
+}
+
+/// Builds the plain and HTML body of an activity notice
+///
+/// `application` is the name and description of the embedded application, if any.
+fn activity_notice(
+    author: &str,
+    kind: MessageActivityType,
+    application: Option<(&str, &str)>,
+    link: &str,
+) -> (String, String) {
+    let verb = activity_verb(kind);
+    let (name, description) = application.unwrap_or(("an activity", ""));
+    let (plain_description, formatted_description) = if description.is_empty() {
+        (String::new(), String::new())
+    } else {
+        (
+            format!(" ({})", description),
+            format!(" <i>({})</i>", html::escape(description)),
+        )
+    };
+    let plain = format!(
+        "{} {} {}{}. Join on discord: {}",
+        author, verb, name, plain_description, link
+    );
+    let formatted = format!(
+        "<b>{}</b> {} <b>{}</b>{}. <a href=\"{}\">Join on discord</a>",
+        html::escape(author),
+        verb,
+        html::escape(name),
+        formatted_description,
+        html::escape(link)
+    );
+    (plain, formatted)
+}
+
+impl App {
+    /// Bridges the activity invite of a discord message, if it has one
+    ///
+    /// Invites sent through other bots' webhooks are sent by the webhook puppet.
+    ///
+    /// # Errors
+    /// This function will return an error if sending the notice fails
+    pub(super) async fn bridge_activity(
+        self: &Arc<Self>,
+        message: &Message,
+        portal: &Portal,
+    ) -> Result<()> {
+        let activity = match message.activity {
+            Some(ref activity) => activity,
+            None => return Ok(()),
+        };
+        let room = match self.webhook_puppet(message, portal).await? {
+            Some(puppet) => Some(puppet.join_room_by_id(&portal.room_id).await?),
+            None => self.client.get_room(&portal.room_id),
+        };
+        let room = match room {
+            Some(room) => room,
+            None => {
+                debug!("Not in portal room {}", portal.room_id);
+                return Ok(());
+            }
+        };
+        let link = message_link(message.guild_id, message.channel_id, message.id);
+        let application = message
+            .application
+            .as_ref()
+            .map(|application| (application.name.as_str(), application.description.as_str()));
+        let (plain, formatted) =
+            activity_notice(&message.author.name, activity.kind, application, &link);
+        self.send_message(
+            &room,
+            RoomMessageEventContent::notice_html(plain, formatted),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_activity_notices() {
+        let (plain, formatted) = activity_notice(
+            "Alice <3",
+            MessageActivityType::Spectate,
+            Some(("Watch Together", "")),
+            "https://discord.com/channels/1/2/3",
+        );
+        assert_eq!(
+            plain,
+            "Alice <3 invited everyone to watch Watch Together. Join on discord: https://discord.com/channels/1/2/3"
+        );
+        assert_eq!(
+            formatted,
+            "<b>Alice &lt;3</b> invited everyone to watch <b>Watch Together</b>. <a href=\"https://discord.com/channels/1/2/3\">Join on discord</a>"
+        );
+    }
+
+    #[test]
+    fn activities_without_application() {
+        let (plain, _) = activity_notice("Bob", MessageActivityType::Join, None, "link");
+        assert_eq!(
+            plain,
+            "Bob invited everyone to join an activity. Join on discord: link"
+        );
+    }
+}
@@ -0,0 +1,240 @@
+//! Announcements broadcast to portal rooms
+//!
+//! The bridge admin can send a notice to every portal room, or to the portals of a single
+//! guild, for example before maintenance. Sends are spaced out to avoid hitting rate limits.
+//!
+//! Single messages can also be sent to one portal room or discord channel from the command line,
+//! for example from cron jobs.
+
+use std::{sync::Arc, time::Duration};
+
+use super::{portals::Portal, App};
+use anyhow::{anyhow, Result};
+use matrix_sdk::{
+    room::Room,
+    ruma::{events::room::message::RoomMessageEventContent, RoomOrAliasId, UserId},
+};
+use tracing::{info, warn};
+use twilight_model::id::{
+    marker::{ChannelMarker, GuildMarker},
+    Id,
+};
+
+/// Delay between two announcement messages
+const SEND_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Parsed `announce` command
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Announcement {
+    /// Only announce in the portals of this guild
+    guild_id: Option<Id<GuildMarker>>,
+    /// Message template
+    template: String,
+}
+
+/// Parses the arguments of the `announce` command
+fn parse_announcement(args: &[&str]) -> Result<Announcement> {
+    let (guild_id, message) = match args {
+        ["announce", "--guild", guild_id, message @ ..] => {
+            let guild_id = guild_id
+                .parse()
+                .ok()
+                .and_then(Id::new_checked)
+                .ok_or_else(|| anyhow!("Invalid guild id {:?}", guild_id))?;
+            (Some(guild_id), message)
+        }
+        ["announce", message @ ..] => (None, message),
+        _ => return Err(anyhow!("Not an announce command")),
+    };
+    if message.is_empty() {
+        return Err(anyhow!("Usage: announce [--guild <guild id>] <message>"));
+    }
+    Ok(Announcement {
+        guild_id,
+        template: message.join(" "),
+    })
+}
+
+/// Fills in the placeholders of an announcement for a portal
+///
+/// Supported placeholders are `{room}`, `{channel}` and `{guild}`.
+fn render_announcement(template: &str, portal: &Portal) -> String {
+    let room = portal
+        .alias
+        .as_ref()
+        .map_or_else(|| portal.room_id.to_string(), ToString::to_string);
+    let guild = portal
+        .guild_id
+        .map_or_else(|| "unknown".to_owned(), |id| id.to_string());
+    template
+        .replace("{room}", &room)
+        .replace("{channel}", &portal.channel_id.to_string())
+        .replace("{guild}", &guild)
+}
+
+impl App {
+    /// Sends an announcement to portal rooms
+    ///
+    /// Returns the number of rooms the announcement was sent to.
+    ///
+    /// # Errors
+    /// This function will return an error if the portals can't be read
+    async fn broadcast_announcement(
+        self: &Arc<Self>,
+        announcement: &Announcement,
+    ) -> Result<usize> {
+        let portals = match announcement.guild_id {
+            Some(guild_id) => self.portals_in_guild(guild_id).await?,
+            None => self.all_portals().await?,
+        };
+        let mut sent = 0;
+        for portal in portals {
+            let room = match self.client.get_room(&portal.room_id) {
+                Some(room) => room,
+                None => continue,
+            };
+            let content = RoomMessageEventContent::notice_plain(render_announcement(
+                &announcement.template,
+                &portal,
+            ));
+            match self.send_message(&room, content).await {
+                Ok(_) => sent += 1,
+                Err(e) => warn!("Failed to announce in {}: {:?}", portal.room_id, e),
+            }
+            tokio::time::sleep(SEND_INTERVAL).await;
+        }
+        Ok(sent)
+    }
+
+    /// Sends a one-off notice to a portal room as the bridge bot
+    ///
+    /// # Errors
+    /// This function will return an error if the room is not a portal or sending fails
+    pub async fn send_to_portal_room(
+        self: &Arc<Self>,
+        target: &RoomOrAliasId,
+        message: &str,
+    ) -> Result<()> {
+        let portal = self
+            .portal_by_room_or_alias(target)
+            .await?
+            .ok_or_else(|| anyhow!("{} is not a portal", target))?;
+        let room = self
+            .client
+            .get_room(&portal.room_id)
+            .ok_or_else(|| anyhow!("The bridge is not in {}", portal.room_id))?;
+        self.send_message(&room, RoomMessageEventContent::notice_plain(message))
+            .await?;
+        info!("Sent message to {}", portal.room_id);
+        Ok(())
+    }
+
+    /// Sends a one-off message to a discord channel as the bridge bot
+    ///
+    /// # Errors
+    /// This function will return an error if sending fails
+    pub async fn send_to_channel(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+        message: &str,
+    ) -> Result<()> {
+        if self.dry_run {
+            info!("[dry-run] Would send to {}: {}", channel_id, message);
+            return Ok(());
+        }
+        self.discord()?
+            .http
+            .create_message(channel_id)
+            .content(message)?
+            .exec()
+            .await?;
+        info!("Sent message to {}", channel_id);
+        Ok(())
+    }
+
+    /// Handles the `announce` command
+    ///
+    /// The announcement is sent in the background and the admin is told once it is done.
+    ///
+    /// # Errors
+    /// This function will return an error if the reply could not be sent
+    pub(super) async fn handle_announce_command(
+        self: &Arc<Self>,
+        sender: &UserId,
+        args: &[&str],
+        room: &Room,
+    ) -> Result<()> {
+        if sender != self.config.bridge.admin {
+            let content =
+                RoomMessageEventContent::text_plain("Only the bridge admin can send announcements");
+            self.send_message(room, content).await?;
+            return Ok(());
+        }
+        let announcement = match parse_announcement(args) {
+            Ok(announcement) => announcement,
+            Err(e) => {
+                self.send_message(room, RoomMessageEventContent::text_plain(e.to_string()))
+                    .await?;
+                return Ok(());
+            }
+        };
+        let this = Arc::clone(self);
+        let room = room.clone();
+        tokio::spawn(async move {
+            let reply = match this.broadcast_announcement(&announcement).await {
+                Ok(sent) => {
+                    info!("Sent announcement to {} rooms", sent);
+                    format!("Announcement sent to {} rooms", sent)
+                }
+                Err(e) => format!("Failed to send announcement: {}", e),
+            };
+            if let Err(e) = this
+                .send_message(&room, RoomMessageEventContent::text_plain(reply))
+                .await
+            {
+                warn!("Failed to report announcement result: {:?}", e);
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit;
+    use matrix_sdk::ruma::room_alias_id;
+
+    #[test]
+    fn parses_announcements() {
+        assert_eq!(
+            parse_announcement(&["announce", "--guild", "5", "Down", "at", "{room}"]).ok(),
+            Some(Announcement {
+                guild_id: Some(Id::new(5)),
+                template: "Down at {room}".to_owned(),
+            })
+        );
+        assert_eq!(
+            parse_announcement(&["announce", "Hello"]).ok(),
+            Some(Announcement {
+                guild_id: None,
+                template: "Hello".to_owned(),
+            })
+        );
+        assert!(parse_announcement(&["announce"]).is_err());
+        assert!(parse_announcement(&["announce", "--guild", "x", "Hi"]).is_err());
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        let portal = Portal {
+            alias: Some(room_alias_id!("#general:chir.rs").to_owned()),
+            guild_id: None,
+            ..testkit::portal(1, "abc")
+        };
+        assert_eq!(
+            render_announcement("{room} ({channel}, {guild})", &portal),
+            "#general:chir.rs (1, unknown)"
+        );
+    }
+}
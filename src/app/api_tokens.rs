@@ -0,0 +1,289 @@
+//! Named API tokens for integrations
+//!
+//! Each integration gets its own token, scoped to what it needs and revocable on its own. Only
+//! a SHA-256 hash of each token is stored; the token itself is shown once when it is created, so
+//! tokens are only managed in the admin's management room. Tokens authenticate requests to the
+//! provisioning API, see `provisioning`.
+
+use std::{fmt::Write, str::FromStr, sync::Arc};
+
+use super::App;
+use anyhow::{anyhow, Result};
+use matrix_sdk::{
+    room::Room,
+    ruma::{events::room::message::RoomMessageEventContent, UserId},
+};
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    thread_rng,
+};
+use sha2::{Digest, Sha256};
+use sqlx::query;
+use tracing::info;
+
+/// Prefix of API tokens, making them recognizable in logs and secret scanners
+const TOKEN_PREFIX: &str = "dmb_";
+
+/// Number of random characters in a token
+const TOKEN_LEN: usize = 48;
+
+/// Actions an API token may perform
+///
+/// Each scope includes the ones before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    /// Read bridge and portal state
+    ReadOnly,
+    /// Create, change and remove portals
+    ManagePortals,
+    /// Everything, including managing other tokens
+    Admin,
+}
+
+impl Scope {
+    /// Database and command line representation
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::ReadOnly => "read-only",
+            Self::ManagePortals => "manage-portals",
+            Self::Admin => "admin",
+        }
+    }
+
+    /// Returns whether a token with this scope may perform actions needing `required`
+    #[must_use]
+    pub fn allows(self, required: Self) -> bool {
+        self >= required
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "read-only" => Ok(Self::ReadOnly),
+            "manage-portals" => Ok(Self::ManagePortals),
+            "admin" => Ok(Self::Admin),
+            _ => Err(format!(
+                "Unknown scope {:?}, expected read-only, manage-portals or admin",
+                s
+            )),
+        }
+    }
+}
+
+/// An API token as listed to the admin
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ApiToken {
+    /// Name of the integration using the token
+    pub name: String,
+    /// Scope of the token
+    pub scope: Scope,
+    /// Whether the token was revoked
+    pub revoked: bool,
+}
+
+/// Returns the stored hash of a token
+fn hash_token(token: &str) -> String {
+    let mut hex = String::with_capacity(64);
+    for byte in Sha256::digest(token.as_bytes()) {
+        // Writing to a string never fails
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+impl App {
+    /// Creates an API token and returns it
+    ///
+    /// # Errors
+    /// This function will return an error if a token with the name exists or the database query
+    /// fails
+    #[allow(clippy::panic)]
+    pub async fn create_api_token(self: &Arc<Self>, name: &str, scope: Scope) -> Result<String> {
+        let token = format!(
+            "{}{}",
+            TOKEN_PREFIX,
+            Alphanumeric.sample_string(&mut thread_rng(), TOKEN_LEN)
+        );
+        let created = query!(
+            "INSERT INTO api_tokens (name, token_hash, scope) VALUES ($1, $2, $3) ON CONFLICT (name) DO NOTHING",
+            name,
+            hash_token(&token),
+            scope.as_str()
+        )
+        .execute(&*self.db)
+        .await?
+        .rows_affected();
+        if created == 0 {
+            return Err(anyhow!("An API token named {:?} already exists", name));
+        }
+        info!("Created API token {} with scope {}", name, scope.as_str());
+        Ok(token)
+    }
+
+    /// Revokes an API token
+    ///
+    /// Returns whether a token was revoked.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub async fn revoke_api_token(self: &Arc<Self>, name: &str) -> Result<bool> {
+        let revoked = query!(
+            "UPDATE api_tokens SET revoked_at = NOW() WHERE name = $1 AND revoked_at IS NULL",
+            name
+        )
+        .execute(&*self.db)
+        .await?
+        .rows_affected();
+        if revoked > 0 {
+            info!("Revoked API token {}", name);
+        }
+        Ok(revoked > 0)
+    }
+
+    /// Lists all API tokens
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub async fn api_tokens(self: &Arc<Self>) -> Result<Vec<ApiToken>> {
+        query!(
+            "SELECT name, scope, revoked_at IS NOT NULL AS revoked FROM api_tokens ORDER BY name"
+        )
+        .fetch_all(&*self.db)
+        .await?
+        .into_iter()
+        .map(|row| {
+            Ok(ApiToken {
+                name: row.name,
+                scope: row.scope.parse().map_err(|e: String| anyhow!(e))?,
+                revoked: row.revoked.unwrap_or(true),
+            })
+        })
+        .collect()
+    }
+
+    /// Checks an API token against the scope an action requires
+    ///
+    /// Returns the name of the token if it is valid and allowed to perform the action.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub async fn authorize_api_token(
+        self: &Arc<Self>,
+        token: &str,
+        required: Scope,
+    ) -> Result<Option<String>> {
+        let row = query!(
+            "UPDATE api_tokens SET last_used_at = NOW() WHERE token_hash = $1 AND revoked_at IS NULL RETURNING name, scope",
+            hash_token(token)
+        )
+        .fetch_optional(&*self.db)
+        .await?;
+        Ok(row.and_then(|row| {
+            let scope = row.scope.parse::<Scope>().ok()?;
+            scope.allows(required).then(|| row.name)
+        }))
+    }
+
+    /// Runs the `api-token` command and returns the reply
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    async fn api_token_command_reply(self: &Arc<Self>, args: &[&str]) -> Result<String> {
+        Ok(match args {
+            ["api-token", "create", name, scope] => match scope.parse() {
+                Ok(scope) => format!(
+                    "Created token {}, it won't be shown again: {}",
+                    name,
+                    self.create_api_token(name, scope).await?
+                ),
+                Err(e) => e,
+            },
+            ["api-token", "revoke", name] => {
+                if self.revoke_api_token(name).await? {
+                    format!("Revoked token {}", name)
+                } else {
+                    format!("There is no active token named {}", name)
+                }
+            }
+            ["api-token", "list"] => {
+                let tokens = self.api_tokens().await?;
+                if tokens.is_empty() {
+                    "There are no API tokens".to_owned()
+                } else {
+                    tokens
+                        .iter()
+                        .map(|token| {
+                            format!(
+                                "{} ({}){}",
+                                token.name,
+                                token.scope.as_str(),
+                                if token.revoked { ", revoked" } else { "" }
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                }
+            }
+            _ => "Usage: api-token create <name> <read-only|manage-portals|admin>, api-token revoke <name>, api-token list".to_owned(),
+        })
+    }
+
+    /// Handles the `api-token` command
+    ///
+    /// # Errors
+    /// This function will return an error if the reply could not be sent
+    pub(super) async fn handle_api_token_command(
+        self: &Arc<Self>,
+        sender: &UserId,
+        args: &[&str],
+        room: &Room,
+    ) -> Result<()> {
+        let reply = if sender == self.config.bridge.admin {
+            match self.api_token_command_reply(args).await {
+                Ok(reply) => reply,
+                Err(e) => format!("Failed to manage API tokens: {}", e),
+            }
+        } else {
+            "Only the bridge admin can manage API tokens".to_owned()
+        };
+        self.send_message(room, RoomMessageEventContent::text_plain(reply))
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scopes_include_lower_scopes() {
+        assert!(Scope::Admin.allows(Scope::ManagePortals));
+        assert!(Scope::ManagePortals.allows(Scope::ReadOnly));
+        assert!(!Scope::ReadOnly.allows(Scope::ManagePortals));
+        assert!(!Scope::ManagePortals.allows(Scope::Admin));
+    }
+
+    #[test]
+    fn scopes_roundtrip() {
+        for scope in [Scope::ReadOnly, Scope::ManagePortals, Scope::Admin] {
+            assert_eq!(scope.as_str().parse(), Ok(scope));
+        }
+        assert!("root".parse::<Scope>().is_err());
+    }
+
+    #[test]
+    fn hashes_tokens() {
+        assert_eq!(
+            hash_token("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
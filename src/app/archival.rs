@@ -0,0 +1,186 @@
+//! Read-only portals for archived discord channels
+//!
+//! When a discord channel becomes read-only for `@everyone` or a thread is archived, the portal
+//! room is made read-only by raising the power level needed to send messages. Read-only portals
+//! are skipped by member sync and backfill until the channel is reactivated.
+
+use std::sync::Arc;
+
+use super::App;
+use crate::{locale::SystemMessage, snowflake};
+use anyhow::Result;
+use matrix_sdk::{
+    room::Room,
+    ruma::events::{room::message::RoomMessageEventContent, StateEventType},
+};
+use serde_json::Value;
+use sqlx::query;
+use tracing::info;
+use twilight_model::{
+    channel::{
+        permission_overwrite::{PermissionOverwrite, PermissionOverwriteType},
+        Channel,
+    },
+    guild::Permissions,
+    id::{
+        marker::{ChannelMarker, GuildMarker},
+        Id,
+    },
+};
+
+/// Power level needed to send messages in read-only portals
+const READ_ONLY_EVENTS_DEFAULT: i64 = 100;
+
+/// Power level needed to send messages in active portals
+const ACTIVE_EVENTS_DEFAULT: i64 = 0;
+
+/// Returns whether `@everyone` is denied sending messages or the thread is archived
+fn is_read_only(
+    guild_id: Option<Id<GuildMarker>>,
+    overwrites: &[PermissionOverwrite],
+    archived: bool,
+) -> bool {
+    if archived {
+        return true;
+    }
+    let guild_id = match guild_id {
+        Some(guild_id) => guild_id,
+        None => return false,
+    };
+    overwrites.iter().any(|overwrite| {
+        overwrite.kind == PermissionOverwriteType::Role
+            && overwrite.id.get() == guild_id.get()
+            && overwrite.deny.contains(Permissions::SEND_MESSAGES)
+    })
+}
+
+impl App {
+    /// Stores whether a portal is read-only
+    ///
+    /// Returns whether the state changed.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    async fn set_portal_read_only(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+        read_only: bool,
+    ) -> Result<bool> {
+        let result = query!(
+            "UPDATE portals SET read_only = $2 WHERE discord_channel_id = $1 AND read_only <> $2",
+            snowflake::to_db(channel_id),
+            read_only
+        )
+        .execute(&*self.db)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Handles a channel or thread update
+    ///
+    /// # Errors
+    /// This function will return an error if updating the portal room fails
+    pub(super) async fn handle_channel_update(self: &Arc<Self>, channel: &Channel) -> Result<()> {
+        let read_only = is_read_only(
+            channel.guild_id,
+            channel.permission_overwrites.as_deref().unwrap_or_default(),
+            channel
+                .thread_metadata
+                .as_ref()
+                .map_or(false, |metadata| metadata.archived),
+        );
+        let portal = match self.portal_by_channel(channel.id).await? {
+            Some(portal) => portal,
+            None => return Ok(()),
+        };
+        let topic = if portal.metadata_sync.syncs_from_discord() {
+            channel.topic.as_deref()
+        } else {
+            None
+        };
+        self.sync_portal_topic(&portal, topic, channel.rate_limit_per_user, read_only)
+            .await?;
+        if portal.read_only == read_only {
+            return Ok(());
+        }
+        let room = match self.client.get_joined_room(&portal.room_id) {
+            Some(room) => room,
+            None => return Ok(()),
+        };
+        if self.dry_run {
+            info!(
+                "[dry-run] Would set read-only state of {} to {}",
+                portal.room_id, read_only
+            );
+            return Ok(());
+        }
+        if !self.set_portal_read_only(channel.id, read_only).await? {
+            return Ok(());
+        }
+
+        let (events_default, notice) = if read_only {
+            info!("Portal {} is now read-only", portal.room_id);
+            (READ_ONLY_EVENTS_DEFAULT, SystemMessage::ChannelArchived)
+        } else {
+            info!("Portal {} was reactivated", portal.room_id);
+            (ACTIVE_EVENTS_DEFAULT, SystemMessage::ChannelReactivated)
+        };
+        let notice = RoomMessageEventContent::notice_plain(
+            self.portal_locale(&portal).system_message(notice),
+        );
+        let joined = Room::Joined(room.clone());
+        if read_only {
+            self.send_message(&joined, notice.clone()).await?;
+        }
+        if let Some(event) = room
+            .get_state_event(StateEventType::RoomPowerLevels, "")
+            .await?
+        {
+            let mut content = event.deserialize_as::<Value>()?["content"].take();
+            content["events_default"] = events_default.into();
+            self.pipeline
+                .run(&self.user_id, "state", async {
+                    room.send_state_event_raw(content, "m.room.power_levels", "")
+                        .await?;
+                    Ok(())
+                })
+                .await?;
+        }
+        if !read_only {
+            self.send_message(&joined, notice).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUILD: Id<GuildMarker> = Id::new(1);
+
+    fn overwrite(id: u64, deny: Permissions) -> PermissionOverwrite {
+        PermissionOverwrite {
+            allow: Permissions::empty(),
+            deny,
+            id: Id::new(id),
+            kind: PermissionOverwriteType::Role,
+        }
+    }
+
+    #[test]
+    fn archived_threads_are_read_only() {
+        assert!(is_read_only(Some(GUILD), &[], true));
+        assert!(!is_read_only(Some(GUILD), &[], false));
+    }
+
+    #[test]
+    fn everyone_denied_sending_is_read_only() {
+        let everyone = overwrite(GUILD.get(), Permissions::SEND_MESSAGES);
+        let role = overwrite(2, Permissions::SEND_MESSAGES);
+        assert!(is_read_only(Some(GUILD), &[everyone], false));
+        assert!(!is_read_only(Some(GUILD), &[role], false));
+        assert!(!is_read_only(None, &[], false));
+    }
+}
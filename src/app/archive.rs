@@ -0,0 +1,200 @@
+//! Read-only archive export of a portal's bridged history
+
+use std::{fmt::Write, path::Path, sync::Arc};
+
+use super::{mappings::MessageMapping, App};
+use crate::{locale::Locale, time};
+use anyhow::{anyhow, Result};
+use matrix_sdk::ruma::{OwnedMxcUri, RoomOrAliasId};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tracing::{info, warn};
+
+/// Subset of a matrix event that is archived
+#[derive(Deserialize)]
+struct RawEvent {
+    /// Sender of the event
+    sender: String,
+    /// Timestamp in milliseconds since the unix epoch
+    origin_server_ts: u64,
+    /// Event content
+    #[serde(default)]
+    content: RawContent,
+}
+
+/// Subset of a message event's content that is archived
+#[derive(Default, Deserialize)]
+struct RawContent {
+    /// Message type
+    msgtype: Option<String>,
+    /// Plain text body
+    body: Option<String>,
+    /// HTML body
+    formatted_body: Option<String>,
+    /// Attached media
+    url: Option<OwnedMxcUri>,
+}
+
+/// An archived message
+#[derive(Clone, Debug, Serialize)]
+struct ArchivedMessage {
+    /// Matrix event id
+    matrix_event_id: String,
+    /// Discord message id
+    discord_message_id: String,
+    /// Matrix sender
+    sender: String,
+    /// Timestamp in milliseconds since the unix epoch
+    timestamp: u64,
+    /// Message type
+    msgtype: Option<String>,
+    /// Plain text body
+    body: Option<String>,
+    /// HTML body
+    formatted_body: Option<String>,
+    /// Path of the attached media, relative to the archive root
+    media: Option<String>,
+}
+
+/// Escapes text for use in HTML
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Renders the archive as a static HTML page
+///
+/// Times are formatted for the locale of the portal.
+fn render_html(room: &str, locale: Locale, messages: &[ArchivedMessage]) -> String {
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html lang=\"{1}\">\n<head>\n<meta charset=\"utf-8\">\n<title>Archive of {0}</title>\n</head>\n<body>\n<h1>Archive of {0}</h1>\n",
+        escape_html(room),
+        locale
+    );
+    for message in messages {
+        // Writing to a string never fails
+        let _ = write!(
+            html,
+            "<div class=\"message\" id=\"{}\">\n<time datetime=\"{}\">{}</time>\n<b>{}</b>\n",
+            escape_html(&message.matrix_event_id),
+            time::format_iso8601(message.timestamp),
+            locale.format_timestamp(message.timestamp),
+            escape_html(&message.sender)
+        );
+        match (&message.media, &message.msgtype) {
+            (Some(media), Some(msgtype)) if msgtype == "m.image" => {
+                let _ = writeln!(
+                    html,
+                    "<img src=\"{}\" alt=\"{}\">",
+                    escape_html(media),
+                    escape_html(message.body.as_deref().unwrap_or_default())
+                );
+            }
+            (Some(media), _) => {
+                let _ = writeln!(
+                    html,
+                    "<a href=\"{}\">{}</a>",
+                    escape_html(media),
+                    escape_html(message.body.as_deref().unwrap_or(media.as_str()))
+                );
+            }
+            (None, _) => {
+                let _ = writeln!(
+                    html,
+                    "<p>{}</p>",
+                    escape_html(message.body.as_deref().unwrap_or_default())
+                );
+            }
+        }
+        html.push_str("</div>\n");
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+impl App {
+    /// Exports the bridged history of a portal into a static archive
+    ///
+    /// The archive contains a `messages.json` with all mapped messages, an `index.html` for
+    /// viewing them and a `media` directory with all attachments. Returns the number of archived
+    /// messages.
+    ///
+    /// # Errors
+    /// This function will return an error if the room is not a portal or writing the archive fails
+    pub async fn export_portal(
+        self: &Arc<Self>,
+        room: &RoomOrAliasId,
+        output: &Path,
+    ) -> Result<usize> {
+        let portal = self
+            .portal_by_room_or_alias(room)
+            .await?
+            .ok_or_else(|| anyhow!("{} is not a portal", room))?;
+        let matrix_room = self
+            .client(None)
+            .await?
+            .get_room(&portal.room_id)
+            .ok_or_else(|| anyhow!("The bridge is not in {}", portal.room_id))?;
+
+        fs::create_dir_all(output.join("media")).await?;
+
+        let mappings = MessageMapping::in_room(&*self.db, &portal.room_id).await?;
+
+        let mut messages = Vec::with_capacity(mappings.len());
+        for mapping in mappings {
+            let event_id = &mapping.event_id;
+            let event = match matrix_room.event(event_id).await {
+                Ok(event) => event.event.deserialize_as::<RawEvent>()?,
+                Err(e) => {
+                    warn!("Failed to fetch {}: {:?}", event_id, e);
+                    continue;
+                }
+            };
+            let media = if let Some(ref url) = event.content.url {
+                let (mime, data) = self.download_matrix_media(url).await?;
+                let file_name = format!(
+                    "media/{}.{}",
+                    event_id
+                        .as_str()
+                        .trim_start_matches('$')
+                        .replace(['/', '+'], "_"),
+                    mime.subtype()
+                );
+                fs::write(output.join(&file_name), data).await?;
+                Some(file_name)
+            } else {
+                None
+            };
+            messages.push(ArchivedMessage {
+                matrix_event_id: mapping.event_id.to_string(),
+                discord_message_id: mapping.message_id.to_string(),
+                sender: event.sender,
+                timestamp: event.origin_server_ts,
+                msgtype: event.content.msgtype,
+                body: event.content.body,
+                formatted_body: event.content.formatted_body,
+                media,
+            });
+        }
+
+        fs::write(
+            output.join("messages.json"),
+            serde_json::to_vec_pretty(&messages)?,
+        )
+        .await?;
+        fs::write(
+            output.join("index.html"),
+            render_html(room.as_str(), self.portal_locale(&portal), &messages),
+        )
+        .await?;
+        info!(
+            "Exported {} messages of {} to {}",
+            messages.len(),
+            room,
+            output.display()
+        );
+        Ok(messages.len())
+    }
+}
@@ -0,0 +1,228 @@
+//! Discord attachments on matrix
+//!
+//! Every attachment of a discord message is bridged as its own event after the message's text,
+//! as `m.image`, `m.video`, `m.audio` or `m.file` depending on its content type, with the size,
+//! mime type and dimensions discord reports. Files are uploaded by the puppet of the message's
+//! author. Uploads are recorded by the SHA-256 hash of their content, and attachments with content
+//! that was uploaded before reuse the earlier upload.
+
+use std::{fmt::Write, sync::Arc};
+
+use super::{media::MediaRetention, App};
+use anyhow::Result;
+use matrix_sdk::ruma::{
+    events::room::{
+        message::{
+            AudioInfo, AudioMessageEventContent, FileInfo, FileMessageEventContent,
+            ImageMessageEventContent, MessageType, RoomMessageEventContent, VideoInfo,
+            VideoMessageEventContent,
+        },
+        ImageInfo,
+    },
+    OwnedMxcUri, UInt,
+};
+use mime::Mime;
+use sha2::{Digest, Sha256};
+use sqlx::query;
+use tracing::info;
+use twilight_model::{
+    channel::{Attachment, Message},
+    id::{
+        marker::{ChannelMarker, UserMarker},
+        Id,
+    },
+};
+
+/// Returns the hash uploads are deduplicated by
+fn content_hash(data: &[u8]) -> String {
+    let mut hex = String::with_capacity(64);
+    for byte in Sha256::digest(data) {
+        // Writing to a string never fails
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+/// Builds the event content of an uploaded attachment
+fn attachment_content(
+    attachment: &Attachment,
+    url: OwnedMxcUri,
+    mime: &Mime,
+) -> RoomMessageEventContent {
+    let body = attachment.filename.clone();
+    let mimetype = Some(mime.essence_str().to_owned());
+    let size = UInt::new(attachment.size);
+    let width = attachment.width.and_then(UInt::new);
+    let height = attachment.height.and_then(UInt::new);
+    let kind = mime.type_();
+    let msgtype = if kind == mime::IMAGE {
+        let mut info = ImageInfo::new();
+        info.mimetype = mimetype;
+        info.size = size;
+        info.width = width;
+        info.height = height;
+        MessageType::Image(ImageMessageEventContent::plain(
+            body,
+            url,
+            Some(Box::new(info)),
+        ))
+    } else if kind == mime::VIDEO {
+        let mut info = VideoInfo::new();
+        info.mimetype = mimetype;
+        info.size = size;
+        info.width = width;
+        info.height = height;
+        MessageType::Video(VideoMessageEventContent::plain(
+            body,
+            url,
+            Some(Box::new(info)),
+        ))
+    } else if kind == mime::AUDIO {
+        let mut info = AudioInfo::new();
+        info.mimetype = mimetype;
+        info.size = size;
+        MessageType::Audio(AudioMessageEventContent::plain(
+            body,
+            url,
+            Some(Box::new(info)),
+        ))
+    } else {
+        let mut info = FileInfo::new();
+        info.mimetype = mimetype;
+        info.size = size;
+        MessageType::File(FileMessageEventContent::plain(
+            body,
+            url,
+            Some(Box::new(info)),
+        ))
+    };
+    RoomMessageEventContent::new(msgtype)
+}
+
+impl App {
+    /// Returns the MXC URI of a discord attachment, uploading it unless its content is known
+    ///
+    /// In dry-run mode nothing is uploaded and `None` is returned.
+    ///
+    /// # Errors
+    /// This function will return an error if the download, upload or a database query fails
+    #[allow(clippy::panic)]
+    async fn attachment_mxc(
+        self: &Arc<Self>,
+        attachment: &Attachment,
+        mime: &Mime,
+        channel_id: Id<ChannelMarker>,
+        uploader: Id<UserMarker>,
+    ) -> Result<Option<OwnedMxcUri>> {
+        let (_, data) = self.download_discord_media(&attachment.url).await?;
+        let hash = content_hash(&data);
+        let known = query!("SELECT mxc_uri FROM media_hashes WHERE sha256 = $1", hash)
+            .fetch_optional(&*self.db)
+            .await?;
+        if let Some(row) = known {
+            return Ok(Some(OwnedMxcUri::from(row.mxc_uri)));
+        }
+        let mxc = match self
+            .upload_matrix_media(
+                mime,
+                data,
+                &attachment.filename,
+                Some(channel_id),
+                MediaRetention::Permanent,
+                Some(uploader),
+            )
+            .await?
+        {
+            Some(mxc) => mxc,
+            None => return Ok(None),
+        };
+        query!(
+            "INSERT INTO media_hashes (sha256, mxc_uri) VALUES ($1, $2) ON CONFLICT (sha256) DO NOTHING",
+            hash,
+            mxc.as_str()
+        )
+        .execute(&*self.db)
+        .await?;
+        Ok(Some(mxc))
+    }
+
+    /// Returns the events the attachments of a discord message are bridged as
+    ///
+    /// In dry-run mode nothing is uploaded and the attachments are left out.
+    ///
+    /// # Errors
+    /// This function will return an error if transferring an attachment fails
+    pub(super) async fn attachment_contents(
+        self: &Arc<Self>,
+        message: &Message,
+    ) -> Result<Vec<RoomMessageEventContent>> {
+        let mut contents = Vec::with_capacity(message.attachments.len());
+        for attachment in &message.attachments {
+            let mime = attachment
+                .content_type
+                .as_deref()
+                .and_then(|content_type| content_type.parse().ok())
+                .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+            match self
+                .attachment_mxc(attachment, &mime, message.channel_id, message.author.id)
+                .await?
+            {
+                Some(mxc) => contents.push(attachment_content(attachment, mxc, &mime)),
+                None => info!(
+                    "[dry-run] Would bridge attachment {} of {}",
+                    attachment.filename, message.id
+                ),
+            }
+        }
+        Ok(contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attachment(filename: &str, width: Option<u64>) -> Attachment {
+        serde_json::from_value(serde_json::json!({
+            "filename": filename,
+            "height": width,
+            "id": "1",
+            "proxy_url": "",
+            "size": 1234,
+            "url": "",
+            "width": width,
+        }))
+        .expect("valid attachment")
+    }
+
+    #[test]
+    fn hashes_content() {
+        assert_eq!(
+            content_hash(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn picks_the_message_type() {
+        let url = OwnedMxcUri::from("mxc://chir.rs/abc");
+        let content = attachment_content(
+            &attachment("cat.png", Some(64)),
+            url.clone(),
+            &mime::IMAGE_PNG,
+        );
+        let info = match content.msgtype {
+            MessageType::Image(image) => image.info,
+            _ => None,
+        }
+        .expect("content is an image");
+        assert_eq!(info.width, UInt::new(64));
+        assert_eq!(info.size, UInt::new(1234));
+        assert_eq!(info.mimetype.as_deref(), Some("image/png"));
+
+        let content =
+            attachment_content(&attachment("notes.txt", None), url, &mime::TEXT_PLAIN_UTF_8);
+        assert!(matches!(content.msgtype, MessageType::File(_)));
+        assert_eq!(content.body(), "notes.txt");
+    }
+}
@@ -0,0 +1,217 @@
+//! Permission audit of a guild
+//!
+//! `audit <guild id>` checks every text channel of a guild for the permissions the bridge bot
+//! needs, and replies with the missing permissions of each channel and the features that won't
+//! work without them, so they can be granted before the channels are bridged.
+
+use std::sync::Arc;
+
+use super::{permissions::channel_permissions, App};
+use anyhow::{anyhow, Result};
+use matrix_sdk::{
+    room::Room,
+    ruma::{events::room::message::RoomMessageEventContent, UserId},
+};
+use twilight_model::{
+    channel::ChannelType,
+    guild::Permissions,
+    id::{marker::GuildMarker, Id},
+};
+
+/// Permissions the bridge needs, with their name in the discord client and the feature needing
+/// them
+const REQUIRED_PERMISSIONS: &[(Permissions, &str, &str)] = &[
+    (
+        Permissions::VIEW_CHANNEL,
+        "View Channel",
+        "seeing the channel",
+    ),
+    (
+        Permissions::SEND_MESSAGES,
+        "Send Messages",
+        "sending messages",
+    ),
+    (
+        Permissions::READ_MESSAGE_HISTORY,
+        "Read Message History",
+        "replies and backfill",
+    ),
+    (
+        Permissions::MANAGE_WEBHOOKS,
+        "Manage Webhooks",
+        "webhook management",
+    ),
+    (
+        Permissions::MANAGE_MESSAGES,
+        "Manage Messages",
+        "deleting and pinning messages",
+    ),
+    (Permissions::ADD_REACTIONS, "Add Reactions", "reactions"),
+    (Permissions::BAN_MEMBERS, "Ban Members", "ban sync"),
+];
+
+/// Returns the required permissions that are missing, with the features needing them
+fn missing_permissions(permissions: Permissions) -> Vec<(&'static str, &'static str)> {
+    REQUIRED_PERMISSIONS
+        .iter()
+        .filter(|(permission, _, _)| !permissions.contains(*permission))
+        .map(|(_, name, feature)| (*name, *feature))
+        .collect()
+}
+
+/// Formats the audit result of a channel
+fn audit_line(channel: &str, missing: &[(&str, &str)]) -> String {
+    if missing.is_empty() {
+        return format!("{}: ok", channel);
+    }
+    let missing = missing
+        .iter()
+        .map(|(name, feature)| format!("{} ({})", name, feature))
+        .collect::<Vec<_>>();
+    format!("{}: missing {}", channel, missing.join(", "))
+}
+
+impl App {
+    /// Audits the permissions of the bridge bot in every text channel of a guild
+    ///
+    /// # Errors
+    /// This function will return an error if a request to discord or the database query fails
+    async fn audit_guild(self: &Arc<Self>, guild_id: Id<GuildMarker>) -> Result<String> {
+        let discord = self.discord()?;
+        let http = &discord.http;
+        let guild = http.guild(guild_id).exec().await?.model().await?;
+        let member = http
+            .guild_member(guild_id, discord.user_id)
+            .exec()
+            .await?
+            .model()
+            .await?;
+        let roles = http
+            .roles(guild_id)
+            .exec()
+            .await?
+            .models()
+            .await?
+            .into_iter()
+            .map(|role| (role.id, role.permissions))
+            .collect::<Vec<_>>();
+        let mut channels = http
+            .guild_channels(guild_id)
+            .exec()
+            .await?
+            .models()
+            .await?
+            .into_iter()
+            .filter(|channel| {
+                matches!(
+                    channel.kind,
+                    ChannelType::GuildText | ChannelType::GuildNews
+                )
+            })
+            .collect::<Vec<_>>();
+        channels.sort_by_key(|channel| (channel.position, channel.id));
+        let required = REQUIRED_PERMISSIONS
+            .iter()
+            .fold(Permissions::empty(), |acc, (permission, _, _)| {
+                acc | *permission
+            });
+        let mut lines = Vec::new();
+        let mut missing_total = Permissions::empty();
+        for channel in channels {
+            let permissions = if guild.owner_id == discord.user_id {
+                Permissions::all()
+            } else {
+                channel_permissions(
+                    guild_id,
+                    discord.user_id,
+                    &roles,
+                    &member.roles,
+                    channel.permission_overwrites.as_deref().unwrap_or_default(),
+                )
+            };
+            let missing = missing_permissions(permissions);
+            missing_total |= required - permissions;
+            let portal = if self.portal_by_channel(channel.id).await?.is_some() {
+                ", portal"
+            } else {
+                ""
+            };
+            let name = format!(
+                "#{} ({}{})",
+                channel.name.as_deref().unwrap_or_default(),
+                channel.id,
+                portal
+            );
+            lines.push(audit_line(&name, &missing));
+        }
+        let summary = if lines.is_empty() {
+            "The guild has no text channels".to_owned()
+        } else if missing_total.is_empty() {
+            "The bridge bot has all permissions it needs".to_owned()
+        } else {
+            let names = missing_permissions(Permissions::all() - missing_total)
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>();
+            format!(
+                "To fix, grant the bridge bot's role {} in the server settings, or remove the channel overwrites denying them",
+                names.join(", ")
+            )
+        };
+        Ok(
+            std::iter::once(format!("Permission audit of guild {}:", guild.name))
+                .chain(lines)
+                .chain(std::iter::once(summary))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        )
+    }
+
+    /// Handles the `audit` command
+    ///
+    /// # Errors
+    /// This function will return an error if the audit or sending the reply fails
+    pub(super) async fn handle_audit_command(
+        self: &Arc<Self>,
+        sender: &UserId,
+        args: &[&str],
+        room: &Room,
+    ) -> Result<()> {
+        let reply = if sender == self.config.bridge.admin {
+            match args {
+                ["audit", guild_id] => {
+                    let guild_id = guild_id
+                        .parse()
+                        .ok()
+                        .and_then(Id::new_checked)
+                        .ok_or_else(|| anyhow!("Invalid guild id {:?}", guild_id))?;
+                    self.audit_guild(guild_id).await?
+                }
+                _ => "Usage: audit <guild id>".to_owned(),
+            }
+        } else {
+            "Only the bridge admin can audit guilds".to_owned()
+        };
+        self.send_message(room, RoomMessageEventContent::text_plain(reply))
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_missing_permissions() {
+        let permissions = Permissions::all() - Permissions::MANAGE_WEBHOOKS;
+        assert_eq!(
+            audit_line("#general (1)", &missing_permissions(permissions)),
+            "#general (1): missing Manage Webhooks (webhook management)"
+        );
+        assert_eq!(
+            audit_line("#general (1)", &missing_permissions(Permissions::all())),
+            "#general (1): ok"
+        );
+    }
+}
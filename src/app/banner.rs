@@ -0,0 +1,127 @@
+//! Startup summary of the effective configuration
+//!
+//! On start the bridge logs where it connects to and which optional features are enabled, so that
+//! a single log excerpt answers most questions about a deployment. Secrets like tokens, keys and
+//! passwords are only reported as being set.
+
+use crate::{
+    config::{CommandScope, DBOptions},
+    features::Feature,
+    ConfigFile,
+};
+use matrix_sdk::ruma::api::appservice::{Namespace, Registration};
+use tracing::info;
+
+/// Describes the database the bridge connects to, without credentials
+fn db_target(db: &DBOptions) -> String {
+    let location = match (&db.socket, &db.host) {
+        (Some(socket), _) => socket.display().to_string(),
+        (None, Some(host)) => format!("{}:{}", host, db.port.unwrap_or(5432)),
+        (None, None) => "default".to_owned(),
+    };
+    format!(
+        "{}/{} as {}",
+        location,
+        db.database.as_deref().unwrap_or("default"),
+        db.user.as_deref().unwrap_or("default")
+    )
+}
+
+/// Returns the names of the enabled optional features
+fn enabled_features(config: &ConfigFile) -> Vec<&'static str> {
+    let bridge = &config.bridge;
+    [
+        (bridge.double_puppet, "double puppeting"),
+        (bridge.moderator_power, "moderator power levels"),
+        (bridge.admin_room.is_some(), "admin room"),
+        (bridge.catalog_room.is_some(), "catalog room"),
+        (
+            bridge.message_retention_months.is_some(),
+            "message retention",
+        ),
+        (bridge.strip_tracking_params, "tracking parameter removal"),
+        (bridge.topic_metadata, "topic metadata"),
+        (bridge.member_roles, "member roles"),
+        (bridge.secret_key.is_some(), "secret encryption"),
+        (bridge.media_admin_token.is_some(), "media cleanup"),
+        (!bridge.event_webhooks.is_empty(), "event webhooks"),
+        (bridge.integrity_check.is_some(), "integrity check"),
+        (
+            config.discord.command_scope != CommandScope::Disabled,
+            "slash commands",
+        ),
+        (config.discord.knock_channel.is_some(), "knock channel"),
+    ]
+    .into_iter()
+    .filter(|(enabled, _)| *enabled)
+    .map(|(_, name)| name)
+    .collect()
+}
+
+/// Joins the regular expressions of namespaces
+fn namespace_regexes(namespaces: &[Namespace]) -> String {
+    namespaces
+        .iter()
+        .map(|namespace| namespace.regex.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Logs a summary of the effective configuration
+///
+/// `shards` is the number of discord gateway shards, or `None` if no bot token is configured.
+pub(super) fn log_startup_summary(
+    config: &ConfigFile,
+    registration: &Registration,
+    shards: Option<usize>,
+    dry_run: bool,
+) {
+    let listen = config
+        .bridge
+        .listen_address
+        .iter()
+        .map(|address| format!("{}:{}", address, config.bridge.port))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let disabled = Feature::ALL
+        .into_iter()
+        .filter(|feature| !config.features.enabled(*feature))
+        .map(Feature::name)
+        .collect::<Vec<_>>();
+    info!(
+        version = %super::upgrade::version(),
+        homeserver = %config.homeserver.address,
+        domain = %config.homeserver.domain,
+        registration = %registration.id,
+        bridge_url = %config.bridge.bridge_url,
+        listen = %listen,
+        users = %namespace_regexes(&registration.namespaces.users),
+        aliases = %namespace_regexes(&registration.namespaces.aliases),
+        db = %db_target(&config.bridge.db),
+        discord_shards = ?shards,
+        features = %enabled_features(config).join(", "),
+        disabled_features = %disabled.join(", "),
+        default_locale = %config.bridge.default_locale,
+        dry_run,
+        "Starting bridge"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_db_without_credentials() {
+        let mut db = DBOptions {
+            host: Some("db.chir.rs".to_owned()),
+            user: Some("bridge".to_owned()),
+            password: Some("hunter2".to_owned()),
+            database: Some("discord".to_owned()),
+            ..DBOptions::default()
+        };
+        assert_eq!(db_target(&db), "db.chir.rs:5432/discord as bridge");
+        db.socket = Some("/run/postgresql".into());
+        assert_eq!(db_target(&db), "/run/postgresql/discord as bridge");
+    }
+}
@@ -0,0 +1,458 @@
+//! Ban list synchronisation between discord guilds and portal rooms
+//!
+//! Moderators can import the ban list of a guild as matrix bans of the puppets in all portal
+//! rooms of the guild, or export bans of puppets issued on the matrix side to discord.
+//!
+//! With `bridge.moderation_sync`, kicks and bans are also bridged as they happen. Discord has no
+//! kick event, so a member leaving the guild counts as a kick if the audit log has a recent kick of
+//! them.
+
+use std::{collections::BTreeSet, sync::Arc};
+
+use super::App;
+use crate::{snowflake, time};
+use anyhow::{anyhow, Result};
+use matrix_sdk::{
+    room::{Joined, Room},
+    ruma::{
+        events::{
+            room::{
+                member::{MembershipState, SyncRoomMemberEvent},
+                message::RoomMessageEventContent,
+            },
+            StateEventType, SyncStateEvent,
+        },
+        OwnedUserId, RoomId, UserId,
+    },
+};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{info, warn};
+use twilight_http::request::AuditLogReason;
+use twilight_model::{
+    guild::{audit_log::AuditLogEventType, Permissions},
+    id::{
+        marker::{GuildMarker, UserMarker},
+        Id,
+    },
+};
+
+/// Reason attached to bans imported from discord
+const IMPORT_REASON: &str = "Banned on discord";
+
+/// Reason attached to kicks from discord without a reason
+const KICK_REASON: &str = "Kicked on discord";
+
+/// Number of audit log entries searched for a kick
+const KICK_LOG_LIMIT: u16 = 10;
+
+/// Age after which a kick in the audit log no longer explains a member leaving
+const KICK_WINDOW_MS: u64 = 30_000;
+
+/// Minimal `m.room.member` state event
+#[derive(Debug, Deserialize)]
+struct MemberState {
+    /// The user the membership belongs to
+    state_key: OwnedUserId,
+    /// Membership content
+    content: MemberContent,
+}
+
+/// Minimal `m.room.member` content
+#[derive(Debug, Deserialize)]
+struct MemberContent {
+    /// The membership of the user
+    membership: MembershipState,
+}
+
+impl App {
+    /// Returns the discord users whose puppets are banned in a room
+    ///
+    /// # Errors
+    /// This function will return an error if the room state could not be read
+    async fn banned_puppets(&self, room: &Room) -> Result<BTreeSet<Id<UserMarker>>> {
+        let mut banned = BTreeSet::new();
+        for event in room.get_state_events(StateEventType::RoomMember).await? {
+            let member = event.deserialize_as::<MemberState>()?;
+            if member.content.membership != MembershipState::Ban {
+                continue;
+            }
+            if let Some(discord_id) = self.puppet_discord_id(&member.state_key) {
+                banned.insert(discord_id);
+            }
+        }
+        Ok(banned)
+    }
+
+    /// Returns the membership of a puppet in a portal room, if it has one
+    ///
+    /// # Errors
+    /// This function will return an error if the member event can't be read
+    async fn puppet_membership(room: &Joined, puppet: &UserId) -> Result<Option<MembershipState>> {
+        Ok(
+            match room
+                .get_state_event(StateEventType::RoomMember, puppet.as_str())
+                .await?
+            {
+                Some(event) => Some(event.deserialize_as::<MemberState>()?.content.membership),
+                None => None,
+            },
+        )
+    }
+
+    /// Bans or unbans the puppet of a discord user in the portal rooms of a guild
+    ///
+    /// Failures are logged so that the remaining portals are still updated.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    pub(super) async fn handle_discord_ban(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+        banned: bool,
+    ) -> Result<()> {
+        if !self.config.bridge.moderation_sync.syncs_from_discord() {
+            return Ok(());
+        }
+        let puppet = self.puppet_user_id(user_id)?;
+        for portal in self.portals_in_guild(guild_id).await? {
+            let room = match self.client.get_joined_room(&portal.room_id) {
+                Some(room) => room,
+                None => continue,
+            };
+            let membership = Self::puppet_membership(&room, &puppet).await?;
+            if banned == (membership == Some(MembershipState::Ban)) {
+                continue;
+            }
+            if self.dry_run {
+                info!(
+                    "[dry-run] Would set the ban of {} in {} to {}",
+                    puppet, portal.room_id, banned
+                );
+                continue;
+            }
+            let result = self
+                .pipeline
+                .run(&self.user_id, "ban", async {
+                    if banned {
+                        room.ban_user(&puppet, Some(IMPORT_REASON)).await?;
+                    } else {
+                        room.send_state_event_raw(
+                            json!({ "membership": "leave" }),
+                            "m.room.member",
+                            puppet.as_str(),
+                        )
+                        .await?;
+                    }
+                    Ok(())
+                })
+                .await;
+            if let Err(e) = result {
+                warn!(
+                    "Failed to update the ban of {} in {}: {:?}",
+                    puppet, portal.room_id, e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the reason of a recent kick of a guild member, if they were kicked
+    ///
+    /// # Errors
+    /// This function will return an error if the audit log can't be read
+    async fn discord_kick_reason(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+    ) -> Result<Option<String>> {
+        let log = self
+            .discord()?
+            .http
+            .audit_log(guild_id)
+            .action_type(AuditLogEventType::MemberKick)
+            .limit(KICK_LOG_LIMIT)?
+            .exec()
+            .await?
+            .model()
+            .await?;
+        let now = time::now_ms();
+        Ok(log
+            .entries
+            .into_iter()
+            .find(|entry| {
+                entry.target_id.map(Id::cast) == Some(user_id)
+                    && now.saturating_sub(snowflake::timestamp_ms(entry.id)) < KICK_WINDOW_MS
+            })
+            .map(|entry| entry.reason.unwrap_or_else(|| KICK_REASON.to_owned())))
+    }
+
+    /// Kicks the puppet of a member that was kicked from a guild from the guild's portals
+    ///
+    /// Members that left on their own are left alone. Failures are logged so that the remaining
+    /// portals are still updated.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    pub(super) async fn handle_discord_kick(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+    ) -> Result<()> {
+        if !self.config.bridge.moderation_sync.syncs_from_discord() {
+            return Ok(());
+        }
+        let reason = match self.discord_kick_reason(guild_id, user_id).await {
+            Ok(Some(reason)) => reason,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                warn!(
+                    "Failed to read the audit log of guild {}: {:?}",
+                    guild_id, e
+                );
+                return Ok(());
+            }
+        };
+        let puppet = self.puppet_user_id(user_id)?;
+        for portal in self.portals_in_guild(guild_id).await? {
+            let room = match self.client.get_joined_room(&portal.room_id) {
+                Some(room) => room,
+                None => continue,
+            };
+            if !matches!(
+                Self::puppet_membership(&room, &puppet).await?,
+                Some(MembershipState::Join | MembershipState::Invite)
+            ) {
+                continue;
+            }
+            if self.dry_run {
+                info!("[dry-run] Would kick {} from {}", puppet, portal.room_id);
+                continue;
+            }
+            let result = self
+                .pipeline
+                .run(&self.user_id, "membership", async {
+                    room.kick_user(&puppet, Some(&reason)).await?;
+                    Ok(())
+                })
+                .await;
+            if let Err(e) = result {
+                warn!("Failed to kick {} from {}: {:?}", puppet, portal.room_id, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Bans the discord user of a puppet that was banned in a portal room
+    ///
+    /// Bans issued by the bridge itself and bans in guilds where the bot may not ban members are
+    /// ignored.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query or a request to discord fails
+    pub(super) async fn handle_matrix_ban(
+        self: &Arc<Self>,
+        room_id: &RoomId,
+        event: &SyncRoomMemberEvent,
+    ) -> Result<()> {
+        let event = match event {
+            SyncStateEvent::Original(event) => event,
+            SyncStateEvent::Redacted(_) => return Ok(()),
+        };
+        if event.content.membership != MembershipState::Ban
+            || !self.config.bridge.moderation_sync.syncs_to_discord()
+            || event.sender == self.user_id
+            || self.puppet_discord_id(&event.sender).is_some()
+        {
+            return Ok(());
+        }
+        let user_id = match self.puppet_discord_id(&event.state_key) {
+            Some(user_id) => user_id,
+            None => return Ok(()),
+        };
+        let portal = match self.portal_by_room(room_id).await? {
+            Some(portal) => portal,
+            None => return Ok(()),
+        };
+        let (guild_id, permissions) = self.bot_channel_permissions(portal.channel_id).await?;
+        let guild_id = match guild_id {
+            Some(guild_id) => guild_id,
+            None => return Ok(()),
+        };
+        if !permissions.contains(Permissions::BAN_MEMBERS) {
+            info!(
+                "Not banning discord user {} in guild {}, the bot may not ban members",
+                user_id, guild_id
+            );
+            return Ok(());
+        }
+        if self.dry_run {
+            info!(
+                "[dry-run] Would ban discord user {} in guild {}",
+                user_id, guild_id
+            );
+            return Ok(());
+        }
+        let reason = format!("Banned in {} by {}", room_id, event.sender);
+        self.discord()?
+            .http
+            .create_ban(guild_id, user_id)
+            .reason(event.content.reason.as_deref().unwrap_or(&reason))?
+            .exec()
+            .await?;
+        info!(
+            "Banned discord user {} in guild {} after {} banned their puppet",
+            user_id, guild_id, event.sender
+        );
+        Ok(())
+    }
+
+    /// Bans the puppets of all users banned on discord in the portal rooms of a guild
+    ///
+    /// Returns the actions that were taken, or would be taken in preview mode.
+    ///
+    /// # Errors
+    /// This function will return an error if a request to discord or the homeserver fails
+    pub async fn import_guild_bans(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+        preview: bool,
+    ) -> Result<Vec<String>> {
+        let bans = self
+            .discord()?
+            .http
+            .bans(guild_id)
+            .exec()
+            .await?
+            .models()
+            .await?;
+        let mut actions = Vec::new();
+        for portal in self.portals_in_guild(guild_id).await? {
+            let room = match self.client.get_joined_room(&portal.room_id) {
+                Some(room) => room,
+                None => continue,
+            };
+            let already_banned = self.banned_puppets(&Room::Joined(room.clone())).await?;
+            for ban in &bans {
+                if already_banned.contains(&ban.user.id) {
+                    continue;
+                }
+                let user_id = self.puppet_user_id(ban.user.id)?;
+                actions.push(format!("Ban {} in {}", user_id, portal.room_id));
+                if preview {
+                    continue;
+                }
+                let reason = ban.reason.as_deref().unwrap_or(IMPORT_REASON);
+                self.pipeline
+                    .run(&self.user_id, "ban", async {
+                        room.ban_user(&user_id, Some(reason)).await?;
+                        Ok(())
+                    })
+                    .await?;
+            }
+        }
+        Ok(actions)
+    }
+
+    /// Bans the discord users whose puppets are banned in the portal rooms of a guild
+    ///
+    /// Returns the actions that were taken, or would be taken in preview mode.
+    ///
+    /// # Errors
+    /// This function will return an error if a request to discord or the homeserver fails
+    pub async fn export_guild_bans(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+        preview: bool,
+    ) -> Result<Vec<String>> {
+        let discord = self.discord()?;
+        let already_banned = discord
+            .http
+            .bans(guild_id)
+            .exec()
+            .await?
+            .models()
+            .await?
+            .into_iter()
+            .map(|ban| ban.user.id)
+            .collect::<BTreeSet<_>>();
+        let mut banned = BTreeSet::new();
+        for portal in self.portals_in_guild(guild_id).await? {
+            if let Some(room) = self.client.get_room(&portal.room_id) {
+                banned.extend(self.banned_puppets(&room).await?);
+            }
+        }
+        let mut actions = Vec::new();
+        for user_id in banned.difference(&already_banned) {
+            actions.push(format!(
+                "Ban discord user {} in guild {}",
+                user_id, guild_id
+            ));
+            if !preview {
+                discord.http.create_ban(guild_id, *user_id).exec().await?;
+            }
+        }
+        Ok(actions)
+    }
+
+    /// Handles the `bans` command
+    ///
+    /// # Errors
+    /// This function will return an error if the import or export fails or the reply could not
+    /// be sent
+    pub(super) async fn handle_bans_command(
+        self: &Arc<Self>,
+        sender: &UserId,
+        args: &[&str],
+        room: &Room,
+    ) -> Result<()> {
+        if sender != self.config.bridge.admin {
+            let content =
+                RoomMessageEventContent::text_plain("Only the bridge admin can manage bans");
+            self.send_message(room, content).await?;
+            return Ok(());
+        }
+        let (direction, guild_id, flags) = match args {
+            ["bans", direction @ ("import" | "export"), guild_id, flags @ ..] => {
+                (*direction, *guild_id, flags)
+            }
+            _ => {
+                let content = RoomMessageEventContent::text_plain(
+                    "Usage: bans import|export <guild id> [--dry-run]",
+                );
+                self.send_message(room, content).await?;
+                return Ok(());
+            }
+        };
+        let guild_id = guild_id
+            .parse()
+            .ok()
+            .and_then(Id::new_checked)
+            .ok_or_else(|| anyhow!("Invalid guild id {:?}", guild_id))?;
+        let preview = self.dry_run || flags.contains(&"--dry-run");
+        let actions = if direction == "import" {
+            self.import_guild_bans(guild_id, preview).await?
+        } else {
+            self.export_guild_bans(guild_id, preview).await?
+        };
+        info!(
+            "{} of bans for guild {}: {} actions",
+            direction,
+            guild_id,
+            actions.len()
+        );
+        let header = match (preview, actions.is_empty()) {
+            (_, true) => "Ban lists are already in sync".to_owned(),
+            (true, false) => format!("Dry run, {} bans would be applied:", actions.len()),
+            (false, false) => format!("Applied {} bans:", actions.len()),
+        };
+        let body = std::iter::once(header)
+            .chain(actions)
+            .collect::<Vec<_>>()
+            .join("\n");
+        self.send_message(room, RoomMessageEventContent::text_plain(body))
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+//! Portal capabilities depending on the permissions of the bridge bot
+//!
+//! Features like webhook management or ban sync need the bridge bot to have certain discord
+//! permissions. The permissions the bot has in each portal channel are stored and re-evaluated
+//! when the guild, its roles or the bot's membership change, and the admin room is alerted when
+//! a portal loses a capability.
+
+use std::sync::Arc;
+
+use super::App;
+use crate::snowflake;
+use anyhow::Result;
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use sqlx::query;
+use tracing::{info, warn};
+use twilight_model::{
+    guild::Permissions,
+    id::{
+        marker::{ChannelMarker, GuildMarker},
+        Id,
+    },
+};
+
+/// Bridge features and the permissions they need
+const CAPABILITIES: &[(Permissions, &str)] = &[
+    (Permissions::SEND_MESSAGES, "sending messages"),
+    (Permissions::MANAGE_WEBHOOKS, "webhook management"),
+    (Permissions::BAN_MEMBERS, "ban sync"),
+    (
+        Permissions::MANAGE_MESSAGES,
+        "deleting and pinning messages",
+    ),
+    (Permissions::ADD_REACTIONS, "reactions"),
+];
+
+/// Returns the permissions relevant to bridge features
+fn relevant(permissions: Permissions) -> Permissions {
+    CAPABILITIES
+        .iter()
+        .map(|(permission, _)| *permission)
+        .filter(|permission| permissions.contains(*permission))
+        .fold(Permissions::empty(), |acc, permission| acc | permission)
+}
+
+/// Returns the features that are possible with `old` but not with `new` permissions
+fn lost_capabilities(old: Permissions, new: Permissions) -> Vec<&'static str> {
+    CAPABILITIES
+        .iter()
+        .filter(|(permission, _)| old.contains(*permission) && !new.contains(*permission))
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+impl App {
+    /// Returns the stored bot permissions of a portal channel
+    ///
+    /// Returns `None` if they were never evaluated.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    async fn stored_bot_permissions(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+    ) -> Result<Option<Permissions>> {
+        let bits = query!(
+            "SELECT bot_permissions FROM portals WHERE discord_channel_id = $1",
+            snowflake::to_db(channel_id)
+        )
+        .fetch_optional(&*self.db)
+        .await?
+        .and_then(|row| row.bot_permissions);
+        Ok(match bits {
+            Some(bits) => Some(Permissions::from_bits_truncate(u64::try_from(bits)?)),
+            None => None,
+        })
+    }
+
+    /// Stores the bot permissions of a portal channel
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    async fn store_bot_permissions(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+        permissions: Permissions,
+    ) -> Result<()> {
+        query!(
+            "UPDATE portals SET bot_permissions = $2 WHERE discord_channel_id = $1",
+            snowflake::to_db(channel_id),
+            i64::try_from(permissions.bits())?
+        )
+        .execute(&*self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Sends an alert to the admin room, or logs it if no admin room is configured
+    ///
+    /// # Errors
+    /// This function will return an error if sending the alert fails
+    pub(super) async fn alert_admin(self: &Arc<Self>, alert: &str) -> Result<()> {
+        let room = match self.config.bridge.admin_room {
+            Some(ref room_id) => self.client.get_room(room_id),
+            None => None,
+        };
+        match room {
+            Some(room) => {
+                self.send_message(&room, RoomMessageEventContent::notice_plain(alert))
+                    .await?;
+            }
+            None => warn!("{}", alert),
+        }
+        Ok(())
+    }
+
+    /// Re-evaluates the capabilities of a portal channel
+    ///
+    /// # Errors
+    /// This function will return an error if a request to discord, the database query or
+    /// alerting the admin room fails
+    pub(super) async fn reevaluate_capabilities(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+    ) -> Result<()> {
+        let portal = match self.portal_by_channel(channel_id).await? {
+            Some(portal) => portal,
+            None => return Ok(()),
+        };
+        let (_, permissions) = self.bot_channel_permissions(channel_id).await?;
+        let permissions = relevant(permissions);
+        let old = self.stored_bot_permissions(channel_id).await?;
+        if old == Some(permissions) {
+            return Ok(());
+        }
+        if self.dry_run {
+            info!(
+                "[dry-run] Would update bot permissions of {} to {:?}",
+                portal.room_id, permissions
+            );
+            return Ok(());
+        }
+        self.store_bot_permissions(channel_id, permissions).await?;
+        let lost = lost_capabilities(old.unwrap_or_else(Permissions::all), permissions);
+        if !lost.is_empty() {
+            self.alert_admin(&format!(
+                "Portal {} (discord channel {}) is degraded, the bridge bot lost the permissions needed for: {}",
+                portal.room_id,
+                channel_id,
+                lost.join(", ")
+            ))
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Re-evaluates the capabilities of all portals of a guild
+    ///
+    /// Failures for single portals are logged so the remaining portals are still evaluated.
+    ///
+    /// # Errors
+    /// This function will return an error if the portals can't be read
+    pub(super) async fn reevaluate_guild_capabilities(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+    ) -> Result<()> {
+        for portal in self.portals_in_guild(guild_id).await? {
+            if let Err(e) = self.reevaluate_capabilities(portal.channel_id).await {
+                warn!(
+                    "Failed to re-evaluate capabilities of {}: {:?}",
+                    portal.room_id, e
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_relevant_permissions_are_kept() {
+        assert_eq!(
+            relevant(Permissions::BAN_MEMBERS | Permissions::CONNECT),
+            Permissions::BAN_MEMBERS
+        );
+    }
+
+    #[test]
+    fn detects_lost_capabilities() {
+        let old = Permissions::SEND_MESSAGES | Permissions::MANAGE_WEBHOOKS;
+        assert_eq!(
+            lost_capabilities(old, Permissions::SEND_MESSAGES),
+            vec!["webhook management"]
+        );
+        assert!(lost_capabilities(Permissions::empty(), Permissions::all()).is_empty());
+    }
+}
@@ -0,0 +1,212 @@
+//! Catalog room listing the public portals
+//!
+//! If `bridge.catalog_room` is set, the bridge keeps one state event per public portal in that
+//! room, with the portal's name, topic, alias and member count. Clients can render the state of
+//! the room as a directory of bridged channels. Entries are refreshed periodically and when a
+//! portal moves or changes its alias; entries of portals that are gone or no longer public are
+//! cleared.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+
+use super::{portals::Portal, App};
+use anyhow::{anyhow, Result};
+use matrix_sdk::ruma::events::room::join_rules::JoinRule;
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::query;
+use tracing::{error, info};
+
+/// State event type of catalog entries, keyed by the room id of the portal
+const CATALOG_EVENT_TYPE: &str = "rs.chir.discord_bridge.portal";
+
+/// Interval between catalog refreshes
+const CATALOG_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// A portal as listed in the catalog
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+struct CatalogEntry {
+    /// Name of the room
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// Topic of the room
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topic: Option<String>,
+    /// Canonical alias of the room
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alias: Option<String>,
+    /// Number of joined members
+    members: u64,
+    /// The discord channel
+    discord_channel: String,
+}
+
+/// Compares the published catalog with the current one
+///
+/// Returns the entries that need to be published and the room ids whose entries need to be
+/// cleared.
+fn diff_catalog<'a>(
+    published: &HashMap<String, String>,
+    current: &'a BTreeMap<String, String>,
+) -> (Vec<(&'a str, &'a str)>, Vec<String>) {
+    let changed = current
+        .iter()
+        .filter(|(room_id, entry)| published.get(*room_id) != Some(*entry))
+        .map(|(room_id, entry)| (room_id.as_str(), entry.as_str()))
+        .collect();
+    let mut removed = published
+        .keys()
+        .filter(|room_id| !current.contains_key(*room_id))
+        .cloned()
+        .collect::<Vec<_>>();
+    removed.sort();
+    (changed, removed)
+}
+
+impl App {
+    /// Returns the catalog entry of a portal, or `None` if it isn't public
+    fn catalog_entry(&self, portal: &Portal) -> Option<CatalogEntry> {
+        let room = self.client.get_joined_room(&portal.room_id)?;
+        if !matches!(room.join_rule(), JoinRule::Public) {
+            return None;
+        }
+        Some(CatalogEntry {
+            name: room.name(),
+            topic: room.topic(),
+            alias: portal.alias.as_ref().map(ToString::to_string),
+            members: room.joined_members_count(),
+            discord_channel: portal.channel_id.to_string(),
+        })
+    }
+
+    /// Brings the catalog room up to date with the public portals
+    ///
+    /// # Errors
+    /// This function will return an error if the bridge isn't in the catalog room, or a database
+    /// query or updating the room state fails
+    #[allow(clippy::panic)]
+    pub(super) async fn refresh_catalog(self: &Arc<Self>) -> Result<()> {
+        let catalog_id = match self.config.bridge.catalog_room {
+            Some(ref catalog_id) => catalog_id,
+            None => return Ok(()),
+        };
+        let catalog = self
+            .client
+            .get_joined_room(catalog_id)
+            .ok_or_else(|| anyhow!("The bridge is not in the catalog room {}", catalog_id))?;
+        let mut current = BTreeMap::new();
+        for portal in self.all_portals().await? {
+            if let Some(entry) = self.catalog_entry(&portal) {
+                current.insert(portal.room_id.to_string(), serde_json::to_string(&entry)?);
+            }
+        }
+        let published = query!("SELECT matrix_room_id, entry FROM catalog_entries")
+            .fetch_all(&*self.db)
+            .await?
+            .into_iter()
+            .map(|row| (row.matrix_room_id, row.entry))
+            .collect::<HashMap<_, _>>();
+        let (changed, removed) = diff_catalog(&published, &current);
+        if changed.is_empty() && removed.is_empty() {
+            return Ok(());
+        }
+        if self.dry_run {
+            info!(
+                "[dry-run] Would update {} and clear {} catalog entries",
+                changed.len(),
+                removed.len()
+            );
+            return Ok(());
+        }
+        for (room_id, entry) in changed {
+            let content = serde_json::from_str::<Value>(entry)?;
+            self.pipeline
+                .run(&self.user_id, "state", async {
+                    catalog
+                        .send_state_event_raw(content, CATALOG_EVENT_TYPE, room_id)
+                        .await?;
+                    Ok(())
+                })
+                .await?;
+            query!(
+                "INSERT INTO catalog_entries (matrix_room_id, entry) VALUES ($1, $2) ON CONFLICT (matrix_room_id) DO UPDATE SET entry = $2",
+                room_id,
+                entry
+            )
+            .execute(&*self.db)
+            .await?;
+        }
+        for room_id in removed {
+            self.pipeline
+                .run(&self.user_id, "state", async {
+                    catalog
+                        .send_state_event_raw(
+                            Value::Object(serde_json::Map::new()),
+                            CATALOG_EVENT_TYPE,
+                            &room_id,
+                        )
+                        .await?;
+                    Ok(())
+                })
+                .await?;
+            query!(
+                "DELETE FROM catalog_entries WHERE matrix_room_id = $1",
+                room_id
+            )
+            .execute(&*self.db)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Refreshes the catalog, logging failures
+    pub(super) async fn try_refresh_catalog(self: &Arc<Self>) {
+        if let Err(e) = self.refresh_catalog().await {
+            error!("Failed to refresh the catalog room: {:?}", e);
+        }
+    }
+
+    /// Spawns a task that periodically refreshes the catalog room, if one is configured
+    pub(super) fn spawn_catalog_refresh(self: &Arc<Self>) {
+        if self.config.bridge.catalog_room.is_none() {
+            return;
+        }
+        let this = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CATALOG_REFRESH_INTERVAL);
+            loop {
+                interval.tick().await;
+                let this = match this.upgrade() {
+                    Some(this) => this,
+                    None => break,
+                };
+                this.try_refresh_catalog().await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diffs_catalogs() {
+        let published = HashMap::from([
+            ("!a:x".to_owned(), "1".to_owned()),
+            ("!b:x".to_owned(), "2".to_owned()),
+            ("!c:x".to_owned(), "3".to_owned()),
+        ]);
+        let current = BTreeMap::from([
+            ("!a:x".to_owned(), "1".to_owned()),
+            ("!b:x".to_owned(), "changed".to_owned()),
+            ("!d:x".to_owned(), "4".to_owned()),
+        ]);
+        let (changed, removed) = diff_catalog(&published, &current);
+        assert_eq!(changed, vec![("!b:x", "changed"), ("!d:x", "4")]);
+        assert_eq!(removed, vec!["!c:x".to_owned()]);
+    }
+}
@@ -3,31 +3,43 @@
 use std::{ops::Deref, sync::Arc, time::Duration};
 
 use super::App;
-use anyhow::Result;
+use crate::snowflake;
+use anyhow::{anyhow, Result};
 use matrix_sdk::{
-    config::SyncSettings,
-    locks::Mutex,
     room::Room,
     ruma::{
         api::{
+            appservice::event::push_events::v1::IncomingRequest,
             client::{error::ErrorKind, uiaa::UiaaResponse},
             error::{FromHttpResponseError, ServerError},
         },
-        RoomId, UserId,
+        OwnedUserId, RoomId, ServerName, UserId,
     },
     Client, HttpError,
 };
 use sqlx::query;
-use twilight_model::id::{marker::UserMarker, Id};
+use tokio::{sync::Notify, time::timeout};
+use tracing::warn;
+use twilight_model::{
+    id::{marker::UserMarker, Id},
+    user::CurrentUser,
+};
+
+/// Time the homeserver has to push a room after a client joined it
+const ROOM_PUSH_TIMEOUT: Duration = Duration::from_secs(10);
 
 /// Wrapped client used by this crate
+///
+/// Clients don't sync. They receive the events of the appservice transactions the homeserver
+/// pushes to the bridge instead, so rooms show up in their store once an event of the room was
+/// pushed.
 #[derive(Debug)]
 #[allow(clippy::module_name_repetitions)]
 pub struct VirtualClient {
     /// Inner client
     client: Client,
-    /// Next sync token to use
-    sync_token: Mutex<Option<String>>,
+    /// Notified after every transaction the client received
+    pushed: Notify,
 }
 
 impl VirtualClient {
@@ -35,40 +47,40 @@ impl VirtualClient {
     pub(super) fn new(client: Client) -> Self {
         Self {
             client,
-            sync_token: Mutex::new(None),
+            pushed: Notify::new(),
         }
     }
 
-    /// Perform a single sync
-    pub(super) async fn sync_once(self: &Arc<Self>) -> Result<()> {
-        let mut token = self.sync_token.lock().await;
-
-        let mut sync_settings = SyncSettings::new().timeout(Duration::from_secs(0));
-        if let Some(token) = token.as_ref() {
-            sync_settings = sync_settings.token(token.clone());
-        }
-
-        let response = self.client.sync_once(sync_settings).await?;
-
-        *token = Some(response.next_batch);
+    /// Processes an appservice transaction pushed by the homeserver
+    pub(super) async fn receive_transaction(&self, transaction: IncomingRequest) -> Result<()> {
+        self.client.receive_transaction(transaction).await?;
+        self.pushed.notify_waiters();
         Ok(())
     }
 
-    /// Join a room by id
-    pub(super) async fn join_room_by_id(self: &Arc<Self>, room_id: &RoomId) -> Result<Room> {
-        // Make sure that we are up to date
-        self.sync_once().await?;
-
-        match self.get_room(room_id) {
-            Some(Room::Joined(room)) => Ok(Room::Joined(room)),
-            Some(Room::Invited(room)) => {
-                room.accept_invitation().await?;
-                self.sync_once().await?;
-                self.get_room(room_id)
-                    .ok_or_else(|| anyhow::anyhow!("Room not found"))
+    /// Returns a room from the store, waiting for the homeserver to push it if it isn't known yet
+    ///
+    /// # Errors
+    /// This function will return an error if the room isn't pushed within [`ROOM_PUSH_TIMEOUT`]
+    async fn pushed_room(&self, room_id: &RoomId) -> Result<Room> {
+        let wait = async {
+            loop {
+                let pushed = self.pushed.notified();
+                if let Some(room) = self.get_room(room_id) {
+                    return room;
+                }
+                pushed.await;
             }
-            r => r.ok_or_else(|| anyhow::anyhow!("Room not found")),
-        }
+        };
+        timeout(ROOM_PUSH_TIMEOUT, wait)
+            .await
+            .map_err(|_| anyhow!("Room {} was not pushed by the homeserver", room_id))
+    }
+
+    /// Join a room by id
+    pub(super) async fn join_room_by_id(&self, room_id: &RoomId) -> Result<Room> {
+        self.client.join_room_by_id(room_id).await?;
+        self.pushed_room(room_id).await
     }
 }
 
@@ -81,6 +93,39 @@ impl Deref for VirtualClient {
 }
 
 impl App {
+    /// Returns the localpart of the puppet of a discord user
+    #[must_use]
+    pub fn puppet_localpart(&self, user_id: Id<UserMarker>) -> String {
+        format!("{}_discord_{}", self.config.bridge.prefix, user_id)
+    }
+
+    /// Returns the matrix user id of the puppet of a discord user
+    ///
+    /// # Errors
+    /// This function will return an error if the configured domain is invalid
+    pub fn puppet_user_id(&self, user_id: Id<UserMarker>) -> Result<OwnedUserId> {
+        Ok(UserId::parse_with_server_name(
+            self.puppet_localpart(user_id),
+            <&ServerName>::try_from(self.config.homeserver.domain.as_str())?,
+        )?)
+    }
+
+    /// Returns the discord user a puppet belongs to
+    ///
+    /// Returns `None` if the user is not a puppet.
+    #[must_use]
+    pub fn puppet_discord_id(&self, user_id: &UserId) -> Option<Id<UserMarker>> {
+        if user_id.server_name().as_str() != self.config.homeserver.domain {
+            return None;
+        }
+        let prefix = format!("{}_discord_", self.config.bridge.prefix);
+        user_id
+            .localpart()
+            .strip_prefix(&prefix)
+            .and_then(|id| id.parse().ok())
+            .and_then(Id::new_checked)
+    }
+
     /// Attempts to register a new user
     pub(super) async fn try_register_user(
         self: &Arc<Self>,
@@ -110,7 +155,7 @@ impl App {
                 if let Some(client) = self.discord_clients.get(&user_id) {
                     Ok(Arc::clone(&*client))
                 } else {
-                    let username = format!("{}_discord_{}", self.config.bridge.prefix, user_id);
+                    let username = self.puppet_localpart(user_id);
                     self.try_register_user(&username).await?;
                     let user = Arc::new(VirtualClient::new(
                         self.appservice.virtual_user_client(&username).await?,
@@ -122,21 +167,137 @@ impl App {
         }
     }
 
+    /// Returns a client acting as a local matrix user
+    ///
+    /// Returns `None` if double puppeting is disabled or the user is not local.
+    ///
+    /// # Errors
+    /// This function will return an error if creating the client fails
+    pub async fn double_puppet_client(
+        self: &Arc<Self>,
+        user_id: &UserId,
+    ) -> Result<Option<Client>> {
+        if !self.config.bridge.double_puppet
+            || user_id.server_name().as_str() != self.config.homeserver.domain
+        {
+            return Ok(None);
+        }
+        Ok(Some(
+            self.appservice
+                .virtual_user_client(user_id.localpart())
+                .await?,
+        ))
+    }
+
     /// Returns the room for a client
     ///
+    /// Puppets join the room first unless the bridge bot sees them in it already and their client
+    /// knows the room. Transactions put every room into the store of every client, so the store of
+    /// a puppet doesn't tell whether it joined.
+    ///
     /// # Errors
-    /// This function will return an error if retrieving the room fails
+    /// This function will return an error if joining or retrieving the room fails
     pub async fn matrix_room_for_client(
         self: &Arc<Self>,
         user_id: Option<Id<UserMarker>>,
         room_id: &RoomId,
     ) -> Result<Room> {
-        self.client(user_id).await?.join_room_by_id(room_id).await
+        let client = self.client(user_id).await?;
+        let user_id = match user_id {
+            Some(user_id) => user_id,
+            None => {
+                return client
+                    .get_room(room_id)
+                    .ok_or_else(|| anyhow!("Room {} not found", room_id))
+            }
+        };
+        let puppet = self.puppet_user_id(user_id)?;
+        let joined = match self.client.get_joined_room(room_id) {
+            Some(room) => Self::room_membership(&room, &puppet).await?.as_deref() == Some("join"),
+            None => false,
+        };
+        if joined {
+            if let Some(room) = client.get_room(room_id) {
+                return Ok(room);
+            }
+        }
+        client.join_room_by_id(room_id).await
+    }
+
+    /// Returns the discord user a token belongs to
+    ///
+    /// # Errors
+    /// This function will return an error if the request fails or the token is invalid
+    pub async fn discord_user_for_token(&self, token: &str) -> Result<Id<UserMarker>> {
+        let response = self
+            .http
+            .get("https://discord.com/api/v10/users/@me")
+            .header(reqwest::header::AUTHORIZATION, token)
+            .send()
+            .await?
+            .error_for_status()?;
+        let user: CurrentUser = serde_json::from_slice(&response.bytes().await?)?;
+        Ok(user.id)
+    }
+
+    /// Returns the discord user linked to a matrix user
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub async fn linked_discord_user(
+        self: &Arc<Self>,
+        user: &UserId,
+    ) -> Result<Option<Id<UserMarker>>> {
+        query!(
+            "SELECT discord_user_id FROM discord_tokens WHERE user_id = $1",
+            user.as_str()
+        )
+        .fetch_optional(&*self.db)
+        .await?
+        .and_then(|row| row.discord_user_id)
+        .map(snowflake::from_db)
+        .transpose()
+    }
+
+    /// Returns the matrix user linked to a discord user
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub async fn linked_matrix_user(
+        self: &Arc<Self>,
+        user_id: Id<UserMarker>,
+    ) -> Result<Option<OwnedUserId>> {
+        query!(
+            "SELECT user_id FROM discord_tokens WHERE discord_user_id = $1",
+            snowflake::to_db(user_id)
+        )
+        .fetch_optional(&*self.db)
+        .await?
+        .map(|row| Ok(OwnedUserId::try_from(row.user_id)?))
+        .transpose()
+    }
+
+    /// Returns whether a matrix user is logged into discord with a token discord accepts
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub async fn is_logged_in(self: &Arc<Self>, user: &UserId) -> Result<bool> {
+        let row = query!(
+            "SELECT user_id FROM discord_tokens WHERE user_id = $1 AND invalid_since IS NULL",
+            user.as_str()
+        )
+        .fetch_optional(&*self.db)
+        .await?;
+        Ok(row.is_some())
     }
 
     /// Unregisters a matrix user
     #[allow(clippy::panic)]
     pub(super) async fn unregister_user(self: &Arc<Self>, user: &UserId) -> Result<()> {
+        self.forget_user_discord_http(user);
         query!(
             "DELETE FROM discord_tokens WHERE user_id = $1",
             user.as_str()
@@ -154,12 +315,20 @@ impl App {
         room: &RoomId,
         token: &str,
     ) -> Result<()> {
+        let discord_user_id = match self.discord_user_for_token(token).await {
+            Ok(id) => Some(id),
+            Err(e) => {
+                warn!("Failed to look up the discord account of {}: {:?}", user, e);
+                None
+            }
+        };
         self.unregister_user(user).await?;
         query!(
-            "INSERT INTO discord_tokens (user_id, token, management_room) VALUES ($1, $2, $3)",
+            "INSERT INTO discord_tokens (user_id, token, management_room, discord_user_id) VALUES ($1, $2, $3, $4)",
             user.as_str(),
             token,
-            room.as_str()
+            room.as_str(),
+            discord_user_id.map(snowflake::to_db)
         )
         .execute(&*self.db)
         .await?;
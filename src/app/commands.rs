@@ -0,0 +1,471 @@
+//! Bridge commands on matrix and discord
+//!
+//! On matrix, commands are run with `!discord <command>`. In a management room, a direct
+//! message with the bridge bot or the room a user logged in from, the prefix can be left out and
+//! unknown commands are answered with a hint. The shared commands can also be run by mentioning
+//! the bridge bot on discord (`@bridge <command>`) or as slash commands.
+
+use std::sync::Arc;
+
+use super::{portals::Portal, slash_commands::COMMANDS, App};
+use crate::snowflake;
+use anyhow::Result;
+use matrix_sdk::{
+    room::Room,
+    ruma::{events::room::message::RoomMessageEventContent, UserId},
+};
+use sqlx::query;
+use tracing::{info, warn};
+use twilight_model::{
+    channel::Message,
+    id::{
+        marker::{ChannelMarker, UserMarker},
+        Id,
+    },
+};
+
+/// Prefix of commands outside of management rooms
+const COMMAND_PREFIX: &str = "!discord";
+
+/// Commands of the management room and what they do
+const MANAGEMENT_COMMANDS: &[(&str, &str)] = &[
+    ("help", "Show this list"),
+    (
+        "login [token]",
+        "Send your messages from your own discord account, logging in with discord without a token",
+    ),
+    ("logout", "Stop sending messages from your discord account"),
+    (
+        "bridge <channel>",
+        "Bridge a discord channel and invite you to its room",
+    ),
+    (
+        "unbridge [channel]",
+        "Stop bridging a channel, or the portal the command is sent in",
+    ),
+    ("list-guilds", "List the discord servers the bridge is in"),
+    ("ping", "Check whether the bridge is running"),
+];
+
+/// Returns the list of management room commands
+fn management_help() -> String {
+    MANAGEMENT_COMMANDS
+        .iter()
+        .map(|(usage, description)| format!("{}: {}", usage, description))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Returns the command arguments of a matrix message
+///
+/// Messages in management rooms are commands even without the prefix.
+fn command_args(body: &str, management: bool) -> Option<Vec<&str>> {
+    let mut parts = body.split_whitespace();
+    let first = parts.next()?;
+    if first == COMMAND_PREFIX {
+        return Some(parts.collect());
+    }
+    if management {
+        return Some(body.split_whitespace().collect());
+    }
+    None
+}
+
+/// Parses a channel argument, either a mention or a plain id
+#[must_use]
+pub fn parse_channel(arg: &str) -> Option<Id<ChannelMarker>> {
+    let id = arg
+        .strip_prefix("<#")
+        .and_then(|arg| arg.strip_suffix('>'))
+        .unwrap_or(arg);
+    id.parse().ok().and_then(Id::new_checked)
+}
+
+/// Parses a user argument, either a mention or a plain id
+#[must_use]
+pub fn parse_user(arg: &str) -> Option<Id<UserMarker>> {
+    let id = arg
+        .strip_prefix("<@")
+        .and_then(|arg| arg.strip_suffix('>'))
+        .map_or(arg, |id| id.trim_start_matches('!'));
+    id.parse().ok().and_then(Id::new_checked)
+}
+
+/// Returns the command arguments of a message mentioning the bot at its start
+fn mention_args(content: &str, bot_id: Id<UserMarker>) -> Option<Vec<&str>> {
+    let mut parts = content.split_whitespace();
+    if parse_user(parts.next()?)? != bot_id {
+        return None;
+    }
+    Some(parts.collect())
+}
+
+impl App {
+    /// Returns whether a room is a management room of a user
+    ///
+    /// # Errors
+    /// This function will return an error if a database query fails
+    #[allow(clippy::panic)]
+    async fn is_management_room(self: &Arc<Self>, room: &Room, user: &UserId) -> Result<bool> {
+        let recorded = query!(
+            "SELECT management_room FROM discord_tokens WHERE user_id = $1",
+            user.as_str()
+        )
+        .fetch_optional(&*self.db)
+        .await?;
+        if recorded.map_or(false, |row| row.management_room == room.room_id().as_str()) {
+            return Ok(true);
+        }
+        Ok(
+            room.joined_members_count() <= 2
+                && self.portal_by_room(room.room_id()).await?.is_none(),
+        )
+    }
+
+    /// Handles a matrix message that may be a command
+    ///
+    /// # Errors
+    /// This function will return an error if running the command fails
+    pub(super) async fn handle_command_message(
+        self: &Arc<Self>,
+        sender: &UserId,
+        body: &str,
+        room: Room,
+    ) -> Result<bool> {
+        if self.is_bridge_user(sender) {
+            return Ok(false);
+        }
+        let prefixed = body.split_whitespace().next() == Some(COMMAND_PREFIX);
+        let management = self.is_management_room(&room, sender).await?;
+        if !prefixed && !management {
+            return Ok(false);
+        }
+        if let Some(args) = command_args(body, management) {
+            self.handle_command(sender, args, room, management).await?;
+        }
+        Ok(true)
+    }
+
+    /// Returns whether a matrix user may bridge and unbridge a discord channel
+    ///
+    /// The bridge admin may bridge any channel the bridge bot can see, other users need to be
+    /// logged in with a discord account that can see the channel.
+    ///
+    /// # Errors
+    /// This function will return an error if the user's discord client can't be created
+    async fn can_bridge(
+        self: &Arc<Self>,
+        sender: &UserId,
+        channel_id: Id<ChannelMarker>,
+    ) -> Result<bool> {
+        if sender == self.config.bridge.admin {
+            return Ok(true);
+        }
+        match self.user_discord_http(sender).await? {
+            Some(client) => Ok(client.channel(channel_id).exec().await.is_ok()),
+            None => Ok(false),
+        }
+    }
+
+    /// Bridges a discord channel and invites the sender to its portal
+    ///
+    /// # Errors
+    /// This function will return an error if the channel can't be fetched, or creating the
+    /// portal or the invite fails
+    async fn bridge_channel(
+        self: &Arc<Self>,
+        sender: &UserId,
+        channel_id: Id<ChannelMarker>,
+    ) -> Result<String> {
+        if !self.can_bridge(sender, channel_id).await? {
+            return Ok(format!(
+                "You need to be logged in with a discord account that can see {} to bridge it",
+                channel_id
+            ));
+        }
+        let channel = self
+            .discord()?
+            .http
+            .channel(channel_id)
+            .exec()
+            .await?
+            .model()
+            .await?;
+        let portal = match self
+            .portal_for_channel(channel_id, channel.guild_id)
+            .await?
+        {
+            Some(portal) => portal,
+            None => return Ok(format!("Would bridge {}", channel_id)),
+        };
+        let room = match self.client.get_joined_room(&portal.room_id) {
+            Some(room) => room,
+            None => return Ok(format!("The bridge is not in {}", portal.room_id)),
+        };
+        let target = portal
+            .alias
+            .as_ref()
+            .map_or_else(|| portal.room_id.to_string(), ToString::to_string);
+        if room.get_member(sender).await?.is_some() {
+            return Ok(format!("{} is bridged as {}", channel_id, target));
+        }
+        if self.dry_run {
+            info!("[dry-run] Would invite {} to {}", sender, portal.room_id);
+            return Ok(format!("Would invite you to {}", target));
+        }
+        self.pipeline
+            .run(&self.user_id, "membership", async {
+                room.invite_user_by_id(sender).await?;
+                Ok(())
+            })
+            .await?;
+        Ok(format!(
+            "{} is bridged as {}, you have been invited",
+            channel_id, target
+        ))
+    }
+
+    /// Stops bridging a portal
+    ///
+    /// The portal room is told that it is no longer bridged and left.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query, sending the notice or leaving
+    /// the room fails
+    #[allow(clippy::panic)]
+    pub(super) async fn unbridge_portal(self: &Arc<Self>, portal: &Portal) -> Result<()> {
+        if self.dry_run {
+            info!(
+                "[dry-run] Would unbridge {} from {}",
+                portal.room_id, portal.channel_id
+            );
+            return Ok(());
+        }
+        if let Some(room) = self.client.get_room(&portal.room_id) {
+            self.send_message(
+                &room,
+                RoomMessageEventContent::notice_plain("This room is no longer bridged to discord"),
+            )
+            .await?;
+        }
+        query!(
+            "DELETE FROM portals WHERE discord_channel_id = $1",
+            snowflake::to_db(portal.channel_id)
+        )
+        .execute(&*self.db)
+        .await?;
+        if let Some(room) = self.client.get_joined_room(&portal.room_id) {
+            self.pipeline
+                .run(&self.user_id, "membership", async {
+                    room.leave().await?;
+                    Ok(())
+                })
+                .await?;
+        }
+        info!("Unbridged {} from {}", portal.room_id, portal.channel_id);
+        Ok(())
+    }
+
+    /// Returns the reply to the `unbridge` command
+    ///
+    /// # Errors
+    /// This function will return an error if looking up or unbridging the portal fails
+    async fn unbridge_reply(
+        self: &Arc<Self>,
+        sender: &UserId,
+        args: &[&str],
+        room: &Room,
+    ) -> Result<String> {
+        let portal = match args {
+            ["unbridge"] => self.portal_by_room(room.room_id()).await?,
+            ["unbridge", channel] => match parse_channel(channel) {
+                Some(channel_id) => self.portal_by_channel(channel_id).await?,
+                None => return Ok(format!("{} is not a discord channel", channel)),
+            },
+            _ => return Ok("Usage: unbridge [channel]".to_owned()),
+        };
+        let portal = match portal {
+            Some(portal) => portal,
+            None => return Ok("The channel is not bridged".to_owned()),
+        };
+        if !self.can_bridge(sender, portal.channel_id).await? {
+            return Ok(format!(
+                "You need to be logged in with a discord account that can see {} to unbridge it",
+                portal.channel_id
+            ));
+        }
+        self.unbridge_portal(&portal).await?;
+        Ok(format!("Unbridged {}", portal.channel_id))
+    }
+
+    /// Returns the discord servers the bridge is in and how many of their channels are bridged
+    ///
+    /// # Errors
+    /// This function will return an error if a database query or the request to discord fails
+    async fn list_guilds_reply(self: &Arc<Self>) -> Result<String> {
+        let guilds = self
+            .discord()?
+            .http
+            .current_user_guilds()
+            .exec()
+            .await?
+            .models()
+            .await?;
+        if guilds.is_empty() {
+            return Ok("The bridge is not in any discord server".to_owned());
+        }
+        let mut lines = Vec::with_capacity(guilds.len());
+        for guild in guilds {
+            let bridged = self.portals_in_guild(guild.id).await?.len();
+            lines.push(format!(
+                "{} ({}): {} bridged channels",
+                guild.name, guild.id, bridged
+            ));
+        }
+        Ok(lines.join("\n"))
+    }
+
+    /// Handles the management commands that aren't shared with discord
+    ///
+    /// Returns `false` if `args` is not such a command.
+    ///
+    /// # Errors
+    /// This function will return an error if running the command or replying fails
+    pub(super) async fn handle_management_command(
+        self: &Arc<Self>,
+        sender: &UserId,
+        args: &[&str],
+        room: &Room,
+    ) -> Result<bool> {
+        let reply = match args {
+            ["help", ..] => management_help(),
+            ["bridge", channel] => match parse_channel(channel) {
+                Some(channel_id) => match self.bridge_channel(sender, channel_id).await {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        warn!("Failed to bridge {} for {}: {:?}", channel_id, sender, e);
+                        format!("Failed to bridge {}: {}", channel_id, e)
+                    }
+                },
+                None => format!("{} is not a discord channel", channel),
+            },
+            ["bridge", ..] => "Usage: bridge <channel>".to_owned(),
+            ["unbridge", ..] => self.unbridge_reply(sender, args, room).await?,
+            ["list-guilds", ..] => self.list_guilds_reply().await?,
+            _ => return Ok(false),
+        };
+        self.send_message(room, RoomMessageEventContent::text_plain(reply))
+            .await?;
+        Ok(true)
+    }
+
+    /// Returns the reply to a shared command
+    ///
+    /// Returns `None` if `args` is not a shared command.
+    ///
+    /// # Errors
+    /// This function will return an error if running the command fails
+    pub async fn shared_command_reply(self: &Arc<Self>, args: &[&str]) -> Result<Option<String>> {
+        let reply = match args {
+            ["help", ..] => COMMANDS
+                .iter()
+                .map(|command| format!("`{}`: {}", command.name, command.description))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            ["ping", ..] => "Pong!".to_owned(),
+            ["whois", user] => match parse_user(user) {
+                Some(user_id) => format!(
+                    "<@{}> is bridged as {}",
+                    user_id,
+                    self.puppet_user_id(user_id)?
+                ),
+                None => format!("{} is not a discord user", user),
+            },
+            ["whois", ..] => "Usage: whois <user>".to_owned(),
+            _ => return Ok(None),
+        };
+        Ok(Some(reply))
+    }
+
+    /// Handles a discord message that may be a command for the bridge bot
+    ///
+    /// # Errors
+    /// This function will return an error if running the command or replying fails
+    pub(super) async fn handle_discord_mention(self: &Arc<Self>, message: &Message) -> Result<()> {
+        if message.author.bot {
+            return Ok(());
+        }
+        let discord = self.discord()?;
+        let args = match mention_args(&message.content, discord.user_id) {
+            Some(args) => args,
+            None => return Ok(()),
+        };
+        let reply = match self.shared_command_reply(&args).await? {
+            Some(reply) => reply,
+            None => "Unknown command, mention me with `help` for a list of commands".to_owned(),
+        };
+        if self.dry_run {
+            info!(
+                "[dry-run] Would reply to {} in {}: {}",
+                message.id, message.channel_id, reply
+            );
+            return Ok(());
+        }
+        discord
+            .http
+            .create_message(message.channel_id)
+            .reply(message.id)
+            .content(&reply)?
+            .exec()
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_users() {
+        assert_eq!(parse_user("<@123>"), Some(Id::new(123)));
+        assert_eq!(parse_user("<@!123>"), Some(Id::new(123)));
+        assert_eq!(parse_user("123"), Some(Id::new(123)));
+        assert_eq!(parse_user("<#123>"), None);
+        assert_eq!(parse_user("0"), None);
+    }
+
+    #[test]
+    fn parses_channels() {
+        assert_eq!(parse_channel("<#123>"), Some(Id::new(123)));
+        assert_eq!(parse_channel("123"), Some(Id::new(123)));
+        assert_eq!(parse_channel("<@123>"), None);
+    }
+
+    #[test]
+    fn management_rooms_need_no_prefix() {
+        assert_eq!(
+            command_args("!discord bridge 123", false),
+            Some(vec!["bridge", "123"])
+        );
+        assert_eq!(
+            command_args("!discord bridge 123", true),
+            Some(vec!["bridge", "123"])
+        );
+        assert_eq!(command_args("list-guilds", true), Some(vec!["list-guilds"]));
+        assert_eq!(command_args("list-guilds", false), None);
+        assert_eq!(command_args("!discordance", false), None);
+    }
+
+    #[test]
+    fn parses_mentions() {
+        let bot = Id::new(42);
+        assert_eq!(
+            mention_args("<@42> whois <@7>", bot),
+            Some(vec!["whois", "<@7>"])
+        );
+        assert_eq!(mention_args("<@!42>", bot), Some(vec![]));
+        assert_eq!(mention_args("hello <@42>", bot), None);
+        assert_eq!(mention_args("<@43> help", bot), None);
+    }
+}
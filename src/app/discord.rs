@@ -0,0 +1,276 @@
+//! Discord connection handling
+
+use std::{
+    collections::HashMap,
+    sync::{atomic::Ordering, Arc},
+};
+
+use super::App;
+use crate::{snowflake, time::Clock};
+use anyhow::Result;
+use futures_util::StreamExt;
+use tracing::{debug, error, info};
+use twilight_gateway::{
+    cluster::Events, shard::ResumeSession, Cluster, Event, EventTypeFlags, Intents,
+};
+use twilight_http::Client;
+use twilight_model::id::{marker::UserMarker, Id};
+
+/// Connection to discord as the bridge bot
+#[derive(Debug)]
+pub struct DiscordBot {
+    /// REST client
+    pub http: Client,
+    /// Gateway connection
+    pub cluster: Cluster,
+    /// User id of the bot
+    pub user_id: Id<UserMarker>,
+}
+
+impl DiscordBot {
+    /// Intents requested from the gateway
+    const INTENTS: Intents = Intents::GUILDS
+        .union(Intents::GUILD_MEMBERS)
+        .union(Intents::GUILD_BANS)
+        .union(Intents::GUILD_MESSAGES)
+        .union(Intents::GUILD_EMOJIS_AND_STICKERS)
+        .union(Intents::GUILD_MESSAGE_REACTIONS)
+        .union(Intents::DIRECT_MESSAGE_REACTIONS);
+
+    /// Creates a new bot connection
+    ///
+    /// The gateway is not started until [`Cluster::up`] is called, which resumes the given
+    /// sessions of each shard.
+    ///
+    /// # Errors
+    /// This function will return an error if the bot user or the gateway information could not
+    /// be retrieved
+    pub(super) async fn new(
+        token: String,
+        sessions: HashMap<u64, ResumeSession>,
+    ) -> Result<(Self, Events)> {
+        let http = Client::new(token.clone());
+        let user_id = http.current_user().exec().await?.model().await?.id;
+        // Events are persisted in the queue as the payloads they were received as, only the
+        // connection state of shards is tracked directly
+        let (cluster, events) = Cluster::builder(token, Self::INTENTS)
+            .event_types(
+                EventTypeFlags::SHARD_PAYLOAD
+                    | EventTypeFlags::SHARD_CONNECTED
+                    | EventTypeFlags::SHARD_DISCONNECTED
+                    | EventTypeFlags::SHARD_RECONNECTING,
+            )
+            .resume_sessions(sessions)
+            .build()
+            .await?;
+        Ok((
+            Self {
+                http,
+                cluster,
+                user_id,
+            },
+            events,
+        ))
+    }
+}
+
+impl App {
+    /// Returns the bridge bot, if one is configured
+    ///
+    /// # Errors
+    /// This function will return an error if no bot token is configured
+    pub fn discord(&self) -> Result<&DiscordBot> {
+        self.discord
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No discord bot token configured"))
+    }
+
+    /// Persists discord gateway payloads in the event queue
+    pub(super) fn spawn_discord_event_loop(self: &Arc<Self>, mut events: Events) {
+        let this = Arc::downgrade(self);
+        tokio::spawn(async move {
+            while let Some((shard_id, event)) = events.next().await {
+                debug!("Received event {:?} on shard {}", event.kind(), shard_id);
+                let this = match this.upgrade() {
+                    Some(this) => this,
+                    None => break,
+                };
+                let payload = match event {
+                    Event::ShardPayload(payload) => payload,
+                    event => {
+                        this.handle_shard_event(shard_id, &event);
+                        continue;
+                    }
+                };
+                if let Err(e) = this.queue_discord_payload(&payload.bytes).await {
+                    if this.queue_closed.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    error!("Failed to queue a discord event: {:?}", e);
+                }
+            }
+            info!("Shutting down discord event loop");
+        });
+    }
+
+    /// Handles a discord gateway event
+    #[tracing::instrument(skip(self))]
+    pub(super) async fn handle_discord_event(self: &Arc<Self>, event: Event) -> Result<()> {
+        match event {
+            Event::GuildCreate(guild) => {
+                let icon = guild.icon.map(|icon| icon.to_string());
+                self.sync_guild_space(guild.id, &guild.name, icon.as_deref())
+                    .await?;
+                self.sync_guild_emojis(guild.id, &guild.emojis).await?;
+                self.sync_guild_stickers(guild.id, &guild.stickers).await?;
+                self.sync_guild_commands(guild.id).await?;
+                self.spawn_guild_member_sync(guild.id);
+            }
+            Event::GuildEmojisUpdate(update) => {
+                self.sync_guild_emojis(update.guild_id, &update.emojis)
+                    .await?;
+            }
+            Event::GuildStickersUpdate(update) => {
+                self.sync_guild_stickers(update.guild_id, &update.stickers)
+                    .await?;
+            }
+            Event::GuildUpdate(update) => {
+                let icon = update.0.icon.map(|icon| icon.to_string());
+                self.sync_guild_space(update.0.id, &update.0.name, icon.as_deref())
+                    .await?;
+                self.reevaluate_guild_capabilities(update.0.id).await?;
+                self.sync_guild_power(update.0.id).await?;
+            }
+            Event::RoleUpdate(update) => {
+                self.reevaluate_guild_capabilities(update.guild_id).await?;
+                self.sync_guild_power(update.guild_id).await?;
+                self.handle_role_change(update.guild_id, update.role.id)
+                    .await?;
+            }
+            Event::RoleDelete(delete) => {
+                self.reevaluate_guild_capabilities(delete.guild_id).await?;
+                self.sync_guild_power(delete.guild_id).await?;
+                self.handle_role_change(delete.guild_id, delete.role_id)
+                    .await?;
+            }
+            Event::MemberAdd(member) => {
+                self.handle_member_profile(member.guild_id, &member.user, member.nick.as_deref())
+                    .await?;
+                self.handle_member_roles(member.guild_id, member.user.id, &member.roles)
+                    .await?;
+                self.sync_member_power(member.guild_id, member.user.id, &member.roles)
+                    .await?;
+                self.sync_member_portals(member.guild_id, member.user.id, &member.roles)
+                    .await?;
+            }
+            Event::MemberUpdate(update) => {
+                if update.user.id == self.discord()?.user_id {
+                    self.reevaluate_guild_capabilities(update.guild_id).await?;
+                }
+                self.handle_member_roles(update.guild_id, update.user.id, &update.roles)
+                    .await?;
+                self.sync_member_power(update.guild_id, update.user.id, &update.roles)
+                    .await?;
+                self.handle_member_profile(update.guild_id, &update.user, update.nick.as_deref())
+                    .await?;
+                self.sync_member_portals(update.guild_id, update.user.id, &update.roles)
+                    .await?;
+            }
+            Event::UserUpdate(user) => {
+                let avatar = user.avatar.map(|avatar| avatar.to_string());
+                self.sync_puppet_profile(
+                    user.id,
+                    &user.name,
+                    user.discriminator,
+                    avatar.as_deref(),
+                )
+                .await?;
+            }
+            Event::MemberRemove(remove) => {
+                self.handle_member_roles_removed(remove.guild_id, remove.user.id)
+                    .await?;
+                self.handle_discord_kick(remove.guild_id, remove.user.id)
+                    .await?;
+                self.handle_member_left(remove.guild_id, remove.user.id)
+                    .await?;
+            }
+            Event::ChannelUpdate(update) => {
+                self.handle_channel_update(&update.0).await?;
+                self.sync_channel_name(&update.0).await?;
+                self.sync_channel_members(&update.0).await?;
+                self.reevaluate_capabilities(update.0.id).await?;
+                self.sync_forum_tags(update.0.id).await?;
+            }
+            Event::ThreadCreate(thread) => {
+                self.handle_thread_create(&thread.0).await?;
+            }
+            Event::ThreadUpdate(update) => {
+                self.handle_channel_update(&update.0).await?;
+                self.sync_channel_name(&update.0).await?;
+                self.sync_forum_tags(update.0.id).await?;
+                self.handle_thread_create(&update.0).await?;
+            }
+            Event::ThreadDelete(delete) => {
+                self.handle_thread_delete(delete.id).await?;
+            }
+            Event::MessageCreate(message) => {
+                self.observe_clock(Clock::Discord, snowflake::timestamp_ms(message.0.id));
+                if self
+                    .is_discord_echo(message.0.id, message.0.author.id, message.0.webhook_id)
+                    .await?
+                {
+                    debug!("Not bridging {}, the bridge sent it", message.0.id);
+                    return Ok(());
+                }
+                self.handle_discord_mention(&message.0).await?;
+                if let Some(portal) = self.portal_for_message(&message.0).await? {
+                    self.handle_member_activity(&message.0, &portal).await?;
+                    self.bridge_discord_message(&message.0, &portal).await?;
+                    self.bridge_activity(&message.0, &portal).await?;
+                }
+                self.handle_discord_read(&message.0).await?;
+            }
+            Event::MessageUpdate(update) => {
+                if let Some(pinned) = update.pinned {
+                    self.set_message_pinned(update.id, pinned).await?;
+                }
+                self.handle_discord_edit(&update).await?;
+            }
+            Event::MessageDelete(delete) => {
+                self.emit_message_deleted(delete.guild_id, delete.channel_id, delete.id)
+                    .await?;
+                self.handle_discord_delete(delete.channel_id, delete.id)
+                    .await?;
+            }
+            Event::MessageDeleteBulk(delete) => {
+                for message_id in delete.ids {
+                    self.emit_message_deleted(delete.guild_id, delete.channel_id, message_id)
+                        .await?;
+                    self.handle_discord_delete(delete.channel_id, message_id)
+                        .await?;
+                }
+            }
+            Event::BanAdd(ban) => {
+                self.emit_discord_ban(ban.guild_id, ban.user.id).await?;
+                self.handle_discord_ban(ban.guild_id, ban.user.id, true)
+                    .await?;
+            }
+            Event::BanRemove(ban) => {
+                self.handle_discord_ban(ban.guild_id, ban.user.id, false)
+                    .await?;
+            }
+            Event::ReactionAdd(reaction) => {
+                self.handle_knock_reaction(&reaction.0).await?;
+                self.handle_discord_reaction_add(&reaction.0).await?;
+            }
+            Event::ReactionRemove(reaction) => {
+                self.handle_discord_reaction_remove(&reaction.0).await?;
+            }
+            Event::InteractionCreate(interaction) => {
+                self.handle_interaction(interaction.0).await?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
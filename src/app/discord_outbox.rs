@@ -0,0 +1,429 @@
+//! Queue for messages to discord during discord outages
+//!
+//! Messages from matrix are written to `pending_discord_sends` before they are sent and removed
+//! once discord accepted them. Each channel's queue is delivered in order by whoever holds the
+//! channel's lock. When discord is unreachable, sending is paused and messages are only queued,
+//! up to `discord.outage_queue_limit` per channel; a background task waits for discord to come
+//! back and then delivers every channel's queue.
+//!
+//! Messages sent in portal rooms enter the queue through `relay_matrix_message`.
+
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use super::{
+    mappings::MessageMapping,
+    uploads::{matrix_attachment, MatrixAttachment},
+    webhooks::OutgoingMessage,
+    App,
+};
+use crate::{
+    config::OverflowPolicy,
+    retry::{retry, Backoff},
+    snowflake,
+};
+use anyhow::Result;
+use matrix_sdk::ruma::{
+    events::room::message::{MessageType, RoomMessageEventContent},
+    EventId, OwnedEventId, OwnedMxcUri, OwnedRoomId, OwnedUserId, RoomId, UserId,
+};
+use sqlx::query;
+use tokio::time::sleep;
+use tracing::{debug, error, info, warn};
+use twilight_model::id::{marker::ChannelMarker, Id};
+
+/// Delay between checks whether discord is back, before backing off
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay between checks whether discord is back
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// Returns the note that replaces messages dropped from a full queue
+fn overflow_summary(dropped: i32) -> String {
+    if dropped == 1 {
+        "1 message from matrix was not delivered while discord was unreachable".to_owned()
+    } else {
+        format!(
+            "{} messages from matrix were not delivered while discord was unreachable",
+            dropped
+        )
+    }
+}
+
+impl App {
+    /// Relays a message from a matrix user to a discord channel
+    ///
+    /// The message is queued and the channel's queue is delivered in order. While discord is
+    /// unreachable, the message stays queued until it is back. Once delivered, the message is
+    /// mapped to `event_id` so that edits can be relayed. Attachments are only downloaded from
+    /// matrix once the message is delivered.
+    ///
+    /// # Errors
+    /// This function will return an error if queueing the message fails
+    #[allow(clippy::panic)]
+    pub async fn relay_to_discord(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+        room_id: &RoomId,
+        event_id: &EventId,
+        sender: &UserId,
+        message: &OutgoingMessage,
+    ) -> Result<()> {
+        if self.dry_run {
+            self.send_to_discord(channel_id, sender, message).await?;
+            return Ok(());
+        }
+        query!(
+            "INSERT INTO pending_discord_sends (discord_channel_id, matrix_user_id, content, matrix_room_id, matrix_event_id, reply_to, attachment_mxc, attachment_name) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+            snowflake::to_db(channel_id),
+            sender.as_str(),
+            message.content,
+            room_id.as_str(),
+            event_id.as_str(),
+            message.reply_to.map(snowflake::to_db),
+            message.attachment.as_ref().map(|attachment| attachment.mxc.as_str()),
+            message.attachment.as_ref().map(|attachment| attachment.filename.as_str())
+        )
+        .execute(&*self.db)
+        .await?;
+        self.enforce_queue_limit(channel_id).await?;
+        // The pause flag is read after queueing, so a message queued while paused is seen by
+        // the final pass of the catch-up task.
+        if self.discord_outbox_paused.load(Ordering::Acquire) {
+            debug!("Discord unreachable, queued message to {}", channel_id);
+            return Ok(());
+        }
+        if !self.deliver_channel_queue(channel_id).await? {
+            self.pause_discord_outbox();
+        }
+        Ok(())
+    }
+
+    /// Relays a message sent in a portal room to its discord channel
+    ///
+    /// Text, notices and emotes are relayed, emotes in italics, and images, videos, audio and
+    /// files are uploaded, see `discord_upload`. Rich replies reference the replied message and
+    /// messages in matrix threads go to their discord thread, see `discord_thread_for_event`.
+    /// Messages in rooms that aren't portals are ignored.
+    ///
+    /// # Errors
+    /// This function will return an error if a database query, converting the message or queueing
+    /// it fails
+    pub(super) async fn relay_matrix_message(
+        self: &Arc<Self>,
+        room_id: &RoomId,
+        event_id: &EventId,
+        sender: &UserId,
+        content: &RoomMessageEventContent,
+    ) -> Result<()> {
+        let is_text = matches!(
+            content.msgtype,
+            MessageType::Text(_) | MessageType::Notice(_) | MessageType::Emote(_)
+        );
+        let attachment = matrix_attachment(content);
+        if !is_text && attachment.is_none() {
+            debug!(
+                "Not relaying {}, it has no text or unencrypted file",
+                event_id
+            );
+            return Ok(());
+        }
+        let portal = match self.portal_by_room(room_id).await? {
+            Some(portal) => portal,
+            None => return Ok(()),
+        };
+        let mut markdown = if is_text {
+            self.discord_markdown(content).await?
+        } else {
+            String::new()
+        };
+        if let MessageType::Emote(_) = content.msgtype {
+            markdown = format!("_{}_", markdown);
+        }
+        let thread_id = self
+            .discord_thread_for_event(content, room_id, portal.channel_id)
+            .await?;
+        let channel_id = thread_id.unwrap_or(portal.channel_id);
+        let message = OutgoingMessage {
+            content: markdown,
+            reply_to: self.discord_reply_target(content, channel_id).await?,
+            attachment,
+        };
+        self.relay_to_discord(channel_id, room_id, event_id, sender, &message)
+            .await?;
+        if let Some(thread_id) = thread_id {
+            self.record_thread_event(thread_id, event_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Shrinks the queue of a channel to the configured limit
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    async fn enforce_queue_limit(self: &Arc<Self>, channel_id: Id<ChannelMarker>) -> Result<()> {
+        let limit = i64::try_from(self.config.discord.outage_queue_limit)?;
+        let mut tx = self.db.begin().await?;
+        loop {
+            let queued = query!(
+                "SELECT COUNT(*) AS count FROM pending_discord_sends WHERE discord_channel_id = $1",
+                snowflake::to_db(channel_id)
+            )
+            .fetch_one(&mut tx)
+            .await?
+            .count
+            .unwrap_or_default();
+            if queued <= limit {
+                break;
+            }
+            let oldest = query!(
+                "SELECT seq, dropped FROM pending_discord_sends WHERE discord_channel_id = $1 ORDER BY seq LIMIT 2",
+                snowflake::to_db(channel_id)
+            )
+            .fetch_all(&mut tx)
+            .await?;
+            match (
+                self.config.discord.outage_overflow,
+                oldest.get(0),
+                oldest.get(1),
+            ) {
+                (OverflowPolicy::Summarize, Some(summary), Some(next)) if summary.dropped > 0 => {
+                    query!(
+                        "UPDATE pending_discord_sends SET dropped = dropped + 1 WHERE seq = $1",
+                        summary.seq
+                    )
+                    .execute(&mut tx)
+                    .await?;
+                    query!("DELETE FROM pending_discord_sends WHERE seq = $1", next.seq)
+                        .execute(&mut tx)
+                        .await?;
+                }
+                (OverflowPolicy::Summarize, Some(oldest), _) => {
+                    query!(
+                        "UPDATE pending_discord_sends SET dropped = 1, matrix_user_id = NULL, content = '' WHERE seq = $1",
+                        oldest.seq
+                    )
+                    .execute(&mut tx)
+                    .await?;
+                }
+                (OverflowPolicy::DropOldest, Some(oldest), _) => {
+                    query!(
+                        "DELETE FROM pending_discord_sends WHERE seq = $1",
+                        oldest.seq
+                    )
+                    .execute(&mut tx)
+                    .await?;
+                }
+                (_, None, _) => break,
+            }
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Delivers the queue of a channel in order
+    ///
+    /// Delivered messages are mapped to their matrix events. Messages discord rejects while it
+    /// is reachable are dropped. Returns `false` if discord became unreachable before the queue
+    /// was delivered.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    async fn deliver_channel_queue(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+    ) -> Result<bool> {
+        let lock = Arc::clone(&*self.discord_outbox_locks.entry(channel_id).or_default());
+        let _guard = lock.lock().await;
+        let queued = query!(
+            "SELECT seq, matrix_user_id, content, dropped, matrix_room_id, matrix_event_id, reply_to, attachment_mxc, attachment_name FROM pending_discord_sends WHERE discord_channel_id = $1 ORDER BY seq",
+            snowflake::to_db(channel_id)
+        )
+        .fetch_all(&*self.db)
+        .await?;
+        for row in queued {
+            let result = match row.matrix_user_id {
+                _ if row.dropped > 0 => self
+                    .send_bot_notice(channel_id, &overflow_summary(row.dropped))
+                    .await
+                    .map(|()| None),
+                Some(sender) => match (
+                    OwnedUserId::try_from(sender),
+                    row.reply_to.map(snowflake::from_db).transpose(),
+                ) {
+                    (Ok(sender), Ok(reply_to)) => {
+                        let message = OutgoingMessage {
+                            content: row.content,
+                            reply_to,
+                            attachment: row.attachment_mxc.zip(row.attachment_name).map(
+                                |(mxc, filename)| MatrixAttachment {
+                                    mxc: OwnedMxcUri::from(mxc),
+                                    filename,
+                                },
+                            ),
+                        };
+                        self.send_to_discord(channel_id, &sender, &message).await
+                    }
+                    (Err(e), _) => Err(e.into()),
+                    (_, Err(e)) => Err(e),
+                },
+                None => Ok(None),
+            };
+            let sent = match result {
+                Ok(sent) => sent,
+                Err(e) => {
+                    if !self.discord_reachable().await {
+                        warn!("Discord unreachable, stopping delivery to {}", channel_id);
+                        return Ok(false);
+                    }
+                    error!("Dropping queued message to {}: {:?}", channel_id, e);
+                    None
+                }
+            };
+            let mut tx = self.db.begin().await?;
+            if let (Some(sent), Some(room_id), Some(event_id)) =
+                (sent, row.matrix_room_id, row.matrix_event_id)
+            {
+                match (
+                    OwnedEventId::try_from(event_id),
+                    OwnedRoomId::try_from(room_id),
+                ) {
+                    (Ok(event_id), Ok(room_id)) => {
+                        MessageMapping {
+                            event_id,
+                            room_id,
+                            message_id: sent.id,
+                            channel_id,
+                            webhook_id: sent.webhook_id,
+                            relayed: true,
+                        }
+                        .insert(&mut tx)
+                        .await?;
+                    }
+                    (Err(e), _) | (_, Err(e)) => {
+                        warn!("Not mapping {} with an invalid id: {:?}", sent.id, e);
+                    }
+                }
+            }
+            query!("DELETE FROM pending_discord_sends WHERE seq = $1", row.seq)
+                .execute(&mut tx)
+                .await?;
+            tx.commit().await?;
+        }
+        Ok(true)
+    }
+
+    /// Sends a message as the bridge bot
+    ///
+    /// # Errors
+    /// This function will return an error if the request to discord fails
+    async fn send_bot_notice(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+        notice: &str,
+    ) -> Result<()> {
+        self.discord()?
+            .http
+            .create_message(channel_id)
+            .content(notice)?
+            .exec()
+            .await?;
+        Ok(())
+    }
+
+    /// Returns whether discord answers requests
+    async fn discord_reachable(self: &Arc<Self>) -> bool {
+        let http = match self.discord() {
+            Ok(discord) => &discord.http,
+            Err(_) => return false,
+        };
+        retry(
+            "discord",
+            Backoff::default().with_max_attempts(1),
+            || async { http.current_user().exec().await },
+        )
+        .await
+        .is_ok()
+    }
+
+    /// Pauses sending to discord until it is reachable again
+    pub(super) fn pause_discord_outbox(self: &Arc<Self>) {
+        if self.discord_outbox_paused.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        warn!("Discord unreachable, queueing messages until it is back");
+        let this = Arc::clone(self);
+        tokio::spawn(async move {
+            this.catch_up_discord().await;
+        });
+    }
+
+    /// Waits for discord to come back and delivers the queues of all channels
+    async fn catch_up_discord(self: &Arc<Self>) {
+        let backoff = Backoff::new(RECONNECT_DELAY, MAX_RECONNECT_DELAY).without_jitter();
+        let mut attempt = 0;
+        loop {
+            sleep(backoff.delay(attempt)).await;
+            attempt = attempt.saturating_add(1);
+            if !self.discord_reachable().await {
+                continue;
+            }
+            match self.deliver_discord_queues().await {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(e) => error!("Failed to catch up on queued messages to discord: {:?}", e),
+            }
+        }
+        self.discord_outbox_paused.store(false, Ordering::Release);
+        if let Err(e) = self.deliver_discord_queues().await {
+            error!("Failed to catch up on queued messages to discord: {:?}", e);
+        }
+        info!("Discord is reachable again, caught up on queued messages");
+    }
+
+    /// Delivers the queues of all channels
+    ///
+    /// Returns `false` if discord became unreachable before all queues were delivered.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub(super) async fn deliver_discord_queues(self: &Arc<Self>) -> Result<bool> {
+        if self.dry_run {
+            return Ok(true);
+        }
+        let channels = query!(
+            "SELECT discord_channel_id FROM pending_discord_sends GROUP BY discord_channel_id ORDER BY MIN(seq)"
+        )
+        .fetch_all(&*self.db)
+        .await?;
+        for row in channels {
+            let channel_id = snowflake::from_db(row.discord_channel_id)?;
+            if !self.deliver_channel_queue(channel_id).await? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_dropped_messages() {
+        assert_eq!(
+            overflow_summary(1),
+            "1 message from matrix was not delivered while discord was unreachable"
+        );
+        assert_eq!(
+            overflow_summary(3),
+            "3 messages from matrix were not delivered while discord was unreachable"
+        );
+    }
+}
@@ -0,0 +1,103 @@
+//! Double puppeting of matrix users on discord
+//!
+//! Matrix users that logged in with `!discord login <token>` or with discord's OAuth2 login have
+//! their messages sent to discord
+//! as their own discord account instead of being relayed through a webhook or the bridge bot.
+//! The discord clients of logged in users are created on first use and dropped when the user
+//! logs out or their token stops working.
+
+use std::sync::Arc;
+
+use super::App;
+use anyhow::Result;
+use matrix_sdk::ruma::UserId;
+use sqlx::query;
+use twilight_http::Client;
+use twilight_model::id::{
+    marker::{ChannelMarker, MessageMarker},
+    Id,
+};
+
+impl App {
+    /// Returns the discord token of a matrix user's own account
+    ///
+    /// Returns `None` if the user isn't logged in or discord rejected their token.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub(super) async fn user_discord_token(
+        self: &Arc<Self>,
+        user: &UserId,
+    ) -> Result<Option<String>> {
+        query!(
+            "SELECT token, sealed FROM discord_tokens WHERE user_id = $1 AND invalid_since IS NULL",
+            user.as_str()
+        )
+        .fetch_optional(&*self.db)
+        .await?
+        .map(|row| self.stored_token(row.token, row.sealed))
+        .transpose()
+    }
+
+    /// Returns the discord client of a matrix user's own account
+    ///
+    /// Returns `None` if the user isn't logged in or discord rejected their token.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    pub(super) async fn user_discord_http(
+        self: &Arc<Self>,
+        user: &UserId,
+    ) -> Result<Option<Arc<Client>>> {
+        if let Some(client) = self.discord_user_http.get(user) {
+            return Ok(Some(Arc::clone(&*client)));
+        }
+        let token = match self.user_discord_token(user).await? {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+        let client = Arc::new(Client::new(token));
+        self.discord_user_http
+            .insert(user.to_owned(), Arc::clone(&client));
+        Ok(Some(client))
+    }
+
+    /// Drops the cached discord client of a matrix user
+    pub(super) fn forget_user_discord_http(&self, user: &UserId) {
+        self.discord_user_http.remove(user);
+    }
+
+    /// Returns the client to edit a message a matrix user sent as their own account with
+    ///
+    /// Returns `None` if the message was relayed instead.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query or the request to discord fails
+    pub(super) async fn puppeted_message_http(
+        self: &Arc<Self>,
+        sender: &UserId,
+        channel_id: Id<ChannelMarker>,
+        message_id: Id<MessageMarker>,
+    ) -> Result<Option<Arc<Client>>> {
+        let client = match self.user_discord_http(sender).await? {
+            Some(client) => client,
+            None => return Ok(None),
+        };
+        let discord_user = match self.linked_discord_user(sender).await? {
+            Some(discord_user) => discord_user,
+            None => return Ok(None),
+        };
+        let author = self
+            .discord()?
+            .http
+            .message(channel_id, message_id)
+            .exec()
+            .await?
+            .model()
+            .await?
+            .author
+            .id;
+        Ok((author == discord_user).then(|| client))
+    }
+}
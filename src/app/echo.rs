@@ -0,0 +1,143 @@
+//! Echo suppression
+//!
+//! Everything the bridge sends comes back to it: events it sends to matrix are pushed by the
+//! homeserver and messages it sends to discord are dispatched by the gateway. The bridge records
+//! the matrix events and discord messages it sent and the webhooks it sent them through, and
+//! drops them when they come back instead of bridging them a second time. The records only cover
+//! a short window, after which the message map is consulted, which also covers events that are
+//! handled again after a restart.
+
+use std::time::{Duration, Instant};
+
+use super::{mappings::MessageMapping, webhooks::SentMessage, App};
+use anyhow::Result;
+use dashmap::{DashMap, DashSet};
+use matrix_sdk::ruma::{EventId, OwnedEventId, UserId};
+use twilight_model::id::{
+    marker::{MessageMarker, UserMarker, WebhookMarker},
+    Id,
+};
+
+/// Time for which sent events and messages are recognized without the message map
+const ECHO_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Number of records from which expired records are pruned
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Removes records that are older than the echo window
+fn prune<K: Eq + std::hash::Hash>(records: &DashMap<K, Instant>, now: Instant) {
+    if records.len() >= PRUNE_THRESHOLD {
+        records.retain(|_, recorded| now.duration_since(*recorded) < ECHO_WINDOW);
+    }
+}
+
+/// Returns whether a record is still within the echo window
+fn recent(recorded: Option<Instant>, now: Instant) -> bool {
+    recorded.map_or(false, |recorded| now.duration_since(recorded) < ECHO_WINDOW)
+}
+
+/// Events and messages recently sent by the bridge
+#[derive(Debug, Default)]
+pub(super) struct EchoGuard {
+    /// Matrix events sent by the bridge and when they were sent
+    events: DashMap<OwnedEventId, Instant>,
+    /// Discord messages sent by the bridge and when they were sent
+    messages: DashMap<Id<MessageMarker>, Instant>,
+    /// Webhooks the bridge sent messages through
+    webhooks: DashSet<Id<WebhookMarker>>,
+}
+
+impl EchoGuard {
+    /// Records a matrix event sent by the bridge
+    pub(super) fn record_event(&self, event_id: OwnedEventId) {
+        let now = Instant::now();
+        prune(&self.events, now);
+        self.events.insert(event_id, now);
+    }
+
+    /// Records a discord message sent by the bridge
+    pub(super) fn record_message(&self, sent: SentMessage) {
+        let now = Instant::now();
+        prune(&self.messages, now);
+        self.messages.insert(sent.id, now);
+        if let Some(webhook_id) = sent.webhook_id {
+            self.webhooks.insert(webhook_id);
+        }
+    }
+
+    /// Returns whether a matrix event was recently sent by the bridge
+    fn sent_event(&self, event_id: &EventId) -> bool {
+        recent(
+            self.events.get(event_id).map(|recorded| *recorded),
+            Instant::now(),
+        )
+    }
+
+    /// Returns whether a discord message was recently sent by the bridge
+    fn sent_message(&self, message_id: Id<MessageMarker>) -> bool {
+        recent(
+            self.messages.get(&message_id).map(|recorded| *recorded),
+            Instant::now(),
+        )
+    }
+}
+
+impl App {
+    /// Returns whether a matrix event was sent by the bridge
+    ///
+    /// Events of the bridge bot and its puppets, events recorded when they were sent and events
+    /// mapped to a discord message they were bridged from are echoes.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    pub(super) async fn is_matrix_echo(&self, sender: &UserId, event_id: &EventId) -> Result<bool> {
+        if self.is_bridge_user(sender) || self.echoes.sent_event(event_id) {
+            return Ok(true);
+        }
+        Ok(MessageMapping::for_event(&*self.db, event_id)
+            .await?
+            .map_or(false, |mapping| !mapping.relayed))
+    }
+
+    /// Returns whether a discord message was sent by the bridge
+    ///
+    /// Messages of the bridge bot, messages sent through a webhook the bridge used, messages
+    /// recorded when they were sent and messages mapped to a matrix event they were relayed from
+    /// are echoes.
+    ///
+    /// # Errors
+    /// This function will return an error if the bridge bot isn't configured or the database
+    /// query fails
+    pub(super) async fn is_discord_echo(
+        &self,
+        message_id: Id<MessageMarker>,
+        author_id: Id<UserMarker>,
+        webhook_id: Option<Id<WebhookMarker>>,
+    ) -> Result<bool> {
+        if author_id == self.discord()?.user_id
+            || webhook_id.map_or(false, |webhook_id| {
+                self.echoes.webhooks.contains(&webhook_id)
+            })
+            || self.echoes.sent_message(message_id)
+        {
+            return Ok(true);
+        }
+        Ok(MessageMapping::for_message(&*self.db, message_id)
+            .await?
+            .iter()
+            .any(|mapping| mapping.relayed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_expire_after_the_window() {
+        let now = Instant::now();
+        assert!(!recent(None, now));
+        assert!(recent(Some(now), now));
+        assert!(!recent(Some(now), now + ECHO_WINDOW));
+    }
+}
@@ -0,0 +1,167 @@
+//! Discord message edits on matrix
+//!
+//! When a discord message that was bridged to matrix is edited, an `m.replace` edit is sent for
+//! its matrix event, as the user that sent the original event so that clients accept the edit.
+//! Edits of messages that were relayed from matrix are edited on matrix already and are ignored.
+//! Edits in the other direction are applied by `edit_on_discord`.
+
+use std::sync::Arc;
+
+use super::{client::VirtualClient, mappings::MessageMapping, App};
+use crate::{
+    features::Feature,
+    formatter::{self, Pills},
+};
+use anyhow::Result;
+use matrix_sdk::{
+    room::Joined,
+    ruma::{
+        events::room::message::{Relation, Replacement, RoomMessageEventContent},
+        EventId, OwnedUserId, UserId,
+    },
+};
+use serde::Deserialize;
+use tracing::{debug, info};
+use twilight_model::gateway::payload::incoming::MessageUpdate;
+
+/// The sender of a matrix event
+#[derive(Debug, Deserialize)]
+struct EventSender {
+    /// User id of the sender
+    sender: OwnedUserId,
+}
+
+/// Builds the edit of a matrix event to a new text
+fn replacement(mapping: &MessageMapping, text: &str, pills: &Pills) -> RoomMessageEventContent {
+    let mut content = formatter::message_content(&format!("* {}", text), pills);
+    content.relates_to = Some(Relation::Replacement(Replacement::new(
+        mapping.event_id.clone(),
+        Box::new(formatter::message_content(text, pills)),
+    )));
+    content
+}
+
+impl App {
+    /// Returns the sender of a matrix event in a room the bridge bot is in
+    ///
+    /// # Errors
+    /// This function will return an error if the event can't be fetched
+    pub(super) async fn event_sender(
+        self: &Arc<Self>,
+        room: &Joined,
+        event_id: &EventId,
+    ) -> Result<OwnedUserId> {
+        Ok(room
+            .event(event_id)
+            .await?
+            .event
+            .deserialize_as::<EventSender>()?
+            .sender)
+    }
+
+    /// Returns the client of a matrix user the bridge sends events as
+    ///
+    /// Returns `None` if the user is neither the bridge bot nor one of its puppets.
+    ///
+    /// # Errors
+    /// This function will return an error if the puppet client can't be created
+    pub(super) async fn bridged_sender_client(
+        self: &Arc<Self>,
+        sender: &UserId,
+    ) -> Result<Option<Arc<VirtualClient>>> {
+        if sender == self.user_id {
+            return Ok(Some(Arc::clone(&self.client)));
+        }
+        if let Some(client) = self.webhook_clients.get(sender.localpart()) {
+            return Ok(Some(Arc::clone(&*client)));
+        }
+        match self.puppet_discord_id(sender) {
+            Some(user_id) => Ok(Some(self.client(Some(user_id)).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Bridges the edit of a discord message to matrix
+    ///
+    /// Only the first event of a message is edited, the others are its attachments.
+    ///
+    /// # Errors
+    /// This function will return an error if a database query, reading the original event or
+    /// sending the edit fails
+    pub(super) async fn handle_discord_edit(
+        self: &Arc<Self>,
+        update: &MessageUpdate,
+    ) -> Result<()> {
+        let text = match update.content {
+            Some(ref text) => text,
+            None => return Ok(()),
+        };
+        let mapping = match MessageMapping::for_message(&*self.db, update.id)
+            .await?
+            .into_iter()
+            .next()
+        {
+            Some(mapping) if !mapping.relayed => mapping,
+            _ => return Ok(()),
+        };
+        if !self.room_feature(&mapping.room_id, Feature::Edits).await? {
+            return Ok(());
+        }
+        let room = match self.client.get_joined_room(&mapping.room_id) {
+            Some(room) => room,
+            None => return Ok(()),
+        };
+        let sender = self.event_sender(&room, &mapping.event_id).await?;
+        let client = match self.bridged_sender_client(&sender).await? {
+            Some(client) => client,
+            None => {
+                debug!(
+                    "Not editing {}, its sender {} isn't bridged",
+                    mapping.event_id, sender
+                );
+                return Ok(());
+            }
+        };
+        let room = match client.get_joined_room(&mapping.room_id) {
+            Some(room) => room,
+            None => return Ok(()),
+        };
+        let pills = self.discord_pills(text, &mapping.room_id).await?;
+        let mut content = replacement(&mapping, text, &pills);
+        if self.dry_run {
+            info!("[dry-run] Would edit {}: {}", mapping.event_id, text);
+            return Ok(());
+        }
+        self.scrub_content(&mut content);
+        self.send_to_joined(&room, content, None).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::{OwnedEventId, OwnedRoomId};
+    use twilight_model::id::Id;
+
+    #[test]
+    fn edits_reference_the_original_event() {
+        let mapping = MessageMapping {
+            event_id: OwnedEventId::try_from("$original:chir.rs").expect("valid event id"),
+            room_id: OwnedRoomId::try_from("!portal:chir.rs").expect("valid room id"),
+            message_id: Id::new(2),
+            channel_id: Id::new(1),
+            webhook_id: None,
+            relayed: false,
+        };
+        let content = replacement(&mapping, "fixed typo", &Pills::new());
+        assert_eq!(content.body(), "* fixed typo");
+        let replacement = match content.relates_to {
+            Some(Relation::Replacement(replacement)) => Some(replacement),
+            _ => None,
+        }
+        .expect("content is an edit");
+        assert_eq!(replacement.event_id, mapping.event_id);
+        assert_eq!(replacement.new_content.body(), "fixed typo");
+    }
+}
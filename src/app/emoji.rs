@@ -0,0 +1,322 @@
+//! Custom emoji and sticker cache
+//!
+//! Discord emojis and stickers are mirrored to the matrix content repository once and the
+//! resulting MXC URIs are cached in the database. Custom emojis in bridged messages are shown as
+//! inline images, and stickers are bridged as images after the message's attachments.
+
+use std::{collections::HashMap, sync::Arc};
+
+use super::{media::MediaRetention, portals::Portal, App};
+use crate::{features::Feature, snowflake};
+use anyhow::Result;
+use matrix_sdk::ruma::{
+    events::room::message::{ImageMessageEventContent, MessageType, RoomMessageEventContent},
+    OwnedMxcUri,
+};
+use sqlx::query;
+use tracing::{debug, info};
+use twilight_model::{
+    channel::{
+        message::sticker::{Sticker, StickerFormatType},
+        Message,
+    },
+    guild::Emoji,
+    id::{
+        marker::{EmojiMarker, GuildMarker, StickerMarker},
+        Id,
+    },
+};
+
+/// Returns the CDN URL of a custom emoji
+fn emoji_url(id: Id<EmojiMarker>, animated: bool) -> String {
+    let extension = if animated { "gif" } else { "png" };
+    format!("https://cdn.discordapp.com/emojis/{}.{}", id, extension)
+}
+
+/// Returns the CDN URL of a sticker, if it can be displayed on matrix
+fn sticker_url(id: Id<StickerMarker>, format: StickerFormatType) -> Option<String> {
+    match format {
+        StickerFormatType::Png | StickerFormatType::Apng => {
+            Some(format!("https://media.discordapp.net/stickers/{}.png", id))
+        }
+        _ => None,
+    }
+}
+
+/// Builds the event content of a sticker
+fn sticker_content(name: &str, url: OwnedMxcUri) -> RoomMessageEventContent {
+    RoomMessageEventContent::new(MessageType::Image(ImageMessageEventContent::plain(
+        name.to_owned(),
+        url,
+        None,
+    )))
+}
+
+impl App {
+    /// Returns the MXC URI of a custom emoji, uploading it if it is not yet known
+    ///
+    /// # Errors
+    /// This function will return an error if the database query or the upload fails
+    #[allow(clippy::panic)]
+    pub async fn emoji_mxc(
+        self: &Arc<Self>,
+        id: Id<EmojiMarker>,
+        name: &str,
+        animated: bool,
+    ) -> Result<Option<OwnedMxcUri>> {
+        let row = query!(
+            "SELECT mxc_url FROM discord_emojis WHERE emoji_id = $1",
+            snowflake::to_db(id)
+        )
+        .fetch_optional(&*self.db)
+        .await?;
+        if let Some(row) = row {
+            return Ok(Some(row.mxc_url.into()));
+        }
+        self.store_emoji(None, id, name, animated).await
+    }
+
+    /// Mirrors an emoji and stores it in the database
+    #[allow(clippy::panic)]
+    async fn store_emoji(
+        self: &Arc<Self>,
+        guild_id: Option<Id<GuildMarker>>,
+        id: Id<EmojiMarker>,
+        name: &str,
+        animated: bool,
+    ) -> Result<Option<OwnedMxcUri>> {
+        let mxc = match self
+            .mirror_discord_media(&emoji_url(id, animated), None, MediaRetention::Permanent)
+            .await?
+        {
+            Some(mxc) => mxc,
+            None => return Ok(None),
+        };
+        query!(
+            "INSERT INTO discord_emojis (emoji_id, guild_id, name, animated, mxc_url) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (emoji_id) DO UPDATE SET guild_id = COALESCE($2, discord_emojis.guild_id), name = $3, animated = $4, mxc_url = $5",
+            snowflake::to_db(id),
+            guild_id.map(snowflake::to_db),
+            name,
+            animated,
+            mxc.as_str()
+        )
+        .execute(&*self.db)
+        .await?;
+        Ok(Some(mxc))
+    }
+
+    /// Incrementally updates the cached emojis of a guild
+    ///
+    /// New emojis are uploaded, renamed emojis are updated and deleted emojis are removed.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query or an upload fails
+    #[allow(clippy::panic)]
+    pub(super) async fn sync_guild_emojis(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+        emojis: &[Emoji],
+    ) -> Result<()> {
+        let known = query!(
+            "SELECT emoji_id, name, animated FROM discord_emojis WHERE guild_id = $1",
+            snowflake::to_db(guild_id)
+        )
+        .fetch_all(&*self.db)
+        .await?
+        .into_iter()
+        .map(|row| (row.emoji_id, (row.name, row.animated)))
+        .collect::<HashMap<_, _>>();
+
+        let ids = emojis
+            .iter()
+            .map(|emoji| snowflake::to_db(emoji.id))
+            .collect::<Vec<_>>();
+        let removed = query!(
+            "DELETE FROM discord_emojis WHERE guild_id = $1 AND NOT (emoji_id = ANY($2))",
+            snowflake::to_db(guild_id),
+            &ids
+        )
+        .execute(&*self.db)
+        .await?
+        .rows_affected();
+
+        let mut added = 0_usize;
+        for emoji in emojis {
+            match known.get(&snowflake::to_db(emoji.id)) {
+                Some((name, animated)) if *name == emoji.name && *animated == emoji.animated => {}
+                Some((_, animated)) if *animated == emoji.animated => {
+                    debug!("Emoji {} was renamed to {}", emoji.id, emoji.name);
+                    query!(
+                        "UPDATE discord_emojis SET name = $2 WHERE emoji_id = $1",
+                        snowflake::to_db(emoji.id),
+                        emoji.name
+                    )
+                    .execute(&*self.db)
+                    .await?;
+                }
+                _ => {
+                    self.store_emoji(Some(guild_id), emoji.id, &emoji.name, emoji.animated)
+                        .await?;
+                    added += 1;
+                }
+            }
+        }
+        info!(
+            "Synced emojis of guild {}: {} added, {} removed",
+            guild_id, added, removed
+        );
+        Ok(())
+    }
+
+    /// Returns the MXC URI of a sticker, uploading it if it is not yet known
+    ///
+    /// Returns `None` for stickers that can't be displayed on matrix.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query or the upload fails
+    #[allow(clippy::panic)]
+    pub async fn sticker_mxc(
+        self: &Arc<Self>,
+        id: Id<StickerMarker>,
+        name: &str,
+        format: StickerFormatType,
+    ) -> Result<Option<OwnedMxcUri>> {
+        let row = query!(
+            "SELECT mxc_url FROM discord_stickers WHERE sticker_id = $1",
+            snowflake::to_db(id)
+        )
+        .fetch_optional(&*self.db)
+        .await?;
+        if let Some(row) = row {
+            return Ok(Some(row.mxc_url.into()));
+        }
+        self.store_sticker(None, id, name, format).await
+    }
+
+    /// Returns the events the stickers of a discord message are bridged as
+    ///
+    /// Stickers that can't be displayed on matrix are left out, as are all stickers in portals
+    /// with stickers disabled.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query or an upload fails
+    pub(super) async fn sticker_contents(
+        self: &Arc<Self>,
+        message: &Message,
+        portal: &Portal,
+    ) -> Result<Vec<RoomMessageEventContent>> {
+        if !self.portal_feature(portal, Feature::Stickers) {
+            return Ok(Vec::new());
+        }
+        let mut contents = Vec::with_capacity(message.sticker_items.len());
+        for sticker in &message.sticker_items {
+            if let Some(mxc) = self
+                .sticker_mxc(sticker.id, &sticker.name, sticker.format_type)
+                .await?
+            {
+                contents.push(sticker_content(&sticker.name, mxc));
+            }
+        }
+        Ok(contents)
+    }
+
+    /// Mirrors a sticker and stores it in the database
+    #[allow(clippy::panic)]
+    async fn store_sticker(
+        self: &Arc<Self>,
+        guild_id: Option<Id<GuildMarker>>,
+        id: Id<StickerMarker>,
+        name: &str,
+        format: StickerFormatType,
+    ) -> Result<Option<OwnedMxcUri>> {
+        let url = match sticker_url(id, format) {
+            Some(url) => url,
+            None => return Ok(None),
+        };
+        let mxc = match self
+            .mirror_discord_media(&url, None, MediaRetention::Permanent)
+            .await?
+        {
+            Some(mxc) => mxc,
+            None => return Ok(None),
+        };
+        query!(
+            "INSERT INTO discord_stickers (sticker_id, guild_id, name, mxc_url) VALUES ($1, $2, $3, $4) ON CONFLICT (sticker_id) DO UPDATE SET guild_id = COALESCE($2, discord_stickers.guild_id), name = $3, mxc_url = $4",
+            snowflake::to_db(id),
+            guild_id.map(snowflake::to_db),
+            name,
+            mxc.as_str()
+        )
+        .execute(&*self.db)
+        .await?;
+        Ok(Some(mxc))
+    }
+
+    /// Incrementally updates the cached stickers of a guild
+    ///
+    /// # Errors
+    /// This function will return an error if the database query or an upload fails
+    #[allow(clippy::panic)]
+    pub(super) async fn sync_guild_stickers(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+        stickers: &[Sticker],
+    ) -> Result<()> {
+        if !self.config.features.stickers {
+            return Ok(());
+        }
+        let known = query!(
+            "SELECT sticker_id, name FROM discord_stickers WHERE guild_id = $1",
+            snowflake::to_db(guild_id)
+        )
+        .fetch_all(&*self.db)
+        .await?
+        .into_iter()
+        .map(|row| (row.sticker_id, row.name))
+        .collect::<HashMap<_, _>>();
+
+        let ids = stickers
+            .iter()
+            .map(|sticker| snowflake::to_db(sticker.id))
+            .collect::<Vec<_>>();
+        let removed = query!(
+            "DELETE FROM discord_stickers WHERE guild_id = $1 AND NOT (sticker_id = ANY($2))",
+            snowflake::to_db(guild_id),
+            &ids
+        )
+        .execute(&*self.db)
+        .await?
+        .rows_affected();
+
+        let mut added = 0_usize;
+        for sticker in stickers {
+            match known.get(&snowflake::to_db(sticker.id)) {
+                Some(name) if *name == sticker.name => {}
+                Some(_) => {
+                    query!(
+                        "UPDATE discord_stickers SET name = $2 WHERE sticker_id = $1",
+                        snowflake::to_db(sticker.id),
+                        sticker.name
+                    )
+                    .execute(&*self.db)
+                    .await?;
+                }
+                None => {
+                    self.store_sticker(
+                        Some(guild_id),
+                        sticker.id,
+                        &sticker.name,
+                        sticker.format_type,
+                    )
+                    .await?;
+                    added += 1;
+                }
+            }
+        }
+        info!(
+            "Synced stickers of guild {}: {} added, {} removed",
+            guild_id, added, removed
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,615 @@
+//! Persistent event queue
+//!
+//! Events from the homeserver and discord are written to the `event_queue` table before they are
+//! acknowledged. An event is only removed once it was handled, so bridging work that was
+//! interrupted by a restart is picked up again. Failed events stay in the table and are retried
+//! with an exponential backoff until they succeed or run out of attempts.
+//!
+//! Every event has an ordering key, the matrix room or discord channel it belongs to. Events with
+//! the same key are handled one after another in the order they arrived, while up to
+//! `bridge.event_parallelism` events with different keys are handled concurrently, so a slow room
+//! doesn't stall the others. An event with the empty key waits for all events before it.
+
+use std::{
+    sync::{atomic::Ordering, Arc, Weak},
+    time::Duration,
+};
+
+use super::{receipts::receipt_threads, App, QueueEvent};
+use crate::retry::Backoff;
+use anyhow::{Context, Result};
+use matrix_sdk::{
+    event_handler::RawEvent,
+    room::Room,
+    ruma::{OwnedRoomId, RoomId},
+};
+use serde::{de::DeserializeSeed, Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::query;
+use tracing::{debug, error, info, warn};
+use twilight_gateway::Event;
+use twilight_model::gateway::event::{GatewayEvent, GatewayEventDeserializer};
+
+/// Gateway opcode of dispatched events
+const DISPATCH_OP: u8 = 0;
+
+/// Ordering key of events that wait for all events queued before them
+const BARRIER_KEY: &str = "";
+
+/// Time after which the worker checks for events that are due for a retry
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Backoff between attempts of a failing event
+const EVENT_BACKOFF: Backoff =
+    Backoff::new(Duration::from_secs(1), Duration::from_secs(600)).with_max_attempts(10);
+
+/// Kind of a queued matrix event
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum MatrixEventKind {
+    /// Stripped member event of an invite
+    StrippedMember,
+    /// Membership change in a joined room
+    Member,
+    /// Room message
+    Message,
+    /// Room upgrade
+    Tombstone,
+    /// Canonical alias change
+    CanonicalAlias,
+    /// Room name change
+    Name,
+    /// Room topic change
+    Topic,
+    /// Reaction
+    Reaction,
+    /// Read receipts
+    Receipt,
+    /// Typing notifications
+    Typing,
+    /// Redaction
+    Redaction,
+    /// Portal settings change
+    PortalSettings,
+}
+
+impl MatrixEventKind {
+    /// Restores the queue event from its JSON in the given room
+    ///
+    /// # Errors
+    /// This function will return an error if the JSON doesn't match the kind of event
+    fn restore(self, event: &str, room: Room) -> Result<QueueEvent> {
+        Ok(match self {
+            Self::StrippedMember => {
+                QueueEvent::RoomMemberEvent(Box::new((serde_json::from_str(event)?, room)))
+            }
+            Self::Member => {
+                QueueEvent::RoomMembershipEvent(Box::new((serde_json::from_str(event)?, room)))
+            }
+            Self::Message => {
+                QueueEvent::RoomMessageEvent(Box::new((serde_json::from_str(event)?, room)))
+            }
+            Self::Tombstone => {
+                QueueEvent::RoomTombstoneEvent(Box::new((serde_json::from_str(event)?, room)))
+            }
+            Self::CanonicalAlias => {
+                QueueEvent::RoomCanonicalAliasEvent(Box::new((serde_json::from_str(event)?, room)))
+            }
+            Self::Name => QueueEvent::RoomNameEvent(Box::new((serde_json::from_str(event)?, room))),
+            Self::Topic => {
+                QueueEvent::RoomTopicEvent(Box::new((serde_json::from_str(event)?, room)))
+            }
+            Self::Reaction => {
+                QueueEvent::ReactionEvent(Box::new((serde_json::from_str(event)?, room)))
+            }
+            Self::Receipt => QueueEvent::ReceiptEvent(Box::new((
+                serde_json::from_str(event)?,
+                room,
+                receipt_threads(event),
+            ))),
+            Self::Typing => QueueEvent::TypingEvent(Box::new((serde_json::from_str(event)?, room))),
+            Self::Redaction => {
+                QueueEvent::RedactionEvent(Box::new((serde_json::from_str(event)?, room)))
+            }
+            Self::PortalSettings => {
+                QueueEvent::PortalSettingsEvent(Box::new((serde_json::from_str(event)?, room)))
+            }
+        })
+    }
+}
+
+/// Event as it is stored in the queue table
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "source", rename_all = "snake_case")]
+enum StoredEvent {
+    /// Matrix event
+    Matrix {
+        /// Kind of the event
+        kind: MatrixEventKind,
+        /// Room the event was sent in
+        room_id: OwnedRoomId,
+        /// JSON of the event
+        event: String,
+    },
+    /// Discord gateway payload
+    Discord {
+        /// JSON of the payload
+        payload: String,
+    },
+    /// End of the events of a batch from the homeserver
+    TransactionEnd {
+        /// Time the batch was received at, in milliseconds since the unix epoch
+        received_ms: u64,
+    },
+}
+
+impl StoredEvent {
+    /// Returns the key of the events this event is ordered with
+    fn ordering_key(&self) -> String {
+        match self {
+            Self::Matrix { room_id, .. } => room_id.to_string(),
+            Self::Discord { payload } => discord_ordering_key(payload),
+            Self::TransactionEnd { .. } => BARRIER_KEY.to_owned(),
+        }
+    }
+
+    /// Returns the id of a matrix event, EDUs and discord events have none
+    fn event_id(&self) -> Option<String> {
+        match self {
+            Self::Matrix { event, .. } => matrix_event_id(event),
+            Self::Discord { .. } | Self::TransactionEnd { .. } => None,
+        }
+    }
+}
+
+/// Returns the id of a matrix event from its JSON
+fn matrix_event_id(event: &str) -> Option<String> {
+    serde_json::from_str::<Value>(event)
+        .ok()?
+        .get("event_id")?
+        .as_str()
+        .map(str::to_owned)
+}
+
+/// Returns the key of the events a gateway payload is ordered with
+///
+/// Events are ordered by the channel they happened in, or by the guild for events outside of
+/// channels. All other events are ordered with each other.
+fn discord_ordering_key(payload: &str) -> String {
+    let payload = serde_json::from_str::<Value>(payload).unwrap_or_default();
+    let data = &payload["d"];
+    if let Some(channel_id) = data["channel_id"].as_str() {
+        format!("discord:channel:{}", channel_id)
+    } else if let Some(guild_id) = data["guild_id"].as_str() {
+        format!("discord:guild:{}", guild_id)
+    } else {
+        "discord".to_owned()
+    }
+}
+
+/// Parses a raw gateway payload into the dispatched event
+///
+/// Returns `None` for payloads that aren't dispatched events, like heartbeats.
+///
+/// # Errors
+/// This function will return an error if the payload is malformed
+pub(super) fn parse_discord_payload(payload: &str) -> Result<Option<Event>> {
+    let deserializer = GatewayEventDeserializer::from_json(payload)
+        .ok_or_else(|| anyhow::anyhow!("Gateway payload without opcode"))?;
+    if deserializer.op() != DISPATCH_OP {
+        return Ok(None);
+    }
+    let mut json = serde_json::Deserializer::from_str(payload);
+    match deserializer.deserialize(&mut json)? {
+        GatewayEvent::Dispatch(_, event) => Ok(Some(Event::from(*event))),
+        _ => Ok(None),
+    }
+}
+
+/// Persists a matrix event from an event handler
+///
+/// The event handlers' errors don't reach the transaction, so the ids of events that couldn't be
+/// stored are recorded for `receive_transaction` to fail the transaction with.
+///
+/// # Errors
+/// This function will return an error if the application is shutting down or the event couldn't
+/// be stored
+pub(super) async fn queue_matrix_event(
+    this: Weak<App>,
+    kind: MatrixEventKind,
+    room: Room,
+    raw: RawEvent,
+) -> Result<()> {
+    let this = this
+        .upgrade()
+        .ok_or_else(|| anyhow::anyhow!("Application is shutting down"))?;
+    let event = StoredEvent::Matrix {
+        kind,
+        room_id: room.room_id().to_owned(),
+        event: raw.0.get().to_owned(),
+    };
+    let result = this.store_event(&event).await;
+    if result.is_err() {
+        if let Some(event_id) = event.event_id() {
+            this.failed_events.insert(event_id);
+        }
+    }
+    result
+}
+
+impl App {
+    /// Writes an event to the queue table and wakes up the worker
+    ///
+    /// Matrix events are only queued once. The homeserver retries transactions that failed, and
+    /// the events of a retried transaction that were queued before are skipped, even if they were
+    /// handled in the meantime.
+    ///
+    /// # Errors
+    /// This function will return an error if the queue is closed or the event couldn't be stored
+    #[allow(clippy::panic)]
+    async fn store_event(&self, event: &StoredEvent) -> Result<()> {
+        if self.queue_closed.load(Ordering::Relaxed) {
+            anyhow::bail!("The event queue is closed");
+        }
+        let payload = serde_json::to_string(event)?;
+        let queued = if let Some(event_id) = event.event_id() {
+            query!(
+                "WITH queued AS (INSERT INTO queued_matrix_events (event_id, dry_run) VALUES ($4, $2) ON CONFLICT DO NOTHING RETURNING event_id) INSERT INTO event_queue (payload, dry_run, ordering_key) SELECT $1, $2, $3 FROM queued",
+                payload,
+                self.dry_run,
+                event.ordering_key(),
+                event_id
+            )
+            .execute(&*self.db)
+            .await?
+            .rows_affected()
+        } else {
+            query!(
+                "INSERT INTO event_queue (payload, dry_run, ordering_key) VALUES ($1, $2, $3)",
+                payload,
+                self.dry_run,
+                event.ordering_key()
+            )
+            .execute(&*self.db)
+            .await?
+            .rows_affected()
+        };
+        if queued == 0 {
+            debug!("Skipping a matrix event that was queued before");
+            return Ok(());
+        }
+        self.load.queued();
+        self.queue_notify.notify_one();
+        Ok(())
+    }
+
+    /// Persists a raw discord gateway payload
+    ///
+    /// # Errors
+    /// This function will return an error if the queue is closed or the payload couldn't be stored
+    pub(super) async fn queue_discord_payload(&self, payload: &[u8]) -> Result<()> {
+        let payload = String::from_utf8(payload.to_vec())?;
+        if GatewayEventDeserializer::from_json(&payload)
+            .map_or(false, |deserializer| deserializer.op() != DISPATCH_OP)
+        {
+            return Ok(());
+        }
+        self.store_event(&StoredEvent::Discord { payload }).await
+    }
+
+    /// Persists an EDU of an appservice transaction that happened in a room
+    ///
+    /// # Errors
+    /// This function will return an error if the queue is closed or the EDU couldn't be stored
+    pub(super) async fn queue_edu(
+        &self,
+        kind: MatrixEventKind,
+        room_id: OwnedRoomId,
+        event: String,
+    ) -> Result<()> {
+        self.store_event(&StoredEvent::Matrix {
+            kind,
+            room_id,
+            event,
+        })
+        .await
+    }
+
+    /// Persists the end of a batch of events from the homeserver
+    ///
+    /// # Errors
+    /// This function will return an error if the queue is closed or the marker couldn't be stored
+    pub(super) async fn queue_transaction_end(&self, received_ms: u64) -> Result<()> {
+        self.store_event(&StoredEvent::TransactionEnd { received_ms })
+            .await
+    }
+
+    /// Stops accepting new events
+    ///
+    /// The worker keeps handling the events that are already due until none are left.
+    pub(super) fn close_queue(&self) {
+        self.queue_closed.store(true, Ordering::Relaxed);
+        self.queue_notify.notify_one();
+    }
+
+    /// Waits for the worker to handle the due events of the closed queue
+    ///
+    /// Events that aren't handled within the timeout, and events waiting for a retry, stay in the
+    /// queue and are handled after the next start.
+    pub(super) async fn drain_queue(&self, timeout: Duration) {
+        info!(
+            "Waiting up to {:?} for queued events to be handled",
+            timeout
+        );
+        if tokio::time::timeout(timeout, self.queue_stopped.notified())
+            .await
+            .is_err()
+        {
+            warn!(
+                "Stopped waiting with {} events in progress, the remaining events are handled after the next start",
+                self.queue_claimed.len()
+            );
+        }
+    }
+
+    /// Starts the worker that dispatches the stored events
+    pub(super) fn spawn_event_queue_worker(self: &Arc<Self>) {
+        let this = Arc::clone(self);
+        tokio::spawn(async move {
+            match this.queue_depth().await {
+                Ok(depth) => {
+                    if depth > 0 {
+                        info!("Resuming {} queued events", depth);
+                    }
+                    this.load.restore_depth(depth);
+                }
+                Err(e) => warn!("Failed to count queued events: {:?}", e),
+            }
+            loop {
+                let permit = match Arc::clone(&this.queue_permits).acquire_owned().await {
+                    Ok(permit) => permit,
+                    Err(_) => break,
+                };
+                match this.claim_next_event().await {
+                    Ok(Some((id, payload, attempts))) => {
+                        let this = Arc::clone(&this);
+                        tokio::spawn(async move {
+                            if let Err(e) = this.process_event(id, &payload, attempts).await {
+                                error!("Failed to process queued event {}: {:?}", id, e);
+                            }
+                            this.queue_claimed.remove(&id);
+                            drop(permit);
+                            this.queue_notify.notify_one();
+                        });
+                        continue;
+                    }
+                    Ok(None) => {}
+                    Err(e) => error!("Failed to process the event queue: {:?}", e),
+                }
+                drop(permit);
+                if this.queue_closed.load(Ordering::Relaxed) && this.queue_claimed.is_empty() {
+                    break;
+                }
+                let _ =
+                    tokio::time::timeout(IDLE_POLL_INTERVAL, this.queue_notify.notified()).await;
+            }
+            info!("Shutting down queue runner");
+            this.queue_stopped.notify_one();
+        });
+    }
+
+    /// Returns the number of stored events
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    async fn queue_depth(&self) -> Result<usize> {
+        let count = query!(
+            r#"SELECT COUNT(*) AS "count!" FROM event_queue WHERE dry_run = $1"#,
+            self.dry_run
+        )
+        .fetch_one(&*self.db)
+        .await?
+        .count;
+        Ok(usize::try_from(count)?)
+    }
+
+    /// Restores a stored event
+    ///
+    /// # Errors
+    /// This function will return an error if the event is malformed or its room is unknown
+    fn restore_event(&self, payload: &str) -> Result<Option<QueueEvent>> {
+        Ok(match serde_json::from_str(payload)? {
+            StoredEvent::Matrix {
+                kind,
+                room_id,
+                event,
+            } => {
+                let room = self.room(&room_id)?;
+                Some(kind.restore(&event, room)?)
+            }
+            StoredEvent::Discord { payload } => parse_discord_payload(&payload)?
+                .map(|event| QueueEvent::DiscordEvent(Box::new(event))),
+            StoredEvent::TransactionEnd { received_ms } => {
+                Some(QueueEvent::TransactionEnd(received_ms))
+            }
+        })
+    }
+
+    /// Returns a room known to the bridge bot
+    ///
+    /// # Errors
+    /// This function will return an error if the bridge bot doesn't know the room
+    fn room(&self, room_id: &RoomId) -> Result<Room> {
+        self.client
+            .get_room(room_id)
+            .with_context(|| format!("Unknown room {}", room_id))
+    }
+
+    /// Claims the oldest event that is due and not ordered after another queued event
+    ///
+    /// Returns the id, payload and number of failed attempts of the event.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    async fn claim_next_event(&self) -> Result<Option<(i64, String, i32)>> {
+        let claimed = self.queue_claimed.iter().map(|id| *id).collect::<Vec<_>>();
+        let row = match query!(
+            "SELECT id, payload, attempts FROM event_queue e WHERE dry_run = $1 AND next_retry_at <= NOW() AND NOT (id = ANY($2)) AND NOT EXISTS (SELECT 1 FROM event_queue o WHERE o.dry_run = e.dry_run AND o.id < e.id AND (o.ordering_key = e.ordering_key OR e.ordering_key = '')) ORDER BY id LIMIT 1",
+            self.dry_run,
+            &claimed[..]
+        )
+        .fetch_optional(&*self.db)
+        .await?
+        {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        self.queue_claimed.insert(row.id);
+        Ok(Some((row.id, row.payload, row.attempts)))
+    }
+
+    /// Handles a claimed event
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    async fn process_event(self: &Arc<Self>, id: i64, payload: &str, attempts: i32) -> Result<()> {
+        let event = match self.restore_event(payload) {
+            Ok(Some(event)) => event,
+            Ok(None) => {
+                debug!("Dropping queued event {} without effect", id);
+                self.load.dequeued();
+                self.remove_event(id).await?;
+                return Ok(());
+            }
+            Err(e) => {
+                warn!("Dropping unreadable queued event {}: {:?}", id, e);
+                self.load.dequeued();
+                self.remove_event(id).await?;
+                return Ok(());
+            }
+        };
+        if self.shed(&event) {
+            self.remove_event(id).await?;
+            return Ok(());
+        }
+        let this = Arc::clone(self);
+        let err = match tokio::spawn(async move { this.handle_event(event).await }).await {
+            Ok(Ok(())) => {
+                self.remove_event(id).await?;
+                return Ok(());
+            }
+            Ok(Err(e)) => e,
+            Err(e) => e.into(),
+        };
+        self.retry_event(id, attempts, &err).await?;
+        Ok(())
+    }
+
+    /// Removes an event from the queue table
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    async fn remove_event(&self, id: i64) -> Result<()> {
+        query!("DELETE FROM event_queue WHERE id = $1", id)
+            .execute(&*self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// Schedules the next attempt of a failed event, or gives up on it
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    async fn retry_event(&self, id: i64, attempts: i32, err: &anyhow::Error) -> Result<()> {
+        sentry::integrations::anyhow::capture_anyhow(err);
+        let attempts = attempts.saturating_add(1);
+        let attempt = u32::try_from(attempts).unwrap_or(u32::MAX);
+        if attempt >= EVENT_BACKOFF.max_attempts {
+            error!(
+                "Giving up on queued event {} after {} attempts: {:?}",
+                id, attempts, err
+            );
+            return self.remove_event(id).await;
+        }
+        let delay = EVENT_BACKOFF.delay(attempt - 1);
+        warn!(
+            "Queued event {} failed, retrying in {:?}: {:?}",
+            id, delay, err
+        );
+        query!(
+            "UPDATE event_queue SET attempts = $2, next_retry_at = NOW() + make_interval(secs => $3), last_error = $4 WHERE id = $1",
+            id,
+            attempts,
+            delay.as_secs_f64(),
+            format!("{:?}", err)
+        )
+        .execute(&*self.db)
+        .await?;
+        self.load.queued();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_dispatched_payloads_only() {
+        let heartbeat = r#"{"op":11,"d":null}"#;
+        assert!(parse_discord_payload(heartbeat)
+            .expect("heartbeat ack is well-formed")
+            .is_none());
+        let typing = r#"{"op":0,"s":42,"t":"TYPING_START","d":{"channel_id":"1","user_id":"2","timestamp":1656000000}}"#;
+        let event = parse_discord_payload(typing)
+            .expect("typing start is well-formed")
+            .expect("typing start is dispatched");
+        assert!(matches!(event, Event::TypingStart(_)));
+    }
+
+    #[test]
+    fn orders_discord_events_by_channel_or_guild() {
+        let message = r#"{"op":0,"t":"MESSAGE_CREATE","d":{"channel_id":"1","guild_id":"2"}}"#;
+        assert_eq!(discord_ordering_key(message), "discord:channel:1");
+        let member = r#"{"op":0,"t":"GUILD_MEMBER_ADD","d":{"guild_id":"2"}}"#;
+        assert_eq!(discord_ordering_key(member), "discord:guild:2");
+        let user = r#"{"op":0,"t":"USER_UPDATE","d":{"id":"3"}}"#;
+        assert_eq!(discord_ordering_key(user), "discord");
+    }
+
+    #[test]
+    fn stored_events_round_trip() {
+        let event = StoredEvent::TransactionEnd { received_ms: 1234 };
+        let json = serde_json::to_string(&event).expect("stored events serialize");
+        assert_eq!(json, r#"{"source":"transaction_end","received_ms":1234}"#);
+        assert_eq!(
+            serde_json::from_str::<StoredEvent>(&json).expect("stored events deserialize"),
+            event
+        );
+    }
+
+    #[test]
+    fn only_matrix_events_with_an_id_are_deduplicated() {
+        let room_id = OwnedRoomId::try_from("!portal:chir.rs").expect("valid room id");
+        let message = StoredEvent::Matrix {
+            kind: MatrixEventKind::Message,
+            room_id: room_id.clone(),
+            event: r#"{"type":"m.room.message","event_id":"$message:chir.rs"}"#.to_owned(),
+        };
+        assert_eq!(message.event_id().as_deref(), Some("$message:chir.rs"));
+        let typing = StoredEvent::Matrix {
+            kind: MatrixEventKind::Typing,
+            room_id,
+            event: r#"{"type":"m.typing","content":{"user_ids":[]}}"#.to_owned(),
+        };
+        assert_eq!(typing.event_id(), None);
+        assert_eq!(
+            StoredEvent::TransactionEnd { received_ms: 1234 }.event_id(),
+            None
+        );
+    }
+}
@@ -0,0 +1,271 @@
+//! Moderation event webhooks
+//!
+//! Events like deleted messages, bans and new portals are posted as JSON to the webhooks in
+//! `bridge.event_webhooks`, so external moderation tooling can react to them without polling the
+//! database. Every request carries the event name in `X-Bridge-Event` and an HMAC-SHA256 of the
+//! body, keyed with the webhook's secret, in `X-Bridge-Signature`.
+
+use std::{fmt::Write, sync::Arc, time::Duration};
+
+use super::App;
+use crate::{
+    config::{EventWebhook, ModerationEvent},
+    retry::{retry, Backoff},
+    time,
+};
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use matrix_sdk::ruma::{
+    events::{
+        room::member::{MembershipState, SyncRoomMemberEvent},
+        SyncStateEvent,
+    },
+    RoomId,
+};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use tracing::{info, warn};
+use twilight_model::id::{
+    marker::{ChannelMarker, GuildMarker, MessageMarker, UserMarker},
+    Id,
+};
+
+/// Header carrying the event name
+const EVENT_HEADER: &str = "X-Bridge-Event";
+
+/// Header carrying the signature of the body
+const SIGNATURE_HEADER: &str = "X-Bridge-Signature";
+
+/// Returns the signature header value of a request body
+///
+/// # Errors
+/// This function will return an error if the secret can't be used as a key
+fn signature(secret: &str, body: &[u8]) -> Result<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+    mac.update(body);
+    let mut hex = String::from("sha256=");
+    for byte in mac.finalize().into_bytes() {
+        write!(hex, "{:02x}", byte)?;
+    }
+    Ok(hex)
+}
+
+/// Returns whether a webhook subscribed to an event
+fn subscribed(webhook: &EventWebhook, event: ModerationEvent) -> bool {
+    webhook.events.is_empty() || webhook.events.contains(&event)
+}
+
+impl App {
+    /// Posts an event to a single webhook, retrying on failure
+    ///
+    /// # Errors
+    /// This function will return an error if signing the body fails or the last attempt failed
+    async fn post_event(
+        self: &Arc<Self>,
+        webhook: &EventWebhook,
+        event: ModerationEvent,
+        body: &[u8],
+    ) -> Result<()> {
+        let signature = signature(&webhook.secret, body)?;
+        let target = webhook.url.host_str().unwrap_or("event_webhook");
+        retry(
+            target,
+            Backoff::new(Duration::from_secs(1), Duration::from_secs(30)).with_max_attempts(5),
+            || async {
+                self.http
+                    .post(webhook.url.clone())
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .header(EVENT_HEADER, event.as_str())
+                    .header(SIGNATURE_HEADER, &signature)
+                    .body(body.to_vec())
+                    .send()
+                    .await
+                    .and_then(reqwest::Response::error_for_status)
+            },
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Posts an event to every webhook subscribed to it
+    ///
+    /// Delivery happens in the background, failures are only logged.
+    fn emit_event(self: &Arc<Self>, event: ModerationEvent, data: Value) {
+        let webhooks = self
+            .config
+            .bridge
+            .event_webhooks
+            .iter()
+            .filter(|webhook| subscribed(webhook, event))
+            .cloned()
+            .collect::<Vec<_>>();
+        if webhooks.is_empty() {
+            return;
+        }
+        if self.dry_run {
+            info!(
+                "[dry-run] Would post {} to {} webhooks",
+                event.as_str(),
+                webhooks.len()
+            );
+            return;
+        }
+        let body = json!({
+            "event": event.as_str(),
+            "timestamp": time::now_ms(),
+            "data": data,
+        })
+        .to_string()
+        .into_bytes();
+        for webhook in webhooks {
+            let this = Arc::clone(self);
+            let body = body.clone();
+            tokio::spawn(async move {
+                if let Err(e) = this.post_event(&webhook, event, &body).await {
+                    warn!(
+                        "Failed to post {} to {}: {:?}",
+                        event.as_str(),
+                        webhook.url,
+                        e
+                    );
+                }
+            });
+        }
+    }
+
+    /// Emits [`ModerationEvent::MessageDeleted`] for a message deleted on discord
+    ///
+    /// # Errors
+    /// This function will return an error if the message mapping can't be read
+    pub(super) async fn emit_message_deleted(
+        self: &Arc<Self>,
+        guild_id: Option<Id<GuildMarker>>,
+        channel_id: Id<ChannelMarker>,
+        message_id: Id<MessageMarker>,
+    ) -> Result<()> {
+        if self.config.bridge.event_webhooks.is_empty() {
+            return Ok(());
+        }
+        let matrix = self
+            .matrix_events_for_message(message_id)
+            .await?
+            .into_iter()
+            .map(|(room_id, event_id)| json!({ "room_id": room_id, "event_id": event_id }))
+            .collect::<Vec<_>>();
+        if matrix.is_empty() {
+            // Only bridged messages are of interest
+            return Ok(());
+        }
+        self.emit_event(
+            ModerationEvent::MessageDeleted,
+            json!({
+                "source": "discord",
+                "guild_id": guild_id,
+                "channel_id": channel_id,
+                "message_id": message_id,
+                "matrix": matrix,
+            }),
+        );
+        Ok(())
+    }
+
+    /// Emits [`ModerationEvent::UserBanned`] for a ban in a discord guild
+    ///
+    /// # Errors
+    /// This function will return an error if the linked matrix user can't be looked up
+    pub(super) async fn emit_discord_ban(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+    ) -> Result<()> {
+        if self.config.bridge.event_webhooks.is_empty() {
+            return Ok(());
+        }
+        let matrix_user = match self.linked_matrix_user(user_id).await? {
+            Some(matrix_user) => matrix_user,
+            None => self.puppet_user_id(user_id)?,
+        };
+        self.emit_event(
+            ModerationEvent::UserBanned,
+            json!({
+                "source": "discord",
+                "guild_id": guild_id,
+                "user_id": user_id,
+                "matrix_user_id": matrix_user,
+            }),
+        );
+        Ok(())
+    }
+
+    /// Emits [`ModerationEvent::UserBanned`] for a ban in a portal room
+    ///
+    /// # Errors
+    /// This function will return an error if the portal can't be looked up
+    pub(super) async fn emit_matrix_ban(
+        self: &Arc<Self>,
+        room_id: &RoomId,
+        event: &SyncRoomMemberEvent,
+    ) -> Result<()> {
+        let event = match event {
+            SyncStateEvent::Original(event) => event,
+            SyncStateEvent::Redacted(_) => return Ok(()),
+        };
+        if event.content.membership != MembershipState::Ban
+            || self.config.bridge.event_webhooks.is_empty()
+        {
+            return Ok(());
+        }
+        let portal = match self.portal_by_room(room_id).await? {
+            Some(portal) => portal,
+            None => return Ok(()),
+        };
+        self.emit_event(
+            ModerationEvent::UserBanned,
+            json!({
+                "source": "matrix",
+                "room_id": room_id,
+                "guild_id": portal.guild_id,
+                "channel_id": portal.channel_id,
+                "user_id": event.state_key,
+                "discord_user_id": self.puppet_discord_id(&event.state_key),
+                "sender": event.sender,
+                "reason": event.content.reason,
+            }),
+        );
+        Ok(())
+    }
+
+    /// Emits [`ModerationEvent::PortalCreated`] for a new portal
+    pub(super) fn emit_portal_created(
+        self: &Arc<Self>,
+        guild_id: Option<Id<GuildMarker>>,
+        channel_id: Id<ChannelMarker>,
+        room_id: &RoomId,
+    ) {
+        self.emit_event(
+            ModerationEvent::PortalCreated,
+            json!({
+                "guild_id": guild_id,
+                "channel_id": channel_id,
+                "room_id": room_id,
+            }),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_bodies() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            signature("Jefe", b"what do ya want for nothing?").ok(),
+            Some(
+                "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+                    .to_owned()
+            )
+        );
+    }
+}
@@ -0,0 +1,223 @@
+//! Discord forum tags in room state
+//!
+//! The tags applied to a forum post are published in its portal as a
+//! `rs.chir.discord_bridge.tags` state event with an `applied` list, and the tags available in a
+//! forum in the forum's portal with an `available` list, so that matrix bots can filter by them.
+//! Changes of the applied tags are also announced with a notice.
+//!
+//! Tags aren't part of the discord model used for gateway events, so the channel is fetched from
+//! the REST API whenever it is updated.
+
+use std::sync::Arc;
+
+use super::App;
+use anyhow::Result;
+use matrix_sdk::{
+    room::Room,
+    ruma::events::{room::message::RoomMessageEventContent, StateEventType},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::info;
+use twilight_model::id::{marker::ChannelMarker, Id};
+
+/// State event type of forum tags
+const TAGS_EVENT_TYPE: &str = "rs.chir.discord_bridge.tags";
+
+/// A forum tag as published in room state
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct ForumTag {
+    /// Id of the tag
+    id: String,
+    /// Name of the tag
+    name: String,
+    /// Unicode emoji of the tag
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    emoji: Option<String>,
+}
+
+/// Returns the tags available in a forum channel, or `None` if it isn't a forum
+fn available_tags(channel: &Value) -> Option<Vec<ForumTag>> {
+    let tags = channel.get("available_tags")?.as_array()?;
+    Some(
+        tags.iter()
+            .filter_map(|tag| {
+                Some(ForumTag {
+                    id: tag.get("id")?.as_str()?.to_owned(),
+                    name: tag.get("name")?.as_str()?.to_owned(),
+                    emoji: tag
+                        .get("emoji_name")
+                        .and_then(Value::as_str)
+                        .map(ToOwned::to_owned),
+                })
+            })
+            .collect(),
+    )
+}
+
+/// Returns the tags applied to a forum post, in the order of the forum
+///
+/// Ids of tags the forum doesn't have anymore are skipped.
+fn applied_tags(post: &Value, available: &[ForumTag]) -> Vec<ForumTag> {
+    let applied = post
+        .get("applied_tags")
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    available
+        .iter()
+        .filter(|tag| applied.iter().any(|id| id.as_str() == Some(&tag.id)))
+        .cloned()
+        .collect()
+}
+
+/// Returns the notice for a change of applied tags, or `None` if nothing changed
+fn tag_change_notice(old: &[ForumTag], new: &[ForumTag]) -> Option<String> {
+    let names = |tags: &[ForumTag], other: &[ForumTag]| {
+        tags.iter()
+            .filter(|tag| !other.iter().any(|o| o.id == tag.id))
+            .map(|tag| tag.name.as_str())
+            .collect::<Vec<_>>()
+    };
+    let added = names(new, old);
+    let removed = names(old, new);
+    let mut parts = Vec::new();
+    if !added.is_empty() {
+        parts.push(format!("Tags added: {}", added.join(", ")));
+    }
+    if !removed.is_empty() {
+        parts.push(format!("Tags removed: {}", removed.join(", ")));
+    }
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join(". "))
+    }
+}
+
+impl App {
+    /// Fetches a channel from the discord REST API
+    ///
+    /// # Errors
+    /// This function will return an error if no bot token is configured or the request fails
+    async fn discord_channel_json(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+    ) -> Result<Value> {
+        let token = self
+            .config
+            .discord
+            .bot_token
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No discord bot token configured"))?;
+        let response = self
+            .http
+            .get(format!(
+                "https://discord.com/api/v10/channels/{}",
+                channel_id
+            ))
+            .header(reqwest::header::AUTHORIZATION, format!("Bot {}", token))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(serde_json::from_slice(&response.bytes().await?)?)
+    }
+
+    /// Publishes the tags of a forum or forum post in its portal
+    ///
+    /// # Errors
+    /// This function will return an error if a request to discord, reading the room state or
+    /// sending the state event or notice fails
+    pub(super) async fn sync_forum_tags(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+    ) -> Result<()> {
+        let portal = match self.portal_by_channel(channel_id).await? {
+            Some(portal) => portal,
+            None => return Ok(()),
+        };
+        let channel = self.discord_channel_json(channel_id).await?;
+        let (key, tags) = if let Some(available) = available_tags(&channel) {
+            ("available", available)
+        } else if channel.get("applied_tags").is_some() {
+            let parent_id = match channel.get("parent_id").and_then(Value::as_str) {
+                Some(parent_id) => parent_id.parse::<Id<ChannelMarker>>()?,
+                None => return Ok(()),
+            };
+            let forum = self.discord_channel_json(parent_id).await?;
+            let available = available_tags(&forum).unwrap_or_default();
+            ("applied", applied_tags(&channel, &available))
+        } else {
+            return Ok(());
+        };
+        let room = match self.client.get_joined_room(&portal.room_id) {
+            Some(room) => room,
+            None => return Ok(()),
+        };
+        let previous = match room
+            .get_state_event(StateEventType::from(TAGS_EVENT_TYPE), "")
+            .await?
+        {
+            Some(event) => serde_json::from_value::<Option<Vec<ForumTag>>>(
+                event.deserialize_as::<Value>()?["content"][key].take(),
+            )?,
+            None => None,
+        };
+        if previous.as_ref() == Some(&tags) {
+            return Ok(());
+        }
+        if self.dry_run {
+            info!(
+                "[dry-run] Would set the {} tags of {} to {:?}",
+                key, portal.room_id, tags
+            );
+            return Ok(());
+        }
+        let content = json!({ key: tags });
+        self.pipeline
+            .run(&self.user_id, "state", async {
+                room.send_state_event_raw(content, TAGS_EVENT_TYPE, "")
+                    .await?;
+                Ok(())
+            })
+            .await?;
+        // Publishing the tags for the first time isn't a change
+        if key == "applied" {
+            if let Some(notice) = previous.and_then(|previous| tag_change_notice(&previous, &tags))
+            {
+                self.send_message(
+                    &Room::Joined(room),
+                    RoomMessageEventContent::notice_plain(notice),
+                )
+                .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announces_tag_changes() {
+        let forum = json!({
+            "available_tags": [
+                { "id": "1", "name": "bug", "emoji_name": "🐛" },
+                { "id": "2", "name": "question", "emoji_id": "5" },
+                { "id": "3", "name": "solved" },
+            ],
+        });
+        let available = available_tags(&forum).expect("forum has tags");
+        assert_eq!(available[0].emoji.as_deref(), Some("🐛"));
+        assert_eq!(available[1].emoji, None);
+        let old = applied_tags(&json!({ "applied_tags": ["2", "1"] }), &available);
+        let new = applied_tags(&json!({ "applied_tags": ["3", "1", "9"] }), &available);
+        assert_eq!(
+            tag_change_notice(&old, &new).as_deref(),
+            Some("Tags added: solved. Tags removed: question")
+        );
+        assert_eq!(tag_change_notice(&new, &new), None);
+    }
+}
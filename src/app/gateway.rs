@@ -0,0 +1,155 @@
+//! Discord gateway sessions and reconnects
+//!
+//! The gateway sessions are closed as resumable on shutdown and stored in the `gateway_sessions`
+//! table, so that the next start resumes them and discord replays the events that were missed in
+//! between instead of identifying anew. Establishing the gateway is retried with exponential
+//! backoff and jitter. Once connected, shards reconnect on their own; the reconnects are counted
+//! in metrics and the admin room is told when a shard fails to reconnect repeatedly.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use super::App;
+use crate::{metrics::METRICS, retry::Backoff};
+use anyhow::Result;
+use dashmap::DashMap;
+use sqlx::{query, PgPool};
+use tracing::{info, warn};
+use twilight_gateway::{shard::ResumeSession, Event};
+
+/// Backoff for establishing the gateway on start
+pub(super) const CONNECT_BACKOFF: Backoff =
+    Backoff::new(Duration::from_secs(1), Duration::from_secs(60)).with_max_attempts(8);
+
+/// Number of consecutive reconnects of a shard after which the admin room is alerted
+const RECONNECT_ALERT_THRESHOLD: u32 = 5;
+
+/// Returns whether a shard's reconnect is the one that is reported to the admin room
+const fn reconnect_alert_due(reconnects: u32) -> bool {
+    reconnects == RECONNECT_ALERT_THRESHOLD
+}
+
+/// Takes the gateway sessions stored on the last shutdown
+///
+/// The sessions are removed, so that a session is never resumed twice.
+///
+/// # Errors
+/// This function will return an error if the database query fails
+#[allow(clippy::panic)]
+pub(super) async fn take_gateway_sessions(db: &PgPool) -> Result<HashMap<u64, ResumeSession>> {
+    query!("DELETE FROM gateway_sessions RETURNING shard_id, session_id, sequence")
+        .fetch_all(db)
+        .await?
+        .into_iter()
+        .map(|row| {
+            Ok((
+                u64::try_from(row.shard_id)?,
+                ResumeSession {
+                    session_id: row.session_id,
+                    sequence: u64::try_from(row.sequence)?,
+                },
+            ))
+        })
+        .collect()
+}
+
+/// Consecutive reconnects of each shard since it was last connected
+#[derive(Debug, Default)]
+pub(super) struct GatewayMonitor {
+    /// Reconnects by shard
+    reconnects: DashMap<u64, u32>,
+}
+
+impl App {
+    /// Stores gateway sessions, so that the next start resumes them
+    ///
+    /// # Errors
+    /// This function will return an error if a database query fails
+    #[allow(clippy::panic)]
+    pub(super) async fn save_gateway_sessions(
+        &self,
+        sessions: HashMap<u64, ResumeSession>,
+    ) -> Result<()> {
+        if self.dry_run {
+            info!(
+                "[dry-run] Would store {} resumable gateway sessions",
+                sessions.len()
+            );
+            return Ok(());
+        }
+        for (shard_id, session) in sessions {
+            query!(
+                "INSERT INTO gateway_sessions (shard_id, session_id, sequence) VALUES ($1, $2, $3) ON CONFLICT (shard_id) DO UPDATE SET session_id = EXCLUDED.session_id, sequence = EXCLUDED.sequence, updated_at = NOW()",
+                i64::try_from(shard_id)?,
+                session.session_id,
+                i64::try_from(session.sequence)?
+            )
+            .execute(&*self.db)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Tracks the connection state of a shard
+    pub(super) fn handle_shard_event(self: &Arc<Self>, shard_id: u64, event: &Event) {
+        let shard = shard_id.to_string();
+        let alert = match event {
+            Event::ShardConnected(_) => {
+                METRICS.set("bridge_gateway_connected", &[("shard", &shard)], 1);
+                match self.gateway.reconnects.remove(&shard_id) {
+                    Some((_, reconnects)) if reconnects >= RECONNECT_ALERT_THRESHOLD => format!(
+                        "Discord gateway shard {} is connected again after {} attempts",
+                        shard_id, reconnects
+                    ),
+                    _ => return,
+                }
+            }
+            Event::ShardDisconnected(disconnected) => {
+                METRICS.set("bridge_gateway_connected", &[("shard", &shard)], 0);
+                warn!(
+                    "Discord gateway shard {} disconnected with code {:?}: {:?}",
+                    shard_id, disconnected.code, disconnected.reason
+                );
+                return;
+            }
+            Event::ShardReconnecting(_) => {
+                METRICS.inc("bridge_gateway_reconnects", &[("shard", &shard)]);
+                let reconnects = {
+                    let mut reconnects = self.gateway.reconnects.entry(shard_id).or_insert(0);
+                    *reconnects = reconnects.saturating_add(1);
+                    *reconnects
+                };
+                info!(
+                    "Discord gateway shard {} is reconnecting, attempt {}",
+                    shard_id, reconnects
+                );
+                if !reconnect_alert_due(reconnects) {
+                    return;
+                }
+                format!(
+                    "Discord gateway shard {} failed to reconnect {} times in a row and keeps trying",
+                    shard_id, reconnects
+                )
+            }
+            _ => return,
+        };
+        let this = Arc::clone(self);
+        tokio::spawn(async move {
+            if let Err(e) = this.alert_admin(&alert).await {
+                warn!("Failed to alert the admin room: {:?}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alerts_once_per_outage() {
+        let alerts = (1..=RECONNECT_ALERT_THRESHOLD * 2)
+            .filter(|reconnects| reconnect_alert_due(*reconnects))
+            .count();
+        assert_eq!(alerts, 1);
+    }
+}
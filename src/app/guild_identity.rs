@@ -0,0 +1,170 @@
+//! Per-guild identity of the bridge bot
+//!
+//! Communities can brand the bridge with `discord.guild_identities`. The bot then uses the
+//! configured name and avatar as its member profile in the guild's portal rooms and the name as
+//! its nickname in the guild. Messages relayed through a webhook from matrix users without an
+//! avatar use the guild's bot avatar.
+
+use std::sync::Arc;
+
+use super::{portals::Portal, App};
+use crate::config::BotIdentity;
+use anyhow::Result;
+use matrix_sdk::ruma::{events::StateEventType, MxcUri};
+use serde_json::{json, Value};
+use tracing::{error, info};
+use twilight_model::id::{
+    marker::{ChannelMarker, GuildMarker},
+    Id,
+};
+
+/// Applies an identity to the content of a member event
+fn apply_identity(content: &mut Value, identity: &BotIdentity) {
+    if let Some(ref name) = identity.name {
+        content["displayname"] = json!(name);
+    }
+    if let Some(ref avatar) = identity.avatar {
+        content["avatar_url"] = json!(avatar);
+    }
+}
+
+impl App {
+    /// Returns the configured identity of the bridge bot in a guild
+    #[must_use]
+    pub fn guild_identity(&self, guild_id: Option<Id<GuildMarker>>) -> Option<&BotIdentity> {
+        guild_id.and_then(|guild_id| self.config.discord.guild_identities.get(&guild_id))
+    }
+
+    /// Returns the avatar relayed messages fall back to in a channel
+    ///
+    /// # Errors
+    /// This function will return an error if the portal can't be looked up
+    pub(super) async fn fallback_avatar(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+    ) -> Result<Option<&MxcUri>> {
+        if self.config.discord.guild_identities.is_empty() {
+            return Ok(None);
+        }
+        let guild_id = self
+            .portal_by_channel(channel_id)
+            .await?
+            .and_then(|portal| portal.guild_id);
+        Ok(self
+            .guild_identity(guild_id)
+            .and_then(|identity| identity.avatar.as_deref()))
+    }
+
+    /// Sets the profile of the bridge bot in a portal room to its guild identity
+    ///
+    /// # Errors
+    /// This function will return an error if the member event can't be read or sent
+    pub(super) async fn apply_room_identity(self: &Arc<Self>, portal: &Portal) -> Result<()> {
+        let identity = match self.guild_identity(portal.guild_id) {
+            Some(identity) => identity,
+            None => return Ok(()),
+        };
+        let room = match self.client.get_joined_room(&portal.room_id) {
+            Some(room) => room,
+            None => return Ok(()),
+        };
+        let mut content = match room
+            .get_state_event(StateEventType::RoomMember, self.user_id.as_str())
+            .await?
+        {
+            Some(event) => event.deserialize_as::<Value>()?["content"].take(),
+            None => json!({ "membership": "join" }),
+        };
+        let before = content.clone();
+        apply_identity(&mut content, identity);
+        if content == before {
+            return Ok(());
+        }
+        if self.dry_run {
+            info!(
+                "[dry-run] Would set the bot profile in {} to {:?}",
+                portal.room_id, identity
+            );
+            return Ok(());
+        }
+        self.pipeline
+            .run(&self.user_id, "state", async {
+                room.send_state_event_raw(content, "m.room.member", self.user_id.as_str())
+                    .await?;
+                Ok(())
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Sets the nickname of the bridge bot in a guild to its guild identity
+    ///
+    /// # Errors
+    /// This function will return an error if the request to discord fails
+    async fn apply_guild_nickname(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+        name: &str,
+    ) -> Result<()> {
+        if self.dry_run {
+            info!(
+                "[dry-run] Would set the bot nickname in {} to {}",
+                guild_id, name
+            );
+            return Ok(());
+        }
+        self.discord()?
+            .http
+            .update_current_member(guild_id)
+            .nick(Some(name))?
+            .exec()
+            .await?;
+        Ok(())
+    }
+
+    /// Applies the configured guild identities to the guilds and their portals
+    ///
+    /// # Errors
+    /// This function will return an error if the portals can't be listed
+    pub(super) async fn apply_guild_identities(self: &Arc<Self>) -> Result<()> {
+        for (guild_id, identity) in &self.config.discord.guild_identities {
+            if let Some(ref name) = identity.name {
+                if let Err(e) = self.apply_guild_nickname(*guild_id, name).await {
+                    error!("Failed to set the bot nickname in {}: {:?}", guild_id, e);
+                }
+            }
+            for portal in self.portals_in_guild(*guild_id).await? {
+                if let Err(e) = self.apply_room_identity(&portal).await {
+                    error!(
+                        "Failed to set the bot profile in {}: {:?}",
+                        portal.room_id, e
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_unset_fields() {
+        let mut content = json!({
+            "membership": "join",
+            "displayname": "discordbot",
+            "avatar_url": "mxc://chir.rs/bot",
+        });
+        apply_identity(
+            &mut content,
+            &BotIdentity {
+                name: Some("Chir.rs Bridge".to_owned()),
+                avatar: None,
+            },
+        );
+        assert_eq!(content["displayname"], "Chir.rs Bridge");
+        assert_eq!(content["avatar_url"], "mxc://chir.rs/bot");
+    }
+}
@@ -0,0 +1,159 @@
+//! Health checks
+//!
+//! `/healthz` reports whether the connections the bridge owns, to Postgres and to the discord
+//! gateway, work, so that a wedged bridge can be restarted. `/readyz` additionally reports whether
+//! the homeserver is reachable, so that traffic is only routed to a bridge that can bridge it.
+//! Both answer with a JSON report of every component and status 503 if any of them failed.
+
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use super::App;
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use sqlx::Connection;
+use twilight_gateway::shard::Stage;
+
+/// Time after which a check counts as failed
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Result of checking a component
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ComponentHealth {
+    /// The component works
+    Ok,
+    /// The component isn't configured
+    Disabled,
+    /// The component failed
+    Failed {
+        /// Why the component failed
+        error: String,
+    },
+}
+
+impl From<Result<()>> for ComponentHealth {
+    fn from(result: Result<()>) -> Self {
+        match result {
+            Ok(()) => Self::Ok,
+            Err(e) => Self::Failed {
+                error: format!("{:#}", e),
+            },
+        }
+    }
+}
+
+/// Health of the bridge and its components
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct HealthReport {
+    /// Whether no component failed
+    pub healthy: bool,
+    /// Health of each component
+    pub components: BTreeMap<&'static str, ComponentHealth>,
+}
+
+impl HealthReport {
+    /// Creates a report from the health of each component
+    #[must_use]
+    pub fn new(components: BTreeMap<&'static str, ComponentHealth>) -> Self {
+        Self {
+            healthy: !components
+                .values()
+                .any(|health| matches!(health, ComponentHealth::Failed { .. })),
+            components,
+        }
+    }
+}
+
+/// Runs a check, failing it if it takes too long
+async fn check(check: impl std::future::Future<Output = Result<()>>) -> ComponentHealth {
+    match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+        Ok(result) => result.into(),
+        Err(_) => ComponentHealth::Failed {
+            error: format!("No answer within {:?}", CHECK_TIMEOUT),
+        },
+    }
+}
+
+impl App {
+    /// Checks whether a database connection works
+    ///
+    /// # Errors
+    /// This function will return an error if no connection could be acquired or it doesn't
+    /// answer
+    async fn check_database(&self) -> Result<()> {
+        self.db.acquire().await?.ping().await?;
+        Ok(())
+    }
+
+    /// Returns the health of the discord gateway
+    ///
+    /// Every shard has to be connected.
+    fn discord_health(&self) -> ComponentHealth {
+        let discord = match self.discord {
+            Some(ref discord) => discord,
+            None => return ComponentHealth::Disabled,
+        };
+        let disconnected = discord
+            .cluster
+            .info()
+            .into_iter()
+            .filter(|(_, info)| info.stage() != Stage::Connected)
+            .map(|(shard_id, _)| shard_id.to_string())
+            .collect::<Vec<_>>();
+        if disconnected.is_empty() {
+            ComponentHealth::Ok
+        } else {
+            ComponentHealth::Failed {
+                error: format!("Shards {} aren't connected", disconnected.join(", ")),
+            }
+        }
+    }
+
+    /// Checks the connections the bridge owns
+    pub(super) async fn liveness(&self) -> HealthReport {
+        let mut components = BTreeMap::new();
+        components.insert("database", check(self.check_database()).await);
+        components.insert("discord", self.discord_health());
+        HealthReport::new(components)
+    }
+
+    /// Checks the connections the bridge owns and whether the homeserver is reachable
+    pub(super) async fn readiness(self: &Arc<Self>) -> HealthReport {
+        let mut report = self.liveness().await;
+        let homeserver = check(async {
+            if self.homeserver_reachable().await {
+                Ok(())
+            } else {
+                Err(anyhow!("The homeserver doesn't answer"))
+            }
+        })
+        .await;
+        report.components.insert("homeserver", homeserver);
+        HealthReport::new(report.components)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unhealthy_if_any_component_failed() {
+        let mut components = BTreeMap::new();
+        components.insert("database", ComponentHealth::Ok);
+        components.insert("discord", ComponentHealth::Disabled);
+        assert!(HealthReport::new(components.clone()).healthy);
+        components.insert(
+            "homeserver",
+            ComponentHealth::Failed {
+                error: "down".to_owned(),
+            },
+        );
+        let report = HealthReport::new(components);
+        assert!(!report.healthy);
+        assert_eq!(
+            serde_json::to_value(&report).expect("reports serialize")["components"]["homeserver"],
+            serde_json::json!({"status": "failed", "error": "down"})
+        );
+    }
+}
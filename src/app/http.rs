@@ -0,0 +1,250 @@
+//! HTTP listener of the bridge
+//!
+//! The bridge listens on every `bridge.listen_address` at `bridge.port`. The homeserver pushes
+//! transactions to the appservice API there, which the bridge passes to all of its clients, and
+//! queries users and rooms, which the appservice answers. Discord redirects users to
+//! `/oauth/callback` after they authorized an OAuth2 login. `/healthz` and `/readyz` report the
+//! health of the bridge for container healthchecks. Integrations manage portals through the
+//! provisioning API, see `provisioning`.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    sync::{Arc, Weak},
+};
+
+use super::{
+    health::{ComponentHealth, HealthReport},
+    provisioning, App,
+};
+use crate::time;
+use matrix_sdk::ruma::api::{appservice::event::push_events::v1, IncomingRequest as _};
+use tracing::{debug, error, info, warn};
+use warp::{
+    http::{Method, Request, StatusCode},
+    hyper::body::Bytes,
+    reply::{self, Json, WithStatus},
+    Filter,
+};
+
+/// Handles discord's redirect after an OAuth2 login
+async fn oauth_callback(
+    this: Weak<App>,
+    params: HashMap<String, String>,
+) -> WithStatus<&'static str> {
+    let this = match this.upgrade() {
+        Some(this) => this,
+        None => {
+            return reply::with_status(
+                "The bridge is shutting down",
+                StatusCode::SERVICE_UNAVAILABLE,
+            )
+        }
+    };
+    let (code, state) = match (params.get("code"), params.get("state")) {
+        (Some(code), Some(state)) => (code, state),
+        _ => {
+            return reply::with_status(
+                "The login was cancelled, start it again with login in your management room",
+                StatusCode::BAD_REQUEST,
+            )
+        }
+    };
+    match this.complete_oauth_login(code, state).await {
+        Ok(()) => reply::with_status("You are logged in, you can close this page", StatusCode::OK),
+        Err(e) => {
+            warn!("OAuth2 login failed: {:?}", e);
+            reply::with_status(
+                "The login failed, start it again with login in your management room",
+                StatusCode::BAD_REQUEST,
+            )
+        }
+    }
+}
+
+/// Returns whether a transaction carries the homeserver token of the registration
+///
+/// Homeservers send the token as `access_token` query parameter, newer ones also as bearer token.
+fn transaction_authorized(
+    hs_token: &str,
+    access_token: Option<&str>,
+    authorization: Option<&str>,
+) -> bool {
+    let bearer = authorization.and_then(|header| header.strip_prefix("Bearer "));
+    access_token.or(bearer) == Some(hs_token)
+}
+
+/// Handles an appservice transaction pushed by the homeserver
+async fn transaction(
+    this: Weak<App>,
+    txn_id: String,
+    params: HashMap<String, String>,
+    authorization: Option<String>,
+    body: Bytes,
+) -> WithStatus<&'static str> {
+    let received = time::now_ms();
+    let this = match this.upgrade() {
+        Some(this) => this,
+        None => {
+            return reply::with_status(
+                r#"{"errcode":"M_UNKNOWN","error":"The bridge is shutting down"}"#,
+                StatusCode::SERVICE_UNAVAILABLE,
+            )
+        }
+    };
+    if !transaction_authorized(
+        &this.appservice.registration().hs_token,
+        params.get("access_token").map(String::as_str),
+        authorization.as_deref(),
+    ) {
+        return reply::with_status(r#"{"errcode":"M_FORBIDDEN"}"#, StatusCode::FORBIDDEN);
+    }
+    match this.transaction_completed(&txn_id).await {
+        Ok(true) => {
+            debug!("Transaction {} was completed before", txn_id);
+            return reply::with_status("{}", StatusCode::OK);
+        }
+        Ok(false) => {}
+        Err(e) => warn!("Failed to look up transaction {}: {:?}", txn_id, e),
+    }
+    let transaction = match Request::builder()
+        .method(Method::PUT)
+        .body(body.clone())
+        .map_err(anyhow::Error::from)
+        .and_then(|request| {
+            Ok(v1::IncomingRequest::try_from_http_request(
+                request,
+                &[txn_id.as_str()],
+            )?)
+        }) {
+        Ok(transaction) => transaction,
+        Err(e) => {
+            warn!("Received a malformed transaction {}: {:?}", txn_id, e);
+            return reply::with_status(r#"{"errcode":"M_BAD_JSON"}"#, StatusCode::BAD_REQUEST);
+        }
+    };
+    if let Err(e) = this.receive_transaction(&transaction).await {
+        warn!("Failed to process transaction {}: {:?}", txn_id, e);
+        return reply::with_status(
+            r#"{"errcode":"M_UNKNOWN"}"#,
+            StatusCode::INTERNAL_SERVER_ERROR,
+        );
+    }
+    if let Err(e) = this.receive_edus(&body).await {
+        warn!(
+            "Failed to queue the EDUs of transaction {}: {:?}",
+            txn_id, e
+        );
+        return reply::with_status(
+            r#"{"errcode":"M_UNKNOWN"}"#,
+            StatusCode::INTERNAL_SERVER_ERROR,
+        );
+    }
+    // The event handlers stored the events of the transaction already, the homeserver retries the
+    // transaction if they couldn't be stored
+    if let Err(e) = this.queue_transaction_end(received).await {
+        warn!("Failed to queue the end of transaction {}: {:?}", txn_id, e);
+        return reply::with_status(
+            r#"{"errcode":"M_UNKNOWN"}"#,
+            StatusCode::INTERNAL_SERVER_ERROR,
+        );
+    }
+    this.push_to_puppets(transaction).await;
+    if let Err(e) = this.complete_transaction(&txn_id).await {
+        warn!("Failed to record transaction {}: {:?}", txn_id, e);
+    }
+    reply::with_status("{}", StatusCode::OK)
+}
+
+/// Answers a health check with its report
+async fn health(this: Weak<App>, ready: bool) -> WithStatus<Json> {
+    let this = match this.upgrade() {
+        Some(this) => this,
+        None => {
+            let mut components = BTreeMap::new();
+            components.insert(
+                "bridge",
+                ComponentHealth::Failed {
+                    error: "The bridge is shutting down".to_owned(),
+                },
+            );
+            return reply::with_status(
+                reply::json(&HealthReport::new(components)),
+                StatusCode::SERVICE_UNAVAILABLE,
+            );
+        }
+    };
+    let report = if ready {
+        this.readiness().await
+    } else {
+        this.liveness().await
+    };
+    let status = if report.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    reply::with_status(reply::json(&report), status)
+}
+
+impl App {
+    /// Starts serving the bridge's HTTP routes on the configured addresses
+    pub(super) fn spawn_http_listener(self: &Arc<Self>) {
+        let this = Arc::downgrade(self);
+        let oauth = warp::get()
+            .and(warp::path!("oauth" / "callback"))
+            .and(warp::query::<HashMap<String, String>>())
+            .then(move |params| oauth_callback(Weak::clone(&this), params));
+        let this = Arc::downgrade(self);
+        let transactions = warp::put()
+            .and(
+                warp::path!("_matrix" / "app" / "v1" / "transactions" / String)
+                    .or(warp::path!("transactions" / String))
+                    .unify(),
+            )
+            .and(warp::query::<HashMap<String, String>>())
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::body::bytes())
+            .then(move |txn_id, params, authorization, body| {
+                transaction(Weak::clone(&this), txn_id, params, authorization, body)
+            });
+        let this = Arc::downgrade(self);
+        let healthz = warp::get()
+            .and(warp::path!("healthz"))
+            .then(move || health(Weak::clone(&this), false));
+        let this = Arc::downgrade(self);
+        let readyz = warp::get()
+            .and(warp::path!("readyz"))
+            .then(move || health(Weak::clone(&this), true));
+        let routes = oauth
+            .or(transactions)
+            .or(healthz)
+            .or(readyz)
+            .or(provisioning::routes(self))
+            .or(self.appservice.warp_filter());
+        for address in &self.config.bridge.listen_address {
+            let address = SocketAddr::new(*address, self.config.bridge.port);
+            match warp::serve(routes.clone()).try_bind_ephemeral(address) {
+                Ok((address, server)) => {
+                    info!("Listening on {}", address);
+                    tokio::spawn(server);
+                }
+                Err(e) => error!("Failed to listen on {}: {:?}", address, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transactions_need_the_homeserver_token() {
+        assert!(transaction_authorized("hs", Some("hs"), None));
+        assert!(transaction_authorized("hs", None, Some("Bearer hs")));
+        assert!(!transaction_authorized("hs", Some("as"), None));
+        assert!(!transaction_authorized("hs", None, Some("hs")));
+        assert!(!transaction_authorized("hs", None, None));
+    }
+}
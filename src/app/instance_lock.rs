@@ -0,0 +1,51 @@
+//! Guard against multiple bridge instances
+//!
+//! Two instances bridging from the same database and registration would bridge every message
+//! twice. On startup the bridge takes a session-level advisory lock keyed by the registration
+//! id and keeps the connection holding it open for as long as it runs.
+
+use anyhow::{anyhow, Result};
+use sqlx::{pool::PoolConnection, query, PgPool, Postgres};
+use tokio::sync::Mutex;
+use tracing::debug;
+
+/// Advisory lock namespace of the bridge
+const LOCK_NAMESPACE: i32 = 0x4443_4d42;
+
+/// Held advisory lock, released when dropped
+#[derive(Debug)]
+pub struct InstanceLock {
+    /// Connection holding the lock
+    _connection: Mutex<PoolConnection<Postgres>>,
+}
+
+impl InstanceLock {
+    /// Takes the instance lock for a registration
+    ///
+    /// # Errors
+    /// This function will return an error if another instance holds the lock or the database
+    /// query fails
+    #[allow(clippy::panic)]
+    pub async fn acquire(db: &PgPool, registration_id: &str) -> Result<Self> {
+        let mut connection = db.acquire().await?;
+        let locked = query!(
+            "SELECT pg_try_advisory_lock($1, hashtext($2)) AS locked",
+            LOCK_NAMESPACE,
+            registration_id
+        )
+        .fetch_one(&mut connection)
+        .await?
+        .locked
+        .unwrap_or(false);
+        if !locked {
+            return Err(anyhow!(
+                "Another bridge instance for registration {:?} is already running against this database",
+                registration_id
+            ));
+        }
+        debug!("Acquired instance lock for {}", registration_id);
+        Ok(Self {
+            _connection: Mutex::new(connection),
+        })
+    }
+}
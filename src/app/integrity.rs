@@ -0,0 +1,386 @@
+//! Integrity check of stored mappings
+//!
+//! Mappings can drift from reality when messages, rooms or channels are deleted while the bridge
+//! doesn't see it. With `bridge.integrity_check`, a sample of message mappings, portals and
+//! account links is checked against matrix and discord once a day, and the admin can run a check
+//! with the `integrity` command. Only entries matrix or discord definitely report as gone count
+//! as dangling, other failures are skipped. With `repair`, dangling message mappings are deleted
+//! and links to deleted discord accounts are removed; dangling portals are only reported.
+
+use std::{fmt, sync::Arc, time::Duration};
+
+use super::{mappings::MessageMapping, pipeline::error_status, App};
+use crate::{config::IntegrityCheck, metrics::METRICS, snowflake};
+use anyhow::Result;
+use matrix_sdk::{
+    room::Room,
+    ruma::{
+        api::client::room::get_room_event, events::room::message::RoomMessageEventContent, EventId,
+        RoomId, UserId,
+    },
+};
+use sqlx::query;
+use tracing::{error, info, warn};
+use twilight_http::error::ErrorType;
+use twilight_model::id::{
+    marker::{ChannelMarker, MessageMarker, UserMarker},
+    Id,
+};
+
+/// Interval between scheduled checks
+const CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Sample size of checks run with the `integrity` command
+const COMMAND_SAMPLE_SIZE: u32 = 50;
+
+/// Whether an entity referenced by a mapping exists
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Existence {
+    /// The entity exists
+    Exists,
+    /// The entity is definitely gone
+    Missing,
+    /// The entity couldn't be checked
+    Unknown,
+}
+
+impl Existence {
+    /// Returns the existence of an entity from the HTTP status of a failed lookup
+    const fn from_status(status: Option<u16>) -> Self {
+        match status {
+            Some(404) => Self::Missing,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// Result of an integrity check
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DriftReport {
+    /// Number of checked message mappings
+    pub messages: usize,
+    /// Number of message mappings referring to a deleted event or message
+    pub dangling_messages: usize,
+    /// Number of checked portals
+    pub portals: usize,
+    /// Number of portals whose room or channel is gone
+    pub dangling_portals: usize,
+    /// Number of checked account links
+    pub links: usize,
+    /// Number of links to deleted discord accounts
+    pub dangling_links: usize,
+    /// Number of removed dangling entries
+    pub repaired: usize,
+}
+
+impl DriftReport {
+    /// Returns whether the check found dangling entries
+    #[must_use]
+    pub const fn has_drift(&self) -> bool {
+        self.dangling_messages + self.dangling_portals + self.dangling_links > 0
+    }
+}
+
+impl fmt::Display for DriftReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Integrity check: {}/{} message mappings, {}/{} portals and {}/{} account links dangling",
+            self.dangling_messages,
+            self.messages,
+            self.dangling_portals,
+            self.portals,
+            self.dangling_links,
+            self.links
+        )?;
+        if self.repaired > 0 {
+            write!(f, ", {} repaired", self.repaired)?;
+        }
+        Ok(())
+    }
+}
+
+impl App {
+    /// Returns whether a matrix event exists
+    async fn matrix_event_existence(
+        self: &Arc<Self>,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Existence {
+        let request = get_room_event::v3::Request::new(room_id, event_id);
+        match self.client.send(request, None).await {
+            Ok(_) => Existence::Exists,
+            Err(e) => Existence::from_status(error_status(&e.into())),
+        }
+    }
+
+    /// Returns whether the bridge bot is still in a matrix room
+    fn matrix_room_existence(&self, room_id: &RoomId) -> Existence {
+        match self.client.get_room(room_id) {
+            Some(Room::Joined(_)) => Existence::Exists,
+            Some(Room::Left(_)) => Existence::Missing,
+            _ => Existence::Unknown,
+        }
+    }
+
+    /// Returns the existence of a discord entity from the result of its lookup
+    fn discord_existence<T>(result: Result<T, twilight_http::Error>) -> Existence {
+        match result {
+            Ok(_) => Existence::Exists,
+            Err(e) => match e.kind() {
+                ErrorType::Response { status, .. } => Existence::from_status(Some(status.get())),
+                _ => Existence::Unknown,
+            },
+        }
+    }
+
+    /// Returns whether a discord message exists
+    ///
+    /// # Errors
+    /// This function will return an error if no discord bot is configured
+    async fn discord_message_existence(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+        message_id: Id<MessageMarker>,
+    ) -> Result<Existence> {
+        let http = &self.discord()?.http;
+        Ok(Self::discord_existence(
+            http.message(channel_id, message_id).exec().await,
+        ))
+    }
+
+    /// Returns whether a discord channel exists
+    ///
+    /// # Errors
+    /// This function will return an error if no discord bot is configured
+    async fn discord_channel_existence(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+    ) -> Result<Existence> {
+        let http = &self.discord()?.http;
+        Ok(Self::discord_existence(
+            http.channel(channel_id).exec().await,
+        ))
+    }
+
+    /// Returns whether a discord user exists
+    ///
+    /// # Errors
+    /// This function will return an error if no discord bot is configured
+    async fn discord_user_existence(
+        self: &Arc<Self>,
+        user_id: Id<UserMarker>,
+    ) -> Result<Existence> {
+        let http = &self.discord()?.http;
+        Ok(Self::discord_existence(http.user(user_id).exec().await))
+    }
+
+    /// Checks a sample of message mappings
+    ///
+    /// # Errors
+    /// This function will return an error if a database query fails
+    async fn check_message_mappings(
+        self: &Arc<Self>,
+        check: IntegrityCheck,
+        report: &mut DriftReport,
+    ) -> Result<()> {
+        for mapping in MessageMapping::sample(&*self.db, check.sample_size).await? {
+            report.messages += 1;
+            let mut existence = self
+                .matrix_event_existence(&mapping.room_id, &mapping.event_id)
+                .await;
+            if existence != Existence::Missing {
+                existence = self
+                    .discord_message_existence(mapping.channel_id, mapping.message_id)
+                    .await?;
+            }
+            if existence != Existence::Missing {
+                continue;
+            }
+            report.dangling_messages += 1;
+            warn!(
+                "Mapping of {} to discord message {} is dangling",
+                mapping.event_id, mapping.message_id
+            );
+            if check.repair && !self.dry_run {
+                mapping.delete(&*self.db).await?;
+                report.repaired += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a sample of portals
+    ///
+    /// # Errors
+    /// This function will return an error if a database query fails
+    async fn check_portals(
+        self: &Arc<Self>,
+        check: IntegrityCheck,
+        report: &mut DriftReport,
+    ) -> Result<()> {
+        for portal in self.sample_portals(check.sample_size).await? {
+            report.portals += 1;
+            let mut existence = self.matrix_room_existence(&portal.room_id);
+            if existence != Existence::Missing {
+                existence = self.discord_channel_existence(portal.channel_id).await?;
+            }
+            if existence == Existence::Missing {
+                report.dangling_portals += 1;
+                warn!(
+                    "Portal of channel {} in {} is dangling",
+                    portal.channel_id, portal.room_id
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a sample of links between matrix and discord accounts
+    ///
+    /// # Errors
+    /// This function will return an error if a database query fails
+    #[allow(clippy::panic)]
+    async fn check_account_links(
+        self: &Arc<Self>,
+        check: IntegrityCheck,
+        report: &mut DriftReport,
+    ) -> Result<()> {
+        let rows = query!(
+            r#"WITH start AS (SELECT MIN(discord_user_id) + floor(random() * (MAX(discord_user_id) - MIN(discord_user_id) + 1))::BIGINT AS id FROM discord_tokens WHERE discord_user_id IS NOT NULL) (SELECT user_id AS "user_id!", discord_user_id FROM discord_tokens WHERE discord_user_id IS NOT NULL AND discord_user_id >= (SELECT id FROM start) ORDER BY discord_user_id LIMIT $1) UNION ALL (SELECT user_id, discord_user_id FROM discord_tokens WHERE discord_user_id IS NOT NULL AND discord_user_id < (SELECT id FROM start) ORDER BY discord_user_id LIMIT $1) LIMIT $1"#,
+            i64::from(check.sample_size)
+        )
+        .fetch_all(&*self.db)
+        .await?;
+        for row in rows {
+            let discord_user = match row.discord_user_id {
+                Some(discord_user) => snowflake::from_db(discord_user)?,
+                None => continue,
+            };
+            report.links += 1;
+            if self.discord_user_existence(discord_user).await? != Existence::Missing {
+                continue;
+            }
+            report.dangling_links += 1;
+            warn!(
+                "{} is linked to the deleted discord account {}",
+                row.user_id, discord_user
+            );
+            if check.repair && !self.dry_run {
+                query!(
+                    "UPDATE discord_tokens SET discord_user_id = NULL WHERE user_id = $1",
+                    row.user_id
+                )
+                .execute(&*self.db)
+                .await?;
+                report.repaired += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a sample of the stored mappings and reports drift
+    ///
+    /// # Errors
+    /// This function will return an error if a database query fails or no discord bot is
+    /// configured
+    pub async fn check_integrity(self: &Arc<Self>, check: IntegrityCheck) -> Result<DriftReport> {
+        let mut report = DriftReport::default();
+        self.check_message_mappings(check, &mut report).await?;
+        self.check_portals(check, &mut report).await?;
+        self.check_account_links(check, &mut report).await?;
+        for (kind, dangling) in [
+            ("messages", report.dangling_messages),
+            ("portals", report.dangling_portals),
+            ("links", report.dangling_links),
+        ] {
+            METRICS.set(
+                "bridge_integrity_dangling",
+                &[("kind", kind)],
+                i64::try_from(dangling).unwrap_or(i64::MAX),
+            );
+        }
+        info!("{}", report);
+        Ok(report)
+    }
+
+    /// Runs the configured integrity check periodically
+    pub(super) fn spawn_integrity_check(self: &Arc<Self>) {
+        let check = match self.config.bridge.integrity_check {
+            Some(check) => check,
+            None => return,
+        };
+        let this = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                let this = match this.upgrade() {
+                    Some(this) => this,
+                    None => break,
+                };
+                match this.check_integrity(check).await {
+                    Ok(report) if report.has_drift() => {
+                        if let Err(e) = this.alert_admin(&report.to_string()).await {
+                            warn!("Failed to alert the admin room: {:?}", e);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => error!("Integrity check failed: {:?}", e),
+                }
+            }
+        });
+    }
+
+    /// Handles the `integrity` command
+    ///
+    /// Runs a check with the configured repair setting and replies with the report.
+    ///
+    /// # Errors
+    /// This function will return an error if the check or sending the reply fails
+    pub(super) async fn handle_integrity_command(
+        self: &Arc<Self>,
+        sender: &UserId,
+        room: &Room,
+    ) -> Result<()> {
+        let reply = if sender == self.config.bridge.admin {
+            let check = self
+                .config
+                .bridge
+                .integrity_check
+                .unwrap_or(IntegrityCheck {
+                    sample_size: COMMAND_SAMPLE_SIZE,
+                    repair: false,
+                });
+            self.check_integrity(check).await?.to_string()
+        } else {
+            "Only the bridge admin can run integrity checks".to_owned()
+        };
+        self.send_message(room, RoomMessageEventContent::text_plain(reply))
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_reports() {
+        let mut report = DriftReport {
+            messages: 50,
+            dangling_messages: 2,
+            portals: 3,
+            ..DriftReport::default()
+        };
+        assert!(report.has_drift());
+        assert_eq!(
+            report.to_string(),
+            "Integrity check: 2/50 message mappings, 0/3 portals and 0/0 account links dangling"
+        );
+        report.repaired = 2;
+        assert!(report.to_string().ends_with(", 2 repaired"));
+        assert_eq!(Existence::from_status(Some(403)), Existence::Unknown);
+    }
+}
@@ -0,0 +1,371 @@
+//! Knocks on portal rooms
+//!
+//! When a matrix user knocks on a portal, the discord moderators are notified in the configured
+//! knock channel, or the guild owner by direct message. Reacting with ✅ or ❌ to the
+//! notification, or running `/knock` in the same channel, invites the knocker or rejects the
+//! knock. Anyone who can see the notification channel is trusted to decide.
+
+use std::sync::Arc;
+
+use super::App;
+use crate::snowflake;
+use anyhow::{anyhow, Result};
+use matrix_sdk::{
+    room::Room,
+    ruma::{
+        events::{
+            room::member::{MembershipState, SyncRoomMemberEvent},
+            SyncStateEvent,
+        },
+        OwnedRoomId, OwnedUserId, RoomId, UserId,
+    },
+};
+use sqlx::query;
+use tracing::{info, warn};
+use twilight_http::request::channel::reaction::RequestReactionType;
+use twilight_model::{
+    channel::{Reaction, ReactionType},
+    id::{
+        marker::{ChannelMarker, MessageMarker},
+        Id,
+    },
+};
+
+/// Reaction approving a knock
+const APPROVE_EMOJI: &str = "✅";
+
+/// Reaction denying a knock
+const DENY_EMOJI: &str = "❌";
+
+/// Decision of a moderator about a knock
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Decision {
+    /// Invite the knocking user
+    Approve,
+    /// Reject the knock
+    Deny,
+}
+
+impl Decision {
+    /// Parses a decision from a reaction emoji or a command argument
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            APPROVE_EMOJI | "approve" => Some(Self::Approve),
+            DENY_EMOJI | "deny" => Some(Self::Deny),
+            _ => None,
+        }
+    }
+}
+
+/// Builds the notification sent to discord moderators
+fn knock_notification(
+    user_id: &UserId,
+    channel_id: Id<ChannelMarker>,
+    reason: Option<&str>,
+) -> String {
+    let reason = reason
+        .filter(|reason| !reason.is_empty())
+        .map_or_else(String::new, |reason| format!(" with reason “{}”", reason));
+    format!(
+        "Matrix user `{}` asked to join <#{}>{}. React with {} to invite them or {} to deny, or use `/knock` with the id of this message.",
+        user_id, channel_id, reason, APPROVE_EMOJI, DENY_EMOJI
+    )
+}
+
+/// A pending knock
+#[derive(Clone, Debug)]
+struct Knock {
+    /// The portal room
+    room_id: OwnedRoomId,
+    /// The knocking user
+    user_id: OwnedUserId,
+}
+
+impl App {
+    /// Handles a membership change in a room the bridge bot is in
+    ///
+    /// # Errors
+    /// This function will return an error if notifying discord or the database query fails
+    #[tracing::instrument(skip(self))]
+    pub(super) async fn handle_room_membership_event(
+        self: &Arc<Self>,
+        event: SyncRoomMemberEvent,
+        room: Room,
+    ) -> Result<()> {
+        let event = match event {
+            SyncStateEvent::Original(event) => event,
+            SyncStateEvent::Redacted(_) => return Ok(()),
+        };
+        if event.content.membership == MembershipState::Knock {
+            self.notify_knock(
+                room.room_id(),
+                &event.state_key,
+                event.content.reason.as_deref(),
+            )
+            .await
+        } else {
+            self.forget_knock(room.room_id(), &event.state_key).await
+        }
+    }
+
+    /// Notifies discord moderators about a knock on a portal
+    ///
+    /// # Errors
+    /// This function will return an error if sending the notification or the database query fails
+    #[allow(clippy::panic)]
+    async fn notify_knock(
+        self: &Arc<Self>,
+        room_id: &RoomId,
+        user_id: &UserId,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        let portal = match self.portal_by_room(room_id).await? {
+            Some(portal) => portal,
+            None => return Ok(()),
+        };
+        let pending = query!(
+            "SELECT discord_message_id FROM knocks WHERE matrix_room_id = $1 AND user_id = $2",
+            room_id.as_str(),
+            user_id.as_str()
+        )
+        .fetch_optional(&*self.db)
+        .await?;
+        if pending.is_some() {
+            return Ok(());
+        }
+        let discord = self.discord()?;
+        let channel_id = match (self.config.discord.knock_channel, portal.guild_id) {
+            (Some(channel_id), _) => channel_id,
+            (None, Some(guild_id)) => {
+                let owner_id = discord
+                    .http
+                    .guild(guild_id)
+                    .exec()
+                    .await?
+                    .model()
+                    .await?
+                    .owner_id;
+                discord
+                    .http
+                    .create_private_channel(owner_id)
+                    .exec()
+                    .await?
+                    .model()
+                    .await?
+                    .id
+            }
+            (None, None) => {
+                warn!("Nowhere to send knock of {} on {}", user_id, room_id);
+                return Ok(());
+            }
+        };
+        let notification = knock_notification(user_id, portal.channel_id, reason);
+        if self.dry_run {
+            info!("[dry-run] Would notify {}: {}", channel_id, notification);
+            return Ok(());
+        }
+        let message = discord
+            .http
+            .create_message(channel_id)
+            .content(&notification)?
+            .exec()
+            .await?
+            .model()
+            .await?;
+        query!(
+            "INSERT INTO knocks (discord_message_id, discord_channel_id, matrix_room_id, user_id) VALUES ($1, $2, $3, $4) ON CONFLICT DO NOTHING",
+            snowflake::to_db(message.id),
+            snowflake::to_db(channel_id),
+            room_id.as_str(),
+            user_id.as_str()
+        )
+        .execute(&*self.db)
+        .await?;
+        for emoji in [APPROVE_EMOJI, DENY_EMOJI] {
+            discord
+                .http
+                .create_reaction(
+                    channel_id,
+                    message.id,
+                    &RequestReactionType::Unicode { name: emoji },
+                )
+                .exec()
+                .await?;
+        }
+        info!(
+            "Notified {} about knock of {} on {}",
+            channel_id, user_id, room_id
+        );
+        Ok(())
+    }
+
+    /// Forgets a pending knock after the user joined, left or was invited
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    async fn forget_knock(self: &Arc<Self>, room_id: &RoomId, user_id: &UserId) -> Result<()> {
+        query!(
+            "DELETE FROM knocks WHERE matrix_room_id = $1 AND user_id = $2",
+            room_id.as_str(),
+            user_id.as_str()
+        )
+        .execute(&*self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Takes a pending knock by the id of its notification
+    ///
+    /// The knock is only returned if the notification was sent to `channel_id`.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    async fn take_knock(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+        message_id: Id<MessageMarker>,
+    ) -> Result<Option<Knock>> {
+        query!(
+            "DELETE FROM knocks WHERE discord_message_id = $1 AND discord_channel_id = $2 RETURNING matrix_room_id, user_id",
+            snowflake::to_db(message_id),
+            snowflake::to_db(channel_id)
+        )
+        .fetch_optional(&*self.db)
+        .await?
+        .map(|row| {
+            Ok(Knock {
+                room_id: OwnedRoomId::try_from(row.matrix_room_id)?,
+                user_id: OwnedUserId::try_from(row.user_id)?,
+            })
+        })
+        .transpose()
+    }
+
+    /// Invites the knocking user or rejects the knock
+    ///
+    /// Returns a description of what was done.
+    ///
+    /// # Errors
+    /// This function will return an error if the bridge is not in the room or the request fails
+    async fn decide_knock(self: &Arc<Self>, knock: &Knock, decision: Decision) -> Result<String> {
+        let room = self
+            .client
+            .get_joined_room(&knock.room_id)
+            .ok_or_else(|| anyhow!("The bridge is not in {}", knock.room_id))?;
+        if self.dry_run {
+            info!(
+                "[dry-run] Would {:?} knock of {} on {}",
+                decision, knock.user_id, knock.room_id
+            );
+            return Ok(format!("Would {:?} `{}`", decision, knock.user_id));
+        }
+        self.pipeline
+            .run(&self.user_id, "membership", async {
+                match decision {
+                    Decision::Approve => room.invite_user_by_id(&knock.user_id).await?,
+                    Decision::Deny => {
+                        room.kick_user(&knock.user_id, Some("Knock denied")).await?;
+                    }
+                }
+                Ok(())
+            })
+            .await?;
+        info!(
+            "{:?} knock of {} on {}",
+            decision, knock.user_id, knock.room_id
+        );
+        Ok(match decision {
+            Decision::Approve => format!("Invited `{}`", knock.user_id),
+            Decision::Deny => format!("Denied the request of `{}`", knock.user_id),
+        })
+    }
+
+    /// Handles a reaction to a knock notification
+    ///
+    /// # Errors
+    /// This function will return an error if deciding the knock or replying fails
+    pub(super) async fn handle_knock_reaction(self: &Arc<Self>, reaction: &Reaction) -> Result<()> {
+        let discord = self.discord()?;
+        if reaction.user_id == discord.user_id {
+            return Ok(());
+        }
+        let decision = match reaction.emoji {
+            ReactionType::Unicode { ref name } => Decision::parse(name),
+            ReactionType::Custom { .. } => None,
+        };
+        let decision = match decision {
+            Some(decision) => decision,
+            None => return Ok(()),
+        };
+        let knock = match self
+            .take_knock(reaction.channel_id, reaction.message_id)
+            .await?
+        {
+            Some(knock) => knock,
+            None => return Ok(()),
+        };
+        let reply = self.decide_knock(&knock, decision).await?;
+        if self.dry_run {
+            return Ok(());
+        }
+        discord
+            .http
+            .create_message(reaction.channel_id)
+            .reply(reaction.message_id)
+            .content(&reply)?
+            .exec()
+            .await?;
+        Ok(())
+    }
+
+    /// Runs the `knock` slash command and returns the reply
+    ///
+    /// # Errors
+    /// This function will return an error if deciding the knock fails
+    pub(super) async fn knock_command_reply(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+        args: &[&str],
+    ) -> Result<String> {
+        let (decision, message_id) = match args {
+            ["knock", decision, message_id] => (
+                Decision::parse(decision),
+                message_id.parse().ok().and_then(Id::new_checked),
+            ),
+            _ => (None, None),
+        };
+        let (decision, message_id) = match (decision, message_id) {
+            (Some(decision), Some(message_id)) => (decision, message_id),
+            _ => return Ok("Usage: /knock <approve|deny> <notification message id>".to_owned()),
+        };
+        match self.take_knock(channel_id, message_id).await? {
+            Some(knock) => self.decide_knock(&knock, decision).await,
+            None => Ok("There is no pending knock for this message in this channel".to_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::user_id;
+
+    #[test]
+    fn parses_decisions() {
+        assert_eq!(Decision::parse("✅"), Some(Decision::Approve));
+        assert_eq!(Decision::parse("deny"), Some(Decision::Deny));
+        assert_eq!(Decision::parse("👍"), None);
+    }
+
+    #[test]
+    fn formats_notifications() {
+        let user = user_id!("@alice:chir.rs");
+        assert_eq!(
+            knock_notification(user, Id::new(5), Some("hi!")),
+            "Matrix user `@alice:chir.rs` asked to join <#5> with reason “hi!”. React with ✅ to invite them or ❌ to deny, or use `/knock` with the id of this message."
+        );
+        assert!(knock_notification(user, Id::new(5), Some("")).contains("<#5>. React"));
+    }
+}
@@ -0,0 +1,161 @@
+//! Load shedding under sustained overload
+//!
+//! Events from the homeserver and discord are handled one after another by the event queue. When
+//! more events arrive than can be handled, the queue table grows without bound and everything is
+//! bridged later and later. Once the queue depth or the processing time of homeserver batches exceeds a
+//! threshold, low-priority events (typing, presence, reactions and read receipts) are dropped
+//! until the pressure subsides. Entering and leaving this mode is reported to the admin room once each.
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+
+use super::{App, QueueEvent};
+use crate::metrics::METRICS;
+use tracing::{info, warn};
+use twilight_gateway::Event;
+
+/// Queue depth from which low-priority events are dropped
+const SHED_DEPTH: usize = 1000;
+
+/// Queue depth below which dropping stops again
+const RECOVER_DEPTH: usize = 100;
+
+/// Processing time of a homeserver batch from which low-priority events are dropped
+const SHED_LATENCY_MS: u64 = 30_000;
+
+/// Processing time of a homeserver batch below which dropping stops again
+const RECOVER_LATENCY_MS: u64 = 5_000;
+
+/// Returns whether the queue is overloaded
+///
+/// The thresholds for recovering are lower than those for entering, so that the mode doesn't
+/// flap while the pressure hovers around a threshold.
+const fn overloaded(shedding: bool, depth: usize, latency_ms: u64) -> bool {
+    if shedding {
+        depth > RECOVER_DEPTH || latency_ms > RECOVER_LATENCY_MS
+    } else {
+        depth >= SHED_DEPTH || latency_ms >= SHED_LATENCY_MS
+    }
+}
+
+/// Returns the kind of a low-priority event, or `None` if the event is always handled
+fn low_priority_kind(event: &QueueEvent) -> Option<&'static str> {
+    match event {
+        QueueEvent::ReactionEvent(_) => Some("matrix_reaction"),
+        QueueEvent::ReceiptEvent(_) => Some("matrix_receipt"),
+        QueueEvent::TypingEvent(_) => Some("matrix_typing"),
+        QueueEvent::DiscordEvent(event) => match **event {
+            Event::TypingStart(_) => Some("discord_typing"),
+            Event::PresenceUpdate(_) => Some("discord_presence"),
+            Event::ReactionAdd(_)
+            | Event::ReactionRemove(_)
+            | Event::ReactionRemoveAll(_)
+            | Event::ReactionRemoveEmoji(_) => Some("discord_reaction"),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Pressure on the event queue
+#[derive(Debug, Default)]
+pub(super) struct LoadShedder {
+    /// Number of queued events that weren't handled yet
+    depth: AtomicUsize,
+    /// Processing time of the last homeserver batch in milliseconds
+    latency_ms: AtomicU64,
+    /// Whether low-priority events are dropped
+    shedding: AtomicBool,
+}
+
+impl LoadShedder {
+    /// Records that an event was queued
+    pub(super) fn queued(&self) {
+        self.depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that an event was taken from the queue and returns the remaining depth
+    pub(super) fn dequeued(&self) -> usize {
+        let previous = self
+            .depth
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |depth| {
+                Some(depth.saturating_sub(1))
+            })
+            .unwrap_or_default();
+        previous.saturating_sub(1)
+    }
+
+    /// Sets the depth to the number of events left in the queue by the previous run
+    pub(super) fn restore_depth(&self, depth: usize) {
+        self.depth.store(depth, Ordering::Relaxed);
+    }
+
+    /// Records the processing time of a homeserver batch
+    pub(super) fn record_latency(&self, latency_ms: u64) {
+        self.latency_ms.store(latency_ms, Ordering::Relaxed);
+    }
+}
+
+impl App {
+    /// Updates the load shedding mode for an event taken from the queue
+    ///
+    /// Returns whether the event is dropped.
+    pub(super) fn shed(self: &Arc<Self>, event: &QueueEvent) -> bool {
+        let depth = self.load.dequeued();
+        METRICS.set(
+            "bridge_queue_depth",
+            &[],
+            i64::try_from(depth).unwrap_or(i64::MAX),
+        );
+        let latency_ms = self.load.latency_ms.load(Ordering::Relaxed);
+        let was_shedding = self.load.shedding.load(Ordering::Relaxed);
+        let shedding = overloaded(was_shedding, depth, latency_ms);
+        if shedding != was_shedding {
+            self.load.shedding.store(shedding, Ordering::Relaxed);
+            METRICS.set("bridge_load_shedding", &[], i64::from(shedding));
+            let alert = if shedding {
+                warn!(
+                    "Overloaded with {} queued events and {}ms batch processing time, dropping low-priority events",
+                    depth, latency_ms
+                );
+                format!("The bridge is overloaded with {} queued events, typing, presence, reactions and read receipts are dropped until it catches up", depth)
+            } else {
+                info!("Caught up with the event queue, handling all events again");
+                "The bridge caught up, typing, presence, reactions and read receipts are bridged again".to_owned()
+            };
+            let this = Arc::clone(self);
+            tokio::spawn(async move {
+                if let Err(e) = this.alert_admin(&alert).await {
+                    warn!("Failed to alert the admin room: {:?}", e);
+                }
+            });
+        }
+        if !shedding {
+            return false;
+        }
+        match low_priority_kind(event) {
+            Some(kind) => {
+                METRICS.inc("bridge_events_shed", &[("kind", kind)]);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovers_below_lower_thresholds() {
+        assert!(!overloaded(false, SHED_DEPTH - 1, 0));
+        assert!(overloaded(false, SHED_DEPTH, 0));
+        assert!(overloaded(false, 0, SHED_LATENCY_MS));
+        assert!(overloaded(true, RECOVER_DEPTH + 1, 0));
+        assert!(overloaded(true, 0, RECOVER_LATENCY_MS + 1));
+        assert!(!overloaded(true, RECOVER_DEPTH, RECOVER_LATENCY_MS));
+    }
+}
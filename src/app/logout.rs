@@ -0,0 +1,100 @@
+//! Logging matrix users out of discord
+//!
+//! When a user unregisters, everything the bridge keeps for their discord account goes with the
+//! token: messages still queued for discord in their name are cancelled, the cached puppet client
+//! of their discord account is dropped, and the logout is confirmed in their management room.
+
+use std::sync::Arc;
+
+use super::App;
+use crate::snowflake;
+use anyhow::Result;
+use matrix_sdk::ruma::{events::room::message::RoomMessageEventContent, RoomId, UserId};
+use sqlx::query;
+use tracing::{info, warn};
+
+/// Formats the confirmation of a logout
+fn logout_confirmation(reason: &str, cancelled: u64) -> String {
+    match cancelled {
+        0 => format!("Logged out of discord ({})", reason),
+        1 => format!(
+            "Logged out of discord ({}), cancelled 1 queued message",
+            reason
+        ),
+        _ => format!(
+            "Logged out of discord ({}), cancelled {} queued messages",
+            reason, cancelled
+        ),
+    }
+}
+
+impl App {
+    /// Logs a matrix user out of discord
+    ///
+    /// `reason` is shown in the confirmation, like `unregistered` or `token revoked`. Returns
+    /// whether the user was logged in.
+    ///
+    /// # Errors
+    /// This function will return an error if a database query fails
+    #[allow(clippy::panic)]
+    pub(super) async fn logout_user(self: &Arc<Self>, user: &UserId, reason: &str) -> Result<bool> {
+        let row = match query!(
+            "SELECT management_room, discord_user_id FROM discord_tokens WHERE user_id = $1",
+            user.as_str()
+        )
+        .fetch_optional(&*self.db)
+        .await?
+        {
+            Some(row) => row,
+            None => return Ok(false),
+        };
+        if self.dry_run {
+            info!("[dry-run] Would log {} out of discord ({})", user, reason);
+            return Ok(true);
+        }
+        let cancelled = query!(
+            "DELETE FROM pending_discord_sends WHERE matrix_user_id = $1",
+            user.as_str()
+        )
+        .execute(&*self.db)
+        .await?
+        .rows_affected();
+        self.unregister_user(user).await?;
+        if let Some(discord_user) = row.discord_user_id.map(snowflake::from_db).transpose()? {
+            self.discord_clients.remove(&discord_user);
+        }
+        info!(
+            "Logged {} out of discord ({}), cancelled {} queued messages",
+            user, reason, cancelled
+        );
+        let confirmation = logout_confirmation(reason, cancelled);
+        let room = <&RoomId>::try_from(row.management_room.as_str())
+            .ok()
+            .and_then(|room_id| self.client.get_room(room_id));
+        match room {
+            Some(room) => {
+                self.send_message(&room, RoomMessageEventContent::notice_plain(confirmation))
+                    .await?;
+            }
+            None => warn!("No management room to confirm the logout of {}", user),
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn confirms_logout() {
+        assert_eq!(
+            logout_confirmation("unregistered", 0),
+            "Logged out of discord (unregistered)"
+        );
+        assert_eq!(
+            logout_confirmation("token revoked", 2),
+            "Logged out of discord (token revoked), cancelled 2 queued messages"
+        );
+    }
+}
@@ -0,0 +1,341 @@
+//! Typed access to the message mappings
+//!
+//! Message mappings between discord messages and matrix events are read and written by most
+//! parts of the bridge. This module wraps them in a typed value with the queries that read and
+//! write them, so the raw rows stay in one place. Portals have their typed access in `portals`.
+//!
+//! The queries take any executor, so that they can run on the pool as well as inside a
+//! transaction. Storing a message mapping needs a transaction, see [`MessageMapping::insert`].
+
+use std::sync::Arc;
+
+use super::App;
+use crate::snowflake;
+use anyhow::Result;
+use matrix_sdk::ruma::{EventId, OwnedEventId, OwnedRoomId, RoomId};
+use sqlx::{query, query_as, PgExecutor, Postgres, Transaction};
+use tracing::warn;
+use twilight_model::id::{
+    marker::{ChannelMarker, MessageMarker, WebhookMarker},
+    Id,
+};
+
+/// Advisory lock namespace of message mappings that are being stored
+const MESSAGE_MAP_LOCK_NAMESPACE: i32 = 0x4443_4d4d;
+
+/// A discord message bridged to or from a matrix event
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MessageMapping {
+    /// The matrix event
+    pub event_id: OwnedEventId,
+    /// The room of the matrix event
+    pub room_id: OwnedRoomId,
+    /// The discord message
+    pub message_id: Id<MessageMarker>,
+    /// The channel of the discord message
+    pub channel_id: Id<ChannelMarker>,
+    /// The webhook the message was sent through, if any
+    pub webhook_id: Option<Id<WebhookMarker>>,
+    /// Whether the event was relayed from matrix to discord
+    pub relayed: bool,
+}
+
+/// Database row of a message mapping
+#[derive(Debug)]
+struct MessageMappingRow {
+    /// The matrix event id
+    matrix_event_id: String,
+    /// The matrix room id
+    matrix_room_id: String,
+    /// The discord message
+    discord_message_id: i64,
+    /// The discord channel
+    discord_channel_id: i64,
+    /// The webhook the message was sent through
+    webhook_id: Option<i64>,
+    /// Whether the event was relayed to discord
+    relayed: bool,
+}
+
+impl TryFrom<MessageMappingRow> for MessageMapping {
+    type Error = anyhow::Error;
+
+    fn try_from(row: MessageMappingRow) -> Result<Self> {
+        Ok(Self {
+            event_id: OwnedEventId::try_from(row.matrix_event_id)?,
+            room_id: OwnedRoomId::try_from(row.matrix_room_id)?,
+            message_id: snowflake::from_db(row.discord_message_id)?,
+            channel_id: snowflake::from_db(row.discord_channel_id)?,
+            webhook_id: row.webhook_id.map(snowflake::from_db).transpose()?,
+            relayed: row.relayed,
+        })
+    }
+}
+
+impl MessageMapping {
+    /// Stores the mapping, unless it is already stored
+    ///
+    /// The partition key of the message map is part of its primary key, so the primary key
+    /// doesn't catch mappings stored twice. Instead, the mappings of the event are locked for the
+    /// rest of the transaction and the mapping is only inserted if it doesn't exist yet.
+    ///
+    /// # Errors
+    /// This function will return an error if a database query fails
+    #[allow(clippy::panic)]
+    pub async fn insert(&self, tx: &mut Transaction<'_, Postgres>) -> Result<()> {
+        query!(
+            r#"SELECT TRUE AS "locked!" FROM pg_advisory_xact_lock($1, hashtext($2))"#,
+            MESSAGE_MAP_LOCK_NAMESPACE,
+            self.event_id.as_str()
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        query!(
+            "INSERT INTO message_map (matrix_event_id, matrix_room_id, discord_message_id, discord_channel_id, webhook_id, relayed) SELECT $1, $2, $3, $4, $5, $6 WHERE NOT EXISTS (SELECT 1 FROM message_map WHERE matrix_event_id = $1 AND discord_message_id = $3)",
+            self.event_id.as_str(),
+            self.room_id.as_str(),
+            snowflake::to_db(self.message_id),
+            snowflake::to_db(self.channel_id),
+            self.webhook_id.map(snowflake::to_db),
+            self.relayed
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+
+    /// Deletes the mapping
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub async fn delete<'e>(&self, db: impl PgExecutor<'e>) -> Result<()> {
+        query!(
+            "DELETE FROM message_map WHERE matrix_event_id = $1 AND discord_message_id = $2",
+            self.event_id.as_str(),
+            snowflake::to_db(self.message_id)
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    /// Returns the first mapping of a matrix event
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub async fn for_event<'e>(
+        db: impl PgExecutor<'e>,
+        event_id: &EventId,
+    ) -> Result<Option<Self>> {
+        query_as!(
+            MessageMappingRow,
+            "SELECT matrix_event_id, matrix_room_id, discord_message_id, discord_channel_id, webhook_id, relayed FROM message_map WHERE matrix_event_id = $1 ORDER BY discord_message_id LIMIT 1",
+            event_id.as_str()
+        )
+        .fetch_optional(db)
+        .await?
+        .map(Self::try_from)
+        .transpose()
+    }
+
+    /// Returns the mapping of a matrix event that was relayed to discord
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub async fn relayed_for_event<'e>(
+        db: impl PgExecutor<'e>,
+        event_id: &EventId,
+    ) -> Result<Option<Self>> {
+        query_as!(
+            MessageMappingRow,
+            "SELECT matrix_event_id, matrix_room_id, discord_message_id, discord_channel_id, webhook_id, relayed FROM message_map WHERE matrix_event_id = $1 AND relayed LIMIT 1",
+            event_id.as_str()
+        )
+        .fetch_optional(db)
+        .await?
+        .map(Self::try_from)
+        .transpose()
+    }
+
+    /// Returns the mappings of a discord message in the order they were created
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub async fn for_message<'e>(
+        db: impl PgExecutor<'e>,
+        message_id: Id<MessageMarker>,
+    ) -> Result<Vec<Self>> {
+        query_as!(
+            MessageMappingRow,
+            "SELECT matrix_event_id, matrix_room_id, discord_message_id, discord_channel_id, webhook_id, relayed FROM message_map WHERE discord_message_id = $1 ORDER BY created_at",
+            snowflake::to_db(message_id)
+        )
+        .fetch_all(db)
+        .await?
+        .into_iter()
+        .map(Self::try_from)
+        .collect()
+    }
+
+    /// Returns the mappings of a matrix room in the order they were created
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub async fn in_room<'e>(db: impl PgExecutor<'e>, room_id: &RoomId) -> Result<Vec<Self>> {
+        query_as!(
+            MessageMappingRow,
+            "SELECT matrix_event_id, matrix_room_id, discord_message_id, discord_channel_id, webhook_id, relayed FROM message_map WHERE matrix_room_id = $1 ORDER BY created_at, discord_message_id",
+            room_id.as_str()
+        )
+        .fetch_all(db)
+        .await?
+        .into_iter()
+        .map(Self::try_from)
+        .collect()
+    }
+
+    /// Returns the mapping of the latest message in a discord channel before a message
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub async fn latest_before<'e>(
+        db: impl PgExecutor<'e>,
+        channel_id: Id<ChannelMarker>,
+        message_id: Id<MessageMarker>,
+    ) -> Result<Option<Self>> {
+        query_as!(
+            MessageMappingRow,
+            "SELECT matrix_event_id, matrix_room_id, discord_message_id, discord_channel_id, webhook_id, relayed FROM message_map WHERE discord_channel_id = $1 AND discord_message_id < $2 ORDER BY discord_message_id DESC, created_at DESC LIMIT 1",
+            snowflake::to_db(channel_id),
+            snowflake::to_db(message_id)
+        )
+        .fetch_optional(db)
+        .await?
+        .map(Self::try_from)
+        .transpose()
+    }
+
+    /// Returns a sample of mappings
+    ///
+    /// The sample is a run of consecutive messages starting at a random message id, wrapping
+    /// around to the oldest message, so that it is taken from the index instead of sorting the
+    /// whole table. Rows with invalid ids are skipped.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub async fn sample<'e>(db: impl PgExecutor<'e>, limit: u32) -> Result<Vec<Self>> {
+        Ok(query_as!(
+            MessageMappingRow,
+            r#"WITH start AS (SELECT MIN(discord_message_id) + floor(random() * (MAX(discord_message_id) - MIN(discord_message_id) + 1))::BIGINT AS id FROM message_map) (SELECT matrix_event_id AS "matrix_event_id!", matrix_room_id AS "matrix_room_id!", discord_message_id AS "discord_message_id!", discord_channel_id AS "discord_channel_id!", webhook_id, relayed AS "relayed!" FROM message_map WHERE discord_message_id >= (SELECT id FROM start) ORDER BY discord_message_id LIMIT $1) UNION ALL (SELECT matrix_event_id, matrix_room_id, discord_message_id, discord_channel_id, webhook_id, relayed FROM message_map WHERE discord_message_id < (SELECT id FROM start) ORDER BY discord_message_id LIMIT $1) LIMIT $1"#,
+            i64::from(limit)
+        )
+        .fetch_all(db)
+        .await?
+        .into_iter()
+        .filter_map(|row| {
+            Self::try_from(row)
+                .map_err(|e| warn!("Invalid message mapping row: {:?}", e))
+                .ok()
+        })
+        .collect())
+    }
+
+    /// Marks the mappings of a discord message as pinned, which exempts them from pruning
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub async fn set_pinned<'e>(
+        db: impl PgExecutor<'e>,
+        message_id: Id<MessageMarker>,
+        pinned: bool,
+    ) -> Result<()> {
+        query!(
+            "UPDATE message_map SET pinned = $2 WHERE discord_message_id = $1",
+            snowflake::to_db(message_id),
+            pinned
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+}
+
+impl App {
+    /// Looks up the discord message a matrix event was bridged to
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    pub async fn discord_message_for_event(
+        self: &Arc<Self>,
+        event_id: &EventId,
+    ) -> Result<Option<(Id<ChannelMarker>, Id<MessageMarker>)>> {
+        Ok(MessageMapping::for_event(&*self.db, event_id)
+            .await?
+            .map(|mapping| (mapping.channel_id, mapping.message_id)))
+    }
+
+    /// Looks up the matrix events a discord message was bridged to
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    pub async fn matrix_events_for_message(
+        self: &Arc<Self>,
+        message_id: Id<MessageMarker>,
+    ) -> Result<Vec<(OwnedRoomId, OwnedEventId)>> {
+        Ok(MessageMapping::for_message(&*self.db, message_id)
+            .await?
+            .into_iter()
+            .map(|mapping| (mapping.room_id, mapping.event_id))
+            .collect())
+    }
+
+    /// Marks the mappings of a discord message as pinned, which exempts them from pruning
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    pub async fn set_message_pinned(
+        self: &Arc<Self>,
+        message_id: Id<MessageMarker>,
+        pinned: bool,
+    ) -> Result<()> {
+        MessageMapping::set_pinned(&*self.db, message_id, pinned).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_message_mapping_rows() {
+        let row = || MessageMappingRow {
+            matrix_event_id: "$event:chir.rs".to_owned(),
+            matrix_room_id: "!room:chir.rs".to_owned(),
+            discord_message_id: 2,
+            discord_channel_id: 1,
+            webhook_id: Some(3),
+            relayed: true,
+        };
+        let mapping = MessageMapping::try_from(row()).expect("valid row");
+        assert_eq!(mapping.message_id, Id::new(2));
+        assert_eq!(mapping.webhook_id, Some(Id::new(3)));
+        assert!(MessageMapping::try_from(MessageMappingRow {
+            discord_message_id: 0,
+            ..row()
+        })
+        .is_err());
+        assert!(MessageMapping::try_from(MessageMappingRow {
+            matrix_room_id: "room".to_owned(),
+            ..row()
+        })
+        .is_err());
+    }
+}
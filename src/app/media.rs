@@ -0,0 +1,384 @@
+//! Media transfer between discord and matrix
+//!
+//! Uploads carry the original file name and content type, so that downloads get a meaningful
+//! `Content-Disposition`. Every upload is recorded with the portal it belongs to and whether it
+//! is transient, like superseded avatars. If `bridge.media_admin_token` is set, transient media
+//! is deleted from the homeserver after a day, and the `purge-media` command deletes all media
+//! the bridge uploaded for a portal, using the Synapse admin API.
+
+use std::{sync::Arc, time::Duration};
+
+use super::App;
+use crate::{
+    retry::{retry, Backoff},
+    snowflake,
+};
+use anyhow::{anyhow, Result};
+use matrix_sdk::{
+    room::Room,
+    ruma::{
+        api::client::media::create_content, events::room::message::RoomMessageEventContent, MxcUri,
+        OwnedMxcUri, RoomOrAliasId, UserId,
+    },
+};
+use mime::Mime;
+use sqlx::query;
+use tracing::{error, info, warn};
+use twilight_model::id::{
+    marker::{ChannelMarker, UserMarker},
+    Id,
+};
+use url::Url;
+
+/// Interval of the transient media cleanup
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Age after which transient media is deleted, in hours
+const TRANSIENT_MEDIA_HOURS: i32 = 24;
+
+/// How long uploaded media is kept
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MediaRetention {
+    /// Kept until the portal's media is purged
+    Permanent,
+    /// Only needed briefly, deleted after a day
+    Transient,
+}
+
+/// Returns the file name of a download URL
+///
+/// URLs without a usable file name get a generic name with an extension matching the mime type.
+fn media_filename(url: &str, mime: &Mime) -> String {
+    let name = url
+        .split(['?', '#'])
+        .next()
+        .and_then(|path| path.rsplit('/').next())
+        .unwrap_or_default();
+    if name.contains('.') && !name.starts_with('.') {
+        name.to_owned()
+    } else {
+        format!("file.{}", mime.subtype())
+    }
+}
+
+/// Extracts the mime type of an HTTP response
+fn response_mime(response: &reqwest::Response) -> Mime {
+    response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM)
+}
+
+impl App {
+    /// Downloads a file from discord
+    ///
+    /// # Errors
+    /// This function will return an error if the download fails
+    pub(super) async fn download_discord_media(&self, url: &str) -> Result<(Mime, Vec<u8>)> {
+        let response = retry("discord", Backoff::default(), || async {
+            self.http.get(url).send().await?.error_for_status()
+        })
+        .await?;
+        let mime = response_mime(&response);
+        Ok((mime, response.bytes().await?.to_vec()))
+    }
+
+    /// Returns the public download URL of an MXC URI
+    ///
+    /// # Errors
+    /// This function will return an error if the MXC URI is invalid
+    pub fn mxc_to_http(&self, mxc: &MxcUri) -> Result<Url> {
+        let (server_name, media_id) = mxc.parts()?;
+        Ok(self.config.homeserver.address.join(&format!(
+            "_matrix/media/r0/download/{}/{}",
+            server_name, media_id
+        ))?)
+    }
+
+    /// Downloads a file from the matrix content repository
+    ///
+    /// # Errors
+    /// This function will return an error if the download fails
+    pub(super) async fn download_matrix_media(&self, mxc: &MxcUri) -> Result<(Mime, Vec<u8>)> {
+        let url = self.mxc_to_http(mxc)?;
+        let response = retry("matrix", Backoff::default(), || async {
+            self.http.get(url.clone()).send().await?.error_for_status()
+        })
+        .await?;
+        let mime = response_mime(&response);
+        Ok((mime, response.bytes().await?.to_vec()))
+    }
+
+    /// Uploads a file to the matrix content repository
+    ///
+    /// `channel_id` is the portal the file is uploaded for, if any. The file is uploaded by the
+    /// puppet of `uploader`, or the bridge bot. In dry-run mode nothing is uploaded and `None` is
+    /// returned.
+    ///
+    /// # Errors
+    /// This function will return an error if the upload or the database query fails
+    #[allow(clippy::panic)]
+    pub(super) async fn upload_matrix_media(
+        self: &Arc<Self>,
+        mime: &Mime,
+        data: Vec<u8>,
+        filename: &str,
+        channel_id: Option<Id<ChannelMarker>>,
+        retention: MediaRetention,
+        uploader: Option<Id<UserMarker>>,
+    ) -> Result<Option<OwnedMxcUri>> {
+        if self.dry_run {
+            info!(
+                "[dry-run] Would upload {} ({} bytes of {})",
+                filename,
+                data.len(),
+                mime
+            );
+            return Ok(None);
+        }
+        let client = self.client(uploader).await?;
+        let user_id = match uploader {
+            Some(uploader) => self.puppet_user_id(uploader)?,
+            None => self.user_id.clone(),
+        };
+        let response = self
+            .pipeline
+            .run(&user_id, "upload", async {
+                let mut request = create_content::v3::Request::new(&data);
+                request.filename = Some(filename);
+                request.content_type = Some(mime.essence_str());
+                Ok(client.send(request, None).await?)
+            })
+            .await?;
+        query!(
+            "INSERT INTO bridged_media (mxc_uri, discord_channel_id, transient) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+            response.content_uri.as_str(),
+            channel_id.map(snowflake::to_db),
+            retention == MediaRetention::Transient
+        )
+        .execute(&*self.db)
+        .await?;
+        Ok(Some(response.content_uri))
+    }
+
+    /// Copies a file from discord to the matrix content repository
+    ///
+    /// # Errors
+    /// This function will return an error if the download or upload fails
+    pub(super) async fn mirror_discord_media(
+        self: &Arc<Self>,
+        url: &str,
+        channel_id: Option<Id<ChannelMarker>>,
+        retention: MediaRetention,
+    ) -> Result<Option<OwnedMxcUri>> {
+        let (mime, data) = self.download_discord_media(url).await?;
+        let filename = media_filename(url, &mime);
+        self.upload_matrix_media(&mime, data, &filename, channel_id, retention, None)
+            .await
+    }
+
+    /// Marks uploaded media as transient, for example an avatar that was replaced
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub async fn supersede_media(self: &Arc<Self>, mxc: &MxcUri) -> Result<()> {
+        query!(
+            "UPDATE bridged_media SET transient = TRUE, created_at = NOW() WHERE mxc_uri = $1",
+            mxc.as_str()
+        )
+        .execute(&*self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Deletes a file the bridge uploaded from the homeserver and forgets it
+    ///
+    /// # Errors
+    /// This function will return an error if no admin token is configured, the MXC URI is
+    /// invalid or the request or database query fails
+    #[allow(clippy::panic)]
+    async fn delete_matrix_media(self: &Arc<Self>, mxc: &MxcUri) -> Result<()> {
+        let token = self
+            .config
+            .bridge
+            .media_admin_token
+            .as_deref()
+            .ok_or_else(|| anyhow!("No media admin token is configured"))?;
+        let (server_name, media_id) = mxc.parts()?;
+        if server_name.as_str() == self.config.homeserver.domain {
+            let url = self.config.homeserver.address.join(&format!(
+                "_synapse/admin/v1/media/{}/{}",
+                server_name, media_id
+            ))?;
+            retry("matrix", Backoff::default(), || async {
+                let response = self
+                    .http
+                    .delete(url.clone())
+                    .bearer_auth(token)
+                    .send()
+                    .await?;
+                // Media that is already gone doesn't need to be deleted
+                if response.status() == reqwest::StatusCode::NOT_FOUND {
+                    return Ok(response);
+                }
+                response.error_for_status()
+            })
+            .await?;
+        }
+        query!("DELETE FROM bridged_media WHERE mxc_uri = $1", mxc.as_str())
+            .execute(&*self.db)
+            .await?;
+        Ok(())
+    }
+
+    /// Deletes a list of uploaded files, returning how many were deleted
+    ///
+    /// Failures are logged so the remaining files are still deleted.
+    async fn delete_media_list(self: &Arc<Self>, mxcs: Vec<String>) -> usize {
+        let mut deleted = 0;
+        for mxc in mxcs {
+            let mxc = OwnedMxcUri::from(mxc);
+            match self.delete_matrix_media(&mxc).await {
+                Ok(()) => deleted += 1,
+                Err(e) => warn!("Failed to delete {}: {:?}", mxc, e),
+            }
+        }
+        deleted
+    }
+
+    /// Deletes transient media older than a day
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub async fn cleanup_transient_media(self: &Arc<Self>) -> Result<()> {
+        if self.config.bridge.media_admin_token.is_none() {
+            return Ok(());
+        }
+        let expired = query!(
+            "SELECT mxc_uri FROM bridged_media WHERE transient AND created_at < NOW() - make_interval(hours => $1)",
+            TRANSIENT_MEDIA_HOURS
+        )
+        .fetch_all(&*self.db)
+        .await?
+        .into_iter()
+        .map(|row| row.mxc_uri)
+        .collect::<Vec<_>>();
+        if expired.is_empty() {
+            return Ok(());
+        }
+        if self.dry_run {
+            info!("[dry-run] Would delete {} transient media", expired.len());
+            return Ok(());
+        }
+        let deleted = self.delete_media_list(expired).await;
+        info!("Deleted {} transient media", deleted);
+        Ok(())
+    }
+
+    /// Runs the transient media cleanup periodically
+    pub(super) fn spawn_media_cleanup(self: &Arc<Self>) {
+        let this = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+            loop {
+                interval.tick().await;
+                let this = match this.upgrade() {
+                    Some(this) => this,
+                    None => break,
+                };
+                if let Err(e) = this.cleanup_transient_media().await {
+                    error!("Transient media cleanup failed: {:?}", e);
+                }
+            }
+        });
+    }
+
+    /// Deletes all media the bridge uploaded for a portal
+    ///
+    /// Returns the number of deleted files.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub async fn purge_portal_media(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+    ) -> Result<usize> {
+        let media = query!(
+            "SELECT mxc_uri FROM bridged_media WHERE discord_channel_id = $1",
+            snowflake::to_db(channel_id)
+        )
+        .fetch_all(&*self.db)
+        .await?
+        .into_iter()
+        .map(|row| row.mxc_uri)
+        .collect::<Vec<_>>();
+        if self.dry_run {
+            info!(
+                "[dry-run] Would delete {} media of {}",
+                media.len(),
+                channel_id
+            );
+            return Ok(0);
+        }
+        Ok(self.delete_media_list(media).await)
+    }
+
+    /// Handles the `purge-media` command
+    ///
+    /// # Errors
+    /// This function will return an error if the purge or sending the reply fails
+    pub(super) async fn handle_purge_media_command(
+        self: &Arc<Self>,
+        sender: &UserId,
+        args: &[&str],
+        room: &Room,
+    ) -> Result<()> {
+        let reply = if sender != self.config.bridge.admin {
+            "Only the bridge admin can purge media".to_owned()
+        } else if self.config.bridge.media_admin_token.is_none() {
+            "Purging media needs bridge.media_admin_token to be configured".to_owned()
+        } else {
+            match args {
+                ["purge-media", target] => match <&RoomOrAliasId>::try_from(*target) {
+                    Ok(target) => match self.portal_by_room_or_alias(target).await? {
+                        Some(portal) => {
+                            let deleted = self.purge_portal_media(portal.channel_id).await?;
+                            format!("Deleted {} media of {}", deleted, target)
+                        }
+                        None => format!("{} is not a portal", target),
+                    },
+                    Err(_) => format!("{} is not a room id or alias", target),
+                },
+                _ => "Usage: purge-media <room>".to_owned(),
+            }
+        };
+        self.send_message(room, RoomMessageEventContent::text_plain(reply))
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_files() {
+        assert_eq!(
+            media_filename(
+                "https://cdn.discordapp.com/attachments/1/2/cat.png?size=64",
+                &mime::IMAGE_PNG
+            ),
+            "cat.png"
+        );
+        assert_eq!(
+            media_filename("https://cdn.discordapp.com/emojis/123", &mime::IMAGE_GIF),
+            "file.gif"
+        );
+    }
+}
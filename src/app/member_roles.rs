@@ -0,0 +1,260 @@
+//! Discord roles of puppets in room state
+//!
+//! If `bridge.member_roles` is enabled, the discord roles of each puppet are published as a
+//! `rs.chir.discord_bridge.roles` state event in the portal rooms of the guild, keyed by the
+//! puppet's user id, so that matrix bots and clients can act on them. The role ids of each member
+//! are stored so that the events can be updated when a role is renamed, recolored or deleted.
+
+use std::sync::Arc;
+
+use super::App;
+use crate::snowflake;
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::query;
+use tracing::info;
+use twilight_model::id::{
+    marker::{GuildMarker, RoleMarker, UserMarker},
+    Id,
+};
+
+/// State event type of member roles, keyed by the user id of the puppet
+const ROLES_EVENT_TYPE: &str = "rs.chir.discord_bridge.roles";
+
+/// A discord role as published in room state
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+struct RoleInfo {
+    /// Id of the role
+    id: String,
+    /// Name of the role
+    name: String,
+    /// Color of the role as RGB, 0 if it has none
+    color: u32,
+    /// Position of the role in the role list, higher is more important
+    position: i64,
+}
+
+/// Returns the roles of a member, most important first
+fn select_roles(guild_roles: &[RoleInfo], member_roles: &[String]) -> Vec<RoleInfo> {
+    let mut roles = guild_roles
+        .iter()
+        .filter(|role| member_roles.contains(&role.id))
+        .cloned()
+        .collect::<Vec<_>>();
+    roles.sort_by(|a, b| b.position.cmp(&a.position).then_with(|| a.id.cmp(&b.id)));
+    roles
+}
+
+impl App {
+    /// Returns the roles of a guild
+    ///
+    /// # Errors
+    /// This function will return an error if the request to discord fails
+    async fn guild_roles(self: &Arc<Self>, guild_id: Id<GuildMarker>) -> Result<Vec<RoleInfo>> {
+        Ok(self
+            .discord()?
+            .http
+            .roles(guild_id)
+            .exec()
+            .await?
+            .models()
+            .await?
+            .into_iter()
+            .map(|role| RoleInfo {
+                id: role.id.to_string(),
+                name: role.name,
+                color: role.color,
+                position: role.position,
+            })
+            .collect())
+    }
+
+    /// Sets the roles state event of a puppet in the portal rooms of a guild it is in
+    ///
+    /// # Errors
+    /// This function will return an error if the portals or room members can't be read, or
+    /// sending a state event fails
+    async fn send_member_roles(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+        content: &Value,
+    ) -> Result<()> {
+        let puppet = self.puppet_user_id(user_id)?;
+        for portal in self.portals_in_guild(guild_id).await? {
+            let room = match self.client.get_joined_room(&portal.room_id) {
+                Some(room) => room,
+                None => continue,
+            };
+            if room.get_member_no_sync(&puppet).await?.is_none() {
+                continue;
+            }
+            if self.dry_run {
+                info!(
+                    "[dry-run] Would set the roles of {} in {} to {}",
+                    puppet, portal.room_id, content
+                );
+                continue;
+            }
+            self.pipeline
+                .run(&self.user_id, "state", async {
+                    room.send_state_event_raw(content.clone(), ROLES_EVENT_TYPE, puppet.as_str())
+                        .await?;
+                    Ok(())
+                })
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Stores and publishes the roles of a guild member
+    ///
+    /// # Errors
+    /// This function will return an error if the database query or sending a state event fails
+    #[allow(clippy::panic)]
+    async fn publish_member_roles(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+        role_ids: &[Id<RoleMarker>],
+        guild_roles: &[RoleInfo],
+    ) -> Result<()> {
+        let member_roles = role_ids.iter().map(ToString::to_string).collect::<Vec<_>>();
+        let roles = select_roles(guild_roles, &member_roles);
+        if !self.dry_run {
+            let ids = role_ids
+                .iter()
+                .map(|role_id| snowflake::to_db(*role_id))
+                .collect::<Vec<_>>();
+            query!(
+                "INSERT INTO member_roles (guild_id, discord_user_id, role_ids) VALUES ($1, $2, $3) ON CONFLICT (guild_id, discord_user_id) DO UPDATE SET role_ids = $3",
+                snowflake::to_db(guild_id),
+                snowflake::to_db(user_id),
+                &ids
+            )
+            .execute(&*self.db)
+            .await?;
+        }
+        self.send_member_roles(guild_id, user_id, &json!({ "roles": roles }))
+            .await
+    }
+
+    /// Handles a guild member joining or changing roles
+    ///
+    /// # Errors
+    /// This function will return an error if publishing the roles fails
+    pub(super) async fn handle_member_roles(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+        role_ids: &[Id<RoleMarker>],
+    ) -> Result<()> {
+        if !self.config.bridge.member_roles || user_id == self.discord()?.user_id {
+            return Ok(());
+        }
+        let guild_roles = self.guild_roles(guild_id).await?;
+        self.publish_member_roles(guild_id, user_id, role_ids, &guild_roles)
+            .await
+    }
+
+    /// Handles a guild member leaving by clearing its roles
+    ///
+    /// # Errors
+    /// This function will return an error if the database query or sending a state event fails
+    #[allow(clippy::panic)]
+    pub(super) async fn handle_member_roles_removed(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+    ) -> Result<()> {
+        if !self.config.bridge.member_roles {
+            return Ok(());
+        }
+        if !self.dry_run {
+            query!(
+                "DELETE FROM member_roles WHERE guild_id = $1 AND discord_user_id = $2",
+                snowflake::to_db(guild_id),
+                snowflake::to_db(user_id)
+            )
+            .execute(&*self.db)
+            .await?;
+        }
+        self.send_member_roles(guild_id, user_id, &json!({})).await
+    }
+
+    /// Handles a role being changed or deleted by republishing the roles of its members
+    ///
+    /// # Errors
+    /// This function will return an error if the database query, the request to discord or
+    /// publishing the roles fails
+    #[allow(clippy::panic)]
+    pub(super) async fn handle_role_change(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+        role_id: Id<RoleMarker>,
+    ) -> Result<()> {
+        if !self.config.bridge.member_roles {
+            return Ok(());
+        }
+        let members = query!(
+            "SELECT discord_user_id, role_ids FROM member_roles WHERE guild_id = $1 AND $2 = ANY(role_ids)",
+            snowflake::to_db(guild_id),
+            snowflake::to_db(role_id)
+        )
+        .fetch_all(&*self.db)
+        .await?;
+        if members.is_empty() {
+            return Ok(());
+        }
+        let guild_roles = self.guild_roles(guild_id).await?;
+        for member in members {
+            // Deleted roles are dropped from the stored role ids
+            let role_ids = member
+                .role_ids
+                .into_iter()
+                .map(snowflake::from_db)
+                .collect::<Result<Vec<Id<RoleMarker>>>>()?
+                .into_iter()
+                .filter(|role_id| {
+                    guild_roles
+                        .iter()
+                        .any(|role| role.id == role_id.to_string())
+                })
+                .collect::<Vec<_>>();
+            self.publish_member_roles(
+                guild_id,
+                snowflake::from_db(member.discord_user_id)?,
+                &role_ids,
+                &guild_roles,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn role(id: &str, position: i64) -> RoleInfo {
+        RoleInfo {
+            id: id.to_owned(),
+            name: format!("role {}", id),
+            color: 0,
+            position,
+        }
+    }
+
+    #[test]
+    fn selects_roles_by_position() {
+        let guild_roles = [role("1", 0), role("2", 3), role("3", 1), role("4", 3)];
+        let selected = select_roles(
+            &guild_roles,
+            &["1".to_owned(), "4".to_owned(), "2".to_owned()],
+        );
+        assert_eq!(selected, vec![role("2", 3), role("4", 3), role("1", 0)]);
+        assert!(select_roles(&guild_roles, &["5".to_owned()]).is_empty());
+    }
+}
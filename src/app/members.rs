@@ -0,0 +1,436 @@
+//! Puppets of guild members in portal rooms
+//!
+//! `bridge.member_sync` selects which members of a guild get a puppet in its portal rooms. In
+//! lazy mode a member's puppet joins a portal once they write in its channel. In full mode the
+//! member list of every guild is paged through when the guild becomes available and every member
+//! that can view a channel is joined to its portal, as are members that join the guild or gain
+//! access later. In both modes puppets leave the portals of a guild when their member leaves it,
+//! and the portals of channels their member can no longer view.
+
+use std::sync::Arc;
+
+use super::{permissions::channel_permissions, portals::Portal, App};
+use crate::config::MemberSync;
+use anyhow::Result;
+use matrix_sdk::{
+    room::{Joined, Room},
+    ruma::{events::StateEventType, UserId},
+};
+use serde_json::Value;
+use tracing::{info, warn};
+use twilight_http::error::ErrorType;
+use twilight_model::{
+    channel::{permission_overwrite::PermissionOverwrite, Channel, Message},
+    guild::Permissions,
+    id::{
+        marker::{GuildMarker, RoleMarker, UserMarker},
+        Id,
+    },
+};
+
+/// Number of members requested per page of a guild's member list
+const MEMBER_PAGE_SIZE: u16 = 1000;
+
+/// Owner and role permissions of a guild
+type GuildContext = (Id<UserMarker>, Vec<(Id<RoleMarker>, Permissions)>);
+
+/// Returns whether a guild member can view a channel
+fn can_view_channel(
+    guild_id: Id<GuildMarker>,
+    user_id: Id<UserMarker>,
+    (owner_id, roles): &GuildContext,
+    member_roles: &[Id<RoleMarker>],
+    overwrites: &[PermissionOverwrite],
+) -> bool {
+    *owner_id == user_id
+        || channel_permissions(guild_id, user_id, roles, member_roles, overwrites)
+            .contains(Permissions::VIEW_CHANNEL)
+}
+
+impl App {
+    /// Returns the membership of a user in a room, if it has one
+    ///
+    /// # Errors
+    /// This function will return an error if the member event can't be read
+    pub(super) async fn room_membership(room: &Joined, user_id: &UserId) -> Result<Option<String>> {
+        Ok(
+            match room
+                .get_state_event(StateEventType::RoomMember, user_id.as_str())
+                .await?
+            {
+                Some(event) => event.deserialize_as::<Value>()?["content"]["membership"]
+                    .as_str()
+                    .map(ToOwned::to_owned),
+                None => None,
+            },
+        )
+    }
+
+    /// Joins the puppet of a discord user into a portal room
+    ///
+    /// The bridge bot invites the puppet first. Returns whether the puppet joined.
+    ///
+    /// # Errors
+    /// This function will return an error if the member event can't be read or inviting or joining
+    /// the puppet fails
+    pub(super) async fn join_puppet(
+        self: &Arc<Self>,
+        portal: &Portal,
+        user_id: Id<UserMarker>,
+    ) -> Result<bool> {
+        let room = match self.client.get_joined_room(&portal.room_id) {
+            Some(room) => room,
+            None => return Ok(false),
+        };
+        let puppet = self.puppet_user_id(user_id)?;
+        let membership = Self::room_membership(&room, &puppet).await?;
+        if membership.as_deref() == Some("join") {
+            return Ok(false);
+        }
+        if self.dry_run {
+            info!("[dry-run] Would join {} to {}", puppet, portal.room_id);
+            return Ok(false);
+        }
+        if membership.as_deref() != Some("invite") {
+            self.pipeline
+                .run(&self.user_id, "membership", async {
+                    room.invite_user_by_id(&puppet).await?;
+                    Ok(())
+                })
+                .await?;
+        }
+        self.pipeline
+            .run(&puppet, "membership", async {
+                self.matrix_room_for_client(Some(user_id), &portal.room_id)
+                    .await?;
+                Ok(())
+            })
+            .await?;
+        Ok(true)
+    }
+
+    /// Makes the puppet of a discord user leave a portal room it joined
+    ///
+    /// # Errors
+    /// This function will return an error if the member event can't be read or leaving fails
+    async fn leave_puppet(
+        self: &Arc<Self>,
+        portal: &Portal,
+        user_id: Id<UserMarker>,
+    ) -> Result<()> {
+        let room = match self.client.get_joined_room(&portal.room_id) {
+            Some(room) => room,
+            None => return Ok(()),
+        };
+        let puppet = self.puppet_user_id(user_id)?;
+        if Self::room_membership(&room, &puppet).await?.as_deref() != Some("join") {
+            return Ok(());
+        }
+        if self.dry_run {
+            info!("[dry-run] Would make {} leave {}", puppet, portal.room_id);
+            return Ok(());
+        }
+        let room = match self
+            .matrix_room_for_client(Some(user_id), &portal.room_id)
+            .await?
+        {
+            Room::Joined(room) => room,
+            _ => return Ok(()),
+        };
+        self.pipeline
+            .run(&puppet, "membership", async {
+                room.leave().await?;
+                Ok(())
+            })
+            .await
+    }
+
+    /// Joins the author of a discord message into the portal the message belongs to
+    ///
+    /// Messages of webhooks and the bridge bot are ignored.
+    ///
+    /// # Errors
+    /// This function will return an error if a database query, joining the puppet or updating its
+    /// profile fails
+    pub(super) async fn handle_member_activity(
+        self: &Arc<Self>,
+        message: &Message,
+        portal: &Portal,
+    ) -> Result<()> {
+        let guild_id = match message.guild_id {
+            Some(guild_id) => guild_id,
+            None => return Ok(()),
+        };
+        if message.webhook_id.is_some() || message.author.id == self.discord()?.user_id {
+            return Ok(());
+        }
+        if self.join_puppet(portal, message.author.id).await? {
+            let nick = message
+                .member
+                .as_ref()
+                .and_then(|member| member.nick.as_deref());
+            self.handle_member_profile(guild_id, &message.author, nick)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Joins every member of a guild into the portals of the channels they can view
+    ///
+    /// Failures to join single puppets are logged so that the remaining members are still joined.
+    ///
+    /// # Errors
+    /// This function will return an error if a database query or a request to discord fails
+    async fn sync_guild_members(self: &Arc<Self>, guild_id: Id<GuildMarker>) -> Result<()> {
+        let portals = self.portals_in_guild(guild_id).await?;
+        if portals.is_empty() {
+            return Ok(());
+        }
+        let context = self.guild_permission_context(guild_id).await?;
+        let http = &self.discord()?.http;
+        let mut channels = Vec::with_capacity(portals.len());
+        for portal in portals {
+            let channel = http
+                .channel(portal.channel_id)
+                .exec()
+                .await?
+                .model()
+                .await?;
+            channels.push((portal, channel.permission_overwrites.unwrap_or_default()));
+        }
+        let bot_id = self.discord()?.user_id;
+        let mut after = None;
+        loop {
+            let mut request = http.guild_members(guild_id).limit(MEMBER_PAGE_SIZE)?;
+            if let Some(after) = after {
+                request = request.after(after);
+            }
+            let members = request.exec().await?.models().await?;
+            for member in &members {
+                if member.user.id == bot_id {
+                    continue;
+                }
+                let mut joined = false;
+                for (portal, overwrites) in &channels {
+                    if !can_view_channel(
+                        guild_id,
+                        member.user.id,
+                        &context,
+                        &member.roles,
+                        overwrites,
+                    ) {
+                        continue;
+                    }
+                    match self.join_puppet(portal, member.user.id).await {
+                        Ok(true) => joined = true,
+                        Ok(false) => {}
+                        Err(e) => warn!(
+                            "Failed to join the puppet of {} to {}: {:?}",
+                            member.user.id, portal.room_id, e
+                        ),
+                    }
+                }
+                if joined {
+                    if let Err(e) = self
+                        .handle_member_profile(guild_id, &member.user, member.nick.as_deref())
+                        .await
+                    {
+                        warn!(
+                            "Failed to update the profile of {}: {:?}",
+                            member.user.id, e
+                        );
+                    }
+                }
+            }
+            if members.len() < usize::from(MEMBER_PAGE_SIZE) {
+                break;
+            }
+            after = members.last().map(|member| member.user.id);
+        }
+        info!("Synced the members of guild {}", guild_id);
+        Ok(())
+    }
+
+    /// Starts joining the members of a guild in the background
+    ///
+    /// Does nothing unless `bridge.member_sync` is `full`.
+    pub(super) fn spawn_guild_member_sync(self: &Arc<Self>, guild_id: Id<GuildMarker>) {
+        if self.config.bridge.member_sync != MemberSync::Full {
+            return;
+        }
+        let this = Arc::clone(self);
+        tokio::spawn(async move {
+            if let Err(e) = this.sync_guild_members(guild_id).await {
+                warn!("Failed to sync the members of guild {}: {:?}", guild_id, e);
+            }
+        });
+    }
+
+    /// Updates the portals a guild member's puppet is in after the member joined or changed roles
+    ///
+    /// Puppets leave the portals of channels the member can no longer view. In full mode they also
+    /// join the portals of channels the member can view.
+    ///
+    /// # Errors
+    /// This function will return an error if a database query or a request to discord fails
+    pub(super) async fn sync_member_portals(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+        member_roles: &[Id<RoleMarker>],
+    ) -> Result<()> {
+        if user_id == self.discord()?.user_id {
+            return Ok(());
+        }
+        let full = self.config.bridge.member_sync == MemberSync::Full;
+        let puppet = self.puppet_user_id(user_id)?;
+        let mut context = None;
+        for portal in self.portals_in_guild(guild_id).await? {
+            let room = match self.client.get_joined_room(&portal.room_id) {
+                Some(room) => room,
+                None => continue,
+            };
+            let joined = Self::room_membership(&room, &puppet).await?.as_deref() == Some("join");
+            if !joined && !full {
+                continue;
+            }
+            let context = match context {
+                Some(ref context) => context,
+                None => context.insert(self.guild_permission_context(guild_id).await?),
+            };
+            let channel = self
+                .discord()?
+                .http
+                .channel(portal.channel_id)
+                .exec()
+                .await?
+                .model()
+                .await?;
+            let overwrites = channel.permission_overwrites.as_deref().unwrap_or_default();
+            let result = match (
+                joined,
+                can_view_channel(guild_id, user_id, context, member_roles, overwrites),
+            ) {
+                (true, false) => self.leave_puppet(&portal, user_id).await,
+                (false, true) => self.join_puppet(&portal, user_id).await.map(drop),
+                _ => Ok(()),
+            };
+            if let Err(e) = result {
+                warn!(
+                    "Failed to update the membership of {} in {}: {:?}",
+                    puppet, portal.room_id, e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes the puppet of a member that left a guild leave the guild's portals
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    pub(super) async fn handle_member_left(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+    ) -> Result<()> {
+        for portal in self.portals_in_guild(guild_id).await? {
+            if let Err(e) = self.leave_puppet(&portal, user_id).await {
+                warn!(
+                    "Failed to remove the puppet of {} from {}: {:?}",
+                    user_id, portal.room_id, e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes puppets leave the portal of a channel whose permissions changed if their member can
+    /// no longer view it or left the guild
+    ///
+    /// # Errors
+    /// This function will return an error if a database query or a request to discord fails
+    pub(super) async fn sync_channel_members(self: &Arc<Self>, channel: &Channel) -> Result<()> {
+        let guild_id = match channel.guild_id {
+            Some(guild_id) => guild_id,
+            None => return Ok(()),
+        };
+        let portal = match self.portal_by_channel(channel.id).await? {
+            Some(portal) => portal,
+            None => return Ok(()),
+        };
+        let room = match self.client.get_joined_room(&portal.room_id) {
+            Some(room) => room,
+            None => return Ok(()),
+        };
+        let members = room.joined_members_no_sync().await?;
+        let user_ids = members
+            .iter()
+            .filter_map(|member| self.puppet_discord_id(member.user_id()))
+            .collect::<Vec<_>>();
+        if user_ids.is_empty() {
+            return Ok(());
+        }
+        let context = self.guild_permission_context(guild_id).await?;
+        let overwrites = channel.permission_overwrites.as_deref().unwrap_or_default();
+        let http = &self.discord()?.http;
+        for user_id in user_ids {
+            let member_roles = match http.guild_member(guild_id, user_id).exec().await {
+                Ok(response) => Some(response.model().await?.roles),
+                Err(e) => match e.kind() {
+                    ErrorType::Response { status, .. } if status.get() == 404 => None,
+                    _ => return Err(e.into()),
+                },
+            };
+            let visible = member_roles.map_or(false, |member_roles| {
+                can_view_channel(guild_id, user_id, &context, &member_roles, overwrites)
+            });
+            if visible {
+                continue;
+            }
+            if let Err(e) = self.leave_puppet(&portal, user_id).await {
+                warn!(
+                    "Failed to remove the puppet of {} from {}: {:?}",
+                    user_id, portal.room_id, e
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use twilight_model::channel::permission_overwrite::PermissionOverwriteType;
+
+    #[test]
+    fn overwrites_hide_channels_from_members() {
+        let guild_id = Id::new(1);
+        let context = (
+            Id::new(2),
+            vec![(guild_id.cast(), Permissions::VIEW_CHANNEL)],
+        );
+        let hidden = [PermissionOverwrite {
+            allow: Permissions::empty(),
+            deny: Permissions::VIEW_CHANNEL,
+            id: guild_id.cast(),
+            kind: PermissionOverwriteType::Role,
+        }];
+        assert!(can_view_channel(guild_id, Id::new(3), &context, &[], &[]));
+        assert!(!can_view_channel(
+            guild_id,
+            Id::new(3),
+            &context,
+            &[],
+            &hidden
+        ));
+        assert!(can_view_channel(
+            guild_id,
+            Id::new(2),
+            &context,
+            &[],
+            &hidden
+        ));
+    }
+}
@@ -0,0 +1,198 @@
+//! Discord notifications for mentions of away matrix users
+//!
+//! Matrix users with a linked discord account can enable `mention-dm`. When they are mentioned
+//! in a portal room while they aren't online on matrix, the bridge bot sends them a direct
+//! message on discord with an excerpt of the message and a matrix.to link to it.
+
+use std::sync::Arc;
+
+use super::{mscs::Msc, App};
+use anyhow::Result;
+use matrix_sdk::ruma::{
+    api::client::presence::get_presence, presence::PresenceState, EventId, OwnedUserId, RoomId,
+    UserId,
+};
+use tracing::{debug, info, warn};
+
+/// Maximum length of the message excerpt, in characters
+const EXCERPT_LENGTH: usize = 200;
+
+/// Returns the users mentioned in a message
+///
+/// Mentions are matrix.to links in the formatted body, and unless `pills_only` is set, plain user
+/// ids in the body.
+fn mentioned_users(body: &str, formatted: Option<&str>, pills_only: bool) -> Vec<OwnedUserId> {
+    let links = formatted
+        .unwrap_or_default()
+        .split("https://matrix.to/#/")
+        .skip(1)
+        .filter_map(|link| {
+            link.split(|c: char| c == '"' || c == '?' || c == '/')
+                .next()
+        });
+    let plain = body
+        .split_whitespace()
+        .filter(|_| !pills_only)
+        .map(|word| word.trim_end_matches(|c: char| matches!(c, ',' | '.' | ':' | '!' | '?')));
+    let mut users = Vec::new();
+    for candidate in links.chain(plain) {
+        let candidate = candidate.replace("%40", "@").replace("%3A", ":");
+        if !candidate.starts_with('@') {
+            continue;
+        }
+        if let Ok(user_id) = UserId::parse(candidate) {
+            if !users.contains(&user_id) {
+                users.push(user_id);
+            }
+        }
+    }
+    users
+}
+
+/// Shortens a message body to at most [`EXCERPT_LENGTH`] characters on a single line
+fn excerpt(body: &str) -> String {
+    let line = body.split_whitespace().collect::<Vec<_>>().join(" ");
+    if line.chars().count() <= EXCERPT_LENGTH {
+        return line;
+    }
+    let mut short = line.chars().take(EXCERPT_LENGTH - 1).collect::<String>();
+    short.push('…');
+    short
+}
+
+/// Formats the direct message about a mention
+fn mention_notification(sender: &str, room: &str, body: &str, link: &str) -> String {
+    format!(
+        "{} mentioned you in {}:\n> {}\n{}",
+        sender,
+        room,
+        excerpt(body),
+        link
+    )
+}
+
+impl App {
+    /// Returns whether a matrix user is away
+    ///
+    /// The presence delivered in appservice transactions is used if there is one. Users whose
+    /// presence can't be retrieved, for example because the homeserver has presence disabled, are
+    /// considered away.
+    async fn is_away(self: &Arc<Self>, user_id: &UserId) -> Result<bool> {
+        if let Some(presence) = self.presence.get(user_id) {
+            return Ok(*presence != PresenceState::Online);
+        }
+        let response = self
+            .client(None)
+            .await?
+            .send(get_presence::v3::Request::new(user_id), None)
+            .await;
+        match response {
+            Ok(response) => Ok(response.presence != PresenceState::Online),
+            Err(e) => {
+                debug!("Failed to fetch presence of {}: {:?}", user_id, e);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Notifies a mentioned user on discord if they enabled it and are away
+    ///
+    /// # Errors
+    /// This function will return an error if a database query or a request fails
+    async fn notify_mention(self: &Arc<Self>, user_id: &UserId, notification: &str) -> Result<()> {
+        let discord_user = match self.linked_discord_user(user_id).await? {
+            Some(discord_user) => discord_user,
+            None => return Ok(()),
+        };
+        if !self.user_settings(user_id).await?.mention_dm || !self.is_away(user_id).await? {
+            return Ok(());
+        }
+        if self.dry_run {
+            info!(
+                "[dry-run] Would notify {} on discord about a mention",
+                user_id
+            );
+            return Ok(());
+        }
+        let http = &self.discord()?.http;
+        let channel = http
+            .create_private_channel(discord_user)
+            .exec()
+            .await?
+            .model()
+            .await?;
+        http.create_message(channel.id)
+            .content(notification)?
+            .exec()
+            .await?;
+        Ok(())
+    }
+
+    /// Handles a message in a portal room that may mention away users
+    ///
+    /// # Errors
+    /// This function will return an error if the portal can't be looked up
+    pub(super) async fn handle_mentions(
+        self: &Arc<Self>,
+        room_id: &RoomId,
+        event_id: &EventId,
+        sender: &UserId,
+        body: &str,
+        formatted: Option<&str>,
+    ) -> Result<()> {
+        // Messages relayed from discord already notify the discord user there
+        if sender == self.user_id || self.puppet_discord_id(sender).is_some() {
+            return Ok(());
+        }
+        // With intentional mentions, user ids in the text are no longer mentions
+        let pills_only = self.msc(Msc::IntentionalMentions);
+        let mentioned = mentioned_users(body, formatted, pills_only);
+        if mentioned.is_empty() || self.portal_by_room(room_id).await?.is_none() {
+            return Ok(());
+        }
+        let room = self
+            .client
+            .get_joined_room(room_id)
+            .and_then(|room| room.name())
+            .unwrap_or_else(|| room_id.to_string());
+        let link = format!("https://matrix.to/#/{}/{}", room_id, event_id);
+        let notification = mention_notification(sender.as_str(), &room, body, &link);
+        for user_id in mentioned.iter().filter(|user_id| *user_id != sender) {
+            if let Err(e) = self.notify_mention(user_id, &notification).await {
+                warn!("Failed to notify {} about a mention: {:?}", user_id, e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_mentions() {
+        let formatted = r#"<a href="https://matrix.to/#/@alice:chir.rs">Alice</a> and <a href="https://matrix.to/#/%40bob%3Achir.rs">Bob</a>"#;
+        assert_eq!(
+            mentioned_users(
+                "Alice and Bob, ping @carol:chir.rs.",
+                Some(formatted),
+                false
+            ),
+            ["@alice:chir.rs", "@bob:chir.rs", "@carol:chir.rs"]
+                .iter()
+                .map(|user| UserId::parse(*user).expect("valid user id"))
+                .collect::<Vec<_>>()
+        );
+        assert!(mentioned_users("@everyone hi", None, false).is_empty());
+        assert!(mentioned_users("ping @carol:chir.rs", None, true).is_empty());
+    }
+
+    #[test]
+    fn shortens_excerpts() {
+        assert_eq!(excerpt("hello\n  world"), "hello world");
+        let long = "a".repeat(300);
+        assert_eq!(excerpt(&long).chars().count(), EXCERPT_LENGTH);
+        assert!(excerpt(&long).ends_with('…'));
+    }
+}
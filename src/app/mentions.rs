@@ -0,0 +1,248 @@
+//! Mentions between discord and matrix
+//!
+//! Discord user mentions become pills of the user's linked matrix account, or of their puppet,
+//! labelled with the user's display name in the portal. Channel mentions of bridged channels
+//! become links to the portal room, and role mentions become text in the color of the role.
+//! Custom emojis are mirrored to the content repository and shown as inline images. Mentions that
+//! can't be resolved are left as they are.
+//!
+//! Pills of puppets and of linked matrix users, and links to portal rooms, are turned back into
+//! discord mentions when a message is relayed to discord.
+
+use std::{collections::HashMap, sync::Arc};
+
+use super::App;
+use crate::{
+    formatter::{self, Mention, Pill, Pills},
+    html,
+};
+use anyhow::Result;
+use matrix_sdk::ruma::{MxcUri, RoomId, RoomOrAliasId, UserId};
+use tracing::debug;
+use twilight_model::id::{
+    marker::{ChannelMarker, UserMarker},
+    Id,
+};
+
+/// Returns a pill linking to a matrix user or room
+fn link_pill(target: &str, label: &str) -> Pill {
+    Pill {
+        text: label.to_owned(),
+        html: format!(
+            "<a href=\"https://matrix.to/#/{}\">{}</a>",
+            html::escape(target),
+            html::escape(label)
+        ),
+    }
+}
+
+/// Returns the pill of a role mention
+///
+/// Roles without a color are shown in bold.
+fn role_pill(name: &str, color: u32) -> Pill {
+    let text = format!("@{}", name);
+    let html = if color == 0 {
+        format!("<strong>{}</strong>", html::escape(&text))
+    } else {
+        format!(
+            "<font data-mx-color=\"#{:06x}\">{}</font>",
+            color,
+            html::escape(&text)
+        )
+    };
+    Pill { text, html }
+}
+
+/// Returns the pill of a custom emoji, shown as an inline image
+fn emoji_pill(name: &str, mxc: &MxcUri) -> Pill {
+    let text = format!(":{}:", name);
+    let html = format!(
+        "<img src=\"{}\" alt=\"{}\" title=\"{}\" height=\"32\">",
+        html::escape(mxc.as_str()),
+        html::escape(&text),
+        html::escape(&text)
+    );
+    Pill { text, html }
+}
+
+impl App {
+    /// Returns the pill of a discord user in a portal
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails or the puppet's user id is
+    /// invalid
+    async fn user_pill(
+        self: &Arc<Self>,
+        room_id: &RoomId,
+        user_id: Id<UserMarker>,
+    ) -> Result<Pill> {
+        let matrix_user = match self.linked_matrix_user(user_id).await? {
+            Some(matrix_user) => matrix_user,
+            None => self.puppet_user_id(user_id)?,
+        };
+        let member = match self.client.get_joined_room(room_id) {
+            Some(room) => room.get_member(&matrix_user).await?,
+            None => None,
+        };
+        let label = member
+            .as_ref()
+            .and_then(|member| member.display_name())
+            .map_or_else(|| matrix_user.to_string(), ToOwned::to_owned);
+        Ok(link_pill(matrix_user.as_str(), &label))
+    }
+
+    /// Returns the pill of a discord channel, if it is bridged
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    async fn channel_pill(self: &Arc<Self>, channel_id: Id<ChannelMarker>) -> Result<Option<Pill>> {
+        let portal = match self.portal_by_channel(channel_id).await? {
+            Some(portal) => portal,
+            None => return Ok(None),
+        };
+        let name = self
+            .client
+            .get_joined_room(&portal.room_id)
+            .and_then(|room| room.name())
+            .unwrap_or_else(|| channel_id.to_string());
+        let target = match portal.alias {
+            Some(ref alias) => alias.as_str(),
+            None => portal.room_id.as_str(),
+        };
+        Ok(Some(link_pill(target, &format!("#{}", name))))
+    }
+
+    /// Resolves the mentions in a discord message sent to a portal
+    ///
+    /// # Errors
+    /// This function will return an error if a database query or a request to discord fails
+    pub(super) async fn discord_pills(
+        self: &Arc<Self>,
+        markdown: &str,
+        room_id: &RoomId,
+    ) -> Result<Pills> {
+        let mentions = formatter::mentions(markdown);
+        let mut pills = Pills::new();
+        let mut roles = None;
+        for mention in mentions {
+            let pill = match mention {
+                Mention::User(user_id) => Some(self.user_pill(room_id, user_id).await?),
+                Mention::Channel(channel_id) => self.channel_pill(channel_id).await?,
+                Mention::Role(role_id) => {
+                    if roles.is_none() {
+                        roles = Some(match self.portal_by_room(room_id).await? {
+                            Some(portal) => match portal.guild_id {
+                                Some(guild_id) => {
+                                    self.discord()?
+                                        .http
+                                        .roles(guild_id)
+                                        .exec()
+                                        .await?
+                                        .models()
+                                        .await?
+                                }
+                                None => Vec::new(),
+                            },
+                            None => Vec::new(),
+                        });
+                    }
+                    roles
+                        .iter()
+                        .flatten()
+                        .find(|role| role.id == role_id)
+                        .map(|role| role_pill(&role.name, role.color))
+                }
+                Mention::Emoji {
+                    id,
+                    ref name,
+                    animated,
+                } => self
+                    .emoji_mxc(id, name, animated)
+                    .await?
+                    .map(|mxc| emoji_pill(name, &mxc)),
+            };
+            match pill {
+                Some(pill) => {
+                    pills.insert(mention, pill);
+                }
+                None => debug!("Leaving unknown mention {} as it is", mention),
+            }
+        }
+        Ok(pills)
+    }
+
+    /// Returns the discord user a matrix user is mentioned as
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    async fn discord_user_mention(
+        self: &Arc<Self>,
+        user: &UserId,
+    ) -> Result<Option<Id<UserMarker>>> {
+        match self.puppet_discord_id(user) {
+            Some(user_id) => Ok(Some(user_id)),
+            None => self.linked_discord_user(user).await,
+        }
+    }
+
+    /// Resolves the matrix.to links in a formatted body that stand for discord mentions
+    ///
+    /// # Errors
+    /// This function will return an error if a database query fails
+    pub(super) async fn discord_mentions(
+        self: &Arc<Self>,
+        html: &str,
+    ) -> Result<HashMap<String, Mention>> {
+        let mut mentions = HashMap::new();
+        for target in formatter::matrix_links(html) {
+            let mention = if let Ok(user) = <&UserId>::try_from(target.as_str()) {
+                self.discord_user_mention(user).await?.map(Mention::User)
+            } else if let Ok(room) = <&RoomOrAliasId>::try_from(target.as_str()) {
+                self.portal_by_room_or_alias(room)
+                    .await?
+                    .map(|portal| Mention::Channel(portal.channel_id))
+            } else {
+                None
+            };
+            if let Some(mention) = mention {
+                mentions.insert(target, mention);
+            }
+        }
+        Ok(mentions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pills_are_escaped_links() {
+        assert_eq!(
+            link_pill("@alice:chir.rs", "<Alice>"),
+            Pill {
+                text: "<Alice>".to_owned(),
+                html: "<a href=\"https://matrix.to/#/@alice:chir.rs\">&lt;Alice&gt;</a>".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn roles_keep_their_color() {
+        assert_eq!(
+            role_pill("mods", 0x1abc9c).html,
+            "<font data-mx-color=\"#1abc9c\">@mods</font>"
+        );
+        assert_eq!(role_pill("everyone", 0).html, "<strong>@everyone</strong>");
+    }
+
+    #[test]
+    fn emojis_are_inline_images() {
+        let pill = emoji_pill("blob<3", <&MxcUri>::from("mxc://chir.rs/blob"));
+        assert_eq!(pill.text, ":blob<3:");
+        assert_eq!(
+            pill.html,
+            "<img src=\"mxc://chir.rs/blob\" alt=\":blob&lt;3:\" title=\":blob&lt;3:\" height=\"32\">"
+        );
+    }
+}
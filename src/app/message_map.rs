@@ -0,0 +1,137 @@
+//! Maintenance of the mapping between matrix events and discord messages
+//!
+//! The mapping table is partitioned by month. A maintenance task creates upcoming partitions
+//! and prunes mappings that are older than the configured retention, except for pinned
+//! messages.
+
+use std::{sync::Arc, time::Duration};
+
+use super::App;
+use anyhow::Result;
+use sqlx::query;
+use tracing::{error, info};
+
+/// Number of monthly partitions created ahead of time
+const PARTITIONS_AHEAD: i32 = 2;
+
+/// Interval between maintenance runs
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+impl App {
+    /// Creates upcoming partitions and prunes expired mappings
+    ///
+    /// # Errors
+    /// This function will return an error if a database query fails
+    #[allow(clippy::panic)]
+    pub async fn maintain_message_map(self: &Arc<Self>) -> Result<()> {
+        let months = self
+            .config
+            .bridge
+            .message_retention_months
+            .map(i32::try_from)
+            .transpose()?;
+        if self.dry_run {
+            info!("[dry-run] Would create upcoming message map partitions");
+            if let Some(months) = months {
+                info!(
+                    "[dry-run] Would prune message mappings older than {} months",
+                    months
+                );
+            }
+            return Ok(());
+        }
+
+        let created = query!(
+            "SELECT message_map_ensure_partitions($1) AS created",
+            PARTITIONS_AHEAD
+        )
+        .fetch_one(&*self.db)
+        .await?
+        .created
+        .unwrap_or_default();
+        if created > 0 {
+            info!("Created {} message map partitions", created);
+        }
+
+        let months = match months {
+            Some(months) => months,
+            None => return Ok(()),
+        };
+        let deleted = query!(
+            "SELECT message_map_prune(NOW() - make_interval(months => $1)) AS deleted",
+            months
+        )
+        .fetch_one(&*self.db)
+        .await?
+        .deleted
+        .unwrap_or_default();
+        info!("Pruned {} expired message mappings", deleted);
+        Ok(())
+    }
+
+    /// Runs the message map maintenance periodically
+    pub(super) fn spawn_message_map_maintenance(self: &Arc<Self>) {
+        let this = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+            loop {
+                interval.tick().await;
+                let this = match this.upgrade() {
+                    Some(this) => this,
+                    None => break,
+                };
+                if let Err(e) = this.maintain_message_map().await {
+                    error!("Message map maintenance failed: {:?}", e);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{query_scalar, PgPool};
+
+    #[tokio::test]
+    #[ignore = "needs a postgres database in DATABASE_URL"]
+    async fn partition_functions_match_the_maintenance_queries() {
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL is set");
+        let db = PgPool::connect(&url).await.expect("database is reachable");
+        sqlx::migrate!()
+            .set_ignore_missing(true)
+            .run(&db)
+            .await
+            .expect("migrations apply");
+        let functions = query_scalar::<_, String>(
+            "SELECT p.oid::regprocedure::text || ' -> ' || pg_get_function_result(p.oid) FROM pg_proc p WHERE p.proname LIKE 'message\\_map\\_%' ORDER BY 1",
+        )
+        .fetch_all(&db)
+        .await
+        .expect("functions are listed");
+        assert_eq!(
+            functions,
+            [
+                "message_map_ensure_partitions(integer) -> integer",
+                "message_map_prune(timestamp with time zone) -> bigint",
+            ]
+        );
+        query_scalar::<_, i32>("SELECT message_map_ensure_partitions($1)")
+            .bind(super::PARTITIONS_AHEAD)
+            .fetch_one(&db)
+            .await
+            .expect("partitions are created");
+        let created = query_scalar::<_, i32>("SELECT message_map_ensure_partitions($1)")
+            .bind(super::PARTITIONS_AHEAD)
+            .fetch_one(&db)
+            .await
+            .expect("partitions are created");
+        assert_eq!(created, 0);
+        let deleted = query_scalar::<_, i64>(
+            "SELECT message_map_prune(TIMESTAMPTZ '2000-01-01 00:00:00+00')",
+        )
+        .fetch_one(&db)
+        .await
+        .expect("mappings are pruned");
+        assert_eq!(deleted, 0);
+    }
+}
@@ -1 +1,289 @@
+//! Message sending logic
 
+use std::sync::Arc;
+
+use super::{portals::Portal, App};
+use crate::{fallback, formatter, html};
+use anyhow::Result;
+use matrix_sdk::{
+    room::{Joined, Room},
+    ruma::{
+        events::room::message::{
+            FormattedBody, MessageFormat, MessageType, Relation, RoomMessageEventContent,
+        },
+        MxcUri, OwnedEventId, TransactionId,
+    },
+};
+use tracing::{debug, info, warn};
+use twilight_model::channel::{message::MessageReference, Message};
+
+/// Sanitizes a message body and its formatted body in place
+///
+/// The body of HTML messages is replaced by the plain-text fallback of the sanitized HTML.
+fn scrub_body(
+    body: &mut String,
+    formatted: Option<&mut FormattedBody>,
+    strip_tracking: bool,
+    media_url: impl Fn(&str) -> String,
+) {
+    if let Some(formatted) = formatted {
+        formatted.body = html::sanitize(&formatted.body, strip_tracking);
+        if formatted.format == MessageFormat::Html {
+            *body = fallback::plain_text(&formatted.body, media_url);
+        }
+    }
+    if strip_tracking {
+        *body = html::strip_tracking_in_text(body);
+    }
+}
+
+/// Numbers the events a discord message is bridged as
+///
+/// Only the first event carries the reply of the message.
+fn message_parts(
+    message: &Message,
+    contents: Vec<RoomMessageEventContent>,
+) -> impl Iterator<Item = (u32, RoomMessageEventContent, Option<&MessageReference>)> {
+    (0..).zip(contents).map(move |(part, content)| {
+        (
+            part,
+            content,
+            message.reference.as_ref().filter(|_| part == 0),
+        )
+    })
+}
+
+impl App {
+    /// Returns whether the bridge is running in dry-run mode
+    #[must_use]
+    pub const fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Returns the download URL of an image source in a formatted body
+    fn media_url(&self, src: &str) -> String {
+        self.mxc_to_http(<&MxcUri>::from(src))
+            .map_or_else(|_| src.to_owned(), |url| url.to_string())
+    }
+
+    /// Returns the discord markdown of a matrix message
+    ///
+    /// Messages with an HTML body are converted from it, with pills turned into discord mentions.
+    /// Others are sent as their plain body, without the quote of the replied message in replies.
+    ///
+    /// # Errors
+    /// This function will return an error if resolving the mentions fails
+    pub(super) async fn discord_markdown(
+        self: &Arc<Self>,
+        content: &RoomMessageEventContent,
+    ) -> Result<String> {
+        let formatted = match content.msgtype {
+            MessageType::Text(ref text) => text.formatted.as_ref(),
+            MessageType::Notice(ref notice) => notice.formatted.as_ref(),
+            MessageType::Emote(ref emote) => emote.formatted.as_ref(),
+            _ => None,
+        };
+        match formatted {
+            Some(formatted) if formatted.format == MessageFormat::Html => {
+                let mentions = self.discord_mentions(&formatted.body).await?;
+                Ok(formatter::html_to_discord(
+                    &formatted.body,
+                    |src| self.media_url(src),
+                    &mentions,
+                ))
+            }
+            _ if matches!(content.relates_to, Some(Relation::Reply { .. })) => {
+                Ok(fallback::strip_reply_fallback(content.body()).to_owned())
+            }
+            _ => Ok(content.body().to_owned()),
+        }
+    }
+
+    /// Scrubs the content of a message before it is sent
+    ///
+    /// Formatted bodies are sanitized and the plain body is generated from them. If configured,
+    /// tracking parameters are removed from links.
+    pub(super) fn scrub_content(&self, content: &mut RoomMessageEventContent) {
+        let strip_tracking = self.config.bridge.strip_tracking_params;
+        let media_url = |src: &str| self.media_url(src);
+        match content.msgtype {
+            MessageType::Text(ref mut text) => {
+                scrub_body(
+                    &mut text.body,
+                    text.formatted.as_mut(),
+                    strip_tracking,
+                    media_url,
+                );
+            }
+            MessageType::Notice(ref mut notice) => {
+                scrub_body(
+                    &mut notice.body,
+                    notice.formatted.as_mut(),
+                    strip_tracking,
+                    media_url,
+                );
+            }
+            MessageType::Emote(ref mut emote) => {
+                scrub_body(
+                    &mut emote.body,
+                    emote.formatted.as_mut(),
+                    strip_tracking,
+                    media_url,
+                );
+            }
+            _ => {}
+        }
+    }
+
+    /// Sends a message to a matrix room
+    ///
+    /// The content is scrubbed first. In dry-run mode the message is only logged and `None` is
+    /// returned.
+    ///
+    /// # Errors
+    /// This function will return an error if sending the message fails
+    pub async fn send_message(
+        self: &Arc<Self>,
+        room: &Room,
+        mut content: RoomMessageEventContent,
+    ) -> Result<Option<OwnedEventId>> {
+        self.scrub_content(&mut content);
+        if self.dry_run {
+            info!(
+                "[dry-run] Would send message to {}: {:?}",
+                room.room_id(),
+                content
+            );
+            return Ok(None);
+        }
+        if let Room::Joined(room) = room {
+            Ok(Some(self.send_to_joined(room, content, None).await?))
+        } else {
+            warn!("Not sending message to {}: not joined", room.room_id());
+            Ok(None)
+        }
+    }
+
+    /// Sends already scrubbed content to a joined room as the user the room belongs to
+    ///
+    /// Sending again with the same transaction id returns the event id of the first send
+    /// instead of creating a duplicate.
+    ///
+    /// # Errors
+    /// This function will return an error if sending the message fails
+    pub(super) async fn send_to_joined(
+        self: &Arc<Self>,
+        room: &Joined,
+        content: RoomMessageEventContent,
+        txn_id: Option<&TransactionId>,
+    ) -> Result<OwnedEventId> {
+        let response = self
+            .pipeline
+            .run(room.own_user_id(), "send", async {
+                Ok(room.send(content, txn_id).await?)
+            })
+            .await?;
+        self.echoes.record_event(response.event_id.clone());
+        Ok(response.event_id)
+    }
+
+    /// Bridges a discord message to its portal
+    ///
+    /// The text is sent first, followed by one event per attachment and sticker, as the puppet of
+    /// the author or, for messages of other bots' webhooks, the webhook puppet. A reply is sent as
+    /// a rich reply by its first event.
+    ///
+    /// # Errors
+    /// This function will return an error if the puppet can't join the portal, or resolving
+    /// mentions, transferring an attachment or sticker or sending fails
+    pub(super) async fn bridge_discord_message(
+        self: &Arc<Self>,
+        message: &Message,
+        portal: &Portal,
+    ) -> Result<()> {
+        let room = match self.webhook_puppet(message, portal).await? {
+            Some(puppet) => Some(puppet.join_room_by_id(&portal.room_id).await?),
+            None if self.dry_run => self.client.get_room(&portal.room_id),
+            None => Some(
+                self.matrix_room_for_client(Some(message.author.id), &portal.room_id)
+                    .await?,
+            ),
+        };
+        let room = match room {
+            Some(room) => room,
+            None => {
+                debug!("Not in portal room {}", portal.room_id);
+                return Ok(());
+            }
+        };
+        let mut contents = Vec::new();
+        if !message.content.is_empty() {
+            let pills = self
+                .discord_pills(&message.content, &portal.room_id)
+                .await?;
+            contents.push(formatter::message_content(&message.content, &pills));
+        }
+        contents.extend(self.attachment_contents(message).await?);
+        contents.extend(self.sticker_contents(message, portal).await?);
+        for (part, content, reference) in message_parts(message, contents) {
+            self.send_mapped_message(
+                &room,
+                content,
+                message.channel_id,
+                message.id,
+                part,
+                reference,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{app::event_queue::parse_discord_payload, testkit};
+    use twilight_gateway::Event;
+    use twilight_model::id::Id;
+
+    #[test]
+    fn gateway_messages_reply_with_their_first_part() {
+        let payload = testkit::discord_message_payload(
+            1,
+            2,
+            3,
+            &testkit::discord_user(4, "lotte"),
+            "look",
+            Some(5),
+        );
+        let message = match parse_discord_payload(&payload)
+            .expect("payload is well-formed")
+            .expect("payload is dispatched")
+        {
+            Event::MessageCreate(create) => Some(create.0),
+            _ => None,
+        }
+        .expect("payload is a new message");
+        let contents = vec![
+            RoomMessageEventContent::text_plain(message.content.clone()),
+            RoomMessageEventContent::text_plain("cat.png"),
+        ];
+        let parts = message_parts(&message, contents)
+            .map(|(part, content, reference)| {
+                (
+                    part,
+                    content.body().to_owned(),
+                    reference.and_then(|reference| reference.message_id),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            parts,
+            [
+                (0, "look".to_owned(), Some(Id::new(5))),
+                (1, "cat.png".to_owned(), None)
+            ]
+        );
+    }
+}
@@ -0,0 +1,162 @@
+//! Negotiation of unstable homeserver features
+//!
+//! Some code paths depend on MSCs the homeserver may or may not implement. They are enabled if
+//! the MSC is declared in `homeserver.mscs`, or if the homeserver advertises it in the
+//! `unstable_features` of its `/versions` response. The active code paths are logged on startup.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use super::App;
+use anyhow::Result;
+use serde::Deserialize;
+use tracing::{info, warn};
+
+/// MSC the bridge has code paths for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Msc {
+    /// MSC2409: EDUs in appservice transactions
+    EduDelivery,
+    /// MSC3440: Threads
+    Threads,
+    /// MSC3952: Intentional mentions
+    IntentionalMentions,
+}
+
+impl Msc {
+    /// All MSCs with code paths in the bridge
+    pub const ALL: [Self; 3] = [Self::EduDelivery, Self::Threads, Self::IntentionalMentions];
+
+    /// Returns the number of the MSC
+    #[must_use]
+    pub const fn number(self) -> u16 {
+        match self {
+            Self::EduDelivery => 2409,
+            Self::Threads => 3440,
+            Self::IntentionalMentions => 3952,
+        }
+    }
+
+    /// Returns the code path the MSC enables
+    #[must_use]
+    pub const fn code_path(self) -> &'static str {
+        match self {
+            Self::EduDelivery => "typing, receipts and presence from appservice transactions",
+            Self::Threads => "discord threads as matrix threads",
+            Self::IntentionalMentions => "mentions only from explicit pills",
+        }
+    }
+}
+
+/// Part of the `/versions` response of the homeserver
+#[derive(Debug, Deserialize)]
+struct Versions {
+    /// Unstable features and whether they are enabled
+    #[serde(default)]
+    unstable_features: BTreeMap<String, bool>,
+}
+
+/// Returns the MSC numbers of the enabled unstable features
+///
+/// Features are named like `org.matrix.msc3440` or `org.matrix.msc3440.stable`.
+fn advertised(unstable_features: &BTreeMap<String, bool>) -> Vec<u16> {
+    unstable_features
+        .iter()
+        .filter(|(_, enabled)| **enabled)
+        .filter_map(|(name, _)| {
+            let number = name.split('.').find_map(|part| part.strip_prefix("msc"))?;
+            number.parse().ok()
+        })
+        .collect()
+}
+
+/// Returns the MSCs that are declared or advertised
+fn active(declared: &[u16], advertised: &[u16]) -> Vec<Msc> {
+    Msc::ALL
+        .into_iter()
+        .filter(|msc| declared.contains(&msc.number()) || advertised.contains(&msc.number()))
+        .collect()
+}
+
+impl App {
+    /// Returns whether the code paths of an MSC are active
+    ///
+    /// Before discovery only the declared MSCs are active.
+    #[must_use]
+    pub fn msc(&self, msc: Msc) -> bool {
+        self.mscs.get().map_or_else(
+            || self.config.homeserver.mscs.contains(&msc.number()),
+            |mscs| mscs.contains(&msc),
+        )
+    }
+
+    /// Fetches the unstable features the homeserver advertises
+    ///
+    /// # Errors
+    /// This function will return an error if the request fails
+    async fn advertised_mscs(self: &Arc<Self>) -> Result<Vec<u16>> {
+        let url = self
+            .config
+            .homeserver
+            .address
+            .join("_matrix/client/versions")?;
+        let response = self.http.get(url).send().await?.error_for_status()?;
+        let versions: Versions = serde_json::from_slice(&response.bytes().await?)?;
+        Ok(advertised(&versions.unstable_features))
+    }
+
+    /// Determines and logs the active MSC code paths
+    ///
+    /// If the homeserver can't be asked, only the declared MSCs are used.
+    pub(super) async fn negotiate_mscs(self: &Arc<Self>) {
+        let advertised = self.advertised_mscs().await.unwrap_or_else(|e| {
+            warn!(
+                "Failed to fetch the unstable features of the homeserver: {:?}",
+                e
+            );
+            Vec::new()
+        });
+        let mscs = active(&self.config.homeserver.mscs, &advertised);
+        for msc in Msc::ALL {
+            let source = if self.config.homeserver.mscs.contains(&msc.number()) {
+                "declared"
+            } else if advertised.contains(&msc.number()) {
+                "advertised"
+            } else {
+                info!(
+                    "MSC{} unavailable: {} disabled",
+                    msc.number(),
+                    msc.code_path()
+                );
+                continue;
+            };
+            info!(
+                "MSC{} {}: {} enabled",
+                msc.number(),
+                source,
+                msc.code_path()
+            );
+        }
+        // Only called once on startup
+        let _ = self.mscs.set(mscs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn combines_declared_and_advertised() {
+        let features = BTreeMap::from([
+            ("org.matrix.msc3440.stable".to_owned(), true),
+            ("org.matrix.msc2716".to_owned(), false),
+            ("org.matrix.e2e_cross_signing".to_owned(), true),
+        ]);
+        let advertised = advertised(&features);
+        assert_eq!(advertised, [3440]);
+        assert_eq!(
+            active(&[2409, 2677], &advertised),
+            [Msc::EduDelivery, Msc::Threads]
+        );
+    }
+}
@@ -0,0 +1,210 @@
+//! Collision-free names for portals and puppets
+//!
+//! Channels of different guilds can share a name, as can users. Names are reserved in the
+//! database when they are first handed out, colliding names get the guild appended, and the
+//! decision is kept so that it is stable across restarts.
+
+use std::sync::Arc;
+
+use super::App;
+use anyhow::{anyhow, Result};
+use sqlx::query;
+use tracing::info;
+
+/// Maximum number of numbered candidates tried before giving up
+const MAX_CANDIDATES: usize = 100;
+
+/// Kind of a reserved name
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NameKind {
+    /// Localpart of a portal room alias
+    Alias,
+    /// Display name of a puppet
+    Displayname,
+}
+
+impl NameKind {
+    /// Database representation
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Alias => "alias",
+            Self::Displayname => "displayname",
+        }
+    }
+}
+
+/// Turns a name into a lowercase ASCII slug usable in aliases
+#[must_use]
+pub fn slugify(name: &str) -> String {
+    let mut slug = String::with_capacity(name.len());
+    for c in name.chars().flat_map(char::to_lowercase) {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    while slug.ends_with('-') {
+        slug.pop();
+    }
+    slug
+}
+
+/// Returns the candidate names in the order they are tried
+///
+/// The base name comes first, followed by the base name with the disambiguator and finally
+/// numbered variants of it.
+fn candidates<'a>(
+    kind: NameKind,
+    base: &'a str,
+    disambiguator: &'a str,
+) -> impl Iterator<Item = String> + 'a {
+    let disambiguated = match kind {
+        NameKind::Alias => format!("{}-{}", base, slugify(disambiguator)),
+        NameKind::Displayname => format!("{} ({})", base, disambiguator),
+    };
+    let separator = match kind {
+        NameKind::Alias => "-",
+        NameKind::Displayname => " ",
+    };
+    std::iter::once(base.to_owned())
+        .chain(std::iter::once(disambiguated.clone()))
+        .chain((2..).map(move |i| format!("{}{}{}", disambiguated, separator, i)))
+        .take(MAX_CANDIDATES)
+}
+
+/// Returns whether a name is one of the candidates for `base` and `disambiguator`
+///
+/// Names reserved for another base, like before a rename, aren't.
+#[must_use]
+pub fn is_candidate(kind: NameKind, name: &str, base: &str, disambiguator: &str) -> bool {
+    candidates(kind, base, disambiguator).any(|candidate| candidate == name)
+}
+
+impl App {
+    /// Reserves a collision-free name for an owner
+    ///
+    /// If the owner already holds a name of this kind, it is returned unchanged. Otherwise the
+    /// first free candidate based on `base` and `disambiguator` (usually the guild name) is
+    /// reserved.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails or no free name was found
+    #[allow(clippy::panic)]
+    pub async fn reserve_name(
+        self: &Arc<Self>,
+        kind: NameKind,
+        owner: &str,
+        base: &str,
+        disambiguator: &str,
+    ) -> Result<String> {
+        let existing = query!(
+            "SELECT name FROM reserved_names WHERE kind = $1 AND owner = $2",
+            kind.as_str(),
+            owner
+        )
+        .fetch_optional(&*self.db)
+        .await?;
+        if let Some(existing) = existing {
+            return Ok(existing.name);
+        }
+
+        for candidate in candidates(kind, base, disambiguator) {
+            let inserted = query!(
+                "INSERT INTO reserved_names (kind, name, owner) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+                kind.as_str(),
+                candidate,
+                owner
+            )
+            .execute(&*self.db)
+            .await?
+            .rows_affected();
+            if inserted > 0 {
+                if candidate != base {
+                    info!(
+                        "{:?} {:?} collides, using {:?} for {}",
+                        kind, base, candidate, owner
+                    );
+                }
+                return Ok(candidate);
+            }
+            // A concurrent reservation for the same owner may have won the race
+            let existing = query!(
+                "SELECT name FROM reserved_names WHERE kind = $1 AND owner = $2",
+                kind.as_str(),
+                owner
+            )
+            .fetch_optional(&*self.db)
+            .await?;
+            if let Some(existing) = existing {
+                return Ok(existing.name);
+            }
+        }
+        Err(anyhow!("No free {:?} found for {:?}", kind, base))
+    }
+
+    /// Releases the name held by an owner
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub async fn release_name(self: &Arc<Self>, kind: NameKind, owner: &str) -> Result<()> {
+        query!(
+            "DELETE FROM reserved_names WHERE kind = $1 AND owner = $2",
+            kind.as_str(),
+            owner
+        )
+        .execute(&*self.db)
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slugify_collapses_separators() {
+        assert_eq!(slugify("General Chat"), "general-chat");
+        assert_eq!(slugify("  --Rust & Friends!! "), "rust-friends");
+        assert_eq!(slugify("Café Ünïcode"), "caf-n-code");
+    }
+
+    #[test]
+    fn alias_candidates() {
+        let names = candidates(NameKind::Alias, "general", "Rust Community")
+            .take(3)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "general",
+                "general-rust-community",
+                "general-rust-community-2"
+            ]
+        );
+    }
+
+    #[test]
+    fn displayname_candidates() {
+        let names = candidates(NameKind::Displayname, "Alice", "Rust Community")
+            .take(3)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "Alice",
+                "Alice (Rust Community)",
+                "Alice (Rust Community) 2"
+            ]
+        );
+    }
+
+    #[test]
+    fn renamed_names_are_no_candidates() {
+        let kind = NameKind::Displayname;
+        assert!(is_candidate(kind, "Alice (#0001) 3", "Alice", "#0001"));
+        assert!(!is_candidate(kind, "Alice (#0001)", "Alicia", "#0001"));
+    }
+}
@@ -0,0 +1,335 @@
+//! OAuth2 login of matrix users
+//!
+//! `login` without a token in a management room replies with a link to discord's authorization
+//! page, which also offers to add the bridge bot to one of the user's servers. Discord redirects
+//! back to the `/oauth/callback` route of the bridge, where the code is exchanged for an access and
+//! a refresh token. Both are stored encrypted with `bridge.secret_key`. Access tokens are
+//! refreshed by the token watchdog a day before they expire.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use super::App;
+use crate::{config::OAuth, snowflake};
+use anyhow::{anyhow, bail, Result};
+use matrix_sdk::ruma::{
+    events::room::message::RoomMessageEventContent, OwnedRoomId, OwnedUserId, RoomId, UserId,
+};
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    thread_rng,
+};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use sqlx::query;
+use tracing::{info, warn};
+use twilight_model::{
+    guild::Permissions,
+    id::{marker::ApplicationMarker, Id},
+};
+use url::Url;
+
+/// Discord's authorization page
+const AUTHORIZE_URL: &str = "https://discord.com/oauth2/authorize";
+
+/// Discord's token endpoint
+const TOKEN_URL: &str = "https://discord.com/api/v10/oauth2/token";
+
+/// Scopes requested from users
+const SCOPES: &str = "identify guilds bot";
+
+/// Length of the state parameter of a login
+const STATE_LEN: usize = 32;
+
+/// Time a user has to complete a login
+pub const LOGIN_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Login waiting for discord's redirect
+#[derive(Clone, Debug)]
+pub struct PendingLogin {
+    /// Matrix user logging in
+    user: OwnedUserId,
+    /// Management room the login was started in
+    room: OwnedRoomId,
+    /// When the login was started
+    started: Instant,
+}
+
+/// Response of the token endpoint
+#[derive(Deserialize)]
+struct TokenResponse {
+    /// Bearer token for requests on behalf of the user
+    access_token: String,
+    /// Token to get a new access token with
+    refresh_token: String,
+    /// Seconds until the access token expires
+    expires_in: f64,
+}
+
+/// Returns the permissions the bot asks for when it is added to a server
+fn bot_permissions() -> Permissions {
+    Permissions::VIEW_CHANNEL
+        | Permissions::SEND_MESSAGES
+        | Permissions::READ_MESSAGE_HISTORY
+        | Permissions::ADD_REACTIONS
+        | Permissions::ATTACH_FILES
+        | Permissions::EMBED_LINKS
+        | Permissions::MANAGE_MESSAGES
+        | Permissions::MANAGE_WEBHOOKS
+}
+
+/// Returns the link to discord's authorization page for a login
+///
+/// # Errors
+/// This function will return an error if the URL can't be built
+fn authorize_url(client_id: Id<ApplicationMarker>, redirect_url: &Url, state: &str) -> Result<Url> {
+    let client_id = client_id.to_string();
+    let permissions = bot_permissions().bits().to_string();
+    Ok(Url::parse_with_params(
+        AUTHORIZE_URL,
+        &[
+            ("response_type", "code"),
+            ("client_id", client_id.as_str()),
+            ("scope", SCOPES),
+            ("permissions", permissions.as_str()),
+            ("redirect_uri", redirect_url.as_str()),
+            ("state", state),
+        ],
+    )?)
+}
+
+impl App {
+    /// Returns the OAuth2 configuration
+    ///
+    /// # Errors
+    /// This function will return an error if OAuth2 login is not configured
+    fn oauth(&self) -> Result<&OAuth> {
+        self.config
+            .discord
+            .oauth
+            .as_ref()
+            .ok_or_else(|| anyhow!("No OAuth2 application configured"))
+    }
+
+    /// Returns the authorization header of a stored discord token
+    ///
+    /// # Errors
+    /// This function will return an error if a sealed token can't be decrypted
+    pub(super) fn stored_token(&self, token: String, sealed: bool) -> Result<String> {
+        if sealed {
+            self.secrets()?.open(&token)
+        } else {
+            Ok(token)
+        }
+    }
+
+    /// Starts the OAuth2 login of a matrix user, returning the link they log in with
+    ///
+    /// Returns `None` if OAuth2 login is not configured.
+    ///
+    /// # Errors
+    /// This function will return an error if no secret key is configured or the application id of
+    /// the bot can't be retrieved
+    pub(super) async fn start_oauth_login(
+        self: &Arc<Self>,
+        user: &UserId,
+        room: &RoomId,
+    ) -> Result<Option<Url>> {
+        let oauth = match self.config.discord.oauth {
+            Some(ref oauth) => oauth,
+            None => return Ok(None),
+        };
+        self.secrets()?;
+        self.oauth_logins
+            .retain(|_, login| login.started.elapsed() < LOGIN_TIMEOUT);
+        let state = Alphanumeric.sample_string(&mut thread_rng(), STATE_LEN);
+        let url = authorize_url(self.application_id().await?, &oauth.redirect_url, &state)?;
+        self.oauth_logins.insert(
+            state,
+            PendingLogin {
+                user: user.to_owned(),
+                room: room.to_owned(),
+                started: Instant::now(),
+            },
+        );
+        Ok(Some(url))
+    }
+
+    /// Requests tokens from discord's token endpoint
+    ///
+    /// Returns `None` if discord rejected the grant.
+    ///
+    /// # Errors
+    /// This function will return an error if discord can't be reached or fails otherwise
+    async fn request_tokens(
+        self: &Arc<Self>,
+        grant: &[(&str, &str)],
+    ) -> Result<Option<TokenResponse>> {
+        let client_id = self.application_id().await?.to_string();
+        let oauth = self.oauth()?;
+        let mut form = vec![
+            ("client_id", client_id.as_str()),
+            ("client_secret", oauth.client_secret.as_str()),
+        ];
+        form.extend_from_slice(grant);
+        let response = self.http.post(TOKEN_URL).form(&form).send().await?;
+        if matches!(
+            response.status(),
+            StatusCode::BAD_REQUEST | StatusCode::UNAUTHORIZED
+        ) {
+            return Ok(None);
+        }
+        let response = response.error_for_status()?;
+        Ok(Some(serde_json::from_slice(&response.bytes().await?)?))
+    }
+
+    /// Completes an OAuth2 login after discord redirected the user back to the bridge
+    ///
+    /// # Errors
+    /// This function will return an error if the login is unknown or expired, the code is rejected
+    /// or storing the tokens fails
+    #[allow(clippy::panic)]
+    pub(super) async fn complete_oauth_login(
+        self: &Arc<Self>,
+        code: &str,
+        state: &str,
+    ) -> Result<()> {
+        let (_, login) = self
+            .oauth_logins
+            .remove(state)
+            .ok_or_else(|| anyhow!("Unknown login"))?;
+        if login.started.elapsed() >= LOGIN_TIMEOUT {
+            bail!("The login of {} expired", login.user);
+        }
+        let redirect_url = self.oauth()?.redirect_url.to_string();
+        let tokens = self
+            .request_tokens(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", redirect_url.as_str()),
+            ])
+            .await?
+            .ok_or_else(|| anyhow!("Discord rejected the authorization code"))?;
+        let authorization = format!("Bearer {}", tokens.access_token);
+        let discord_user_id = self.discord_user_for_token(&authorization).await?;
+        self.unregister_user(&login.user).await?;
+        query!(
+            "INSERT INTO discord_tokens (user_id, token, management_room, discord_user_id, refresh_token, expires_at, sealed) VALUES ($1, $2, $3, $4, $5, NOW() + make_interval(secs => $6), TRUE)",
+            login.user.as_str(),
+            self.secrets()?.seal(&authorization)?,
+            login.room.as_str(),
+            snowflake::to_db(discord_user_id),
+            self.secrets()?.seal(&tokens.refresh_token)?,
+            tokens.expires_in
+        )
+        .execute(&*self.db)
+        .await?;
+        info!(
+            "{} logged in as discord user {}",
+            login.user, discord_user_id
+        );
+        if let Some(room) = self.client.get_room(&login.room) {
+            self.send_message(
+                &room,
+                RoomMessageEventContent::text_plain(
+                    "Successfully logged in with discord, your messages are now sent from your account",
+                ),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the access token of a user with a fresh one
+    ///
+    /// A refresh token discord rejects invalidates the login.
+    ///
+    /// # Errors
+    /// This function will return an error if the request to discord or the database query fails
+    #[allow(clippy::panic)]
+    async fn refresh_oauth_token(
+        self: &Arc<Self>,
+        user: &UserId,
+        refresh_token: &str,
+        management_room: &str,
+    ) -> Result<()> {
+        let refresh_token = self.secrets()?.open(refresh_token)?;
+        let tokens = match self
+            .request_tokens(&[
+                ("grant_type", "refresh_token"),
+                ("refresh_token", refresh_token.as_str()),
+            ])
+            .await?
+        {
+            Some(tokens) => tokens,
+            None => return self.invalidate_user_token(user, management_room).await,
+        };
+        query!(
+            "UPDATE discord_tokens SET token = $2, refresh_token = $3, expires_at = NOW() + make_interval(secs => $4), sealed = TRUE WHERE user_id = $1",
+            user.as_str(),
+            self.secrets()?
+                .seal(&format!("Bearer {}", tokens.access_token))?,
+            self.secrets()?.seal(&tokens.refresh_token)?,
+            tokens.expires_in
+        )
+        .execute(&*self.db)
+        .await?;
+        self.forget_user_discord_http(user);
+        Ok(())
+    }
+
+    /// Refreshes the access tokens that expire within a day
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub(super) async fn refresh_oauth_tokens(self: &Arc<Self>) -> Result<()> {
+        if self.config.discord.oauth.is_none() {
+            return Ok(());
+        }
+        let rows = query!(
+            "SELECT user_id, refresh_token, management_room FROM discord_tokens WHERE refresh_token IS NOT NULL AND invalid_since IS NULL AND expires_at < NOW() + INTERVAL '1 day'"
+        )
+        .fetch_all(&*self.db)
+        .await?;
+        for row in rows {
+            let (user, refresh_token) =
+                match (UserId::parse(row.user_id.as_str()), row.refresh_token) {
+                    (Ok(user), Some(refresh_token)) => (user, refresh_token),
+                    _ => continue,
+                };
+            if let Err(e) = self
+                .refresh_oauth_token(&user, &refresh_token, &row.management_room)
+                .await
+            {
+                warn!("Failed to refresh the discord token of {}: {:?}", user, e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authorization_links_carry_the_login() {
+        let url = authorize_url(
+            Id::new(1234),
+            &Url::parse("https://discord-bridge.chir.rs/oauth/callback").expect("valid URL"),
+            "abc",
+        )
+        .expect("valid authorization URL");
+        let params = url.query_pairs().into_owned().collect::<Vec<_>>();
+        assert!(params.contains(&("client_id".to_owned(), "1234".to_owned())));
+        assert!(params.contains(&("scope".to_owned(), "identify guilds bot".to_owned())));
+        assert!(params.contains(&("state".to_owned(), "abc".to_owned())));
+        assert!(params.contains(&(
+            "redirect_uri".to_owned(),
+            "https://discord-bridge.chir.rs/oauth/callback".to_owned()
+        )));
+    }
+}
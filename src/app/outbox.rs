@@ -0,0 +1,347 @@
+//! Journaled sends of bridged messages
+//!
+//! A bridged message is first written to `pending_sends` together with its content and an
+//! idempotency key derived from the discord message. Only then is it sent, using the key as the
+//! matrix transaction id, and the message mapping is recorded while the journal entry is removed
+//! in one transaction. If the bridge crashes in between, the entry is replayed on startup; the
+//! homeserver deduplicates the transaction id, so the replay yields the event id of the original
+//! send and the mapping is completed instead of leaving an unmapped message behind.
+//!
+//! The journal doubles as the queue for homeserver outages. When a send fails and the
+//! homeserver doesn't answer, sending is paused: further messages are only journaled, and a
+//! background task waits for the homeserver to come back and then catches up in journal order.
+//! The sequence number of the oldest journaled send is the watermark up to which everything was
+//! delivered.
+
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use super::{mappings::MessageMapping, App};
+use crate::{
+    retry::{retry, Backoff},
+    snowflake,
+};
+use anyhow::{anyhow, Result};
+use matrix_sdk::{
+    room::Room,
+    ruma::{
+        events::room::message::RoomMessageEventContent, EventId, OwnedEventId, OwnedRoomId,
+        OwnedTransactionId, OwnedUserId, RoomId, TransactionId, UserId,
+    },
+};
+use sqlx::query;
+use tokio::time::sleep;
+use tracing::{debug, error, info, warn};
+use twilight_model::{
+    channel::message::MessageReference,
+    id::{
+        marker::{ChannelMarker, MessageMarker},
+        Id,
+    },
+};
+
+/// Number of replays after which a journaled send is given up
+const MAX_ATTEMPTS: i32 = 5;
+
+/// Delay between checks whether the homeserver is back, before backing off
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay between checks whether the homeserver is back
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// Returns the idempotency key for one matrix event of a discord message
+///
+/// Discord messages with attachments are bridged as several events, `part` tells them apart.
+fn idempotency_key(message_id: Id<MessageMarker>, part: u32) -> OwnedTransactionId {
+    OwnedTransactionId::from(format!("discord-{}-{}", message_id, part))
+}
+
+impl App {
+    /// Sends a bridged discord message to matrix and records its mapping
+    ///
+    /// The event is sent by the user `room` belongs to. With `reference`, it is sent as a rich
+    /// reply; only the first event of a discord reply gets it. Messages in a bridged thread are
+    /// sent to the portal of its parent channel as thread events.
+    ///
+    /// In dry-run mode the message is only logged and `None` is returned. `None` is also returned
+    /// if the homeserver is unreachable, in which case the message is sent once it is back.
+    ///
+    /// # Errors
+    /// This function will return an error if journaling, sending or recording the mapping fails.
+    /// A journaled send that failed is retried on the next start.
+    #[allow(clippy::panic)]
+    pub async fn send_mapped_message(
+        self: &Arc<Self>,
+        room: &Room,
+        mut content: RoomMessageEventContent,
+        channel_id: Id<ChannelMarker>,
+        message_id: Id<MessageMarker>,
+        part: u32,
+        reference: Option<&MessageReference>,
+    ) -> Result<Option<OwnedEventId>> {
+        self.apply_discord_reply(&mut content, reference).await?;
+        self.apply_discord_thread(&mut content, channel_id).await?;
+        let joined = match room {
+            Room::Joined(joined) if !self.dry_run => joined,
+            _ => return self.send_message(room, content).await,
+        };
+        self.scrub_content(&mut content);
+        let txn_id = idempotency_key(message_id, part);
+        query!(
+            "INSERT INTO pending_sends (txn_id, matrix_room_id, discord_channel_id, discord_message_id, content, matrix_user_id) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (txn_id) DO NOTHING",
+            txn_id.as_str(),
+            room.room_id().as_str(),
+            snowflake::to_db(channel_id),
+            snowflake::to_db(message_id),
+            serde_json::to_string(&content)?,
+            room.own_user_id().as_str()
+        )
+        .execute(&*self.db)
+        .await?;
+        if self.outbox_paused.load(Ordering::Acquire) {
+            debug!("Homeserver unreachable, queued send {}", txn_id);
+            return Ok(None);
+        }
+        let err = match self.send_to_joined(joined, content, Some(&txn_id)).await {
+            Ok(event_id) => {
+                self.confirm_send(&txn_id, room.room_id(), &event_id, channel_id, message_id)
+                    .await?;
+                return Ok(Some(event_id));
+            }
+            Err(e) => e,
+        };
+        if self.homeserver_reachable().await {
+            return Err(err);
+        }
+        debug!(
+            "Send {} failed while the homeserver is unreachable: {:?}",
+            txn_id, err
+        );
+        self.pause_outbox();
+        Ok(None)
+    }
+
+    /// Records the mapping of a sent message and removes its journal entry
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    async fn confirm_send(
+        self: &Arc<Self>,
+        txn_id: &TransactionId,
+        room_id: &RoomId,
+        event_id: &EventId,
+        channel_id: Id<ChannelMarker>,
+        message_id: Id<MessageMarker>,
+    ) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+        MessageMapping {
+            event_id: event_id.to_owned(),
+            room_id: room_id.to_owned(),
+            message_id,
+            channel_id,
+            webhook_id: None,
+            relayed: false,
+        }
+        .insert(&mut tx)
+        .await?;
+        query!(
+            "DELETE FROM pending_sends WHERE txn_id = $1",
+            txn_id.as_str()
+        )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        self.record_thread_event(channel_id, event_id).await
+    }
+
+    /// Replays one journaled send
+    ///
+    /// The send is replayed by the user that sent it, so that the homeserver recognizes the
+    /// transaction id. Sends journaled without a sender are replayed by the bridge bot.
+    ///
+    /// # Errors
+    /// This function will return an error if the sender isn't bridged or can't join the room, or
+    /// sending or recording the mapping fails
+    async fn replay_send(
+        self: &Arc<Self>,
+        txn_id: &TransactionId,
+        room_id: &RoomId,
+        sender: Option<&UserId>,
+        content: &str,
+        channel_id: Id<ChannelMarker>,
+        message_id: Id<MessageMarker>,
+    ) -> Result<()> {
+        let client = match sender {
+            Some(sender) => self
+                .bridged_sender_client(sender)
+                .await?
+                .ok_or_else(|| anyhow!("{} is not bridged", sender))?,
+            None => Arc::clone(&self.client),
+        };
+        let room = match client.get_joined_room(room_id) {
+            Some(room) => room,
+            None => match client.join_room_by_id(room_id).await? {
+                Room::Joined(room) => room,
+                _ => return Err(anyhow!("Could not join {} to replay {}", room_id, txn_id)),
+            },
+        };
+        let content = serde_json::from_str::<RoomMessageEventContent>(content)?;
+        let event_id = self.send_to_joined(&room, content, Some(txn_id)).await?;
+        self.confirm_send(txn_id, room_id, &event_id, channel_id, message_id)
+            .await
+    }
+
+    /// Returns whether the homeserver answers requests
+    pub(super) async fn homeserver_reachable(self: &Arc<Self>) -> bool {
+        let url = match self
+            .config
+            .homeserver
+            .address
+            .join("_matrix/client/versions")
+        {
+            Ok(url) => url,
+            Err(_) => return false,
+        };
+        retry(
+            "matrix",
+            Backoff::default().with_max_attempts(1),
+            || async {
+                self.http
+                    .get(url.clone())
+                    .send()
+                    .await
+                    .and_then(reqwest::Response::error_for_status)
+            },
+        )
+        .await
+        .is_ok()
+    }
+
+    /// Pauses sending to the homeserver until it is reachable again
+    ///
+    /// Sends are journaled in the meantime and delivered in order by a background task.
+    pub(super) fn pause_outbox(self: &Arc<Self>) {
+        if self.outbox_paused.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        warn!("Homeserver unreachable, queueing messages until it is back");
+        let this = Arc::clone(self);
+        tokio::spawn(async move {
+            this.catch_up().await;
+        });
+    }
+
+    /// Waits for the homeserver to come back and delivers the queued sends
+    async fn catch_up(self: &Arc<Self>) {
+        let backoff = Backoff::new(RECONNECT_DELAY, MAX_RECONNECT_DELAY).without_jitter();
+        let mut attempt = 0;
+        loop {
+            sleep(backoff.delay(attempt)).await;
+            attempt = attempt.saturating_add(1);
+            if !self.homeserver_reachable().await {
+                continue;
+            }
+            match self.replay_pending_sends().await {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(e) => error!("Failed to catch up on queued sends: {:?}", e),
+            }
+        }
+        self.outbox_paused.store(false, Ordering::Release);
+        // Sends journaled while the last batch was replayed are picked up here. Sends racing
+        // with this replay are deduplicated by their transaction id.
+        if let Err(e) = self.replay_pending_sends().await {
+            error!("Failed to catch up on queued sends: {:?}", e);
+        }
+        info!("Homeserver is reachable again, caught up on queued messages");
+    }
+
+    /// Delivers journaled sends in order
+    ///
+    /// This completes sends that were interrupted by a crash or restart and catches up after a
+    /// homeserver outage. Sends that keep failing while the homeserver is reachable are dropped
+    /// after [`MAX_ATTEMPTS`] replays. Returns `false` if the homeserver became unreachable
+    /// before all sends were delivered.
+    ///
+    /// # Errors
+    /// This function will return an error if reading the journal fails
+    #[allow(clippy::panic)]
+    pub(super) async fn replay_pending_sends(self: &Arc<Self>) -> Result<bool> {
+        if self.dry_run {
+            return Ok(true);
+        }
+        let pending = query!(
+            "SELECT txn_id, matrix_room_id, discord_channel_id, discord_message_id, content, attempts, matrix_user_id FROM pending_sends ORDER BY seq"
+        )
+        .fetch_all(&*self.db)
+        .await?;
+        if !pending.is_empty() {
+            info!("Replaying {} queued sends", pending.len());
+        }
+        for row in pending {
+            let txn_id = OwnedTransactionId::from(row.txn_id);
+            let result = match (
+                OwnedRoomId::try_from(row.matrix_room_id),
+                row.matrix_user_id.map(OwnedUserId::try_from).transpose(),
+                snowflake::from_db(row.discord_channel_id),
+                snowflake::from_db(row.discord_message_id),
+            ) {
+                (Ok(room_id), Ok(sender), Ok(channel_id), Ok(message_id)) => {
+                    self.replay_send(
+                        &txn_id,
+                        &room_id,
+                        sender.as_deref(),
+                        &row.content,
+                        channel_id,
+                        message_id,
+                    )
+                    .await
+                }
+                _ => Err(anyhow!("Invalid journal entry")),
+            };
+            let err = match result {
+                Ok(()) => continue,
+                Err(e) => e,
+            };
+            if !self.homeserver_reachable().await {
+                warn!("Homeserver unreachable, stopping replay at send {}", txn_id);
+                return Ok(false);
+            }
+            if row.attempts + 1 >= MAX_ATTEMPTS {
+                error!("Giving up on send {}: {:?}", txn_id, err);
+                query!(
+                    "DELETE FROM pending_sends WHERE txn_id = $1",
+                    txn_id.as_str()
+                )
+                .execute(&*self.db)
+                .await?;
+            } else {
+                warn!("Failed to replay send {}: {:?}", txn_id, err);
+                query!(
+                    "UPDATE pending_sends SET attempts = attempts + 1 WHERE txn_id = $1",
+                    txn_id.as_str()
+                )
+                .execute(&*self.db)
+                .await?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idempotency_keys_are_stable() {
+        assert_eq!(idempotency_key(Id::new(42), 0).as_str(), "discord-42-0");
+        assert_ne!(
+            idempotency_key(Id::new(42), 0),
+            idempotency_key(Id::new(42), 1)
+        );
+    }
+}
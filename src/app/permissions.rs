@@ -0,0 +1,183 @@
+//! Discord permission calculation for the bridge bot and discord users
+
+use std::sync::Arc;
+
+use super::App;
+use anyhow::Result;
+use twilight_model::{
+    channel::permission_overwrite::{PermissionOverwrite, PermissionOverwriteType},
+    guild::Permissions,
+    id::{
+        marker::{ChannelMarker, GuildMarker, RoleMarker, UserMarker},
+        Id,
+    },
+};
+
+/// Computes the permissions of a guild member in a channel
+///
+/// `roles` contains the permissions of all roles of the guild, including `@everyone`, which
+/// shares its id with the guild.
+#[must_use]
+pub fn channel_permissions(
+    guild_id: Id<GuildMarker>,
+    user_id: Id<UserMarker>,
+    roles: &[(Id<RoleMarker>, Permissions)],
+    member_roles: &[Id<RoleMarker>],
+    overwrites: &[PermissionOverwrite],
+) -> Permissions {
+    let is_everyone = |id: u64| id == guild_id.get();
+    let is_member_role = |id: u64| member_roles.iter().any(|role| role.get() == id);
+    let mut permissions = roles
+        .iter()
+        .filter(|(id, _)| is_everyone(id.get()) || is_member_role(id.get()))
+        .fold(Permissions::empty(), |acc, (_, permissions)| {
+            acc | *permissions
+        });
+    if permissions.contains(Permissions::ADMINISTRATOR) {
+        return Permissions::all();
+    }
+
+    let apply = |permissions: Permissions, allow: Permissions, deny: Permissions| {
+        (permissions - deny) | allow
+    };
+    for overwrite in overwrites {
+        if overwrite.kind == PermissionOverwriteType::Role && is_everyone(overwrite.id.get()) {
+            permissions = apply(permissions, overwrite.allow, overwrite.deny);
+        }
+    }
+    let (allow, deny) = overwrites
+        .iter()
+        .filter(|overwrite| {
+            overwrite.kind == PermissionOverwriteType::Role && is_member_role(overwrite.id.get())
+        })
+        .fold(
+            (Permissions::empty(), Permissions::empty()),
+            |(allow, deny), overwrite| (allow | overwrite.allow, deny | overwrite.deny),
+        );
+    permissions = apply(permissions, allow, deny);
+    for overwrite in overwrites {
+        if overwrite.kind == PermissionOverwriteType::Member && overwrite.id.get() == user_id.get()
+        {
+            permissions = apply(permissions, overwrite.allow, overwrite.deny);
+        }
+    }
+    permissions
+}
+
+impl App {
+    /// Returns the guild of a channel and the permissions the bridge bot has in it
+    ///
+    /// Channels outside of guilds and guilds owned by the bot grant every permission.
+    ///
+    /// # Errors
+    /// This function will return an error if a request to discord fails
+    pub async fn bot_channel_permissions(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+    ) -> Result<(Option<Id<GuildMarker>>, Permissions)> {
+        let user_id = self.discord()?.user_id;
+        self.member_channel_permissions(channel_id, user_id).await
+    }
+
+    /// Returns the guild of a channel and the permissions a discord user has in it
+    ///
+    /// Channels outside of guilds and guilds owned by the user grant every permission.
+    ///
+    /// # Errors
+    /// This function will return an error if a request to discord fails, for example because the
+    /// user is not a member of the guild
+    pub async fn member_channel_permissions(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+        user_id: Id<UserMarker>,
+    ) -> Result<(Option<Id<GuildMarker>>, Permissions)> {
+        let http = &self.discord()?.http;
+        let channel = http.channel(channel_id).exec().await?.model().await?;
+        let guild_id = match channel.guild_id {
+            Some(guild_id) => guild_id,
+            None => return Ok((None, Permissions::all())),
+        };
+        let guild = http.guild(guild_id).exec().await?.model().await?;
+        if guild.owner_id == user_id {
+            return Ok((Some(guild_id), Permissions::all()));
+        }
+        let member = http
+            .guild_member(guild_id, user_id)
+            .exec()
+            .await?
+            .model()
+            .await?;
+        let roles = http
+            .roles(guild_id)
+            .exec()
+            .await?
+            .models()
+            .await?
+            .into_iter()
+            .map(|role| (role.id, role.permissions))
+            .collect::<Vec<_>>();
+        let permissions = channel_permissions(
+            guild_id,
+            user_id,
+            &roles,
+            &member.roles,
+            channel.permission_overwrites.as_deref().unwrap_or_default(),
+        );
+        Ok((Some(guild_id), permissions))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUILD: Id<GuildMarker> = Id::new(1);
+    const USER: Id<UserMarker> = Id::new(2);
+    const ROLE: Id<RoleMarker> = Id::new(3);
+
+    fn deny(id: u64, kind: PermissionOverwriteType, deny: Permissions) -> PermissionOverwrite {
+        PermissionOverwrite {
+            allow: Permissions::empty(),
+            deny,
+            id: Id::new(id),
+            kind,
+        }
+    }
+
+    #[test]
+    fn everyone_and_roles_are_combined() {
+        let roles = [
+            (GUILD.cast(), Permissions::ADD_REACTIONS),
+            (ROLE, Permissions::USE_EXTERNAL_EMOJIS),
+        ];
+        let permissions = channel_permissions(GUILD, USER, &roles, &[ROLE], &[]);
+        assert_eq!(
+            permissions,
+            Permissions::ADD_REACTIONS | Permissions::USE_EXTERNAL_EMOJIS
+        );
+    }
+
+    #[test]
+    fn overwrites_deny_permissions() {
+        let roles = [(GUILD.cast(), Permissions::ADD_REACTIONS)];
+        let overwrites = [deny(
+            GUILD.get(),
+            PermissionOverwriteType::Role,
+            Permissions::ADD_REACTIONS,
+        )];
+        let permissions = channel_permissions(GUILD, USER, &roles, &[], &overwrites);
+        assert!(!permissions.contains(Permissions::ADD_REACTIONS));
+    }
+
+    #[test]
+    fn administrator_ignores_overwrites() {
+        let roles = [(ROLE, Permissions::ADMINISTRATOR)];
+        let overwrites = [deny(
+            USER.get(),
+            PermissionOverwriteType::Member,
+            Permissions::ADD_REACTIONS,
+        )];
+        let permissions = channel_permissions(GUILD, USER, &roles, &[ROLE], &overwrites);
+        assert_eq!(permissions, Permissions::all());
+    }
+}
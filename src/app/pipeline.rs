@@ -0,0 +1,274 @@
+//! Request pipeline for the homeserver
+//!
+//! Requests of different users run concurrently up to a configurable limit, while requests of
+//! the same user are serialized so that their order is preserved.
+//!
+//! Users the homeserver keeps rejecting with 403 or 429, for example because a puppet got rate
+//! limited or banned, are paused: their requests are only tried as occasional probes, while other
+//! users continue. Once a probe succeeds, the user resumes with a slow start, spacing requests
+//! apart and speeding up with every success. Pauses and resumes are reported as alerts.
+
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Error, Result};
+use dashmap::DashMap;
+use matrix_sdk::{
+    ruma::{
+        api::error::{FromHttpResponseError, ServerError},
+        OwnedUserId, UserId,
+    },
+    HttpError, RumaApiError,
+};
+use tokio::{
+    sync::{
+        mpsc::{UnboundedReceiver, UnboundedSender},
+        Mutex, Semaphore,
+    },
+    time::sleep,
+};
+use tracing::warn;
+
+use super::App;
+use crate::metrics::METRICS;
+
+/// Requests slower than this are logged
+const SLOW_REQUEST_MS: u128 = 5000;
+
+/// Consecutive rejections after which a user is paused
+const PAUSE_THRESHOLD: u32 = 5;
+
+/// Delay between probes of a paused user
+const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Spacing between requests right after a user is resumed
+const SLOW_START: Duration = Duration::from_secs(4);
+
+/// Spacing below which requests are no longer held back
+const MIN_SPACING: Duration = Duration::from_millis(100);
+
+/// Returns the HTTP status of a homeserver error response
+pub(super) fn error_status(error: &Error) -> Option<u16> {
+    let error = match error.downcast_ref::<matrix_sdk::Error>() {
+        Some(matrix_sdk::Error::Http(error)) => error,
+        _ => error.downcast_ref::<HttpError>()?,
+    };
+    match error {
+        HttpError::Api(FromHttpResponseError::Server(ServerError::Known(
+            RumaApiError::ClientApi(error),
+        ))) => Some(error.status_code.as_u16()),
+        _ => None,
+    }
+}
+
+/// Change of the state of a user
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Transition {
+    /// The user was paused
+    Paused,
+    /// The user was resumed
+    Resumed,
+}
+
+/// Rejection state of a user
+#[derive(Clone, Copy, Debug, Default)]
+struct Throttle {
+    /// Number of consecutive rejections
+    rejections: u32,
+    /// Whether the user is paused
+    paused: bool,
+    /// Spacing between requests during slow start
+    spacing: Duration,
+}
+
+impl Throttle {
+    /// Returns how long to wait before the next request
+    const fn delay(&self) -> Duration {
+        if self.paused {
+            PROBE_INTERVAL
+        } else {
+            self.spacing
+        }
+    }
+
+    /// Records whether a request was rejected
+    fn record(&mut self, rejected: bool) -> Option<Transition> {
+        if rejected {
+            self.rejections = self.rejections.saturating_add(1);
+            if self.rejections == PAUSE_THRESHOLD && !self.paused {
+                self.paused = true;
+                return Some(Transition::Paused);
+            }
+            return None;
+        }
+        self.rejections = 0;
+        if self.paused {
+            self.paused = false;
+            self.spacing = SLOW_START;
+            return Some(Transition::Resumed);
+        }
+        self.spacing /= 2;
+        if self.spacing < MIN_SPACING {
+            self.spacing = Duration::ZERO;
+        }
+        None
+    }
+}
+
+/// Bounded concurrent request pipeline with per-user ordering
+#[derive(Debug)]
+pub struct Pipeline {
+    /// Limits the number of requests in flight
+    permits: Semaphore,
+    /// Serializes the requests of each user
+    users: DashMap<OwnedUserId, Arc<Mutex<()>>>,
+    /// Rejection state of each user the homeserver rejected requests of
+    throttles: DashMap<OwnedUserId, Throttle>,
+    /// Receives alerts about paused and resumed users
+    alerts: UnboundedSender<String>,
+}
+
+impl Pipeline {
+    /// Creates a new pipeline allowing `parallelism` concurrent requests
+    ///
+    /// Alerts about paused and resumed users are sent to `alerts`.
+    #[must_use]
+    pub fn new(parallelism: usize, alerts: UnboundedSender<String>) -> Self {
+        Self {
+            permits: Semaphore::new(parallelism.max(1)),
+            users: DashMap::new(),
+            throttles: DashMap::new(),
+            alerts,
+        }
+    }
+
+    /// Records the outcome of a request and reports changes of the user's state
+    fn record_outcome(&self, user_id: &UserId, error: Option<&Error>) {
+        let status = error.and_then(error_status);
+        let rejected = matches!(status, Some(403 | 429));
+        if error.is_some() && !rejected {
+            // Other failures say nothing about whether the user is rejected
+            return;
+        }
+        if !rejected && !self.throttles.contains_key(user_id) {
+            return;
+        }
+        let mut throttle = self.throttles.entry(user_id.to_owned()).or_default();
+        let transition = throttle.record(rejected);
+        let idle = !throttle.paused && throttle.spacing.is_zero() && throttle.rejections == 0;
+        drop(throttle);
+        if idle {
+            self.throttles.remove(user_id);
+        }
+        let alert = match transition {
+            Some(Transition::Paused) => {
+                METRICS.add("bridge_homeserver_paused_users", &[], 1);
+                format!(
+                    "The homeserver rejected {} requests of {} in a row, pausing them (last error: {})",
+                    PAUSE_THRESHOLD,
+                    user_id,
+                    error.map(ToString::to_string).unwrap_or_default()
+                )
+            }
+            Some(Transition::Resumed) => {
+                METRICS.add("bridge_homeserver_paused_users", &[], -1);
+                format!(
+                    "The homeserver accepts requests of {} again, resuming slowly",
+                    user_id
+                )
+            }
+            None => return,
+        };
+        warn!("{}", alert);
+        // Only fails once the bridge shuts down
+        let _ = self.alerts.send(alert);
+    }
+
+    /// Runs a request on behalf of a user
+    ///
+    /// The request starts once all earlier requests of the same user have finished and a
+    /// concurrency permit is available. `kind` is used to label the metrics.
+    ///
+    /// # Errors
+    /// This function returns the error of the request
+    pub async fn run<T, F>(&self, user_id: &UserId, kind: &str, request: F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let lock = Arc::clone(&*self.users.entry(user_id.to_owned()).or_default());
+        let _user_guard = lock.lock().await;
+        let delay = self
+            .throttles
+            .get(user_id)
+            .map_or(Duration::ZERO, |throttle| throttle.delay());
+        if !delay.is_zero() {
+            sleep(delay).await;
+        }
+        let _permit = self.permits.acquire().await?;
+
+        let labels = [("kind", kind)];
+        METRICS.add("bridge_homeserver_requests_in_flight", &labels, 1);
+        let start = Instant::now();
+        let result = request.await;
+        let elapsed = start.elapsed().as_millis();
+        METRICS.add("bridge_homeserver_requests_in_flight", &labels, -1);
+        METRICS.inc("bridge_homeserver_requests_count", &labels);
+        METRICS.add(
+            "bridge_homeserver_requests_duration_ms_sum",
+            &labels,
+            i64::try_from(elapsed).unwrap_or(i64::MAX),
+        );
+        if result.is_err() {
+            METRICS.inc("bridge_homeserver_requests_failed", &labels);
+        }
+        self.record_outcome(user_id, result.as_ref().err());
+        if elapsed > SLOW_REQUEST_MS {
+            warn!("{} request for {} took {}ms", kind, user_id, elapsed);
+        }
+        result
+    }
+}
+
+impl App {
+    /// Forwards alerts of the pipeline to the admin room
+    pub(super) fn spawn_pipeline_alerts(self: &Arc<Self>, mut alerts: UnboundedReceiver<String>) {
+        let this = Arc::downgrade(self);
+        tokio::spawn(async move {
+            while let Some(alert) = alerts.recv().await {
+                let this = match this.upgrade() {
+                    Some(this) => this,
+                    None => break,
+                };
+                if let Err(e) = this.alert_admin(&alert).await {
+                    warn!("Failed to alert the admin room: {:?}", e);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pauses_and_resumes_slowly() {
+        let mut throttle = Throttle::default();
+        for _ in 1..PAUSE_THRESHOLD {
+            assert_eq!(throttle.record(true), None);
+        }
+        assert_eq!(throttle.record(true), Some(Transition::Paused));
+        assert_eq!(throttle.record(true), None);
+        assert_eq!(throttle.delay(), PROBE_INTERVAL);
+        assert_eq!(throttle.record(false), Some(Transition::Resumed));
+        assert_eq!(throttle.delay(), SLOW_START);
+        assert_eq!(throttle.record(false), None);
+        assert_eq!(throttle.delay(), SLOW_START / 2);
+        while !throttle.delay().is_zero() {
+            throttle.record(false);
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! Per-portal feature overrides
+//!
+//! The `features` section of the config sets which optional features are bridged. Portals can
+//! override each of them with the `feature` command or the portal settings state event.
+
+use std::sync::Arc;
+
+use super::{portals::Portal, App};
+use crate::features::{Feature, FeatureOverrides};
+use anyhow::Result;
+use matrix_sdk::{
+    room::Room,
+    ruma::{events::room::message::RoomMessageEventContent, RoomId, RoomOrAliasId, UserId},
+};
+use sqlx::query;
+use tracing::info;
+
+/// Formats the state of a feature in a portal
+fn feature_line(feature: Feature, enabled: bool, overridden: bool) -> String {
+    format!(
+        "{}: {}{}",
+        feature,
+        if enabled { "on" } else { "off" },
+        if overridden { " (portal)" } else { "" }
+    )
+}
+
+impl App {
+    /// Returns whether a feature is enabled in a portal
+    #[must_use]
+    pub fn portal_feature(&self, portal: &Portal, feature: Feature) -> bool {
+        portal.features.resolve(&self.config.features, feature)
+    }
+
+    /// Returns whether a feature is enabled in a room
+    ///
+    /// Rooms that aren't portals use the configured value.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    pub async fn room_feature(
+        self: &Arc<Self>,
+        room_id: &RoomId,
+        feature: Feature,
+    ) -> Result<bool> {
+        Ok(match self.portal_by_room(room_id).await? {
+            Some(portal) => self.portal_feature(&portal, feature),
+            None => self.config.features.enabled(feature),
+        })
+    }
+
+    /// Stores the feature overrides of a portal
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub(super) async fn set_portal_features(
+        self: &Arc<Self>,
+        room_id: &RoomId,
+        features: &FeatureOverrides,
+    ) -> Result<()> {
+        let names = |features: &[Feature]| {
+            features
+                .iter()
+                .map(|feature| feature.name().to_owned())
+                .collect::<Vec<_>>()
+        };
+        query!(
+            "UPDATE portals SET features_enabled = $2, features_disabled = $3 WHERE matrix_room_id = $1",
+            room_id.as_str(),
+            &names(&features.enabled),
+            &names(&features.disabled)
+        )
+        .execute(&*self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Handles the `feature` command
+    ///
+    /// `feature <room>` lists the features of a portal, `feature <room> <feature> on|off`
+    /// overrides one and `feature <room> <feature> default` goes back to the config.
+    ///
+    /// # Errors
+    /// This function will return an error if the portal can't be updated or the reply could not
+    /// be sent
+    pub(super) async fn handle_feature_command(
+        self: &Arc<Self>,
+        sender: &UserId,
+        args: &[&str],
+        room: &Room,
+    ) -> Result<()> {
+        let reply = if sender == self.config.bridge.admin {
+            self.feature_command_reply(args).await?
+        } else {
+            "Only the bridge admin can change portal features".to_owned()
+        };
+        self.send_message(room, RoomMessageEventContent::text_plain(reply))
+            .await?;
+        Ok(())
+    }
+
+    /// Runs the `feature` command and returns the reply
+    ///
+    /// # Errors
+    /// This function will return an error if the portal can't be updated
+    async fn feature_command_reply(self: &Arc<Self>, args: &[&str]) -> Result<String> {
+        let (target, change) = match args {
+            ["feature", target] => (target, None),
+            ["feature", target, feature, state] => (target, Some((feature, state))),
+            _ => {
+                return Ok("Usage: feature <room> [<feature> on|off|default]".to_owned());
+            }
+        };
+        let target = match <&RoomOrAliasId>::try_from(*target) {
+            Ok(target) => target,
+            Err(_) => return Ok(format!("{} is not a room id or alias", target)),
+        };
+        let mut portal = match self.portal_by_room_or_alias(target).await? {
+            Some(portal) => portal,
+            None => return Ok(format!("{} is not a portal", target)),
+        };
+        let (feature, state) = match change {
+            Some(change) => change,
+            None => {
+                let lines = Feature::ALL.map(|feature| {
+                    feature_line(
+                        feature,
+                        self.portal_feature(&portal, feature),
+                        portal.features.get(feature).is_some(),
+                    )
+                });
+                return Ok(format!("Features of {}:\n{}", target, lines.join("\n")));
+            }
+        };
+        let feature = match Feature::try_from(*feature) {
+            Ok(feature) => feature,
+            Err(e) => return Ok(e.to_string()),
+        };
+        let enabled = match *state {
+            "on" => Some(true),
+            "off" => Some(false),
+            "default" => None,
+            _ => return Ok("The state of a feature can be on, off or default".to_owned()),
+        };
+        portal.features.set(feature, enabled);
+        if self.dry_run {
+            info!(
+                "[dry-run] Would set {} in {} to {:?}",
+                feature, portal.room_id, enabled
+            );
+            return Ok(format!("Would update {} in {}", feature, target));
+        }
+        self.set_portal_features(&portal.room_id, &portal.features)
+            .await?;
+        self.publish_portal_settings(&portal.room_id).await?;
+        Ok(format!(
+            "Set {} in {}",
+            feature_line(
+                feature,
+                self.portal_feature(&portal, feature),
+                enabled.is_some()
+            ),
+            target
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_features() {
+        assert_eq!(
+            feature_line(Feature::Typing, false, true),
+            "typing: off (portal)"
+        );
+        assert_eq!(feature_line(Feature::Edits, true, false), "edits: on");
+    }
+}
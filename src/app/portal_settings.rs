@@ -0,0 +1,267 @@
+//! Per-portal settings in room state
+//!
+//! The settings of a portal are kept in a `rs.chir.discord_bridge.portal_settings` state event
+//! in the portal room and mirrored to the database. Room members with the power level to send
+//! that event can change the settings from their client, and since the room state outlives the
+//! database, the mirror is restored from it on startup.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use super::{room_metadata::MetadataSync, App};
+use crate::{
+    features::{Feature, FeatureOverrides},
+    locale::Locale,
+};
+use anyhow::Result;
+use matrix_sdk::{
+    room::Room,
+    ruma::{
+        events::{macros::EventContent, StateEventType, SyncStateEvent},
+        RoomId,
+    },
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{info, warn};
+
+/// State event type of portal settings
+const SETTINGS_EVENT_TYPE: &str = "rs.chir.discord_bridge.portal_settings";
+
+/// Content of the portal settings state event
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "rs.chir.discord_bridge.portal_settings", kind = State)]
+pub struct PortalSettingsEventContent {
+    /// Preferred locale of the portal as an IETF language tag
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+    /// Features enabled or disabled in the portal regardless of the config
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub features: BTreeMap<String, bool>,
+    /// Direction in which the channel name and topic are synced, from discord if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_sync: Option<String>,
+}
+
+impl PortalSettingsEventContent {
+    /// Returns the preferred locale
+    ///
+    /// # Errors
+    /// This function will return an error if the locale is not supported
+    fn locale(&self) -> Result<Option<Locale>> {
+        self.locale.as_deref().map(Locale::try_from).transpose()
+    }
+
+    /// Returns the sync direction of channel metadata
+    ///
+    /// # Errors
+    /// This function will return an error if the direction is unknown
+    fn metadata_sync(&self) -> Result<MetadataSync> {
+        self.metadata_sync
+            .as_deref()
+            .map_or(Ok(MetadataSync::default()), MetadataSync::try_from)
+    }
+
+    /// Returns the feature overrides
+    ///
+    /// # Errors
+    /// This function will return an error if a feature is unknown
+    fn features(&self) -> Result<FeatureOverrides> {
+        let mut features = FeatureOverrides::default();
+        for (name, enabled) in &self.features {
+            features.set(Feature::try_from(name.as_str())?, Some(*enabled));
+        }
+        Ok(features)
+    }
+}
+
+impl App {
+    /// Mirrors settings of a portal to the database
+    ///
+    /// # Errors
+    /// This function will return an error if a setting is invalid or the database query fails
+    async fn apply_portal_settings(
+        self: &Arc<Self>,
+        room_id: &RoomId,
+        settings: &PortalSettingsEventContent,
+    ) -> Result<()> {
+        let portal = match self.portal_by_room(room_id).await? {
+            Some(portal) => portal,
+            None => return Ok(()),
+        };
+        let locale = settings.locale()?;
+        let features = settings.features()?;
+        let metadata_sync = settings.metadata_sync()?;
+        if portal.locale == locale
+            && portal.metadata_sync == metadata_sync
+            && Feature::ALL
+                .into_iter()
+                .all(|feature| portal.features.get(feature) == features.get(feature))
+        {
+            return Ok(());
+        }
+        if self.dry_run {
+            info!(
+                "[dry-run] Would set the locale of {} to {:?}, the features to {:?} and the metadata sync to {:?}",
+                room_id, locale, features, metadata_sync
+            );
+            return Ok(());
+        }
+        info!(
+            "Settings of {} changed, locale is {:?}, features are {:?}, metadata sync is {:?}",
+            room_id, locale, features, metadata_sync
+        );
+        self.set_portal_locale(room_id, locale).await?;
+        self.set_portal_metadata_sync(room_id, metadata_sync)
+            .await?;
+        self.set_portal_features(room_id, &features).await
+    }
+
+    /// Handles a change of the portal settings state event
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    pub(super) async fn handle_portal_settings_event(
+        self: &Arc<Self>,
+        event: SyncStateEvent<PortalSettingsEventContent>,
+        room: Room,
+    ) -> Result<()> {
+        if let SyncStateEvent::Original(event) = event {
+            if event.sender == self.user_id {
+                return Ok(());
+            }
+            if let Err(e) = self
+                .apply_portal_settings(room.room_id(), &event.content)
+                .await
+            {
+                warn!(
+                    "Ignoring invalid settings of {} from {}: {:?}",
+                    room.room_id(),
+                    event.sender,
+                    e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the settings of a portal to its room state
+    ///
+    /// # Errors
+    /// This function will return an error if the database query or sending the state event
+    /// fails
+    pub(super) async fn publish_portal_settings(self: &Arc<Self>, room_id: &RoomId) -> Result<()> {
+        let portal = match self.portal_by_room(room_id).await? {
+            Some(portal) => portal,
+            None => return Ok(()),
+        };
+        let room = match self.client.get_joined_room(room_id) {
+            Some(room) => room,
+            None => return Ok(()),
+        };
+        let content = serde_json::to_value(PortalSettingsEventContent {
+            locale: portal.locale.map(|locale| locale.tag().to_owned()),
+            features: Feature::ALL
+                .into_iter()
+                .filter_map(|feature| {
+                    portal
+                        .features
+                        .get(feature)
+                        .map(|enabled| (feature.name().to_owned(), enabled))
+                })
+                .collect(),
+            metadata_sync: if portal.metadata_sync == MetadataSync::default() {
+                None
+            } else {
+                Some(portal.metadata_sync.name().to_owned())
+            },
+        })?;
+        if self.dry_run {
+            info!(
+                "[dry-run] Would set the settings of {} to {}",
+                room_id, content
+            );
+            return Ok(());
+        }
+        self.pipeline
+            .run(&self.user_id, "state", async {
+                room.send_state_event_raw(content, SETTINGS_EVENT_TYPE, "")
+                    .await?;
+                Ok(())
+            })
+            .await
+    }
+
+    /// Restores the database mirror of the settings of all portals from their room state
+    ///
+    /// Failures for single portals are logged so the remaining portals are still restored.
+    ///
+    /// # Errors
+    /// This function will return an error if the portals can't be read
+    pub(super) async fn restore_portal_settings(self: &Arc<Self>) -> Result<()> {
+        for portal in self.all_portals().await? {
+            let room = match self.client.get_joined_room(&portal.room_id) {
+                Some(room) => room,
+                None => continue,
+            };
+            let result = async {
+                let event = room
+                    .get_state_event(StateEventType::from(SETTINGS_EVENT_TYPE), "")
+                    .await?;
+                if let Some(event) = event {
+                    let settings = serde_json::from_value::<PortalSettingsEventContent>(
+                        event.deserialize_as::<Value>()?["content"].take(),
+                    )?;
+                    self.apply_portal_settings(&portal.room_id, &settings)
+                        .await?;
+                }
+                Ok::<_, anyhow::Error>(())
+            }
+            .await;
+            if let Err(e) = result {
+                warn!(
+                    "Failed to restore the settings of {}: {:?}",
+                    portal.room_id, e
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_settings() {
+        let settings =
+            serde_json::from_str::<PortalSettingsEventContent>(r#"{"locale":"de"}"#).ok();
+        assert_eq!(
+            settings
+                .as_ref()
+                .and_then(|settings| settings.locale().ok()),
+            Some(Some(Locale::German))
+        );
+        let settings = serde_json::from_str::<PortalSettingsEventContent>("{}").ok();
+        assert_eq!(
+            settings
+                .as_ref()
+                .and_then(|settings| settings.locale().ok()),
+            Some(None)
+        );
+        let settings = PortalSettingsEventContent {
+            locale: Some("xx".to_owned()),
+            ..PortalSettingsEventContent::default()
+        };
+        assert!(settings.locale().is_err());
+        let settings = serde_json::from_str::<PortalSettingsEventContent>(
+            r#"{"features":{"typing":true,"reactions":false}}"#,
+        )
+        .ok();
+        let features = settings.and_then(|settings| settings.features().ok());
+        assert_eq!(
+            features.map(|features| (features.enabled, features.disabled)),
+            Some((vec![Feature::Typing], vec![Feature::Reactions]))
+        );
+    }
+}
@@ -0,0 +1,659 @@
+//! Portal store
+//!
+//! A portal is a matrix room that is bridged to a discord channel.
+
+use std::{future::Future, sync::Arc};
+
+use super::{
+    names::{slugify, NameKind},
+    room_metadata::MetadataSync,
+    App,
+};
+use crate::{features::FeatureOverrides, locale::Locale, snowflake};
+use anyhow::{anyhow, Result};
+use matrix_sdk::{
+    room::Room,
+    ruma::{
+        api::client::room::create_room,
+        events::{
+            room::message::RoomMessageEventContent,
+            room::{
+                canonical_alias::SyncRoomCanonicalAliasEvent, tombstone::SyncRoomTombstoneEvent,
+            },
+            SyncStateEvent,
+        },
+        OwnedRoomAliasId, OwnedRoomId, RoomAliasId, RoomId, RoomOrAliasId, UserId,
+    },
+};
+use sqlx::{query, query_as};
+use tracing::{debug, info, warn};
+use twilight_model::{
+    channel::{ChannelType, Message},
+    id::{
+        marker::{ChannelMarker, GuildMarker},
+        Id,
+    },
+};
+
+/// A bridged discord channel
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Portal {
+    /// The discord channel
+    pub channel_id: Id<ChannelMarker>,
+    /// The resolved matrix room id
+    pub room_id: OwnedRoomId,
+    /// The canonical alias of the room, if any
+    pub alias: Option<OwnedRoomAliasId>,
+    /// The guild the channel belongs to, if any
+    pub guild_id: Option<Id<GuildMarker>>,
+    /// Whether the channel is archived or read-only on discord
+    pub read_only: bool,
+    /// Preferred locale of the portal, if any
+    pub locale: Option<Locale>,
+    /// Features enabled or disabled in the portal regardless of the config
+    pub features: FeatureOverrides,
+    /// Direction in which the channel name and topic are synced
+    pub metadata_sync: MetadataSync,
+}
+
+/// Database row of a portal
+#[derive(Debug)]
+struct PortalRow {
+    /// The discord channel
+    discord_channel_id: i64,
+    /// The matrix room id
+    matrix_room_id: String,
+    /// The canonical alias of the room
+    room_alias: Option<String>,
+    /// The guild the channel belongs to
+    guild_id: Option<i64>,
+    /// Whether the channel is read-only
+    read_only: bool,
+    /// Preferred locale
+    locale: Option<String>,
+    /// Features enabled in the portal
+    features_enabled: Vec<String>,
+    /// Features disabled in the portal
+    features_disabled: Vec<String>,
+    /// Direction in which the channel name and topic are synced
+    metadata_sync: String,
+}
+
+/// Returns whether a channel is a thread
+const fn is_thread(kind: ChannelType) -> bool {
+    matches!(
+        kind,
+        ChannelType::GuildNewsThread
+            | ChannelType::GuildPublicThread
+            | ChannelType::GuildPrivateThread
+    )
+}
+
+impl TryFrom<PortalRow> for Portal {
+    type Error = anyhow::Error;
+
+    fn try_from(row: PortalRow) -> Result<Self> {
+        Ok(Self {
+            channel_id: snowflake::from_db(row.discord_channel_id)?,
+            room_id: OwnedRoomId::try_from(row.matrix_room_id)?,
+            alias: row.room_alias.map(OwnedRoomAliasId::try_from).transpose()?,
+            guild_id: row.guild_id.map(snowflake::from_db).transpose()?,
+            read_only: row.read_only,
+            locale: row.locale.map(Locale::try_from).transpose()?,
+            features: FeatureOverrides::from_names(&row.features_enabled, &row.features_disabled),
+            metadata_sync: MetadataSync::try_from(row.metadata_sync.as_str())?,
+        })
+    }
+}
+
+impl App {
+    /// Looks up the portal for a discord channel
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub async fn portal_by_channel(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+    ) -> Result<Option<Portal>> {
+        query_as!(
+            PortalRow,
+            "SELECT discord_channel_id, matrix_room_id, room_alias, guild_id, read_only, locale, features_enabled, features_disabled, metadata_sync FROM portals WHERE discord_channel_id = $1",
+            snowflake::to_db(channel_id)
+        )
+        .fetch_optional(&*self.db)
+        .await?
+        .map(Portal::try_from)
+        .transpose()
+    }
+
+    /// Returns the portal of a channel, creating it if there is none
+    ///
+    /// `create` creates the portal room and returns its id. Creation is serialized per channel,
+    /// so triggers racing each other, like an alias query and the first message, create only one
+    /// room. If the channel got a portal from elsewhere in the meantime, for example from another
+    /// bridge process, the unique channel id in the portal table rejects the new room, which is
+    /// left again, and the existing portal is returned.
+    ///
+    /// # Errors
+    /// This function will return an error if creating the room or the database query fails, or
+    /// the bridge is running in dry-run mode
+    #[allow(clippy::panic)]
+    pub async fn ensure_portal<F, Fut>(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+        guild_id: Option<Id<GuildMarker>>,
+        create: F,
+    ) -> Result<Portal>
+    where
+        F: FnOnce() -> Fut + Send,
+        Fut: Future<Output = Result<OwnedRoomId>> + Send,
+    {
+        let lock = Arc::clone(&*self.portal_creation_locks.entry(channel_id).or_default());
+        let _guard = lock.lock().await;
+        if let Some(portal) = self.portal_by_channel(channel_id).await? {
+            return Ok(portal);
+        }
+        if self.dry_run {
+            info!("[dry-run] Would create a portal for {}", channel_id);
+            return Err(anyhow!("Not creating portals in dry-run mode"));
+        }
+        let room_id = create().await?;
+        let inserted = query!(
+            "INSERT INTO portals (discord_channel_id, matrix_room_id, guild_id) VALUES ($1, $2, $3) ON CONFLICT (discord_channel_id) DO NOTHING",
+            snowflake::to_db(channel_id),
+            room_id.as_str(),
+            guild_id.map(snowflake::to_db)
+        )
+        .execute(&*self.db)
+        .await?
+        .rows_affected();
+        if inserted == 0 {
+            warn!(
+                "Channel {} got a portal while {} was created, leaving it",
+                channel_id, room_id
+            );
+            if let Some(room) = self.client.get_joined_room(&room_id) {
+                self.pipeline
+                    .run(&self.user_id, "state", async {
+                        room.leave().await?;
+                        Ok(())
+                    })
+                    .await?;
+            }
+        } else {
+            info!("Created portal {} for {}", room_id, channel_id);
+            self.emit_portal_created(guild_id, channel_id, &room_id);
+        }
+        let portal = self
+            .portal_by_channel(channel_id)
+            .await?
+            .ok_or_else(|| anyhow!("The portal of {} vanished during creation", channel_id))?;
+        if inserted > 0 {
+            if let Err(e) = self.apply_room_identity(&portal).await {
+                warn!("Failed to set the bot profile in {}: {:?}", room_id, e);
+            }
+            if let Err(e) = self.add_portal_to_space(&portal).await {
+                warn!("Failed to add {} to its guild's space: {:?}", room_id, e);
+            }
+        }
+        Ok(portal)
+    }
+
+    /// Creates the matrix room of a new portal, named after the discord channel
+    ///
+    /// The room alias is reserved from the channel name, with the guild name appended if another
+    /// guild has a channel of the same name, see `reserve_name`.
+    ///
+    /// # Errors
+    /// This function will return an error if the channel or guild can't be fetched, no alias could
+    /// be reserved or the room can't be created
+    async fn create_portal_room(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+    ) -> Result<OwnedRoomId> {
+        let channel = self
+            .discord()?
+            .http
+            .channel(channel_id)
+            .exec()
+            .await?
+            .model()
+            .await?;
+        let name = channel.name.unwrap_or_else(|| channel_id.to_string());
+        let guild_name = match channel.guild_id {
+            Some(guild_id) => {
+                self.discord()?
+                    .http
+                    .guild(guild_id)
+                    .exec()
+                    .await?
+                    .model()
+                    .await?
+                    .name
+            }
+            None => channel_id.to_string(),
+        };
+        let slug = match slugify(&name) {
+            slug if slug.is_empty() => channel_id.to_string(),
+            slug => slug,
+        };
+        let alias = self
+            .reserve_name(
+                NameKind::Alias,
+                &channel_id.to_string(),
+                &format!("{}_discord_{}", self.config.bridge.prefix, slug),
+                &guild_name,
+            )
+            .await?;
+        let mut request = create_room::v3::Request::new();
+        request.name = Some(&name);
+        request.topic = channel.topic.as_deref();
+        request.room_alias_name = Some(&alias);
+        let response = self
+            .pipeline
+            .run(&self.user_id, "create_room", async {
+                Ok(self.client.create_room(request).await?)
+            })
+            .await?;
+        Ok(response.room_id)
+    }
+
+    /// Returns the portal of a channel that received traffic, creating it if there is none
+    ///
+    /// In dry-run mode no portal is created and `None` is returned for unbridged channels.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query or creating the portal fails
+    pub async fn portal_for_channel(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+        guild_id: Option<Id<GuildMarker>>,
+    ) -> Result<Option<Portal>> {
+        if self.dry_run {
+            let portal = self.portal_by_channel(channel_id).await?;
+            if portal.is_none() {
+                info!("[dry-run] Would create a portal for {}", channel_id);
+            }
+            return Ok(portal);
+        }
+        self.ensure_portal(channel_id, guild_id, || self.create_portal_room(channel_id))
+            .await
+            .map(Some)
+    }
+
+    /// Returns the portal a discord message belongs to, creating it on the channel's first message
+    ///
+    /// Messages in threads belong to the portal of the thread's parent channel. Direct messages
+    /// don't create portals.
+    ///
+    /// # Errors
+    /// This function will return an error if a database query, fetching the channel or creating
+    /// the portal fails
+    pub(super) async fn portal_for_message(
+        self: &Arc<Self>,
+        message: &Message,
+    ) -> Result<Option<Portal>> {
+        if let Some(parent_id) = self.thread_parent(message.channel_id).await? {
+            return self.portal_by_channel(parent_id).await;
+        }
+        if let Some(portal) = self.portal_by_channel(message.channel_id).await? {
+            return Ok(Some(portal));
+        }
+        if message.guild_id.is_none() {
+            return Ok(None);
+        }
+        let channel = self
+            .discord()?
+            .http
+            .channel(message.channel_id)
+            .exec()
+            .await?
+            .model()
+            .await?;
+        let channel_id = match channel.parent_id {
+            Some(parent_id) if is_thread(channel.kind) => parent_id,
+            _ => channel.id,
+        };
+        self.portal_for_channel(channel_id, message.guild_id).await
+    }
+
+    /// Returns all portals
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub async fn all_portals(self: &Arc<Self>) -> Result<Vec<Portal>> {
+        query_as!(
+            PortalRow,
+            "SELECT discord_channel_id, matrix_room_id, room_alias, guild_id, read_only, locale, features_enabled, features_disabled, metadata_sync FROM portals"
+        )
+        .fetch_all(&*self.db)
+        .await?
+        .into_iter()
+        .map(Portal::try_from)
+        .collect()
+    }
+
+    /// Returns a sample of portals
+    ///
+    /// The sample is a run of consecutive channel ids starting at a random one, like
+    /// `MessageMapping::sample`. Rows with invalid ids are skipped.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub(super) async fn sample_portals(self: &Arc<Self>, limit: u32) -> Result<Vec<Portal>> {
+        Ok(query_as!(
+            PortalRow,
+            r#"WITH start AS (SELECT MIN(discord_channel_id) + floor(random() * (MAX(discord_channel_id) - MIN(discord_channel_id) + 1))::BIGINT AS id FROM portals) (SELECT discord_channel_id AS "discord_channel_id!", matrix_room_id AS "matrix_room_id!", room_alias, guild_id, read_only AS "read_only!", locale, features_enabled AS "features_enabled!", features_disabled AS "features_disabled!", metadata_sync AS "metadata_sync!" FROM portals WHERE discord_channel_id >= (SELECT id FROM start) ORDER BY discord_channel_id LIMIT $1) UNION ALL (SELECT discord_channel_id, matrix_room_id, room_alias, guild_id, read_only, locale, features_enabled, features_disabled, metadata_sync FROM portals WHERE discord_channel_id < (SELECT id FROM start) ORDER BY discord_channel_id LIMIT $1) LIMIT $1"#,
+            i64::from(limit)
+        )
+        .fetch_all(&*self.db)
+        .await?
+        .into_iter()
+        .filter_map(|row| {
+            Portal::try_from(row)
+                .map_err(|e| warn!("Invalid portal row: {:?}", e))
+                .ok()
+        })
+        .collect())
+    }
+
+    /// Returns all portals of a guild
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub async fn portals_in_guild(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+    ) -> Result<Vec<Portal>> {
+        query_as!(
+            PortalRow,
+            "SELECT discord_channel_id, matrix_room_id, room_alias, guild_id, read_only, locale, features_enabled, features_disabled, metadata_sync FROM portals WHERE guild_id = $1",
+            snowflake::to_db(guild_id)
+        )
+        .fetch_all(&*self.db)
+        .await?
+        .into_iter()
+        .map(Portal::try_from)
+        .collect()
+    }
+
+    /// Looks up the portal for a matrix room id
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub async fn portal_by_room(self: &Arc<Self>, room_id: &RoomId) -> Result<Option<Portal>> {
+        query_as!(
+            PortalRow,
+            "SELECT discord_channel_id, matrix_room_id, room_alias, guild_id, read_only, locale, features_enabled, features_disabled, metadata_sync FROM portals WHERE matrix_room_id = $1",
+            room_id.as_str()
+        )
+        .fetch_optional(&*self.db)
+        .await?
+        .map(Portal::try_from)
+        .transpose()
+    }
+
+    /// Looks up the portal for a matrix room alias, resolving the alias if necessary
+    ///
+    /// The stored alias is only used as a hint. The alias is always resolved on the homeserver so
+    /// that repointed aliases are picked up, and the stored alias is refreshed afterwards.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub async fn portal_by_alias(self: &Arc<Self>, alias: &RoomAliasId) -> Result<Option<Portal>> {
+        let resolved = match self.client(None).await?.resolve_room_alias(alias).await {
+            Ok(response) => response.room_id,
+            Err(e) => {
+                debug!("Failed to resolve {}: {:?}, using stored alias", alias, e);
+                return query_as!(
+                    PortalRow,
+                    "SELECT discord_channel_id, matrix_room_id, room_alias, guild_id, read_only, locale, features_enabled, features_disabled, metadata_sync FROM portals WHERE room_alias = $1",
+                    alias.as_str()
+                )
+                .fetch_optional(&*self.db)
+                .await?
+                .map(Portal::try_from)
+                .transpose();
+            }
+        };
+        let portal = self.portal_by_room(&resolved).await?;
+        if let Some(ref portal) = portal {
+            if portal.alias.as_deref() != Some(alias) {
+                self.update_portal_alias(&portal.room_id, Some(alias))
+                    .await?;
+                return self.portal_by_room(&resolved).await;
+            }
+        }
+        Ok(portal)
+    }
+
+    /// Looks up the portal for a matrix room id or alias
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    pub async fn portal_by_room_or_alias(
+        self: &Arc<Self>,
+        room: &RoomOrAliasId,
+    ) -> Result<Option<Portal>> {
+        match <&RoomId>::try_from(room) {
+            Ok(room_id) => self.portal_by_room(room_id).await,
+            Err(alias) => self.portal_by_alias(alias).await,
+        }
+    }
+
+    /// Updates the stored alias of a portal
+    ///
+    /// The alias is removed from any other portal that previously used it.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub(super) async fn update_portal_alias(
+        self: &Arc<Self>,
+        room_id: &RoomId,
+        alias: Option<&RoomAliasId>,
+    ) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+        if let Some(alias) = alias {
+            query!(
+                "UPDATE portals SET room_alias = NULL WHERE room_alias = $1 AND matrix_room_id <> $2",
+                alias.as_str(),
+                room_id.as_str()
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+        query!(
+            "UPDATE portals SET room_alias = $2 WHERE matrix_room_id = $1",
+            room_id.as_str(),
+            alias.map(RoomAliasId::as_str)
+        )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Returns the locale used for text generated in a portal
+    ///
+    /// Falls back to the configured default locale.
+    #[must_use]
+    pub fn portal_locale(&self, portal: &Portal) -> Locale {
+        portal.locale.unwrap_or(self.config.bridge.default_locale)
+    }
+
+    /// Sets or clears the preferred locale of a portal
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub(super) async fn set_portal_locale(
+        self: &Arc<Self>,
+        room_id: &RoomId,
+        locale: Option<Locale>,
+    ) -> Result<()> {
+        query!(
+            "UPDATE portals SET locale = $2 WHERE matrix_room_id = $1",
+            room_id.as_str(),
+            locale.map(Locale::tag)
+        )
+        .execute(&*self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Handles the `locale` command
+    ///
+    /// `locale <room>` shows the locale of a portal, `locale <room> <tag>` sets it and
+    /// `locale <room> default` goes back to the configured default.
+    ///
+    /// # Errors
+    /// This function will return an error if the portal can't be updated or the reply could not
+    /// be sent
+    pub(super) async fn handle_locale_command(
+        self: &Arc<Self>,
+        sender: &UserId,
+        args: &[&str],
+        room: &Room,
+    ) -> Result<()> {
+        let reply = if sender == self.config.bridge.admin {
+            self.locale_command_reply(args).await?
+        } else {
+            "Only the bridge admin can change portal locales".to_owned()
+        };
+        self.send_message(room, RoomMessageEventContent::text_plain(reply))
+            .await?;
+        Ok(())
+    }
+
+    /// Runs the `locale` command and returns the reply
+    ///
+    /// # Errors
+    /// This function will return an error if the portal can't be updated
+    async fn locale_command_reply(self: &Arc<Self>, args: &[&str]) -> Result<String> {
+        let (target, tag) = match args {
+            ["locale", target] => (target, None),
+            ["locale", target, tag] => (target, Some(tag)),
+            _ => return Ok("Usage: locale <room> [<locale>|default]".to_owned()),
+        };
+        let target = match <&RoomOrAliasId>::try_from(*target) {
+            Ok(target) => target,
+            Err(_) => return Ok(format!("{} is not a room id or alias", target)),
+        };
+        let portal = match self.portal_by_room_or_alias(target).await? {
+            Some(portal) => portal,
+            None => return Ok(format!("{} is not a portal", target)),
+        };
+        let locale = match tag {
+            None => {
+                return Ok(match portal.locale {
+                    Some(locale) => format!("The locale of {} is {}", target, locale),
+                    None => format!(
+                        "{} uses the default locale {}",
+                        target, self.config.bridge.default_locale
+                    ),
+                })
+            }
+            Some(&"default") => None,
+            Some(tag) => match Locale::try_from(*tag) {
+                Ok(locale) => Some(locale),
+                Err(e) => return Ok(e.to_string()),
+            },
+        };
+        if self.dry_run {
+            info!(
+                "[dry-run] Would set the locale of {} to {:?}",
+                portal.room_id, locale
+            );
+            return Ok(format!("Would update the locale of {}", target));
+        }
+        self.set_portal_locale(&portal.room_id, locale).await?;
+        self.publish_portal_settings(&portal.room_id).await?;
+        Ok(match locale {
+            Some(locale) => format!("Set the locale of {} to {}", target, locale),
+            None => format!("{} now uses the default locale", target),
+        })
+    }
+
+    /// Moves a portal to a new room after a room upgrade
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub(super) async fn update_portal_room(
+        self: &Arc<Self>,
+        old_room_id: &RoomId,
+        new_room_id: &RoomId,
+    ) -> Result<()> {
+        let result = query!(
+            "UPDATE portals SET matrix_room_id = $2 WHERE matrix_room_id = $1",
+            old_room_id.as_str(),
+            new_room_id.as_str()
+        )
+        .execute(&*self.db)
+        .await?;
+        if result.rows_affected() > 0 {
+            info!("Portal {} was upgraded to {}", old_room_id, new_room_id);
+        }
+        Ok(())
+    }
+
+    /// Handle [`SyncRoomTombstoneEvent`]
+    ///
+    /// Follows room upgrades of portal rooms
+    #[tracing::instrument(skip(self))]
+    pub(super) async fn handle_room_tombstone_event(
+        self: &Arc<Self>,
+        event: SyncRoomTombstoneEvent,
+        room: Room,
+    ) -> Result<()> {
+        if let SyncStateEvent::Original(event) = event {
+            if self.portal_by_room(room.room_id()).await?.is_none() {
+                return Ok(());
+            }
+            let new_room_id = event.content.replacement_room;
+            if self.dry_run {
+                info!(
+                    "[dry-run] Would follow room upgrade {} -> {}",
+                    room.room_id(),
+                    new_room_id
+                );
+                return Ok(());
+            }
+            self.client(None)
+                .await?
+                .join_room_by_id(&new_room_id)
+                .await?;
+            self.update_portal_room(room.room_id(), &new_room_id)
+                .await?;
+            self.try_refresh_catalog().await;
+        }
+        Ok(())
+    }
+
+    /// Handle [`SyncRoomCanonicalAliasEvent`]
+    ///
+    /// Keeps the stored alias of a portal in sync with its canonical alias
+    #[tracing::instrument(skip(self))]
+    pub(super) async fn handle_room_canonical_alias_event(
+        self: &Arc<Self>,
+        event: SyncRoomCanonicalAliasEvent,
+        room: Room,
+    ) -> Result<()> {
+        if let SyncStateEvent::Original(event) = event {
+            if self.portal_by_room(room.room_id()).await?.is_none() {
+                return Ok(());
+            }
+            self.update_portal_alias(room.room_id(), event.content.alias.as_deref())
+                .await?;
+            self.try_refresh_catalog().await;
+        }
+        Ok(())
+    }
+}
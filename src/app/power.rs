@@ -0,0 +1,314 @@
+//! Matrix power levels for discord admins and moderators
+//!
+//! If `bridge.moderator_power` is enabled, double-puppeted users get elevated power levels in the
+//! portal rooms of a guild when they are administrators or moderators on discord, and lose them
+//! again when their roles change. Granted levels are recorded, so that levels set by room admins
+//! are never lowered or removed by the bridge.
+
+use std::sync::Arc;
+
+use super::{permissions::channel_permissions, App};
+use crate::snowflake;
+use anyhow::Result;
+use matrix_sdk::ruma::{events::StateEventType, OwnedUserId, RoomId, UserId};
+use serde_json::Value;
+use sqlx::query;
+use tracing::{info, warn};
+use twilight_model::{
+    guild::Permissions,
+    id::{
+        marker::{GuildMarker, RoleMarker, UserMarker},
+        Id,
+    },
+};
+
+/// Power level of discord administrators and guild owners
+const ADMIN_POWER: i64 = 100;
+
+/// Power level of discord moderators
+const MODERATOR_POWER: i64 = 50;
+
+/// Returns the power level matching guild-level discord permissions
+fn power_for(permissions: Permissions, owner: bool) -> i64 {
+    if owner || permissions.contains(Permissions::ADMINISTRATOR) {
+        ADMIN_POWER
+    } else if permissions.intersects(
+        Permissions::MANAGE_MESSAGES | Permissions::KICK_MEMBERS | Permissions::BAN_MEMBERS,
+    ) {
+        MODERATOR_POWER
+    } else {
+        0
+    }
+}
+
+/// Returns the new power level entry of a user, or `None` if it stays as it is
+///
+/// `current` is the user's entry in the power levels, `granted` the level the bridge granted
+/// before and `target` the level matching the discord roles. Levels above the target are only
+/// lowered if the bridge granted them.
+fn power_change(current: Option<i64>, granted: Option<i64>, target: i64) -> Option<Option<i64>> {
+    match current {
+        _ if target == 0 => {
+            if granted.is_some() && current == granted {
+                Some(None)
+            } else {
+                None
+            }
+        }
+        Some(current) if current == target => None,
+        Some(current) if current > target && granted != Some(current) => None,
+        _ => Some(Some(target)),
+    }
+}
+
+impl App {
+    /// Returns the matrix user of a discord user if it is double-puppeted
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    async fn double_puppeted_user(
+        self: &Arc<Self>,
+        user_id: Id<UserMarker>,
+    ) -> Result<Option<OwnedUserId>> {
+        if !self.config.bridge.double_puppet {
+            return Ok(None);
+        }
+        Ok(self
+            .linked_matrix_user(user_id)
+            .await?
+            .filter(|user| user.server_name().as_str() == self.config.homeserver.domain))
+    }
+
+    /// Returns the owner and the role permissions of a guild
+    ///
+    /// # Errors
+    /// This function will return an error if a request to discord fails
+    pub(super) async fn guild_permission_context(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+    ) -> Result<(Id<UserMarker>, Vec<(Id<RoleMarker>, Permissions)>)> {
+        let http = &self.discord()?.http;
+        let guild = http.guild(guild_id).exec().await?.model().await?;
+        let roles = http
+            .roles(guild_id)
+            .exec()
+            .await?
+            .models()
+            .await?
+            .into_iter()
+            .map(|role| (role.id, role.permissions))
+            .collect();
+        Ok((guild.owner_id, roles))
+    }
+
+    /// Sets the power level of a user in a portal room according to its discord roles
+    ///
+    /// # Errors
+    /// This function will return an error if the database query or updating the power levels
+    /// fails
+    #[allow(clippy::panic)]
+    async fn apply_moderator_power(
+        self: &Arc<Self>,
+        room_id: &RoomId,
+        user: &UserId,
+        target: i64,
+    ) -> Result<()> {
+        let room = match self.client.get_joined_room(room_id) {
+            Some(room) => room,
+            None => return Ok(()),
+        };
+        let event = match room
+            .get_state_event(StateEventType::RoomPowerLevels, "")
+            .await?
+        {
+            Some(event) => event,
+            None => return Ok(()),
+        };
+        let mut content = event.deserialize_as::<Value>()?["content"].take();
+        let current = content["users"][user.as_str()].as_i64();
+        let granted = query!(
+            "SELECT power_level FROM granted_power_levels WHERE matrix_room_id = $1 AND matrix_user_id = $2",
+            room_id.as_str(),
+            user.as_str()
+        )
+        .fetch_optional(&*self.db)
+        .await?
+        .map(|row| row.power_level);
+        let change = power_change(current, granted, target);
+        if self.dry_run {
+            if let Some(level) = change {
+                info!(
+                    "[dry-run] Would set the power level of {} in {} to {:?}",
+                    user, room_id, level
+                );
+            }
+            return Ok(());
+        }
+        if let Some(level) = change {
+            info!(
+                "Setting the power level of {} in {} to {:?}",
+                user, room_id, level
+            );
+            match (level, content["users"].as_object_mut()) {
+                (Some(level), Some(users)) => {
+                    users.insert(user.to_string(), level.into());
+                }
+                (Some(level), None) => {
+                    content["users"] = serde_json::json!({ user.as_str(): level });
+                }
+                (None, Some(users)) => {
+                    users.remove(user.as_str());
+                }
+                (None, None) => {}
+            }
+            self.pipeline
+                .run(&self.user_id, "state", async {
+                    room.send_state_event_raw(content, "m.room.power_levels", "")
+                        .await?;
+                    Ok(())
+                })
+                .await?;
+        }
+        match change {
+            Some(Some(level)) => {
+                query!(
+                    "INSERT INTO granted_power_levels (matrix_room_id, matrix_user_id, power_level) VALUES ($1, $2, $3) ON CONFLICT (matrix_room_id, matrix_user_id) DO UPDATE SET power_level = $3",
+                    room_id.as_str(),
+                    user.as_str(),
+                    level
+                )
+                .execute(&*self.db)
+                .await?;
+            }
+            _ if target == 0 => {
+                query!(
+                    "DELETE FROM granted_power_levels WHERE matrix_room_id = $1 AND matrix_user_id = $2",
+                    room_id.as_str(),
+                    user.as_str()
+                )
+                .execute(&*self.db)
+                .await?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Updates the power levels of a double-puppeted guild member in the portals of the guild
+    ///
+    /// # Errors
+    /// This function will return an error if the portals can't be read
+    async fn sync_member_power_with(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+        user: &UserId,
+        role_ids: &[Id<RoleMarker>],
+        (owner_id, roles): &(Id<UserMarker>, Vec<(Id<RoleMarker>, Permissions)>),
+    ) -> Result<()> {
+        let permissions = channel_permissions(guild_id, user_id, roles, role_ids, &[]);
+        let target = power_for(permissions, *owner_id == user_id);
+        for portal in self.portals_in_guild(guild_id).await? {
+            if let Err(e) = self
+                .apply_moderator_power(&portal.room_id, user, target)
+                .await
+            {
+                warn!(
+                    "Failed to update the power level of {} in {}: {:?}",
+                    user, portal.room_id, e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles a guild member joining or changing roles
+    ///
+    /// # Errors
+    /// This function will return an error if updating the power levels fails
+    pub(super) async fn sync_member_power(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+        role_ids: &[Id<RoleMarker>],
+    ) -> Result<()> {
+        if !self.config.bridge.moderator_power {
+            return Ok(());
+        }
+        let user = match self.double_puppeted_user(user_id).await? {
+            Some(user) => user,
+            None => return Ok(()),
+        };
+        let context = self.guild_permission_context(guild_id).await?;
+        self.sync_member_power_with(guild_id, user_id, &user, role_ids, &context)
+            .await
+    }
+
+    /// Updates the power levels of all double-puppeted members of a guild
+    ///
+    /// Called when roles or the owner of the guild change.
+    ///
+    /// # Errors
+    /// This function will return an error if a request to discord, the database query or
+    /// updating the power levels fails
+    #[allow(clippy::panic)]
+    pub(super) async fn sync_guild_power(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+    ) -> Result<()> {
+        if !self.config.bridge.moderator_power || !self.config.bridge.double_puppet {
+            return Ok(());
+        }
+        let linked =
+            query!("SELECT discord_user_id FROM discord_tokens WHERE discord_user_id IS NOT NULL")
+                .fetch_all(&*self.db)
+                .await?;
+        if linked.is_empty() {
+            return Ok(());
+        }
+        let context = self.guild_permission_context(guild_id).await?;
+        let http = &self.discord()?.http;
+        for row in linked {
+            let user_id = match row.discord_user_id {
+                Some(user_id) => snowflake::from_db(user_id)?,
+                None => continue,
+            };
+            let user = match self.double_puppeted_user(user_id).await? {
+                Some(user) => user,
+                None => continue,
+            };
+            // Users that aren't members of the guild can't be looked up
+            let member = match http.guild_member(guild_id, user_id).exec().await {
+                Ok(response) => response.model().await?,
+                Err(_) => continue,
+            };
+            self.sync_member_power_with(guild_id, user_id, &user, &member.roles, &context)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_permissions_to_power() {
+        assert_eq!(power_for(Permissions::ADMINISTRATOR, false), ADMIN_POWER);
+        assert_eq!(power_for(Permissions::empty(), true), ADMIN_POWER);
+        assert_eq!(power_for(Permissions::BAN_MEMBERS, false), MODERATOR_POWER);
+        assert_eq!(power_for(Permissions::SEND_MESSAGES, false), 0);
+    }
+
+    #[test]
+    fn keeps_manual_power_levels() {
+        assert_eq!(power_change(None, None, 50), Some(Some(50)));
+        assert_eq!(power_change(Some(50), Some(50), 50), None);
+        assert_eq!(power_change(Some(100), None, 50), None);
+        assert_eq!(power_change(Some(100), Some(100), 50), Some(Some(50)));
+        assert_eq!(power_change(Some(50), Some(50), 0), Some(None));
+        assert_eq!(power_change(Some(75), Some(50), 0), None);
+        assert_eq!(power_change(None, None, 0), None);
+    }
+}
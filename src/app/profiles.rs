@@ -0,0 +1,277 @@
+//! Profiles of puppets
+//!
+//! Puppets carry the name and avatar of their discord user, with the display name built from
+//! `bridge.displayname_template`. Avatars are mirrored once per avatar hash and kept until the
+//! user changes their avatar. Display names are reserved, so that puppets of users with the same
+//! name get their discriminator appended, see `reserve_name`. Nicknames differ per guild, so a nickname is set as the puppet's
+//! member profile in the portal rooms of its guild. The homeserver overwrites member profiles
+//! when the global display name changes, so nicknames are applied again after that.
+
+use std::sync::Arc;
+
+use super::{
+    media::MediaRetention,
+    names::{is_candidate, NameKind},
+    App,
+};
+use crate::snowflake;
+use anyhow::Result;
+use matrix_sdk::{
+    room::Room,
+    ruma::{events::StateEventType, MxcUri, OwnedMxcUri},
+};
+use serde_json::{json, Value};
+use sqlx::query;
+use tracing::info;
+use twilight_model::{
+    id::{
+        marker::{GuildMarker, UserMarker},
+        Id,
+    },
+    user::User,
+};
+
+/// Returns the URL of a user's avatar
+pub(super) fn avatar_url(user_id: Id<UserMarker>, avatar: &str) -> String {
+    format!(
+        "https://cdn.discordapp.com/avatars/{}/{}.png",
+        user_id, avatar
+    )
+}
+
+/// Builds the display name of a puppet from the template
+///
+/// Outside of guilds and for members without a nickname, the nickname is the username.
+fn render_displayname(template: &str, username: &str, nick: Option<&str>) -> String {
+    template
+        .split("{displayname}")
+        .map(|part| part.replace("{username}", username))
+        .collect::<Vec<_>>()
+        .join(nick.unwrap_or(username))
+}
+
+impl App {
+    /// Updates the display name and avatar of the puppet of a discord user
+    ///
+    /// `avatar` is the avatar hash of the user. Nothing is done if neither changed since the last
+    /// update. A changed display name releases the reserved one and reserves the new one.
+    ///
+    /// # Errors
+    /// This function will return an error if a database query, mirroring the avatar or updating
+    /// the profile fails
+    #[allow(clippy::panic)]
+    pub(super) async fn sync_puppet_profile(
+        self: &Arc<Self>,
+        user_id: Id<UserMarker>,
+        username: &str,
+        discriminator: u16,
+        avatar: Option<&str>,
+    ) -> Result<()> {
+        let base = render_displayname(&self.config.bridge.displayname_template, username, None);
+        let discriminator = format!("#{:04}", discriminator);
+        let stored = query!(
+            "SELECT displayname, avatar_hash, avatar_mxc FROM puppet_profiles WHERE discord_user_id = $1",
+            snowflake::to_db(user_id)
+        )
+        .fetch_optional(&*self.db)
+        .await?;
+        let (stored_name, avatar_changed, mut avatar_mxc) = match stored {
+            Some(stored) => (
+                Some(stored.displayname),
+                stored.avatar_hash.as_deref() != avatar,
+                stored.avatar_mxc.map(OwnedMxcUri::from),
+            ),
+            None => (None, true, None),
+        };
+        let name_changed = !stored_name.as_deref().map_or(false, |name| {
+            is_candidate(NameKind::Displayname, name, &base, &discriminator)
+        });
+        if !name_changed && !avatar_changed {
+            return Ok(());
+        }
+        if self.dry_run {
+            info!(
+                "[dry-run] Would set the profile of the puppet of {} to {:?}",
+                user_id, base
+            );
+            return Ok(());
+        }
+        let displayname = match stored_name {
+            Some(name) if !name_changed => name,
+            _ => {
+                let owner = user_id.to_string();
+                self.release_name(NameKind::Displayname, &owner).await?;
+                self.reserve_name(NameKind::Displayname, &owner, &base, &discriminator)
+                    .await?
+            }
+        };
+        if avatar_changed {
+            if let Some(old) = avatar_mxc.take() {
+                self.supersede_media(&old).await?;
+            }
+            avatar_mxc = match avatar {
+                Some(avatar) => {
+                    self.mirror_discord_media(
+                        &avatar_url(user_id, avatar),
+                        None,
+                        MediaRetention::Permanent,
+                    )
+                    .await?
+                }
+                None => None,
+            };
+        }
+        let client = self.client(Some(user_id)).await?;
+        let puppet = self.puppet_user_id(user_id)?;
+        self.pipeline
+            .run(&puppet, "profile", async {
+                let account = client.account();
+                if name_changed {
+                    account.set_display_name(Some(&displayname)).await?;
+                }
+                if avatar_changed {
+                    account.set_avatar_url(avatar_mxc.as_deref()).await?;
+                }
+                Ok(())
+            })
+            .await?;
+        query!(
+            "INSERT INTO puppet_profiles (discord_user_id, displayname, avatar_hash, avatar_mxc) VALUES ($1, $2, $3, $4) ON CONFLICT (discord_user_id) DO UPDATE SET displayname = $2, avatar_hash = $3, avatar_mxc = $4",
+            snowflake::to_db(user_id),
+            displayname,
+            avatar,
+            avatar_mxc.as_deref().map(MxcUri::as_str)
+        )
+        .execute(&*self.db)
+        .await?;
+        if name_changed {
+            query!(
+                "DELETE FROM puppet_nicks WHERE discord_user_id = $1",
+                snowflake::to_db(user_id)
+            )
+            .execute(&*self.db)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Sets the display name of a puppet in the portal rooms of a guild to its nickname
+    ///
+    /// # Errors
+    /// This function will return an error if a database query fails or a member event can't be
+    /// read or sent
+    #[allow(clippy::panic)]
+    async fn sync_puppet_nick(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+        username: &str,
+        nick: Option<&str>,
+    ) -> Result<()> {
+        let template = &self.config.bridge.displayname_template;
+        let displayname = render_displayname(template, username, nick);
+        let current = query!(
+            "SELECT displayname FROM puppet_nicks WHERE guild_id = $1 AND discord_user_id = $2",
+            snowflake::to_db(guild_id),
+            snowflake::to_db(user_id)
+        )
+        .fetch_optional(&*self.db)
+        .await?
+        .map_or_else(
+            || render_displayname(template, username, None),
+            |row| row.displayname,
+        );
+        if current == displayname {
+            return Ok(());
+        }
+        let puppet = self.puppet_user_id(user_id)?;
+        for portal in self.portals_in_guild(guild_id).await? {
+            let room = match self.client.get_joined_room(&portal.room_id) {
+                Some(room) => room,
+                None => continue,
+            };
+            let mut content = match room
+                .get_state_event(StateEventType::RoomMember, puppet.as_str())
+                .await?
+            {
+                Some(event) => event.deserialize_as::<Value>()?["content"].take(),
+                None => continue,
+            };
+            if content["membership"] != "join" || content["displayname"] == displayname.as_str() {
+                continue;
+            }
+            if self.dry_run {
+                info!(
+                    "[dry-run] Would set the display name of {} in {} to {:?}",
+                    puppet, portal.room_id, displayname
+                );
+                continue;
+            }
+            content["displayname"] = json!(displayname);
+            let room = match self
+                .matrix_room_for_client(Some(user_id), &portal.room_id)
+                .await?
+            {
+                Room::Joined(room) => room,
+                _ => continue,
+            };
+            self.pipeline
+                .run(&puppet, "state", async {
+                    room.send_state_event_raw(content, "m.room.member", puppet.as_str())
+                        .await?;
+                    Ok(())
+                })
+                .await?;
+        }
+        if !self.dry_run {
+            query!(
+                "INSERT INTO puppet_nicks (guild_id, discord_user_id, displayname) VALUES ($1, $2, $3) ON CONFLICT (guild_id, discord_user_id) DO UPDATE SET displayname = $3",
+                snowflake::to_db(guild_id),
+                snowflake::to_db(user_id),
+                displayname
+            )
+            .execute(&*self.db)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Updates the profile of a guild member's puppet after it joined or changed
+    ///
+    /// # Errors
+    /// This function will return an error if updating the profile or the nickname fails
+    pub(super) async fn handle_member_profile(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+        user: &User,
+        nick: Option<&str>,
+    ) -> Result<()> {
+        let avatar = user.avatar.map(|avatar| avatar.to_string());
+        self.sync_puppet_profile(user.id, &user.name, user.discriminator, avatar.as_deref())
+            .await?;
+        self.sync_puppet_nick(guild_id, user.id, &user.name, nick)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn displaynames_follow_the_template() {
+        let template = "{displayname} (Discord)";
+        assert_eq!(
+            render_displayname(template, "lotte", None),
+            "lotte (Discord)"
+        );
+        assert_eq!(
+            render_displayname(template, "lotte", Some("Lotte")),
+            "Lotte (Discord)"
+        );
+        assert_eq!(
+            render_displayname("{displayname} [{username}]", "{displayname}", Some("Lotte")),
+            "Lotte [{displayname}]"
+        );
+    }
+}
@@ -0,0 +1,258 @@
+//! Provisioning API for integrations
+//!
+//! Integrations manage portals at `/_provisioning/v1`, authenticated with an API token as bearer
+//! token. `GET /portals` lists the portals and needs the `read-only` scope, `PUT
+//! /portals/<channel>` bridges a discord channel and `DELETE /portals/<channel>` unbridges it,
+//! both need the `manage-portals` scope. Errors are answered in the format of the matrix APIs.
+
+use std::sync::{Arc, Weak};
+
+use super::{api_tokens::Scope, portals::Portal, App};
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::json;
+use tracing::{debug, warn};
+use twilight_model::id::{marker::ChannelMarker, Id};
+use warp::{
+    http::StatusCode,
+    reply::{self, Json, WithStatus},
+    Filter, Rejection,
+};
+
+/// A portal as reported by the provisioning API
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PortalInfo {
+    /// The discord channel
+    pub channel_id: String,
+    /// The guild the channel belongs to, if any
+    pub guild_id: Option<String>,
+    /// The matrix room
+    pub room_id: String,
+    /// The canonical alias of the room, if any
+    pub alias: Option<String>,
+}
+
+impl From<&Portal> for PortalInfo {
+    fn from(portal: &Portal) -> Self {
+        Self {
+            channel_id: portal.channel_id.to_string(),
+            guild_id: portal.guild_id.map(|guild_id| guild_id.to_string()),
+            room_id: portal.room_id.to_string(),
+            alias: portal.alias.as_ref().map(ToString::to_string),
+        }
+    }
+}
+
+/// Returns an error response
+fn error(status: StatusCode, errcode: &str, message: &str) -> WithStatus<Json> {
+    reply::with_status(
+        reply::json(&json!({ "errcode": errcode, "error": message })),
+        status,
+    )
+}
+
+/// Returns the response to a request that failed on the bridge's side
+fn internal_error(action: &str, e: &anyhow::Error) -> WithStatus<Json> {
+    warn!("Failed to {} for a provisioning request: {:?}", action, e);
+    error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "M_UNKNOWN",
+        &format!("Failed to {}", action),
+    )
+}
+
+/// Returns the token of a bearer authorization header
+fn bearer_token(authorization: Option<&str>) -> Option<&str> {
+    authorization?
+        .strip_prefix("Bearer ")
+        .filter(|token| !token.is_empty())
+}
+
+/// Checks the API token of a request against the scope it needs
+///
+/// Returns the response to send instead if the request isn't authorized.
+async fn authorize(
+    this: &Weak<App>,
+    authorization: Option<&str>,
+    required: Scope,
+) -> Result<Arc<App>, WithStatus<Json>> {
+    let this = this.upgrade().ok_or_else(|| {
+        error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "M_UNKNOWN",
+            "The bridge is shutting down",
+        )
+    })?;
+    let token = bearer_token(authorization).ok_or_else(|| {
+        error(
+            StatusCode::UNAUTHORIZED,
+            "M_MISSING_TOKEN",
+            "An API token is required",
+        )
+    })?;
+    match this.authorize_api_token(token, required).await {
+        Ok(Some(name)) => {
+            debug!("Provisioning request with the token {}", name);
+            Ok(this)
+        }
+        Ok(None) => Err(error(
+            StatusCode::FORBIDDEN,
+            "M_FORBIDDEN",
+            &format!(
+                "The API token is invalid or lacks the {} scope",
+                required.as_str()
+            ),
+        )),
+        Err(e) => Err(internal_error("check the API token", &e)),
+    }
+}
+
+/// Parses the channel of a request's path
+fn channel_id(channel_id: u64) -> Result<Id<ChannelMarker>, WithStatus<Json>> {
+    Id::new_checked(channel_id).ok_or_else(|| {
+        error(
+            StatusCode::BAD_REQUEST,
+            "M_INVALID_PARAM",
+            "Channel ids are never 0",
+        )
+    })
+}
+
+/// Lists the portals
+async fn list_portals(this: Weak<App>, authorization: Option<String>) -> WithStatus<Json> {
+    let this = match authorize(&this, authorization.as_deref(), Scope::ReadOnly).await {
+        Ok(this) => this,
+        Err(response) => return response,
+    };
+    match this.all_portals().await {
+        Ok(portals) => reply::with_status(
+            reply::json(&portals.iter().map(PortalInfo::from).collect::<Vec<_>>()),
+            StatusCode::OK,
+        ),
+        Err(e) => internal_error("list the portals", &e),
+    }
+}
+
+/// Bridges a discord channel
+async fn bridge_portal(
+    this: Weak<App>,
+    channel_id: u64,
+    authorization: Option<String>,
+) -> WithStatus<Json> {
+    let this = match authorize(&this, authorization.as_deref(), Scope::ManagePortals).await {
+        Ok(this) => this,
+        Err(response) => return response,
+    };
+    let channel_id = match self::channel_id(channel_id) {
+        Ok(channel_id) => channel_id,
+        Err(response) => return response,
+    };
+    match this.provision_portal(channel_id).await {
+        Ok(Some(portal)) => {
+            reply::with_status(reply::json(&PortalInfo::from(&portal)), StatusCode::OK)
+        }
+        Ok(None) => reply::with_status(reply::json(&json!({})), StatusCode::ACCEPTED),
+        Err(e) => internal_error(&format!("bridge {}", channel_id), &e),
+    }
+}
+
+/// Unbridges a discord channel
+async fn unbridge_portal(
+    this: Weak<App>,
+    channel_id: u64,
+    authorization: Option<String>,
+) -> WithStatus<Json> {
+    let this = match authorize(&this, authorization.as_deref(), Scope::ManagePortals).await {
+        Ok(this) => this,
+        Err(response) => return response,
+    };
+    let channel_id = match self::channel_id(channel_id) {
+        Ok(channel_id) => channel_id,
+        Err(response) => return response,
+    };
+    let portal = match this.portal_by_channel(channel_id).await {
+        Ok(Some(portal)) => portal,
+        Ok(None) => {
+            return error(
+                StatusCode::NOT_FOUND,
+                "M_NOT_FOUND",
+                "The channel is not bridged",
+            )
+        }
+        Err(e) => return internal_error("look up the portal", &e),
+    };
+    match this.unbridge_portal(&portal).await {
+        Ok(()) => reply::with_status(reply::json(&json!({})), StatusCode::OK),
+        Err(e) => internal_error(&format!("unbridge {}", channel_id), &e),
+    }
+}
+
+/// Returns the routes of the provisioning API
+pub(super) fn routes(
+    this: &Arc<App>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone {
+    let base = warp::path!("_provisioning" / "v1" / "portals" / ..);
+    let authorization = warp::header::optional::<String>("authorization");
+    let weak = Arc::downgrade(this);
+    let list = warp::get()
+        .and(base.clone())
+        .and(warp::path::end())
+        .and(authorization.clone())
+        .then(move |authorization| list_portals(Weak::clone(&weak), authorization));
+    let weak = Arc::downgrade(this);
+    let bridge = warp::put()
+        .and(base.clone())
+        .and(warp::path::param::<u64>())
+        .and(warp::path::end())
+        .and(authorization.clone())
+        .then(move |channel_id, authorization| {
+            bridge_portal(Weak::clone(&weak), channel_id, authorization)
+        });
+    let weak = Arc::downgrade(this);
+    let unbridge = warp::delete()
+        .and(base)
+        .and(warp::path::param::<u64>())
+        .and(warp::path::end())
+        .and(authorization)
+        .then(move |channel_id, authorization| {
+            unbridge_portal(Weak::clone(&weak), channel_id, authorization)
+        });
+    list.or(bridge).unify().or(unbridge).unify()
+}
+
+impl App {
+    /// Bridges a discord channel the bridge bot can see for the provisioning API
+    ///
+    /// Returns the existing portal if the channel is bridged already and `None` in dry-run mode.
+    ///
+    /// # Errors
+    /// This function will return an error if the channel can't be fetched or creating the portal
+    /// fails
+    async fn provision_portal(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+    ) -> Result<Option<Portal>> {
+        let channel = self
+            .discord()?
+            .http
+            .channel(channel_id)
+            .exec()
+            .await?
+            .model()
+            .await?;
+        self.portal_for_channel(channel_id, channel.guild_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_need_a_bearer_token() {
+        assert_eq!(bearer_token(Some("Bearer dmb_abc")), Some("dmb_abc"));
+        assert_eq!(bearer_token(Some("Bearer ")), None);
+        assert_eq!(bearer_token(Some("dmb_abc")), None);
+        assert_eq!(bearer_token(None), None);
+    }
+}
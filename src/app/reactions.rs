@@ -0,0 +1,546 @@
+//! Reaction bridging
+//!
+//! Matrix reactions are added on discord by the bridge bot. Before a reaction is sent, the bot's
+//! permissions in the channel and the availability of the emoji are checked, and the sender is
+//! told why a reaction could not be bridged. Discord reactions are sent to matrix as `m.reaction`
+//! annotations by the puppet of the reacting user, with custom emojis as their `:name:`.
+//!
+//! Bridged reactions are recorded in `reaction_map`, so that removing a reaction on one side
+//! removes it on the other. The bot's reaction on discord is only removed once no matrix user
+//! reacts with its emoji anymore. Unicode emojis are compared without variation selectors, which
+//! matrix clients usually add and discord leaves out.
+
+use std::sync::Arc;
+
+use super::{mappings::MessageMapping, App};
+use crate::{features::Feature, snowflake};
+use anyhow::Result;
+use matrix_sdk::{
+    room::Room,
+    ruma::{
+        events::{
+            reaction::{ReactionEventContent, Relation, SyncReactionEvent},
+            room::message::RoomMessageEventContent,
+        },
+        EventId, OwnedEventId, OwnedRoomId, RoomId,
+    },
+};
+use sqlx::query;
+use tracing::{debug, info};
+use twilight_http::request::channel::reaction::RequestReactionType;
+use twilight_model::{
+    channel::{Reaction, ReactionType},
+    guild::Permissions,
+    id::{
+        marker::{ChannelMarker, EmojiMarker, GuildMarker, MessageMarker, UserMarker},
+        Id,
+    },
+};
+
+/// Variation selector that requests the emoji presentation of a character
+const EMOJI_PRESENTATION: char = '\u{fe0f}';
+
+/// A custom emoji known to the bridge
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct CustomEmoji {
+    /// Emoji id
+    id: Id<EmojiMarker>,
+    /// Emoji name
+    name: String,
+    /// Guild the emoji belongs to, if known
+    guild_id: Option<Id<GuildMarker>>,
+}
+
+/// Emoji a reaction is sent with
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum ReactionEmoji {
+    /// Unicode emoji
+    Unicode(String),
+    /// Custom emoji
+    Custom(CustomEmoji),
+}
+
+impl ReactionEmoji {
+    /// Returns the key the emoji is recorded under in `reaction_map`
+    fn key(&self) -> String {
+        match self {
+            Self::Unicode(name) => name.clone(),
+            Self::Custom(emoji) => emoji.id.to_string(),
+        }
+    }
+}
+
+/// Strips variation selectors from a unicode emoji
+fn normalize_emoji(name: &str) -> String {
+    name.chars().filter(|&c| c != EMOJI_PRESENTATION).collect()
+}
+
+/// Returns the key a discord reaction is recorded under in `reaction_map`
+fn discord_reaction_key(emoji: &ReactionType) -> String {
+    match emoji {
+        ReactionType::Unicode { name } => normalize_emoji(name),
+        ReactionType::Custom { id, .. } => id.to_string(),
+    }
+}
+
+/// Returns the discord reaction of a key recorded in `reaction_map`
+fn request_reaction(key: &str) -> RequestReactionType<'_> {
+    match key.parse().ok().and_then(Id::new_checked) {
+        Some(id) => RequestReactionType::Custom { id, name: None },
+        None => RequestReactionType::Unicode { name: key },
+    }
+}
+
+/// Returns the matrix annotation key of a discord reaction
+///
+/// Custom emojis are sent as their `:name:`, or their id if discord doesn't know their name
+/// anymore.
+fn matrix_reaction_key(emoji: &ReactionType) -> String {
+    match emoji {
+        ReactionType::Unicode { name } => name.clone(),
+        ReactionType::Custom {
+            name: Some(name), ..
+        } => format!(":{}:", name),
+        ReactionType::Custom { id, .. } => format!(":{}:", id),
+    }
+}
+
+/// A bridged reaction
+#[derive(Clone, Debug)]
+struct ReactionMapping {
+    /// The `m.reaction` event
+    event_id: OwnedEventId,
+    /// Room of the reaction
+    room_id: OwnedRoomId,
+    /// Channel of the reacted message
+    channel_id: Id<ChannelMarker>,
+    /// The reacted message
+    message_id: Id<MessageMarker>,
+    /// The discord user that reacted
+    user_id: Id<UserMarker>,
+    /// Key of the emoji, see [`discord_reaction_key`]
+    emoji: String,
+    /// Whether the reaction was relayed from matrix to discord
+    relayed: bool,
+}
+
+/// Returns whether a reaction key refers to a custom emoji
+fn is_custom_key(key: &str) -> bool {
+    key.starts_with("mxc://") || (key.len() > 2 && key.starts_with(':') && key.ends_with(':'))
+}
+
+/// Checks whether a reaction can be sent and returns why not otherwise
+///
+/// `emoji` is the custom emoji the reaction key refers to, if any.
+fn check_reaction(
+    key: &str,
+    emoji: Option<CustomEmoji>,
+    guild_id: Option<Id<GuildMarker>>,
+    permissions: Permissions,
+) -> Result<ReactionEmoji, String> {
+    if !permissions.contains(Permissions::ADD_REACTIONS) {
+        return Err(
+            "The bridge is not allowed to add reactions in this discord channel".to_owned(),
+        );
+    }
+    let emoji = match emoji {
+        Some(emoji) => emoji,
+        None if is_custom_key(key) => {
+            return Err(format!(
+                "{} is not an emoji of a discord server the bridge is in",
+                key
+            ))
+        }
+        None => return Ok(ReactionEmoji::Unicode(normalize_emoji(key))),
+    };
+    let external = emoji.guild_id.is_none() || emoji.guild_id != guild_id;
+    if external && !permissions.contains(Permissions::USE_EXTERNAL_EMOJIS) {
+        return Err(format!(
+            "The emoji :{}: belongs to another discord server and external emojis are not allowed in this channel",
+            emoji.name
+        ));
+    }
+    Ok(ReactionEmoji::Custom(emoji))
+}
+
+impl App {
+    /// Records a bridged reaction
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    async fn insert_reaction_mapping(self: &Arc<Self>, mapping: &ReactionMapping) -> Result<()> {
+        query!(
+            "INSERT INTO reaction_map (matrix_event_id, matrix_room_id, discord_channel_id, discord_message_id, discord_user_id, emoji, relayed) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            mapping.event_id.as_str(),
+            mapping.room_id.as_str(),
+            snowflake::to_db(mapping.channel_id),
+            snowflake::to_db(mapping.message_id),
+            snowflake::to_db(mapping.user_id),
+            mapping.emoji,
+            mapping.relayed
+        )
+        .execute(&*self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Removes the record of a bridged reaction
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    async fn delete_reaction_mapping(self: &Arc<Self>, event_id: &EventId) -> Result<()> {
+        query!(
+            "DELETE FROM reaction_map WHERE matrix_event_id = $1",
+            event_id.as_str()
+        )
+        .execute(&*self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Bridges a discord reaction to matrix
+    ///
+    /// The reaction is sent to the first matrix event of the message.
+    ///
+    /// # Errors
+    /// This function will return an error if a database query, joining the puppet or sending the
+    /// reaction fails
+    pub(super) async fn handle_discord_reaction_add(
+        self: &Arc<Self>,
+        reaction: &Reaction,
+    ) -> Result<()> {
+        if reaction.user_id == self.discord()?.user_id {
+            return Ok(());
+        }
+        let message = match MessageMapping::for_message(&*self.db, reaction.message_id)
+            .await?
+            .into_iter()
+            .next()
+        {
+            Some(message) => message,
+            None => return Ok(()),
+        };
+        if !self
+            .room_feature(&message.room_id, Feature::Reactions)
+            .await?
+        {
+            return Ok(());
+        }
+        let key = matrix_reaction_key(&reaction.emoji);
+        if self.dry_run {
+            info!(
+                "[dry-run] Would react with {} to {} as {}",
+                key, message.event_id, reaction.user_id
+            );
+            return Ok(());
+        }
+        let sender = self.puppet_user_id(reaction.user_id)?;
+        let room = match self
+            .matrix_room_for_client(Some(reaction.user_id), &message.room_id)
+            .await?
+        {
+            Room::Joined(room) => room,
+            _ => {
+                debug!("Puppet {} could not join {}", sender, message.room_id);
+                return Ok(());
+            }
+        };
+        let content = ReactionEventContent::new(Relation::new(message.event_id.clone(), key));
+        let response = self
+            .pipeline
+            .run(&sender, "send", async {
+                Ok(room.send(content, None).await?)
+            })
+            .await?;
+        self.insert_reaction_mapping(&ReactionMapping {
+            event_id: response.event_id,
+            room_id: message.room_id,
+            channel_id: reaction.channel_id,
+            message_id: reaction.message_id,
+            user_id: reaction.user_id,
+            emoji: discord_reaction_key(&reaction.emoji),
+            relayed: false,
+        })
+        .await
+    }
+
+    /// Redacts the matrix reactions of a removed discord reaction
+    ///
+    /// # Errors
+    /// This function will return an error if a database query or the redaction fails
+    #[allow(clippy::panic)]
+    pub(super) async fn handle_discord_reaction_remove(
+        self: &Arc<Self>,
+        reaction: &Reaction,
+    ) -> Result<()> {
+        let rows = query!(
+            "SELECT matrix_event_id, matrix_room_id FROM reaction_map WHERE discord_message_id = $1 AND discord_user_id = $2 AND emoji = $3 AND NOT relayed",
+            snowflake::to_db(reaction.message_id),
+            snowflake::to_db(reaction.user_id),
+            discord_reaction_key(&reaction.emoji)
+        )
+        .fetch_all(&*self.db)
+        .await?;
+        for row in rows {
+            let event_id = OwnedEventId::try_from(row.matrix_event_id)?;
+            let room_id = OwnedRoomId::try_from(row.matrix_room_id)?;
+            if self.dry_run {
+                info!("[dry-run] Would redact reaction {}", event_id);
+                continue;
+            }
+            if let Some(room) = self
+                .client(Some(reaction.user_id))
+                .await?
+                .get_joined_room(&room_id)
+            {
+                self.pipeline
+                    .run(&self.puppet_user_id(reaction.user_id)?, "redact", async {
+                        room.redact(&event_id, None, None).await?;
+                        Ok(())
+                    })
+                    .await?;
+            }
+            self.delete_reaction_mapping(&event_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Removes the bot's reaction on discord for a redacted matrix reaction
+    ///
+    /// Returns whether the redacted event was a relayed reaction.
+    ///
+    /// # Errors
+    /// This function will return an error if a database query or the request to discord fails
+    #[allow(clippy::panic)]
+    pub(super) async fn handle_reaction_redaction(
+        self: &Arc<Self>,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<bool> {
+        let row = query!(
+            "SELECT discord_channel_id, discord_message_id, emoji FROM reaction_map WHERE matrix_event_id = $1 AND matrix_room_id = $2 AND relayed",
+            event_id.as_str(),
+            room_id.as_str()
+        )
+        .fetch_optional(&*self.db)
+        .await?;
+        let row = match row {
+            Some(row) => row,
+            None => return Ok(false),
+        };
+        if self.dry_run {
+            info!(
+                "[dry-run] Would remove the reaction {} on discord",
+                event_id
+            );
+            return Ok(true);
+        }
+        self.delete_reaction_mapping(event_id).await?;
+        let others = query!(
+            "SELECT matrix_event_id FROM reaction_map WHERE discord_message_id = $1 AND emoji = $2 AND relayed LIMIT 1",
+            row.discord_message_id,
+            row.emoji
+        )
+        .fetch_optional(&*self.db)
+        .await?;
+        if others.is_some() {
+            return Ok(true);
+        }
+        self.discord()?
+            .http
+            .delete_current_user_reaction(
+                snowflake::from_db(row.discord_channel_id)?,
+                snowflake::from_db(row.discord_message_id)?,
+                &request_reaction(&row.emoji),
+            )
+            .exec()
+            .await?;
+        Ok(true)
+    }
+
+    /// Looks up the custom emoji a reaction key refers to
+    ///
+    /// Keys can either be the MXC URI of a mirrored emoji or its `:name:`. Emojis of the guild
+    /// the reaction is sent to are preferred.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    async fn custom_emoji_for_key(
+        self: &Arc<Self>,
+        key: &str,
+        guild_id: Option<Id<GuildMarker>>,
+    ) -> Result<Option<CustomEmoji>> {
+        let name = key.trim_matches(':');
+        query!(
+            "SELECT emoji_id, guild_id, name FROM discord_emojis WHERE mxc_url = $1 OR name = $2 ORDER BY (guild_id IS NOT DISTINCT FROM $3) DESC LIMIT 1",
+            key,
+            name,
+            guild_id.map(snowflake::to_db)
+        )
+        .fetch_optional(&*self.db)
+        .await?
+        .map(|row| {
+            Ok(CustomEmoji {
+                id: snowflake::from_db(row.emoji_id)?,
+                name: row.name,
+                guild_id: row.guild_id.map(snowflake::from_db).transpose()?,
+            })
+        })
+        .transpose()
+    }
+
+    /// Bridges a matrix reaction to discord
+    ///
+    /// # Errors
+    /// This function will return an error if a request fails
+    #[tracing::instrument(skip(self))]
+    pub(super) async fn handle_reaction_event(
+        self: &Arc<Self>,
+        event: SyncReactionEvent,
+        room: Room,
+    ) -> Result<()> {
+        let event = match event.as_original() {
+            Some(event) => event,
+            None => return Ok(()),
+        };
+        if self.is_matrix_echo(&event.sender, &event.event_id).await? {
+            return Ok(());
+        }
+        if !self
+            .room_feature(room.room_id(), Feature::Reactions)
+            .await?
+        {
+            return Ok(());
+        }
+        let relation = &event.content.relates_to;
+        let (channel_id, message_id) =
+            match self.discord_message_for_event(&relation.event_id).await? {
+                Some(message) => message,
+                None => {
+                    debug!("Reaction to unbridged event {}", relation.event_id);
+                    return Ok(());
+                }
+            };
+        let (guild_id, permissions) = self.bot_channel_permissions(channel_id).await?;
+        let emoji = self.custom_emoji_for_key(&relation.key, guild_id).await?;
+        let emoji = match check_reaction(&relation.key, emoji, guild_id, permissions) {
+            Ok(emoji) => emoji,
+            Err(reason) => {
+                info!("Not bridging reaction {}: {}", event.event_id, reason);
+                let content = RoomMessageEventContent::notice_plain(format!(
+                    "Your reaction {} could not be bridged to discord: {}",
+                    relation.key, reason
+                ));
+                self.send_message(&room, content).await?;
+                return Ok(());
+            }
+        };
+        if self.dry_run {
+            info!(
+                "[dry-run] Would react with {:?} to message {} in {}",
+                emoji, message_id, channel_id
+            );
+            return Ok(());
+        }
+        let reaction = match &emoji {
+            ReactionEmoji::Unicode(name) => RequestReactionType::Unicode { name },
+            ReactionEmoji::Custom(emoji) => RequestReactionType::Custom {
+                id: emoji.id,
+                name: Some(&emoji.name),
+            },
+        };
+        let discord = self.discord()?;
+        discord
+            .http
+            .create_reaction(channel_id, message_id, &reaction)
+            .exec()
+            .await?;
+        self.insert_reaction_mapping(&ReactionMapping {
+            event_id: event.event_id.clone(),
+            room_id: room.room_id().to_owned(),
+            channel_id,
+            message_id,
+            user_id: discord.user_id,
+            emoji: emoji.key(),
+            relayed: true,
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUILD: Id<GuildMarker> = Id::new(1);
+
+    fn emoji(guild_id: u64) -> CustomEmoji {
+        CustomEmoji {
+            id: Id::new(10),
+            name: "blobcat".to_owned(),
+            guild_id: Some(Id::new(guild_id)),
+        }
+    }
+
+    #[test]
+    fn reactions_need_permission() {
+        assert!(check_reaction("👍", None, Some(GUILD), Permissions::empty()).is_err());
+        assert_eq!(
+            check_reaction("👍", None, Some(GUILD), Permissions::ADD_REACTIONS),
+            Ok(ReactionEmoji::Unicode("👍".to_owned()))
+        );
+    }
+
+    #[test]
+    fn external_emojis_need_permission() {
+        let permissions = Permissions::ADD_REACTIONS;
+        assert!(check_reaction(":blobcat:", Some(emoji(2)), Some(GUILD), permissions).is_err());
+        assert_eq!(
+            check_reaction(":blobcat:", Some(emoji(1)), Some(GUILD), permissions),
+            Ok(ReactionEmoji::Custom(emoji(1)))
+        );
+        let permissions = permissions | Permissions::USE_EXTERNAL_EMOJIS;
+        assert!(check_reaction(":blobcat:", Some(emoji(2)), Some(GUILD), permissions).is_ok());
+    }
+
+    #[test]
+    fn variation_selectors_are_ignored() {
+        assert_eq!(
+            check_reaction("❤\u{fe0f}", None, Some(GUILD), Permissions::ADD_REACTIONS),
+            Ok(ReactionEmoji::Unicode("❤".to_owned()))
+        );
+        let heart = ReactionType::Unicode {
+            name: "❤\u{fe0f}".to_owned(),
+        };
+        assert_eq!(discord_reaction_key(&heart), "❤");
+    }
+
+    #[test]
+    fn custom_emojis_are_sent_as_names() {
+        let emoji = ReactionType::Custom {
+            animated: false,
+            id: Id::new(10),
+            name: Some("blobcat".to_owned()),
+        };
+        assert_eq!(matrix_reaction_key(&emoji), ":blobcat:");
+        assert_eq!(discord_reaction_key(&emoji), "10");
+        assert!(matches!(
+            request_reaction("10"),
+            RequestReactionType::Custom { name: None, .. }
+        ));
+        assert!(matches!(
+            request_reaction("❤"),
+            RequestReactionType::Unicode { name: "❤" }
+        ));
+    }
+
+    #[test]
+    fn unknown_custom_emojis_are_rejected() {
+        let permissions = Permissions::ADD_REACTIONS | Permissions::USE_EXTERNAL_EMOJIS;
+        assert!(check_reaction("mxc://example.com/abc", None, None, permissions).is_err());
+        assert!(check_reaction(":unknown:", None, None, permissions).is_err());
+    }
+}
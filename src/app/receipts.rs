@@ -0,0 +1,282 @@
+//! Read receipt synchronization
+//!
+//! Read receipts of matrix users that logged in with their own discord account mark the discord
+//! message of the read event as read for that account. Bots aren't told when users read a
+//! channel on discord, so a logged-in user sending a message on discord counts as reading the
+//! channel up to the message before it, which is sent to matrix as a read receipt of the user's
+//! double puppet.
+//!
+//! The latest message read in each direction is kept per user and channel, so receipts the
+//! bridge sent itself and receipts for older messages aren't bridged back. Messages of matrix
+//! threads are bridged to discord threads, and threaded read receipts (MSC3771) only mark their
+//! discord thread as read, never the parent channel.
+
+use std::{collections::HashMap, sync::Arc};
+
+use super::{mappings::MessageMapping, App};
+use crate::features::Feature;
+use anyhow::Result;
+use matrix_sdk::{
+    room::Room,
+    ruma::{
+        api::client::receipt::create_receipt,
+        events::{
+            receipt::{ReceiptEventContent, ReceiptType},
+            SyncEphemeralRoomEvent,
+        },
+        EventId, OwnedEventId, OwnedUserId, UserId,
+    },
+};
+use serde_json::Value;
+use tracing::{debug, info};
+use twilight_model::{
+    channel::Message,
+    id::{
+        marker::{ChannelMarker, MessageMarker},
+        Id,
+    },
+};
+
+/// Returns the users and the events they read
+fn read_receipts(content: &ReceiptEventContent) -> Vec<(&UserId, &EventId)> {
+    content
+        .iter()
+        .flat_map(|(event_id, receipts)| {
+            receipts
+                .get(&ReceiptType::Read)
+                .into_iter()
+                .flat_map(move |users| users.keys().map(move |user| (&**user, &**event_id)))
+        })
+        .collect()
+}
+
+/// Thread roots of threaded read receipts by reader and read event
+pub(super) type ReceiptThreads = HashMap<(OwnedUserId, OwnedEventId), OwnedEventId>;
+
+/// Returns the threads of the read receipts of a receipt event
+///
+/// Receipts for the main timeline and unthreaded receipts aren't included.
+pub(super) fn receipt_threads(event: &str) -> ReceiptThreads {
+    let event = serde_json::from_str::<Value>(event).unwrap_or_default();
+    let mut threads = ReceiptThreads::new();
+    for (event_id, receipts) in event["content"].as_object().into_iter().flatten() {
+        for (user, receipt) in receipts["m.read"].as_object().into_iter().flatten() {
+            let thread_id = match receipt["thread_id"].as_str() {
+                Some(thread_id) if thread_id != "main" => thread_id,
+                _ => continue,
+            };
+            if let (Ok(user), Ok(event_id), Ok(thread_id)) = (
+                OwnedUserId::try_from(user.as_str()),
+                OwnedEventId::try_from(event_id.as_str()),
+                OwnedEventId::try_from(thread_id),
+            ) {
+                threads.insert((user, event_id), thread_id);
+            }
+        }
+    }
+    threads
+}
+
+impl App {
+    /// Records that a user read a channel up to a message
+    ///
+    /// Returns `false` if the user already read a later message.
+    fn advance_read_marker(
+        &self,
+        user: &UserId,
+        channel_id: Id<ChannelMarker>,
+        message_id: Id<MessageMarker>,
+    ) -> bool {
+        let mut marker = self
+            .read_markers
+            .entry((user.to_owned(), channel_id))
+            .or_insert(message_id);
+        if *marker > message_id {
+            return false;
+        }
+        *marker = message_id;
+        true
+    }
+
+    /// Marks a discord message as read for a user's own account
+    ///
+    /// # Errors
+    /// This function will return an error if the request fails
+    async fn ack_discord_message(
+        self: &Arc<Self>,
+        token: &str,
+        channel_id: Id<ChannelMarker>,
+        message_id: Id<MessageMarker>,
+    ) -> Result<()> {
+        self.http
+            .post(format!(
+                "https://discord.com/api/v10/channels/{}/messages/{}/ack",
+                channel_id, message_id
+            ))
+            .header(reqwest::header::AUTHORIZATION, token)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(r#"{"token":null}"#)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Bridges the read receipts of a portal to the discord accounts of their users
+    ///
+    /// Threaded receipts are only bridged if the read message is in the discord thread of their
+    /// matrix thread, see `receipt_threads`.
+    ///
+    /// # Errors
+    /// This function will return an error if a database query or a request to discord fails
+    pub(super) async fn handle_receipt_event(
+        self: &Arc<Self>,
+        event: SyncEphemeralRoomEvent<ReceiptEventContent>,
+        room: Room,
+        threads: ReceiptThreads,
+    ) -> Result<()> {
+        if !self.room_feature(room.room_id(), Feature::Receipts).await? {
+            return Ok(());
+        }
+        for (user, event_id) in read_receipts(&event.content) {
+            if self.is_bridge_user(user) {
+                continue;
+            }
+            let mapping = match MessageMapping::for_event(&*self.db, event_id).await? {
+                Some(mapping) => mapping,
+                None => continue,
+            };
+            if let Some(root) = threads.get(&(user.to_owned(), event_id.to_owned())) {
+                let thread_id = self.thread_for_root(room.room_id(), root).await?;
+                if thread_id != Some(mapping.channel_id) {
+                    debug!(
+                        "Not bridging the receipt of {} for {}, it isn't in the discord thread of {}",
+                        user, event_id, root
+                    );
+                    continue;
+                }
+            }
+            let token = match self.user_discord_token(user).await? {
+                Some(token) => token,
+                None => continue,
+            };
+            if !self.advance_read_marker(user, mapping.channel_id, mapping.message_id) {
+                continue;
+            }
+            if self.dry_run {
+                info!(
+                    "[dry-run] Would mark {} in {} as read for {}",
+                    mapping.message_id, mapping.channel_id, user
+                );
+                continue;
+            }
+            self.ack_discord_message(&token, mapping.channel_id, mapping.message_id)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Sends a read receipt for a discord channel as the double puppet of a message's author
+    ///
+    /// # Errors
+    /// This function will return an error if a database query or sending the receipt fails
+    pub(super) async fn handle_discord_read(self: &Arc<Self>, message: &Message) -> Result<()> {
+        let user = match self.linked_matrix_user(message.author.id).await? {
+            Some(user) => user,
+            None => return Ok(()),
+        };
+        let mapping =
+            match MessageMapping::latest_before(&*self.db, message.channel_id, message.id).await? {
+                Some(mapping) => mapping,
+                None => return Ok(()),
+            };
+        if !self
+            .room_feature(&mapping.room_id, Feature::Receipts)
+            .await?
+        {
+            return Ok(());
+        }
+        let client = match self.double_puppet_client(&user).await? {
+            Some(client) => client,
+            None => {
+                debug!("No double puppet to send the read receipt of {} with", user);
+                return Ok(());
+            }
+        };
+        if !self.advance_read_marker(&user, mapping.channel_id, mapping.message_id) {
+            return Ok(());
+        }
+        if self.dry_run {
+            info!(
+                "[dry-run] Would mark {} as read for {}",
+                mapping.event_id, user
+            );
+            return Ok(());
+        }
+        self.pipeline
+            .run(&user, "receipt", async {
+                client
+                    .send(
+                        create_receipt::v3::Request::new(
+                            &mapping.room_id,
+                            create_receipt::v3::ReceiptType::Read,
+                            &mapping.event_id,
+                        ),
+                        None,
+                    )
+                    .await?;
+                Ok(())
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn only_read_receipts_are_bridged() {
+        let content = serde_json::from_value::<ReceiptEventContent>(json!({
+            "$read:chir.rs": {
+                "m.read": { "@alice:chir.rs": { "ts": 1 } },
+            },
+            "$private:chir.rs": {
+                "m.read.private": { "@bob:chir.rs": { "ts": 2 } },
+            },
+        }))
+        .expect("valid receipt content");
+        let receipts = read_receipts(&content);
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].0, "@alice:chir.rs");
+        assert_eq!(receipts[0].1, "$read:chir.rs");
+    }
+
+    #[test]
+    fn threaded_receipts_name_their_thread() {
+        let event = json!({
+            "type": "m.receipt",
+            "content": {
+                "$reply:chir.rs": {
+                    "m.read": {
+                        "@alice:chir.rs": { "ts": 1, "thread_id": "$root:chir.rs" },
+                        "@bob:chir.rs": { "ts": 1, "thread_id": "main" },
+                        "@carol:chir.rs": { "ts": 1 },
+                    },
+                },
+            },
+        });
+        let threads = receipt_threads(&event.to_string());
+        assert_eq!(threads.len(), 1);
+        assert_eq!(
+            threads
+                .get(&(
+                    crate::testkit::user("alice"),
+                    OwnedEventId::try_from("$reply:chir.rs").expect("valid event id")
+                ))
+                .map(OwnedEventId::as_str),
+            Some("$root:chir.rs")
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! Deletions and redactions
+//!
+//! Deleting a discord message redacts the matrix events it was bridged to, as the user that sent
+//! them. Redacting a matrix event that was relayed to discord deletes the discord message, through
+//! the webhook it was sent with, as the sender's own discord account, or as the bridge bot.
+//! Redactions of relayed reactions are handled in `reactions`. Redactions and deletions caused by
+//! the bridge itself aren't bridged back.
+
+use std::sync::Arc;
+
+use super::{mappings::MessageMapping, App};
+use anyhow::{anyhow, Result};
+use matrix_sdk::{
+    room::Room,
+    ruma::{events::room::redaction::SyncRoomRedactionEvent, UserId},
+};
+use tracing::{debug, info};
+use twilight_model::id::{
+    marker::{ChannelMarker, MessageMarker},
+    Id,
+};
+
+/// Reason given for redactions of deleted discord messages
+const DELETED_REASON: &str = "Deleted on discord";
+
+impl App {
+    /// Returns whether a matrix user is the bridge bot or one of its puppets
+    pub(super) fn is_bridge_user(&self, user: &UserId) -> bool {
+        user == self.user_id
+            || self.puppet_discord_id(user).is_some()
+            || self.webhook_clients.contains_key(user.localpart())
+    }
+
+    /// Redacts the matrix events of a deleted discord message
+    ///
+    /// # Errors
+    /// This function will return an error if a database query, reading an event or redacting it
+    /// fails
+    pub(super) async fn handle_discord_delete(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+        message_id: Id<MessageMarker>,
+    ) -> Result<()> {
+        for mapping in MessageMapping::for_message(&*self.db, message_id).await? {
+            // The matrix event of a relayed message is already redacted if the deletion came
+            // from matrix
+            if mapping.relayed {
+                mapping.delete(&*self.db).await?;
+                continue;
+            }
+            let room = match self.client.get_joined_room(&mapping.room_id) {
+                Some(room) => room,
+                None => continue,
+            };
+            let sender = self.event_sender(&room, &mapping.event_id).await?;
+            let client = match self.bridged_sender_client(&sender).await? {
+                Some(client) => client,
+                None => {
+                    debug!(
+                        "Not redacting {}, its sender {} isn't bridged",
+                        mapping.event_id, sender
+                    );
+                    continue;
+                }
+            };
+            let room = match client.get_joined_room(&mapping.room_id) {
+                Some(room) => room,
+                None => continue,
+            };
+            if self.dry_run {
+                info!(
+                    "[dry-run] Would redact {} for the deletion of {} in {}",
+                    mapping.event_id, message_id, channel_id
+                );
+                continue;
+            }
+            self.pipeline
+                .run(&sender, "redact", async {
+                    room.redact(&mapping.event_id, Some(DELETED_REASON), None)
+                        .await?;
+                    Ok(())
+                })
+                .await?;
+            mapping.delete(&*self.db).await?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the discord message of a redacted matrix event
+    ///
+    /// # Errors
+    /// This function will return an error if a database query or the request to discord fails
+    pub(super) async fn handle_redaction_event(
+        self: &Arc<Self>,
+        event: SyncRoomRedactionEvent,
+        room: Room,
+    ) -> Result<()> {
+        let event = match event {
+            SyncRoomRedactionEvent::Original(event) => event,
+            SyncRoomRedactionEvent::Redacted(_) => return Ok(()),
+        };
+        if self.is_matrix_echo(&event.sender, &event.event_id).await?
+            || self
+                .handle_reaction_redaction(room.room_id(), &event.redacts)
+                .await?
+        {
+            return Ok(());
+        }
+        let mapping = match MessageMapping::relayed_for_event(&*self.db, &event.redacts).await? {
+            Some(mapping) if *mapping.room_id == *room.room_id() => mapping,
+            _ => return Ok(()),
+        };
+        if self.dry_run {
+            info!(
+                "[dry-run] Would delete {} in {} for the redaction of {}",
+                mapping.message_id, mapping.channel_id, event.redacts
+            );
+            return Ok(());
+        }
+        let http = &self.discord()?.http;
+        if let Some(webhook_id) = mapping.webhook_id {
+            let token = match self.portal_webhook(mapping.channel_id).await? {
+                Some((id, token)) if id == webhook_id => token,
+                _ => {
+                    return Err(anyhow!(
+                        "The webhook {} of {} was removed or replaced",
+                        webhook_id,
+                        mapping.channel_id
+                    ))
+                }
+            };
+            http.delete_webhook_message(webhook_id, &token, mapping.message_id)
+                .exec()
+                .await?;
+        } else {
+            let original_sender = match self.client.get_joined_room(&mapping.room_id) {
+                Some(joined) => Some(self.event_sender(&joined, &mapping.event_id).await?),
+                None => None,
+            };
+            let puppeted = match original_sender {
+                Some(ref sender) => {
+                    self.puppeted_message_http(sender, mapping.channel_id, mapping.message_id)
+                        .await?
+                }
+                None => None,
+            };
+            match puppeted {
+                Some(client) => {
+                    client
+                        .delete_message(mapping.channel_id, mapping.message_id)
+                        .exec()
+                        .await?;
+                }
+                None => {
+                    http.delete_message(mapping.channel_id, mapping.message_id)
+                        .exec()
+                        .await?;
+                }
+            }
+        }
+        mapping.delete(&*self.db).await?;
+        Ok(())
+    }
+}
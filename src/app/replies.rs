@@ -0,0 +1,172 @@
+//! Replies
+//!
+//! Discord replies are sent to matrix as rich replies to the first event of the replied message.
+//! Matrix rich replies to an event that was bridged from or relayed to the same channel are sent
+//! to discord with a message reference. Webhooks can't send references, so messages sent through
+//! the portal's webhook start with a link to the replied message instead.
+
+use std::sync::Arc;
+
+use super::{mappings::MessageMapping, App};
+use anyhow::Result;
+use matrix_sdk::ruma::{
+    events::room::message::{InReplyTo, Relation, RoomMessageEventContent},
+    EventId, OwnedEventId,
+};
+use twilight_model::{
+    channel::message::MessageReference,
+    id::{
+        marker::{ChannelMarker, GuildMarker, MessageMarker},
+        Id,
+    },
+};
+
+/// Returns the link to a discord message
+pub(super) fn message_link(
+    guild_id: Option<Id<GuildMarker>>,
+    channel_id: Id<ChannelMarker>,
+    message_id: Id<MessageMarker>,
+) -> String {
+    let guild = guild_id.map_or_else(|| "@me".to_owned(), |id| id.to_string());
+    format!(
+        "https://discord.com/channels/{}/{}/{}",
+        guild, channel_id, message_id
+    )
+}
+
+/// Returns the event matrix content is a rich reply to
+fn replied_event(content: &RoomMessageEventContent) -> Option<&EventId> {
+    match content.relates_to {
+        Some(Relation::Reply { ref in_reply_to }) => Some(&in_reply_to.event_id),
+        _ => None,
+    }
+}
+
+/// Makes matrix content a rich reply to an event
+fn set_reply(content: &mut RoomMessageEventContent, event_id: OwnedEventId) {
+    content.relates_to = Some(Relation::Reply {
+        in_reply_to: InReplyTo::new(event_id),
+    });
+}
+
+impl App {
+    /// Returns the matrix event a reply to a discord message refers to
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    pub(super) async fn matrix_reply_target(
+        self: &Arc<Self>,
+        message_id: Id<MessageMarker>,
+    ) -> Result<Option<OwnedEventId>> {
+        Ok(MessageMapping::for_message(&*self.db, message_id)
+            .await?
+            .into_iter()
+            .next()
+            .map(|mapping| mapping.event_id))
+    }
+
+    /// Makes matrix content of a discord reply a rich reply
+    ///
+    /// Content of replies to messages that weren't bridged is left alone.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    pub(super) async fn apply_discord_reply(
+        self: &Arc<Self>,
+        content: &mut RoomMessageEventContent,
+        reference: Option<&MessageReference>,
+    ) -> Result<()> {
+        let message_id = match reference.and_then(|reference| reference.message_id) {
+            Some(message_id) => message_id,
+            None => return Ok(()),
+        };
+        if let Some(event_id) = self.matrix_reply_target(message_id).await? {
+            set_reply(content, event_id);
+        }
+        Ok(())
+    }
+
+    /// Returns the discord message a matrix rich reply refers to
+    ///
+    /// Returns `None` if the content isn't a reply, or the replied event wasn't bridged to
+    /// `channel_id`. The plain-text body of replies still contains the quote of the replied
+    /// message, see [`crate::fallback::strip_reply_fallback`].
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    pub async fn discord_reply_target(
+        self: &Arc<Self>,
+        content: &RoomMessageEventContent,
+        channel_id: Id<ChannelMarker>,
+    ) -> Result<Option<Id<MessageMarker>>> {
+        let event_id = match replied_event(content) {
+            Some(event_id) => event_id,
+            None => return Ok(None),
+        };
+        Ok(MessageMapping::for_event(&*self.db, event_id)
+            .await?
+            .filter(|mapping| mapping.channel_id == channel_id)
+            .map(|mapping| mapping.message_id))
+    }
+
+    /// Returns the line a webhook message replying to a discord message starts with
+    ///
+    /// # Errors
+    /// This function will return an error if the portal lookup fails
+    pub(super) async fn webhook_reply_line(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+        message_id: Id<MessageMarker>,
+    ) -> Result<String> {
+        let portal_channel = self.portal_channel(channel_id).await?;
+        let guild_id = self
+            .portal_by_channel(portal_channel)
+            .await?
+            .and_then(|portal| portal.guild_id);
+        Ok(format!(
+            "> Reply to {}",
+            message_link(guild_id, channel_id, message_id)
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit;
+    use matrix_sdk::ruma::events::SyncMessageLikeEvent;
+
+    #[test]
+    fn links_discord_messages() {
+        assert_eq!(
+            message_link(Some(Id::new(1)), Id::new(2), Id::new(3)),
+            "https://discord.com/channels/1/2/3"
+        );
+        assert_eq!(
+            message_link(None, Id::new(2), Id::new(3)),
+            "https://discord.com/channels/@me/2/3"
+        );
+    }
+
+    #[test]
+    fn matrix_replies_name_the_replied_event() {
+        let reply = match testkit::room_reply(&testkit::user("lotte"), "b", "answer", "$a:chir.rs")
+        {
+            SyncMessageLikeEvent::Original(event) => Some(event.content),
+            SyncMessageLikeEvent::Redacted(_) => None,
+        }
+        .expect("reply is not redacted");
+        assert_eq!(
+            replied_event(&reply).map(EventId::as_str),
+            Some("$a:chir.rs")
+        );
+        assert_eq!(
+            crate::fallback::strip_reply_fallback(reply.body()),
+            "answer"
+        );
+        assert_eq!(
+            replied_event(&RoomMessageEventContent::text_plain("hi")),
+            None
+        );
+    }
+}
@@ -0,0 +1,372 @@
+//! Channel metadata in portal rooms
+//!
+//! The name and topic of a discord channel are kept in sync with its portal room, and the icon
+//! of the guild becomes the avatar of its portal rooms when it changes. Portals choose the
+//! direction of the sync with the `metadata_sync` setting: not at all, from discord only, or in
+//! both directions. Matrix changes are only applied on discord for users whose linked discord
+//! account may manage the channel.
+
+use std::sync::Arc;
+
+use super::{portals::Portal, topic::strip_topic_metadata, App};
+use anyhow::{anyhow, Error, Result};
+use matrix_sdk::{
+    room::Room,
+    ruma::{
+        events::{
+            room::{
+                message::RoomMessageEventContent, name::SyncRoomNameEvent,
+                topic::SyncRoomTopicEvent,
+            },
+            SyncStateEvent,
+        },
+        MxcUri, RoomId, RoomName, RoomOrAliasId, UserId,
+    },
+};
+use serde_json::json;
+use sqlx::query;
+use tracing::{debug, info, warn};
+use twilight_model::{channel::Channel, guild::Permissions};
+
+/// Direction in which channel metadata is synced
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetadataSync {
+    /// Channel and room metadata are independent
+    Off,
+    /// Discord changes are applied to the room
+    FromDiscord,
+    /// Changes on either side are applied to the other
+    Both,
+}
+
+impl Default for MetadataSync {
+    fn default() -> Self {
+        Self::FromDiscord
+    }
+}
+
+impl MetadataSync {
+    /// Returns the name of the direction in settings and commands
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::FromDiscord => "from_discord",
+            Self::Both => "both",
+        }
+    }
+
+    /// Returns whether discord changes are applied to the room
+    #[must_use]
+    pub const fn syncs_from_discord(self) -> bool {
+        !matches!(self, Self::Off)
+    }
+
+    /// Returns whether matrix changes are applied to the channel
+    #[must_use]
+    pub const fn syncs_to_discord(self) -> bool {
+        matches!(self, Self::Both)
+    }
+}
+
+impl TryFrom<&str> for MetadataSync {
+    type Error = Error;
+
+    fn try_from(name: &str) -> Result<Self> {
+        match name {
+            "off" => Ok(Self::Off),
+            "from_discord" => Ok(Self::FromDiscord),
+            "both" => Ok(Self::Both),
+            _ => Err(anyhow!(
+                "Unknown sync direction {:?}, supported are off, from_discord and both",
+                name
+            )),
+        }
+    }
+}
+
+/// Metadata change of a portal room
+#[derive(Clone, Copy, Debug)]
+enum MetadataChange<'a> {
+    /// The room was renamed
+    Name(&'a str),
+    /// The topic of the room changed
+    Topic(&'a str),
+}
+
+impl App {
+    /// Sets the name of a portal room to the name of its channel
+    ///
+    /// # Errors
+    /// This function will return an error if the database query or updating the name fails
+    pub(super) async fn sync_channel_name(self: &Arc<Self>, channel: &Channel) -> Result<()> {
+        let name = match channel.name {
+            Some(ref name) => name,
+            None => return Ok(()),
+        };
+        let portal = match self.portal_by_channel(channel.id).await? {
+            Some(portal) if portal.metadata_sync.syncs_from_discord() => portal,
+            _ => return Ok(()),
+        };
+        let room = match self.client.get_joined_room(&portal.room_id) {
+            Some(room) => room,
+            None => return Ok(()),
+        };
+        if room.name().as_ref() == Some(name) {
+            return Ok(());
+        }
+        if self.dry_run {
+            info!("[dry-run] Would rename {} to {:?}", portal.room_id, name);
+            return Ok(());
+        }
+        self.pipeline
+            .run(&self.user_id, "state", async {
+                room.send_state_event_raw(json!({ "name": name }), "m.room.name", "")
+                    .await?;
+                Ok(())
+            })
+            .await
+    }
+
+    /// Sets the avatar of the portal rooms of a guild after the guild icon changed
+    ///
+    /// Failures are logged so that the remaining portals are still updated.
+    pub(super) async fn sync_portal_avatars(
+        self: &Arc<Self>,
+        portals: &[Portal],
+        avatar: Option<&MxcUri>,
+    ) {
+        let content = match avatar {
+            Some(avatar) => json!({ "url": avatar }),
+            None => json!({}),
+        };
+        for portal in portals {
+            if !portal.metadata_sync.syncs_from_discord() {
+                continue;
+            }
+            let room = match self.client.get_joined_room(&portal.room_id) {
+                Some(room) => room,
+                None => continue,
+            };
+            let result = self
+                .pipeline
+                .run(&self.user_id, "state", async {
+                    room.send_state_event_raw(content.clone(), "m.room.avatar", "")
+                        .await?;
+                    Ok(())
+                })
+                .await;
+            if let Err(e) = result {
+                warn!("Failed to set the avatar of {}: {:?}", portal.room_id, e);
+            }
+        }
+    }
+
+    /// Applies a matrix change of a portal's metadata to its discord channel
+    ///
+    /// # Errors
+    /// This function will return an error if a database query or a request to discord fails
+    async fn relay_metadata_change(
+        self: &Arc<Self>,
+        sender: &UserId,
+        room_id: &RoomId,
+        change: MetadataChange<'_>,
+    ) -> Result<()> {
+        if sender == self.user_id || self.puppet_discord_id(sender).is_some() {
+            return Ok(());
+        }
+        let portal = match self.portal_by_room(room_id).await? {
+            Some(portal) if portal.metadata_sync.syncs_to_discord() => portal,
+            _ => return Ok(()),
+        };
+        let discord_user = match self.linked_discord_user(sender).await? {
+            Some(discord_user) => discord_user,
+            None => {
+                debug!(
+                    "Not syncing {:?} by {} to discord, they have no linked account",
+                    change, sender
+                );
+                return Ok(());
+            }
+        };
+        let (_, permissions) = self
+            .member_channel_permissions(portal.channel_id, discord_user)
+            .await?;
+        if !permissions.contains(Permissions::MANAGE_CHANNELS) {
+            info!(
+                "Not syncing {:?} by {} to discord, they can't manage {}",
+                change, sender, portal.channel_id
+            );
+            return Ok(());
+        }
+        if self.dry_run {
+            info!(
+                "[dry-run] Would apply {:?} to {}",
+                change, portal.channel_id
+            );
+            return Ok(());
+        }
+        let request = self.discord()?.http.update_channel(portal.channel_id);
+        match change {
+            MetadataChange::Name(name) => request.name(name)?.exec().await?,
+            MetadataChange::Topic(topic) => request.topic(Some(topic))?.exec().await?,
+        };
+        Ok(())
+    }
+
+    /// Handles a change of the name of a matrix room
+    ///
+    /// # Errors
+    /// This function will return an error if applying the change on discord fails
+    pub(super) async fn handle_room_name_event(
+        self: &Arc<Self>,
+        event: SyncRoomNameEvent,
+        room: Room,
+    ) -> Result<()> {
+        if let SyncStateEvent::Original(event) = event {
+            if let Some(name) = event.content.name.as_deref().map(RoomName::as_str) {
+                self.relay_metadata_change(
+                    &event.sender,
+                    room.room_id(),
+                    MetadataChange::Name(name),
+                )
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles a change of the topic of a matrix room
+    ///
+    /// The channel constraints the bridge appends to the topic aren't sent to discord.
+    ///
+    /// # Errors
+    /// This function will return an error if applying the change on discord fails
+    pub(super) async fn handle_room_topic_event(
+        self: &Arc<Self>,
+        event: SyncRoomTopicEvent,
+        room: Room,
+    ) -> Result<()> {
+        if let SyncStateEvent::Original(event) = event {
+            let topic = strip_topic_metadata(&event.content.topic);
+            self.relay_metadata_change(&event.sender, room.room_id(), MetadataChange::Topic(topic))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Sets the sync direction of a portal's metadata
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub(super) async fn set_portal_metadata_sync(
+        self: &Arc<Self>,
+        room_id: &RoomId,
+        metadata_sync: MetadataSync,
+    ) -> Result<()> {
+        query!(
+            "UPDATE portals SET metadata_sync = $2 WHERE matrix_room_id = $1",
+            room_id.as_str(),
+            metadata_sync.name()
+        )
+        .execute(&*self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Handles the `metadata-sync` command
+    ///
+    /// `metadata-sync <room>` shows the sync direction of a portal and
+    /// `metadata-sync <room> off|from_discord|both` sets it.
+    ///
+    /// # Errors
+    /// This function will return an error if the portal can't be updated or the reply could not
+    /// be sent
+    pub(super) async fn handle_metadata_sync_command(
+        self: &Arc<Self>,
+        sender: &UserId,
+        args: &[&str],
+        room: &Room,
+    ) -> Result<()> {
+        let reply = if sender == self.config.bridge.admin {
+            self.metadata_sync_command_reply(args).await?
+        } else {
+            "Only the bridge admin can change how portal metadata is synced".to_owned()
+        };
+        self.send_message(room, RoomMessageEventContent::text_plain(reply))
+            .await?;
+        Ok(())
+    }
+
+    /// Runs the `metadata-sync` command and returns the reply
+    ///
+    /// # Errors
+    /// This function will return an error if the portal can't be updated
+    async fn metadata_sync_command_reply(self: &Arc<Self>, args: &[&str]) -> Result<String> {
+        let (target, direction) = match args {
+            ["metadata-sync", target] => (target, None),
+            ["metadata-sync", target, direction] => (target, Some(direction)),
+            _ => return Ok("Usage: metadata-sync <room> [off|from_discord|both]".to_owned()),
+        };
+        let target = match <&RoomOrAliasId>::try_from(*target) {
+            Ok(target) => target,
+            Err(_) => return Ok(format!("{} is not a room id or alias", target)),
+        };
+        let portal = match self.portal_by_room_or_alias(target).await? {
+            Some(portal) => portal,
+            None => return Ok(format!("{} is not a portal", target)),
+        };
+        let metadata_sync = match direction {
+            None => {
+                return Ok(format!(
+                    "The metadata of {} is synced {}",
+                    target,
+                    portal.metadata_sync.name()
+                ))
+            }
+            Some(direction) => match MetadataSync::try_from(*direction) {
+                Ok(metadata_sync) => metadata_sync,
+                Err(e) => return Ok(e.to_string()),
+            },
+        };
+        if self.dry_run {
+            info!(
+                "[dry-run] Would set the metadata sync of {} to {:?}",
+                portal.room_id, metadata_sync
+            );
+            return Ok(format!("Would update the metadata sync of {}", target));
+        }
+        self.set_portal_metadata_sync(&portal.room_id, metadata_sync)
+            .await?;
+        self.publish_portal_settings(&portal.room_id).await?;
+        Ok(format!(
+            "The metadata of {} is now synced {}",
+            target,
+            metadata_sync.name()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sync_directions() {
+        for direction in [
+            MetadataSync::Off,
+            MetadataSync::FromDiscord,
+            MetadataSync::Both,
+        ] {
+            assert_eq!(
+                MetadataSync::try_from(direction.name()).ok(),
+                Some(direction)
+            );
+        }
+        assert!(MetadataSync::try_from("to_discord").is_err());
+        assert!(MetadataSync::Both.syncs_to_discord());
+        assert!(!MetadataSync::FromDiscord.syncs_to_discord());
+        assert!(!MetadataSync::Off.syncs_from_discord());
+    }
+}
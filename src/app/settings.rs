@@ -0,0 +1,295 @@
+//! Per-user bridge settings
+
+use std::sync::Arc;
+
+use super::App;
+use anyhow::Result;
+use matrix_sdk::{
+    room::Room,
+    ruma::{
+        api::client::{
+            config::{get_global_account_data, set_global_account_data},
+            profile::get_profile,
+        },
+        events::room::message::RoomMessageEventContent,
+        MxcUri, OwnedMxcUri, UserId,
+    },
+};
+use serde::{Deserialize, Serialize};
+use serde_json::value::to_raw_value;
+use sqlx::query;
+use tracing::{debug, info, warn};
+
+/// Name used for users in privacy mode who did not configure a pseudonym
+const DEFAULT_PSEUDONYM: &str = "Matrix User";
+
+/// Account data event type the settings are stored under
+const SETTINGS_EVENT_TYPE: &str = "rs.chir.discord_bridge.settings";
+
+/// Bridge settings of a matrix user
+///
+/// The settings are kept in the database and, if double puppeting is enabled, in the account
+/// data of the user, which is used to restore them when the database doesn't know the user.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct UserSettings {
+    /// Whether the user is relayed to discord under a pseudonym
+    pub privacy_mode: bool,
+    /// Display name used on discord in privacy mode
+    pub pseudonym: Option<String>,
+    /// Avatar used on discord in privacy mode
+    pub pseudonym_avatar: Option<OwnedMxcUri>,
+    /// Whether the user is notified on discord when mentioned on matrix while away
+    pub mention_dm: bool,
+}
+
+/// Identity a matrix user is shown with on discord
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RelayIdentity {
+    /// Display name
+    pub name: String,
+    /// Avatar
+    pub avatar: Option<OwnedMxcUri>,
+}
+
+impl App {
+    /// Returns the settings of a user
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub async fn user_settings(self: &Arc<Self>, user_id: &UserId) -> Result<UserSettings> {
+        let row = query!(
+            "SELECT privacy_mode, pseudonym, pseudonym_avatar, mention_dm FROM user_settings WHERE user_id = $1",
+            user_id.as_str()
+        )
+        .fetch_optional(&*self.db)
+        .await?;
+        if let Some(row) = row {
+            return Ok(UserSettings {
+                privacy_mode: row.privacy_mode,
+                pseudonym: row.pseudonym,
+                pseudonym_avatar: row.pseudonym_avatar.map(Into::into),
+                mention_dm: row.mention_dm,
+            });
+        }
+        match self.account_data_settings(user_id).await {
+            Ok(Some(settings)) => {
+                info!("Restored settings of {} from account data", user_id);
+                self.store_user_settings(user_id, &settings).await?;
+                Ok(settings)
+            }
+            Ok(None) => Ok(UserSettings::default()),
+            Err(e) => {
+                debug!("Failed to read account data of {}: {:?}", user_id, e);
+                Ok(UserSettings::default())
+            }
+        }
+    }
+
+    /// Reads the settings of a user from their account data
+    ///
+    /// # Errors
+    /// This function will return an error if the request fails or the settings are invalid
+    async fn account_data_settings(
+        self: &Arc<Self>,
+        user_id: &UserId,
+    ) -> Result<Option<UserSettings>> {
+        let client = match self.double_puppet_client(user_id).await? {
+            Some(client) => client,
+            None => return Ok(None),
+        };
+        let response = client
+            .send(
+                get_global_account_data::v3::Request::new(user_id, SETTINGS_EVENT_TYPE),
+                None,
+            )
+            .await?;
+        Ok(Some(response.account_data.deserialize_as()?))
+    }
+
+    /// Stores the settings of a user
+    ///
+    /// The settings are also written to the account data of the user. Failing to do so is only
+    /// logged, as the database is authoritative while the bridge is running.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    pub async fn set_user_settings(
+        self: &Arc<Self>,
+        user_id: &UserId,
+        settings: &UserSettings,
+    ) -> Result<()> {
+        self.store_user_settings(user_id, settings).await?;
+        if let Err(e) = self.set_account_data_settings(user_id, settings).await {
+            warn!(
+                "Failed to store settings of {} in account data: {:?}",
+                user_id, e
+            );
+        }
+        Ok(())
+    }
+
+    /// Writes the settings of a user to their account data
+    ///
+    /// # Errors
+    /// This function will return an error if the request fails
+    async fn set_account_data_settings(
+        self: &Arc<Self>,
+        user_id: &UserId,
+        settings: &UserSettings,
+    ) -> Result<()> {
+        let client = match self.double_puppet_client(user_id).await? {
+            Some(client) => client,
+            None => return Ok(()),
+        };
+        if self.dry_run {
+            info!(
+                "[dry-run] Would store settings of {} in account data",
+                user_id
+            );
+            return Ok(());
+        }
+        let data = to_raw_value(settings)?;
+        self.pipeline
+            .run(user_id, "account_data", async {
+                client
+                    .send(
+                        set_global_account_data::v3::Request::new(
+                            &data,
+                            SETTINGS_EVENT_TYPE,
+                            user_id,
+                        ),
+                        None,
+                    )
+                    .await?;
+                Ok(())
+            })
+            .await
+    }
+
+    /// Stores the settings of a user in the database
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    async fn store_user_settings(
+        self: &Arc<Self>,
+        user_id: &UserId,
+        settings: &UserSettings,
+    ) -> Result<()> {
+        query!(
+            "INSERT INTO user_settings (user_id, privacy_mode, pseudonym, pseudonym_avatar, mention_dm) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (user_id) DO UPDATE SET privacy_mode = $2, pseudonym = $3, pseudonym_avatar = $4, mention_dm = $5",
+            user_id.as_str(),
+            settings.privacy_mode,
+            settings.pseudonym.as_deref(),
+            settings.pseudonym_avatar.as_deref().map(MxcUri::as_str),
+            settings.mention_dm
+        )
+        .execute(&*self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Returns the identity a matrix user is relayed to discord with
+    ///
+    /// Users in privacy mode are shown with their pseudonym, everyone else with their matrix
+    /// profile.
+    ///
+    /// # Errors
+    /// This function will return an error if the settings or the profile can't be retrieved
+    pub async fn relay_identity(self: &Arc<Self>, user_id: &UserId) -> Result<RelayIdentity> {
+        let settings = self.user_settings(user_id).await?;
+        if settings.privacy_mode {
+            return Ok(RelayIdentity {
+                name: settings
+                    .pseudonym
+                    .unwrap_or_else(|| DEFAULT_PSEUDONYM.to_owned()),
+                avatar: settings.pseudonym_avatar,
+            });
+        }
+        let profile = self
+            .client(None)
+            .await?
+            .send(get_profile::v3::Request::new(user_id), None)
+            .await;
+        match profile {
+            Ok(profile) => Ok(RelayIdentity {
+                name: profile
+                    .displayname
+                    .unwrap_or_else(|| user_id.localpart().to_owned()),
+                avatar: profile.avatar_url,
+            }),
+            Err(e) => {
+                debug!("Failed to fetch profile of {}: {:?}", user_id, e);
+                Ok(RelayIdentity {
+                    name: user_id.localpart().to_owned(),
+                    avatar: None,
+                })
+            }
+        }
+    }
+
+    /// Handles the `privacy`, `pseudonym`, `pseudonym-avatar` and `mention-dm` commands
+    pub(super) async fn handle_privacy_command(
+        self: &Arc<Self>,
+        sender: &UserId,
+        args: &[&str],
+        room: &Room,
+    ) -> Result<()> {
+        let mut settings = self.user_settings(sender).await?;
+        let reply = match args {
+            ["privacy", "on"] => {
+                settings.privacy_mode = true;
+                "Privacy mode enabled, you will be shown on discord under your pseudonym"
+            }
+            ["privacy", "off"] => {
+                settings.privacy_mode = false;
+                "Privacy mode disabled, you will be shown on discord with your matrix profile"
+            }
+            ["pseudonym"] => {
+                settings.pseudonym = None;
+                "Pseudonym removed"
+            }
+            ["pseudonym", name @ ..] => {
+                settings.pseudonym = Some(name.join(" "));
+                "Pseudonym updated"
+            }
+            ["pseudonym-avatar"] => {
+                settings.pseudonym_avatar = None;
+                "Pseudonym avatar removed"
+            }
+            ["pseudonym-avatar", avatar] => {
+                let avatar = <&MxcUri>::from(*avatar);
+                if !avatar.is_valid() {
+                    let content = RoomMessageEventContent::text_plain(
+                        "The pseudonym avatar needs to be an mxc:// URI",
+                    );
+                    self.send_message(room, content).await?;
+                    return Ok(());
+                }
+                settings.pseudonym_avatar = Some(avatar.to_owned());
+                "Pseudonym avatar updated"
+            }
+            ["mention-dm", "on"] => {
+                settings.mention_dm = true;
+                "You will be notified on discord when you are mentioned on matrix while away"
+            }
+            ["mention-dm", "off"] => {
+                settings.mention_dm = false;
+                "You will no longer be notified on discord about mentions on matrix"
+            }
+            _ => {
+                let content = RoomMessageEventContent::text_plain(
+                    "Usage: privacy on|off, pseudonym [name], pseudonym-avatar [mxc uri], mention-dm on|off",
+                );
+                self.send_message(room, content).await?;
+                return Ok(());
+            }
+        };
+        self.set_user_settings(sender, &settings).await?;
+        self.send_message(room, RoomMessageEventContent::text_plain(reply))
+            .await?;
+        Ok(())
+    }
+}
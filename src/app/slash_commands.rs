@@ -0,0 +1,307 @@
+//! Discord slash command registration
+//!
+//! The registered commands are diffed against the commands offered by this version of the
+//! bridge, so that upgrades neither leave stale commands behind nor create duplicates.
+
+use std::sync::Arc;
+
+use super::App;
+use crate::config::CommandScope;
+use anyhow::Result;
+use tracing::{debug, info};
+use twilight_model::{
+    application::{
+        command::{BaseCommandOptionData, ChoiceCommandOptionData, Command, CommandOption},
+        interaction::{application_command::CommandOptionValue, ApplicationCommand, Interaction},
+    },
+    http::interaction::{InteractionResponse, InteractionResponseData, InteractionResponseType},
+    id::{
+        marker::{ApplicationMarker, CommandMarker, GuildMarker},
+        Id,
+    },
+};
+
+/// A slash command offered by the bridge
+#[derive(Clone, Copy, Debug)]
+pub struct SlashCommand {
+    /// Name of the command
+    pub name: &'static str,
+    /// Description shown in the discord client
+    pub description: &'static str,
+    /// Builds the options of the command
+    pub options: fn() -> Vec<CommandOption>,
+}
+
+impl PartialEq for SlashCommand {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+/// Slash commands offered by the bridge
+pub const COMMANDS: &[SlashCommand] = &[
+    SlashCommand {
+        name: "help",
+        description: "Show what the matrix bridge can do",
+        options: Vec::new,
+    },
+    SlashCommand {
+        name: "ping",
+        description: "Check whether the matrix bridge is running",
+        options: Vec::new,
+    },
+    SlashCommand {
+        name: "whois",
+        description: "Show the matrix identity linked to a discord user",
+        options: whois_options,
+    },
+    SlashCommand {
+        name: "knock",
+        description: "Approve or deny a matrix user's request to join a channel",
+        options: knock_options,
+    },
+];
+
+/// Options of the `whois` command
+fn whois_options() -> Vec<CommandOption> {
+    vec![CommandOption::User(BaseCommandOptionData {
+        name: "user".to_owned(),
+        description: "The discord user to look up".to_owned(),
+        required: true,
+        ..BaseCommandOptionData::default()
+    })]
+}
+
+/// Options of the `knock` command
+fn knock_options() -> Vec<CommandOption> {
+    vec![
+        CommandOption::String(ChoiceCommandOptionData {
+            name: "decision".to_owned(),
+            description: "approve or deny".to_owned(),
+            required: true,
+            ..ChoiceCommandOptionData::default()
+        }),
+        CommandOption::String(ChoiceCommandOptionData {
+            name: "notification".to_owned(),
+            description: "Id of the knock notification message".to_owned(),
+            required: true,
+            ..ChoiceCommandOptionData::default()
+        }),
+    ]
+}
+
+/// Returns the arguments of a slash command in the form used by the shared commands
+fn slash_command_args(command: &ApplicationCommand) -> Vec<String> {
+    std::iter::once(command.data.name.clone())
+        .chain(
+            command
+                .data
+                .options
+                .iter()
+                .filter_map(|option| match &option.value {
+                    CommandOptionValue::String(value) => Some(value.clone()),
+                    CommandOptionValue::Integer(value) => Some(value.to_string()),
+                    CommandOptionValue::Boolean(value) => Some(value.to_string()),
+                    CommandOptionValue::User(id) => Some(id.to_string()),
+                    _ => None,
+                }),
+        )
+        .collect()
+}
+
+/// Changes needed to bring the registered commands up to date
+#[derive(Debug, Default, PartialEq)]
+struct CommandDiff {
+    /// Commands that need to be created or updated
+    upsert: Vec<SlashCommand>,
+    /// Registered commands that need to be removed
+    delete: Vec<Id<CommandMarker>>,
+}
+
+/// Computes the changes needed to turn the registered commands into the desired ones
+fn diff_commands(registered: &[Command], desired: &[SlashCommand]) -> CommandDiff {
+    let mut diff = CommandDiff::default();
+    for command in desired {
+        let up_to_date = registered.iter().any(|registered| {
+            registered.name == command.name
+                && registered.description == command.description
+                && registered.options == (command.options)()
+        });
+        if !up_to_date {
+            diff.upsert.push(*command);
+        }
+    }
+    for registered in registered {
+        if !desired
+            .iter()
+            .any(|command| command.name == registered.name)
+        {
+            if let Some(id) = registered.id {
+                diff.delete.push(id);
+            }
+        }
+    }
+    diff
+}
+
+impl App {
+    /// Returns the application id of the bridge bot
+    ///
+    /// # Errors
+    /// This function will return an error if no bot is configured or the request fails
+    pub async fn application_id(self: &Arc<Self>) -> Result<Id<ApplicationMarker>> {
+        let discord = self.discord()?;
+        let id = self
+            .application_id
+            .get_or_try_init(|| async {
+                let application = discord
+                    .http
+                    .current_user_application()
+                    .exec()
+                    .await?
+                    .model()
+                    .await?;
+                Ok::<_, anyhow::Error>(application.id)
+            })
+            .await?;
+        Ok(*id)
+    }
+
+    /// Brings the global slash commands up to date
+    ///
+    /// # Errors
+    /// This function will return an error if a request to discord fails
+    pub async fn sync_global_commands(self: &Arc<Self>) -> Result<()> {
+        let desired = match self.config.discord.command_scope {
+            CommandScope::Global => COMMANDS,
+            CommandScope::Guild | CommandScope::Disabled => &[],
+        };
+        let application_id = self.application_id().await?;
+        let discord = self.discord()?;
+        let client = discord.http.interaction(application_id);
+        let registered = client.global_commands().exec().await?.models().await?;
+        let diff = diff_commands(&registered, desired);
+        if self.dry_run {
+            info!("[dry-run] Would update global commands: {:?}", diff);
+            return Ok(());
+        }
+        for command in &diff.upsert {
+            info!("Registering global command {}", command.name);
+            client
+                .create_global_command()
+                .chat_input(command.name, command.description)?
+                .command_options(&(command.options)())?
+                .exec()
+                .await?;
+        }
+        for id in diff.delete {
+            info!("Removing stale global command {}", id);
+            client.delete_global_command(id).exec().await?;
+        }
+        Ok(())
+    }
+
+    /// Brings the slash commands of a guild up to date
+    ///
+    /// # Errors
+    /// This function will return an error if a request to discord fails
+    pub async fn sync_guild_commands(self: &Arc<Self>, guild_id: Id<GuildMarker>) -> Result<()> {
+        let desired = match self.config.discord.command_scope {
+            CommandScope::Guild => COMMANDS,
+            CommandScope::Global | CommandScope::Disabled => &[],
+        };
+        let application_id = self.application_id().await?;
+        let discord = self.discord()?;
+        let client = discord.http.interaction(application_id);
+        let registered = client
+            .guild_commands(guild_id)
+            .exec()
+            .await?
+            .models()
+            .await?;
+        let diff = diff_commands(&registered, desired);
+        if diff == CommandDiff::default() {
+            debug!("Commands of guild {} are up to date", guild_id);
+            return Ok(());
+        }
+        if self.dry_run {
+            info!(
+                "[dry-run] Would update commands of guild {}: {:?}",
+                guild_id, diff
+            );
+            return Ok(());
+        }
+        for command in &diff.upsert {
+            info!("Registering command {} in guild {}", command.name, guild_id);
+            client
+                .create_guild_command(guild_id)
+                .chat_input(command.name, command.description)?
+                .command_options(&(command.options)())?
+                .exec()
+                .await?;
+        }
+        for id in diff.delete {
+            info!("Removing stale command {} from guild {}", id, guild_id);
+            client.delete_guild_command(guild_id, id).exec().await?;
+        }
+        Ok(())
+    }
+
+    /// Handles an interaction
+    ///
+    /// # Errors
+    /// This function will return an error if responding to the interaction fails
+    pub(super) async fn handle_interaction(
+        self: &Arc<Self>,
+        interaction: Interaction,
+    ) -> Result<()> {
+        if let Interaction::ApplicationCommand(command) = interaction {
+            let args = slash_command_args(&command);
+            let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+            let reply = if command.data.name == "knock" {
+                self.knock_command_reply(command.channel_id, &args).await?
+            } else {
+                match self.shared_command_reply(&args).await? {
+                    Some(reply) => reply,
+                    None => format!("The command `/{}` is not supported", command.data.name),
+                }
+            };
+            if self.dry_run {
+                info!("[dry-run] Would reply to /{}: {}", command.data.name, reply);
+                return Ok(());
+            }
+            let response = InteractionResponse {
+                kind: InteractionResponseType::ChannelMessageWithSource,
+                data: Some(InteractionResponseData {
+                    content: Some(reply),
+                    ..InteractionResponseData::default()
+                }),
+            };
+            let application_id = self.application_id().await?;
+            self.discord()?
+                .http
+                .interaction(application_id)
+                .create_response(command.id, &command.token, &response)
+                .exec()
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_with_nothing_registered() {
+        let diff = diff_commands(&[], COMMANDS);
+        assert_eq!(diff.upsert, COMMANDS);
+        assert!(diff.delete.is_empty());
+    }
+
+    #[test]
+    fn diff_without_desired_commands() {
+        assert_eq!(diff_commands(&[], &[]), CommandDiff::default());
+    }
+}
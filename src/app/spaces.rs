@@ -0,0 +1,272 @@
+//! Matrix spaces of discord guilds
+//!
+//! Every guild with portals gets a matrix space named after it, with the guild icon as avatar.
+//! Portals are added as children of their guild's space with `m.space.child` and
+//! `m.space.parent` state events. Changes of the guild's name or icon are applied to the space.
+
+use std::sync::Arc;
+
+use super::{media::MediaRetention, portals::Portal, App};
+use crate::snowflake;
+use anyhow::{anyhow, Result};
+use matrix_sdk::ruma::{
+    api::client::room::create_room::{self, v3::CreationContent},
+    events::StateEventType,
+    room::RoomType,
+    serde::Raw,
+    OwnedRoomId, RoomId,
+};
+use serde_json::json;
+use sqlx::query;
+use tracing::{info, warn};
+use twilight_model::id::{marker::GuildMarker, Id};
+
+/// Returns the URL of a guild icon
+fn icon_url(guild_id: Id<GuildMarker>, icon: &str) -> String {
+    format!("https://cdn.discordapp.com/icons/{}/{}.png", guild_id, icon)
+}
+
+impl App {
+    /// Creates the space of a guild
+    ///
+    /// # Errors
+    /// This function will return an error if the room can't be created
+    async fn create_space_room(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+        name: &str,
+        icon: Option<&str>,
+    ) -> Result<OwnedRoomId> {
+        let avatar = match icon {
+            Some(icon) => {
+                self.mirror_discord_media(
+                    &icon_url(guild_id, icon),
+                    None,
+                    MediaRetention::Permanent,
+                )
+                .await?
+            }
+            None => None,
+        };
+        let mut creation_content = CreationContent::new();
+        creation_content.room_type = Some(RoomType::Space);
+        let initial_state = match avatar {
+            Some(avatar) => vec![Raw::from_json(serde_json::value::to_raw_value(&json!({
+                "type": "m.room.avatar",
+                "state_key": "",
+                "content": { "url": avatar },
+            }))?)],
+            None => Vec::new(),
+        };
+        let mut request = create_room::v3::Request::new();
+        request.name = Some(name);
+        request.creation_content = Some(Raw::new(&creation_content)?);
+        request.initial_state = &initial_state;
+        let response = self
+            .pipeline
+            .run(&self.user_id, "create_room", async {
+                Ok(self.client.create_room(request).await?)
+            })
+            .await?;
+        Ok(response.room_id)
+    }
+
+    /// Returns the space of a guild, creating it if there is none
+    ///
+    /// # Errors
+    /// This function will return an error if the database query or creating the space fails
+    #[allow(clippy::panic)]
+    async fn ensure_guild_space(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+        name: &str,
+        icon: Option<&str>,
+    ) -> Result<OwnedRoomId> {
+        let lock = Arc::clone(&*self.space_creation_locks.entry(guild_id).or_default());
+        let _guard = lock.lock().await;
+        let existing = query!(
+            "SELECT space_room_id FROM guilds WHERE guild_id = $1",
+            snowflake::to_db(guild_id)
+        )
+        .fetch_optional(&*self.db)
+        .await?;
+        if let Some(row) = existing {
+            return Ok(OwnedRoomId::try_from(row.space_room_id)?);
+        }
+        let room_id = self.create_space_room(guild_id, name, icon).await?;
+        query!(
+            "INSERT INTO guilds (guild_id, space_room_id, name, icon) VALUES ($1, $2, $3, $4)",
+            snowflake::to_db(guild_id),
+            room_id.as_str(),
+            name,
+            icon
+        )
+        .execute(&*self.db)
+        .await?;
+        info!("Created space {} for guild {}", room_id, guild_id);
+        Ok(room_id)
+    }
+
+    /// Links a portal and the space of its guild with child and parent events
+    ///
+    /// Links that already exist are left alone.
+    ///
+    /// # Errors
+    /// This function will return an error if reading or sending the state events fails
+    async fn link_space_child(self: &Arc<Self>, space_id: &RoomId, portal: &Portal) -> Result<()> {
+        let via = json!([self.config.homeserver.domain]);
+        for (room_id, event_type, state_key, content) in [
+            (
+                space_id,
+                "m.space.child",
+                portal.room_id.as_str(),
+                json!({ "via": via }),
+            ),
+            (
+                &*portal.room_id,
+                "m.space.parent",
+                space_id.as_str(),
+                json!({ "via": via, "canonical": true }),
+            ),
+        ] {
+            let room = match self.client.get_joined_room(room_id) {
+                Some(room) => room,
+                None => continue,
+            };
+            if room
+                .get_state_event(StateEventType::from(event_type), state_key)
+                .await?
+                .is_some()
+            {
+                continue;
+            }
+            self.pipeline
+                .run(&self.user_id, "state", async {
+                    room.send_state_event_raw(content, event_type, state_key)
+                        .await?;
+                    Ok(())
+                })
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Adds a new portal to the space of its guild
+    ///
+    /// # Errors
+    /// This function will return an error if the guild can't be fetched, or creating or linking
+    /// the space fails
+    pub(super) async fn add_portal_to_space(self: &Arc<Self>, portal: &Portal) -> Result<()> {
+        let guild_id = match portal.guild_id {
+            Some(guild_id) => guild_id,
+            None => return Ok(()),
+        };
+        if self.dry_run {
+            info!(
+                "[dry-run] Would add {} to the space of {}",
+                portal.room_id, guild_id
+            );
+            return Ok(());
+        }
+        let guild = self
+            .discord()?
+            .http
+            .guild(guild_id)
+            .exec()
+            .await?
+            .model()
+            .await?;
+        let icon = guild.icon.map(|icon| icon.to_string());
+        let space_id = self
+            .ensure_guild_space(guild_id, &guild.name, icon.as_deref())
+            .await?;
+        self.link_space_child(&space_id, portal).await
+    }
+
+    /// Sets the name and avatar of a guild's space to the guild's
+    ///
+    /// Guilds without portals don't get a space. Portals that aren't in the space yet are added.
+    /// A new icon also becomes the avatar of the portal rooms that sync metadata from discord.
+    ///
+    /// # Errors
+    /// This function will return an error if a database query, or creating or updating the space
+    /// fails
+    #[allow(clippy::panic)]
+    pub(super) async fn sync_guild_space(
+        self: &Arc<Self>,
+        guild_id: Id<GuildMarker>,
+        name: &str,
+        icon: Option<&str>,
+    ) -> Result<()> {
+        let portals = self.portals_in_guild(guild_id).await?;
+        if portals.is_empty() {
+            return Ok(());
+        }
+        if self.dry_run {
+            info!("[dry-run] Would sync the space of guild {}", guild_id);
+            return Ok(());
+        }
+        let space_id = self.ensure_guild_space(guild_id, name, icon).await?;
+        let stored = query!(
+            "SELECT name, icon FROM guilds WHERE guild_id = $1",
+            snowflake::to_db(guild_id)
+        )
+        .fetch_one(&*self.db)
+        .await?;
+        let room = self
+            .client
+            .get_joined_room(&space_id)
+            .ok_or_else(|| anyhow!("The bridge is not in the space {}", space_id))?;
+        if stored.name != name {
+            self.pipeline
+                .run(&self.user_id, "state", async {
+                    room.send_state_event_raw(json!({ "name": name }), "m.room.name", "")
+                        .await?;
+                    Ok(())
+                })
+                .await?;
+        }
+        if stored.icon.as_deref() != icon {
+            let avatar = match icon {
+                Some(icon) => {
+                    self.mirror_discord_media(
+                        &icon_url(guild_id, icon),
+                        None,
+                        MediaRetention::Permanent,
+                    )
+                    .await?
+                }
+                None => None,
+            };
+            let content = match avatar {
+                Some(ref avatar) => json!({ "url": avatar }),
+                None => json!({}),
+            };
+            self.pipeline
+                .run(&self.user_id, "state", async {
+                    room.send_state_event_raw(content, "m.room.avatar", "")
+                        .await?;
+                    Ok(())
+                })
+                .await?;
+            self.sync_portal_avatars(&portals, avatar.as_deref()).await;
+        }
+        query!(
+            "UPDATE guilds SET name = $2, icon = $3 WHERE guild_id = $1",
+            snowflake::to_db(guild_id),
+            name,
+            icon
+        )
+        .execute(&*self.db)
+        .await?;
+        for portal in portals {
+            if let Err(e) = self.link_space_child(&space_id, &portal).await {
+                warn!(
+                    "Failed to add {} to the space {}: {:?}",
+                    portal.room_id, space_id, e
+                );
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,426 @@
+//! Discord threads as matrix threads (MSC3440)
+//!
+//! Threads are recorded in `thread_map` with the matrix event they are rooted at. Threads started
+//! from a bridged message are rooted at its first event, other threads at a notice the bridge
+//! sends to the parent's portal when the thread is created. Messages in a thread are sent to the
+//! parent's portal as thread events, and the thread's latest event is kept for clients without
+//! thread support.
+//!
+//! Matrix thread events are relayed to the discord thread of their root, which is created from
+//! the root's discord message if there is none yet. Without MSC3440 threads aren't bridged.
+
+use std::sync::Arc;
+
+use super::{mappings::MessageMapping, mscs::Msc, App};
+use crate::snowflake;
+use anyhow::Result;
+use matrix_sdk::ruma::{
+    events::room::message::{Relation, RoomMessageEventContent, Thread},
+    EventId, OwnedEventId, OwnedRoomId, RoomId,
+};
+use serde::Deserialize;
+use sqlx::query;
+use tracing::{debug, info};
+use twilight_model::{
+    channel::Channel,
+    id::{marker::ChannelMarker, Id},
+};
+
+/// Longest name discord accepts for a thread
+const MAX_THREAD_NAME: usize = 100;
+
+/// Content of a thread root, as far as it is needed to name the thread
+#[derive(Debug, Deserialize)]
+struct RootContent {
+    /// Plain-text body
+    #[serde(default)]
+    body: String,
+}
+
+/// A thread root
+#[derive(Debug, Deserialize)]
+struct RootEvent {
+    /// Content of the event
+    content: RootContent,
+}
+
+/// A bridged thread
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ThreadMapping {
+    /// The discord thread
+    thread_id: Id<ChannelMarker>,
+    /// The channel the thread was created in
+    parent_id: Id<ChannelMarker>,
+    /// The portal of the parent channel
+    room_id: OwnedRoomId,
+    /// The event the matrix thread is rooted at
+    root_event_id: OwnedEventId,
+    /// The latest event in the matrix thread
+    latest_event_id: OwnedEventId,
+}
+
+/// Returns the name of a discord thread started from a message
+///
+/// The name is the message's first line, shortened to what discord accepts.
+fn thread_name(body: &str) -> String {
+    let line = body.lines().map(str::trim).find(|line| !line.is_empty());
+    match line {
+        Some(line) => line.chars().take(MAX_THREAD_NAME).collect(),
+        None => "Thread".to_owned(),
+    }
+}
+
+/// Returns the relation of a message in a bridged thread
+///
+/// Replies within the thread keep their reply, other messages fall back to replying to the
+/// thread's latest event.
+fn thread_relation(mapping: &ThreadMapping, relation: Option<Relation>) -> Relation {
+    match relation {
+        Some(Relation::Reply { in_reply_to }) => Relation::Thread(Thread::reply(
+            mapping.root_event_id.clone(),
+            in_reply_to.event_id,
+        )),
+        _ => Relation::Thread(Thread::plain(
+            mapping.root_event_id.clone(),
+            mapping.latest_event_id.clone(),
+        )),
+    }
+}
+
+impl App {
+    /// Returns the mapping of a discord thread
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    async fn thread_mapping(
+        self: &Arc<Self>,
+        thread_id: Id<ChannelMarker>,
+    ) -> Result<Option<ThreadMapping>> {
+        query!(
+            "SELECT discord_parent_id, matrix_room_id, root_event_id, latest_event_id FROM thread_map WHERE discord_thread_id = $1",
+            snowflake::to_db(thread_id)
+        )
+        .fetch_optional(&*self.db)
+        .await?
+        .map(|row| {
+            Ok(ThreadMapping {
+                thread_id,
+                parent_id: snowflake::from_db(row.discord_parent_id)?,
+                room_id: OwnedRoomId::try_from(row.matrix_room_id)?,
+                root_event_id: OwnedEventId::try_from(row.root_event_id)?,
+                latest_event_id: OwnedEventId::try_from(row.latest_event_id)?,
+            })
+        })
+        .transpose()
+    }
+
+    /// Returns the parent channel of a bridged discord thread
+    ///
+    /// Returns `None` if `channel_id` is not a bridged thread.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    pub(super) async fn thread_parent(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+    ) -> Result<Option<Id<ChannelMarker>>> {
+        Ok(self
+            .thread_mapping(channel_id)
+            .await?
+            .map(|mapping| mapping.parent_id))
+    }
+
+    /// Returns the channel of the portal a discord channel belongs to
+    ///
+    /// That is the parent of bridged threads and the channel itself otherwise.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    pub(super) async fn portal_channel(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+    ) -> Result<Id<ChannelMarker>> {
+        Ok(self.thread_parent(channel_id).await?.unwrap_or(channel_id))
+    }
+
+    /// Returns the discord thread of a matrix thread
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub(super) async fn thread_for_root(
+        self: &Arc<Self>,
+        room_id: &RoomId,
+        root_event_id: &EventId,
+    ) -> Result<Option<Id<ChannelMarker>>> {
+        query!(
+            "SELECT discord_thread_id FROM thread_map WHERE matrix_room_id = $1 AND root_event_id = $2",
+            room_id.as_str(),
+            root_event_id.as_str()
+        )
+        .fetch_optional(&*self.db)
+        .await?
+        .map(|row| snowflake::from_db(row.discord_thread_id))
+        .transpose()
+    }
+
+    /// Records a bridged thread
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    async fn insert_thread_mapping(self: &Arc<Self>, mapping: &ThreadMapping) -> Result<()> {
+        query!(
+            "INSERT INTO thread_map (discord_thread_id, discord_parent_id, matrix_room_id, root_event_id, latest_event_id) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (discord_thread_id) DO NOTHING",
+            snowflake::to_db(mapping.thread_id),
+            snowflake::to_db(mapping.parent_id),
+            mapping.room_id.as_str(),
+            mapping.root_event_id.as_str(),
+            mapping.latest_event_id.as_str()
+        )
+        .execute(&*self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Roots a new discord thread in the portal of its parent channel
+    ///
+    /// Also called for thread updates, so threads created while the bridge was down are bridged
+    /// once they are active again.
+    ///
+    /// # Errors
+    /// This function will return an error if a database query or sending the root notice fails
+    pub(super) async fn handle_thread_create(self: &Arc<Self>, channel: &Channel) -> Result<()> {
+        if !self.msc(Msc::Threads) {
+            return Ok(());
+        }
+        let parent_id = match channel.parent_id {
+            Some(parent_id) => parent_id,
+            None => return Ok(()),
+        };
+        if self.thread_mapping(channel.id).await?.is_some() {
+            return Ok(());
+        }
+        let portal = match self.portal_by_channel(parent_id).await? {
+            Some(portal) => portal,
+            None => return Ok(()),
+        };
+        // Threads started from a message share its id
+        let starter = MessageMapping::for_message(&*self.db, channel.id.cast())
+            .await?
+            .into_iter()
+            .find(|mapping| mapping.room_id == portal.room_id);
+        let root_event_id = match starter {
+            Some(starter) => starter.event_id,
+            None => {
+                let room = match self.client.get_room(&portal.room_id) {
+                    Some(room) => room,
+                    None => return Ok(()),
+                };
+                let name = channel.name.as_deref().unwrap_or("Thread");
+                let notice = RoomMessageEventContent::notice_plain(format!(
+                    "Thread \"{}\" was started on discord",
+                    name
+                ));
+                match self.send_message(&room, notice).await? {
+                    Some(event_id) => event_id,
+                    None => return Ok(()),
+                }
+            }
+        };
+        if self.dry_run {
+            info!(
+                "[dry-run] Would root thread {} at {}",
+                channel.id, root_event_id
+            );
+            return Ok(());
+        }
+        self.insert_thread_mapping(&ThreadMapping {
+            thread_id: channel.id,
+            parent_id,
+            room_id: portal.room_id,
+            latest_event_id: root_event_id.clone(),
+            root_event_id,
+        })
+        .await
+    }
+
+    /// Forgets a deleted discord thread
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub(super) async fn handle_thread_delete(
+        self: &Arc<Self>,
+        thread_id: Id<ChannelMarker>,
+    ) -> Result<()> {
+        query!(
+            "DELETE FROM thread_map WHERE discord_thread_id = $1",
+            snowflake::to_db(thread_id)
+        )
+        .execute(&*self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Makes matrix content of a message in a discord thread a thread event
+    ///
+    /// Content of messages outside of bridged threads is left alone.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    pub(super) async fn apply_discord_thread(
+        self: &Arc<Self>,
+        content: &mut RoomMessageEventContent,
+        channel_id: Id<ChannelMarker>,
+    ) -> Result<()> {
+        if !self.msc(Msc::Threads) {
+            return Ok(());
+        }
+        if let Some(mapping) = self.thread_mapping(channel_id).await? {
+            content.relates_to = Some(thread_relation(&mapping, content.relates_to.take()));
+        }
+        Ok(())
+    }
+
+    /// Records the latest event of a bridged thread
+    ///
+    /// Does nothing if `channel_id` is not a bridged thread.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub(super) async fn record_thread_event(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+        event_id: &EventId,
+    ) -> Result<()> {
+        query!(
+            "UPDATE thread_map SET latest_event_id = $2 WHERE discord_thread_id = $1",
+            snowflake::to_db(channel_id),
+            event_id.as_str()
+        )
+        .execute(&*self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Returns the discord thread a matrix thread event has to be relayed to
+    ///
+    /// If the thread's root was bridged to `parent_id` but has no discord thread yet, one is
+    /// started from the root's message. Returns `None` if the content isn't a thread event or the
+    /// root wasn't bridged.
+    ///
+    /// # Errors
+    /// This function will return an error if a database query, reading the root or creating the
+    /// discord thread fails
+    pub async fn discord_thread_for_event(
+        self: &Arc<Self>,
+        content: &RoomMessageEventContent,
+        room_id: &RoomId,
+        parent_id: Id<ChannelMarker>,
+    ) -> Result<Option<Id<ChannelMarker>>> {
+        if !self.msc(Msc::Threads) {
+            return Ok(None);
+        }
+        let root_event_id = match content.relates_to {
+            Some(Relation::Thread(ref thread)) => &thread.event_id,
+            _ => return Ok(None),
+        };
+        if let Some(thread_id) = self.thread_for_root(room_id, root_event_id).await? {
+            return Ok(Some(thread_id));
+        }
+        let starter = match MessageMapping::for_event(&*self.db, root_event_id).await? {
+            Some(starter) if starter.channel_id == parent_id => starter,
+            _ => {
+                debug!("Thread root {} was not bridged", root_event_id);
+                return Ok(None);
+            }
+        };
+        let room = match self.client.get_joined_room(room_id) {
+            Some(room) => room,
+            None => return Ok(None),
+        };
+        let root = room
+            .event(root_event_id)
+            .await?
+            .event
+            .deserialize_as::<RootEvent>()?;
+        let name = thread_name(&root.content.body);
+        if self.dry_run {
+            info!(
+                "[dry-run] Would start thread \"{}\" from {} in {}",
+                name, starter.message_id, parent_id
+            );
+            return Ok(None);
+        }
+        let thread = self
+            .discord()?
+            .http
+            .create_thread_from_message(parent_id, starter.message_id, &name)?
+            .exec()
+            .await?
+            .model()
+            .await?;
+        self.insert_thread_mapping(&ThreadMapping {
+            thread_id: thread.id,
+            parent_id,
+            room_id: room_id.to_owned(),
+            root_event_id: root_event_id.clone(),
+            latest_event_id: root_event_id.clone(),
+        })
+        .await?;
+        Ok(Some(thread.id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::events::room::message::InReplyTo;
+
+    fn mapping() -> ThreadMapping {
+        ThreadMapping {
+            thread_id: Id::new(2),
+            parent_id: Id::new(1),
+            room_id: OwnedRoomId::try_from("!portal:chir.rs").expect("valid room id"),
+            root_event_id: OwnedEventId::try_from("$root:chir.rs").expect("valid event id"),
+            latest_event_id: OwnedEventId::try_from("$latest:chir.rs").expect("valid event id"),
+        }
+    }
+
+    #[test]
+    fn threads_are_named_after_their_first_line() {
+        assert_eq!(
+            thread_name("\n  Release plans \nmore text"),
+            "Release plans"
+        );
+        assert_eq!(thread_name(""), "Thread");
+        assert_eq!(thread_name(&"a".repeat(150)).len(), MAX_THREAD_NAME);
+    }
+
+    #[test]
+    fn thread_events_fall_back_to_the_latest_event() {
+        let thread = match thread_relation(&mapping(), None) {
+            Relation::Thread(thread) => Some(thread),
+            _ => None,
+        }
+        .expect("relation is a thread");
+        assert_eq!(thread.event_id, mapping().root_event_id);
+        assert_eq!(thread.in_reply_to.event_id, mapping().latest_event_id);
+        assert!(thread.is_falling_back);
+
+        let reply_to = OwnedEventId::try_from("$reply:chir.rs").expect("valid event id");
+        let reply = Relation::Reply {
+            in_reply_to: InReplyTo::new(reply_to.clone()),
+        };
+        let thread = match thread_relation(&mapping(), Some(reply)) {
+            Relation::Thread(thread) => Some(thread),
+            _ => None,
+        }
+        .expect("relation is a thread");
+        assert_eq!(thread.in_reply_to.event_id, reply_to);
+        assert!(!thread.is_falling_back);
+    }
+}
@@ -0,0 +1,188 @@
+//! Watchdog for revoked discord tokens
+//!
+//! Stored user tokens and the bot token are validated periodically. A user token discord rejects
+//! is marked as invalid in the database, which pauses the bridging that needs the user to be
+//! logged in, and the user is asked in their management room to log in again. A rejected bot token
+//! is reported to the admin room. Network errors and outages aren't treated as invalidation.
+//! Access tokens of OAuth2 logins are refreshed before they are validated.
+
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use super::App;
+use crate::metrics::METRICS;
+use anyhow::Result;
+use matrix_sdk::ruma::{events::room::message::RoomMessageEventContent, RoomId, UserId};
+use reqwest::StatusCode;
+use sqlx::query;
+use tracing::{error, info, warn};
+
+/// Interval between validations
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Message to users whose token was rejected
+const RELOGIN_INSTRUCTIONS: &str = "Discord no longer accepts your token, so bridging your account is paused. Log in again with `login` in this room.";
+
+impl App {
+    /// Returns whether discord accepts a token
+    ///
+    /// `authorization` is the value of the authorization header, so bot tokens need the `Bot `
+    /// prefix.
+    ///
+    /// # Errors
+    /// This function will return an error if discord can't be reached or fails otherwise
+    async fn token_valid(self: &Arc<Self>, authorization: &str) -> Result<bool> {
+        let response = self
+            .http
+            .get("https://discord.com/api/v10/users/@me")
+            .header(reqwest::header::AUTHORIZATION, authorization)
+            .send()
+            .await?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            return Ok(false);
+        }
+        response.error_for_status()?;
+        Ok(true)
+    }
+
+    /// Marks the token of a user as invalid and asks them to log in again
+    ///
+    /// # Errors
+    /// This function will return an error if the database query or the notification fails
+    #[allow(clippy::panic)]
+    pub(super) async fn invalidate_user_token(
+        self: &Arc<Self>,
+        user: &UserId,
+        management_room: &str,
+    ) -> Result<()> {
+        METRICS.inc("bridge_invalid_user_tokens", &[]);
+        if self.dry_run {
+            info!(
+                "[dry-run] Would mark the discord token of {} as invalid",
+                user
+            );
+            return Ok(());
+        }
+        warn!(
+            "Discord rejected the token of {}, pausing their bridging",
+            user
+        );
+        query!(
+            "UPDATE discord_tokens SET invalid_since = NOW() WHERE user_id = $1",
+            user.as_str()
+        )
+        .execute(&*self.db)
+        .await?;
+        self.forget_user_discord_http(user);
+        let room = <&RoomId>::try_from(management_room)
+            .ok()
+            .and_then(|room_id| self.client.get_room(room_id));
+        if let Some(room) = room {
+            self.send_message(
+                &room,
+                RoomMessageEventContent::notice_plain(RELOGIN_INSTRUCTIONS),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Validates the stored user tokens that aren't known to be invalid
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    async fn validate_user_tokens(self: &Arc<Self>) -> Result<()> {
+        let tokens = query!(
+            "SELECT user_id, token, management_room, sealed FROM discord_tokens WHERE invalid_since IS NULL"
+        )
+        .fetch_all(&*self.db)
+        .await?;
+        for row in tokens {
+            let user = match UserId::parse(row.user_id.as_str()) {
+                Ok(user) => user,
+                Err(e) => {
+                    warn!(
+                        "Invalid user id {:?} in discord_tokens: {:?}",
+                        row.user_id, e
+                    );
+                    continue;
+                }
+            };
+            let token = match self.stored_token(row.token, row.sealed) {
+                Ok(token) => token,
+                Err(e) => {
+                    warn!("Failed to decrypt the token of {}: {:?}", user, e);
+                    continue;
+                }
+            };
+            match self.token_valid(&token).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    if let Err(e) = self
+                        .invalidate_user_token(&user, &row.management_room)
+                        .await
+                    {
+                        error!("Failed to invalidate the token of {}: {:?}", user, e);
+                    }
+                }
+                Err(e) => warn!("Failed to validate the token of {}: {:?}", user, e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Validates the bot token and reports changes of its validity to the admin room
+    ///
+    /// # Errors
+    /// This function will return an error if alerting the admin room fails
+    async fn validate_bot_token(self: &Arc<Self>) -> Result<()> {
+        let token = match self.config.discord.bot_token {
+            Some(ref token) => token,
+            None => return Ok(()),
+        };
+        let valid = match self.token_valid(&format!("Bot {}", token)).await {
+            Ok(valid) => valid,
+            Err(e) => {
+                warn!("Failed to validate the bot token: {:?}", e);
+                return Ok(());
+            }
+        };
+        let was_invalid = self.bot_token_invalid.swap(!valid, Ordering::AcqRel);
+        if valid == was_invalid {
+            self.alert_admin(if valid {
+                "Discord accepts the bot token again"
+            } else {
+                "Discord rejected the bot token, nothing is bridged until it is replaced"
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Validates the discord tokens periodically
+    pub(super) fn spawn_token_watchdog(self: &Arc<Self>) {
+        let this = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(WATCHDOG_INTERVAL);
+            loop {
+                interval.tick().await;
+                let this = match this.upgrade() {
+                    Some(this) => this,
+                    None => break,
+                };
+                if let Err(e) = this.validate_bot_token().await {
+                    error!("Bot token validation failed: {:?}", e);
+                }
+                if let Err(e) = this.refresh_oauth_tokens().await {
+                    error!("Refreshing OAuth2 tokens failed: {:?}", e);
+                }
+                if let Err(e) = this.validate_user_tokens().await {
+                    error!("User token validation failed: {:?}", e);
+                }
+            }
+        });
+    }
+}
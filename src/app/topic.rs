@@ -0,0 +1,159 @@
+//! Channel constraints in portal room topics
+//!
+//! If `bridge.topic_metadata` is enabled, constraints of the discord channel that matrix users
+//! can't see otherwise, like slowmode or the channel being read-only, are appended to the topic
+//! of the portal room in a delimited block. The block is replaced when the channel changes and
+//! the rest of the topic is left alone, unless the portal syncs the channel topic.
+
+use std::sync::Arc;
+
+use super::{portals::Portal, App};
+use anyhow::Result;
+use serde_json::json;
+use tracing::info;
+
+/// Start of the bridge-maintained block in a topic
+const BLOCK_START: &str = "[discord: ";
+
+/// End of the bridge-maintained block in a topic
+const BLOCK_END: &str = "]";
+
+/// Separator between the topic and the bridge-maintained block
+const BLOCK_SEPARATOR: &str = "\n\n";
+
+/// Formats a duration in seconds the way discord shows slowmode
+fn format_duration(seconds: u16) -> String {
+    match seconds {
+        s if s >= 3600 && s % 3600 == 0 => format!("{}h", s / 3600),
+        s if s >= 60 && s % 60 == 0 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+/// Returns the constraints of a channel to show in the topic
+fn topic_metadata(slowmode: Option<u16>, read_only: bool) -> Vec<String> {
+    let mut metadata = Vec::new();
+    if let Some(seconds) = slowmode.filter(|seconds| *seconds > 0) {
+        metadata.push(format!("slowmode {}", format_duration(seconds)));
+    }
+    if read_only {
+        metadata.push("read-only for Matrix".to_owned());
+    }
+    metadata
+}
+
+/// Returns the topic without the bridge-maintained block
+pub(super) fn strip_topic_metadata(topic: &str) -> &str {
+    if !topic.ends_with(BLOCK_END) {
+        return topic;
+    }
+    if let Some(index) = topic.rfind(&format!("{}{}", BLOCK_SEPARATOR, BLOCK_START)) {
+        return &topic[..index];
+    }
+    if topic.starts_with(BLOCK_START) && !topic.contains('\n') {
+        return "";
+    }
+    topic
+}
+
+/// Replaces the bridge-maintained block of a topic
+fn apply_topic_metadata(topic: &str, metadata: &[String]) -> String {
+    let topic = strip_topic_metadata(topic);
+    if metadata.is_empty() {
+        return topic.to_owned();
+    }
+    let block = format!("{}{}{}", BLOCK_START, metadata.join(", "), BLOCK_END);
+    if topic.is_empty() {
+        block
+    } else {
+        format!("{}{}{}", topic, BLOCK_SEPARATOR, block)
+    }
+}
+
+impl App {
+    /// Updates the topic of a portal room after its discord channel changed
+    ///
+    /// `text` replaces the topic apart from the channel constraints if the portal syncs the
+    /// channel topic. The constraints are only kept up to date if `bridge.topic_metadata` is
+    /// enabled.
+    ///
+    /// # Errors
+    /// This function will return an error if updating the topic fails
+    pub(super) async fn sync_portal_topic(
+        self: &Arc<Self>,
+        portal: &Portal,
+        text: Option<&str>,
+        slowmode: Option<u16>,
+        read_only: bool,
+    ) -> Result<()> {
+        if text.is_none() && !self.config.bridge.topic_metadata {
+            return Ok(());
+        }
+        let room = match self.client.get_joined_room(&portal.room_id) {
+            Some(room) => room,
+            None => return Ok(()),
+        };
+        let old = room.topic().unwrap_or_default();
+        let text = text.unwrap_or_else(|| strip_topic_metadata(&old));
+        let new = if self.config.bridge.topic_metadata {
+            apply_topic_metadata(text, &topic_metadata(slowmode, read_only))
+        } else {
+            text.to_owned()
+        };
+        if old == new {
+            return Ok(());
+        }
+        if self.dry_run {
+            info!(
+                "[dry-run] Would set the topic of {} to {:?}",
+                portal.room_id, new
+            );
+            return Ok(());
+        }
+        self.pipeline
+            .run(&self.user_id, "state", async {
+                room.send_state_event_raw(json!({ "topic": new }), "m.room.topic", "")
+                    .await?;
+                Ok(())
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_slowmode() {
+        assert_eq!(topic_metadata(Some(30), false), vec!["slowmode 30s"]);
+        assert_eq!(topic_metadata(Some(120), false), vec!["slowmode 2m"]);
+        assert_eq!(
+            topic_metadata(Some(21_600), true),
+            vec!["slowmode 6h", "read-only for Matrix"]
+        );
+        assert_eq!(topic_metadata(Some(90), false), vec!["slowmode 90s"]);
+        assert!(topic_metadata(Some(0), false).is_empty());
+    }
+
+    #[test]
+    fn replaces_metadata_block() {
+        let metadata = vec!["slowmode 30s".to_owned()];
+        let topic = apply_topic_metadata("General chat", &metadata);
+        assert_eq!(topic, "General chat\n\n[discord: slowmode 30s]");
+        assert_eq!(
+            apply_topic_metadata(&topic, &["read-only for Matrix".to_owned()]),
+            "General chat\n\n[discord: read-only for Matrix]"
+        );
+        assert_eq!(apply_topic_metadata(&topic, &[]), "General chat");
+        assert_eq!(
+            apply_topic_metadata("", &metadata),
+            "[discord: slowmode 30s]"
+        );
+        assert_eq!(apply_topic_metadata("[discord: slowmode 30s]", &[]), "");
+        assert_eq!(
+            apply_topic_metadata("Links [see pins]", &[]),
+            "Links [see pins]"
+        );
+    }
+}
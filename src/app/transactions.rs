@@ -0,0 +1,428 @@
+//! Appservice transactions from the homeserver
+//!
+//! The homeserver pushes events to the bridge in transactions. Every transaction is passed to the
+//! bridge bot, and puppet clients concurrently receive the events of the rooms they know and of
+//! their own memberships, which keeps their stores up to date without syncing.
+//!
+//! The events of a transaction are stored in the event queue and handled one after another. A
+//! marker is queued after the last event of every transaction, so that the time from its arrival until all
+//! of its events are handled can be measured end-to-end. Homeservers retry transactions that
+//! aren't answered within their timeout, so transactions taking a large part of it are logged
+//! before slow processing turns into redelivery storms.
+//!
+//! Completed transactions are recorded in the `transaction_log` table and answered right away
+//! when the homeserver sends them again, for example because the answer got lost. A transaction
+//! that failed halfway is processed again, the event queue skips the events of it that were
+//! queued before.
+//!
+//! Homeservers implementing MSC2409 also push EDUs in transactions. Typing notifications and
+//! read receipts are queued like the events of their room, the presence of users is kept in
+//! memory for the mention notifications, see `mention_dm`.
+
+use std::{sync::Arc, time::Duration};
+
+use super::{event_queue::MatrixEventKind, mscs::Msc, App};
+use crate::{metrics::METRICS, time};
+use anyhow::{bail, Result};
+use futures_util::future::join_all;
+use matrix_sdk::ruma::{
+    api::appservice::event::push_events::v1::IncomingRequest, events::AnyRoomEvent,
+    presence::PresenceState, serde::Raw, OwnedRoomId, OwnedUserId, RoomId, UserId,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::query;
+use tracing::{debug, error, info, warn};
+
+/// Time after which homeservers retry an unanswered transaction in milliseconds
+const TRANSACTION_TIMEOUT_MS: u128 = 60_000;
+
+/// Batches taking longer than this share of the transaction timeout are logged, in percent
+const SLOW_TRANSACTION_PERCENT: u128 = 50;
+
+/// Days after which completed transactions and queued event ids are forgotten
+const TRANSACTION_LOG_RETENTION_DAYS: i32 = 7;
+
+/// Interval between prunings of the transaction log
+const PRUNE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// EDUs of an appservice transaction
+#[derive(Debug, Default, Deserialize)]
+struct Ephemeral {
+    /// EDUs of homeservers implementing the stable MSC2409
+    #[serde(default)]
+    ephemeral: Vec<Value>,
+    /// EDUs of homeservers implementing MSC2409 before it was stable
+    #[serde(default, rename = "de.sorunome.msc2409.ephemeral")]
+    unstable_ephemeral: Vec<Value>,
+}
+
+/// An EDU the bridge handles
+#[derive(Debug, PartialEq)]
+enum Edu {
+    /// Typing notifications or read receipts in a room
+    Room {
+        /// Kind of the EDU
+        kind: MatrixEventKind,
+        /// Room the EDU happened in
+        room_id: OwnedRoomId,
+        /// JSON of the EDU
+        event: String,
+    },
+    /// Presence of a user
+    Presence {
+        /// The user
+        user_id: OwnedUserId,
+        /// The user's presence
+        presence: PresenceState,
+    },
+}
+
+impl Edu {
+    /// Reads an EDU, returning `None` for EDUs the bridge doesn't handle
+    fn parse(edu: Value) -> Option<Self> {
+        let kind = match edu["type"].as_str()? {
+            "m.typing" => MatrixEventKind::Typing,
+            "m.receipt" => MatrixEventKind::Receipt,
+            "m.presence" => {
+                return Some(Self::Presence {
+                    user_id: OwnedUserId::try_from(edu["sender"].as_str()?).ok()?,
+                    presence: serde_json::from_value(edu["content"]["presence"].clone()).ok()?,
+                })
+            }
+            _ => return None,
+        };
+        Some(Self::Room {
+            kind,
+            room_id: OwnedRoomId::try_from(edu["room_id"].as_str()?).ok()?,
+            event: edu.to_string(),
+        })
+    }
+}
+
+/// Returns the EDUs of a transaction body that the bridge handles
+///
+/// # Errors
+/// This function will return an error if the body isn't a JSON object
+fn edus(body: &[u8]) -> Result<Vec<Edu>> {
+    let ephemeral: Ephemeral = serde_json::from_slice(body)?;
+    Ok(ephemeral
+        .ephemeral
+        .into_iter()
+        .chain(ephemeral.unstable_ephemeral)
+        .filter_map(Edu::parse)
+        .collect())
+}
+
+/// An event of a transaction with the fields deciding which puppets receive it
+#[derive(Debug)]
+struct PushedEvent {
+    /// The event
+    event: Raw<AnyRoomEvent>,
+    /// Room of the event
+    room_id: Option<OwnedRoomId>,
+    /// State key of the event, the user of membership events
+    state_key: Option<String>,
+}
+
+impl PushedEvent {
+    /// Reads the fields of an event
+    fn new(event: Raw<AnyRoomEvent>) -> Self {
+        Self {
+            room_id: event.get_field("room_id").ok().flatten(),
+            state_key: event.get_field("state_key").ok().flatten(),
+            event,
+        }
+    }
+}
+
+/// Returns the events of a transaction a puppet receives
+///
+/// Those are the events of the rooms in the puppet's store and the puppet's own membership
+/// changes, which bring new rooms into its store.
+fn puppet_events(
+    events: &[PushedEvent],
+    puppet: &UserId,
+    known_room: impl Fn(&RoomId) -> bool,
+) -> Vec<Raw<AnyRoomEvent>> {
+    events
+        .iter()
+        .filter(|pushed| {
+            pushed.state_key.as_deref() == Some(puppet.as_str())
+                || pushed.room_id.as_deref().map_or(false, &known_room)
+        })
+        .map(|pushed| pushed.event.clone())
+        .collect()
+}
+
+impl App {
+    /// Handles the EDUs of an appservice transaction if MSC2409 is active
+    ///
+    /// # Errors
+    /// This function will return an error if the body is malformed or an EDU couldn't be queued
+    pub(super) async fn receive_edus(self: &Arc<Self>, body: &[u8]) -> Result<()> {
+        if !self.msc(Msc::EduDelivery) {
+            return Ok(());
+        }
+        for edu in edus(body)? {
+            match edu {
+                Edu::Room {
+                    kind,
+                    room_id,
+                    event,
+                } => self.queue_edu(kind, room_id, event).await?,
+                Edu::Presence { user_id, presence } => {
+                    debug!("{} is {}", user_id, presence.as_str());
+                    self.presence.insert(user_id, presence);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Passes an appservice transaction to the bridge bot
+    ///
+    /// # Errors
+    /// This function will return an error if the bridge bot can't process the transaction or its
+    /// event handlers failed to queue an event of it, so that the homeserver retries it
+    pub(super) async fn receive_transaction(
+        self: &Arc<Self>,
+        transaction: &IncomingRequest,
+    ) -> Result<()> {
+        let event_ids = transaction
+            .events
+            .iter()
+            .filter_map(|event| event.get_field::<String>("event_id").ok().flatten())
+            .collect::<Vec<_>>();
+        self.client.receive_transaction(transaction.clone()).await?;
+        let failed = event_ids
+            .iter()
+            .filter(|event_id| self.failed_events.remove(*event_id).is_some())
+            .count();
+        if failed > 0 {
+            bail!("{} events of the transaction couldn't be queued", failed);
+        }
+        Ok(())
+    }
+
+    /// Passes the events of a transaction to the puppets concurrently
+    ///
+    /// Each puppet only receives the events it needs, see `puppet_events`. This happens once the
+    /// transaction was queued completely, so that the homeserver doesn't retry transactions that
+    /// some puppets received already. Failures of puppets are only logged, so that a failing
+    /// puppet neither fails the transaction nor keeps the other puppets from receiving it.
+    pub(super) async fn push_to_puppets(self: &Arc<Self>, mut transaction: IncomingRequest) {
+        let events = std::mem::take(&mut transaction.events)
+            .into_iter()
+            .map(PushedEvent::new)
+            .collect::<Vec<_>>();
+        let domain = &self.config.homeserver.domain;
+        let mut puppets = Vec::new();
+        for client in self.discord_clients.iter() {
+            match self.puppet_user_id(*client.key()) {
+                Ok(user_id) => puppets.push((user_id, Arc::clone(&*client))),
+                Err(e) => warn!("Invalid puppet of {}: {:?}", client.key(), e),
+            }
+        }
+        for client in self.webhook_clients.iter() {
+            match UserId::parse(format!("@{}:{}", client.key(), domain)) {
+                Ok(user_id) => puppets.push((user_id, Arc::clone(&*client))),
+                Err(e) => warn!("Invalid webhook puppet {}: {:?}", client.key(), e),
+            }
+        }
+        let deliveries = puppets.into_iter().filter_map(|(user_id, puppet)| {
+            let events = puppet_events(&events, &user_id, |room_id| {
+                puppet.get_room(room_id).is_some()
+            });
+            if events.is_empty() {
+                return None;
+            }
+            let mut pushed = transaction.clone();
+            pushed.events = events;
+            Some(async move {
+                if let Err(e) = puppet.receive_transaction(pushed).await {
+                    warn!("{} failed to process a transaction: {:?}", user_id, e);
+                }
+            })
+        });
+        join_all(deliveries).await;
+    }
+
+    /// Records the processing time of a transaction once all of its events are handled
+    pub(super) fn finish_transaction(self: &Arc<Self>, received_ms: u64) {
+        let elapsed = u128::from(time::now_ms().saturating_sub(received_ms));
+        let elapsed_ms = i64::try_from(elapsed).unwrap_or(i64::MAX);
+        self.load
+            .record_latency(u64::try_from(elapsed).unwrap_or(u64::MAX));
+        METRICS.inc("bridge_transactions_count", &[]);
+        METRICS.add("bridge_transactions_duration_ms_sum", &[], elapsed_ms);
+        METRICS.set("bridge_transactions_last_duration_ms", &[], elapsed_ms);
+        METRICS.set(
+            "bridge_transactions_timeout_headroom_ms",
+            &[],
+            i64::try_from(TRANSACTION_TIMEOUT_MS)
+                .unwrap_or(i64::MAX)
+                .saturating_sub(elapsed_ms),
+        );
+        if elapsed * 100 > TRANSACTION_TIMEOUT_MS * SLOW_TRANSACTION_PERCENT {
+            METRICS.inc("bridge_transactions_slow", &[]);
+            warn!(
+                "Processing a transaction took {}ms, the homeserver retries after {}ms",
+                elapsed, TRANSACTION_TIMEOUT_MS
+            );
+        }
+    }
+
+    /// Returns whether a transaction was completed before
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub(super) async fn transaction_completed(&self, txn_id: &str) -> Result<bool> {
+        Ok(query!(
+            r#"SELECT EXISTS (SELECT 1 FROM transaction_log WHERE txn_id = $1 AND dry_run = $2) AS "completed!""#,
+            txn_id,
+            self.dry_run
+        )
+        .fetch_one(&*self.db)
+        .await?
+        .completed)
+    }
+
+    /// Records a transaction as completed
+    ///
+    /// # Errors
+    /// This function will return an error if the database query fails
+    #[allow(clippy::panic)]
+    pub(super) async fn complete_transaction(&self, txn_id: &str) -> Result<()> {
+        query!(
+            "INSERT INTO transaction_log (txn_id, dry_run) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            txn_id,
+            self.dry_run
+        )
+        .execute(&*self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Forgets completed transactions and queued event ids after the retention period
+    ///
+    /// # Errors
+    /// This function will return an error if a database query fails
+    #[allow(clippy::panic)]
+    async fn prune_transaction_log(&self) -> Result<()> {
+        let transactions = query!(
+            "DELETE FROM transaction_log WHERE dry_run = $1 AND completed_at < NOW() - make_interval(days => $2)",
+            self.dry_run,
+            TRANSACTION_LOG_RETENTION_DAYS
+        )
+        .execute(&*self.db)
+        .await?
+        .rows_affected();
+        let events = query!(
+            "DELETE FROM queued_matrix_events WHERE dry_run = $1 AND queued_at < NOW() - make_interval(days => $2)",
+            self.dry_run,
+            TRANSACTION_LOG_RETENTION_DAYS
+        )
+        .execute(&*self.db)
+        .await?
+        .rows_affected();
+        info!(
+            "Pruned {} completed transactions and {} queued event ids",
+            transactions, events
+        );
+        Ok(())
+    }
+
+    /// Prunes the transaction log periodically
+    pub(super) fn spawn_transaction_log_pruning(self: &Arc<Self>) {
+        let this = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+            loop {
+                interval.tick().await;
+                let this = match this.upgrade() {
+                    Some(this) => this,
+                    None => break,
+                };
+                if let Err(e) = this.prune_transaction_log().await {
+                    error!("Pruning the transaction log failed: {:?}", e);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_stable_and_unstable_edus() {
+        let body = serde_json::json!({
+            "events": [],
+            "ephemeral": [
+                {
+                    "type": "m.typing",
+                    "room_id": "!portal:chir.rs",
+                    "content": { "user_ids": ["@lotte:chir.rs"] },
+                },
+                { "type": "m.unknown", "content": {} },
+            ],
+            "de.sorunome.msc2409.ephemeral": [
+                {
+                    "type": "m.presence",
+                    "sender": "@lotte:chir.rs",
+                    "content": { "presence": "online" },
+                },
+            ],
+        });
+        let edus = edus(body.to_string().as_bytes()).expect("the body is an object");
+        assert_eq!(edus.len(), 2);
+        assert!(matches!(
+            edus[0],
+            Edu::Room {
+                kind: MatrixEventKind::Typing,
+                ..
+            }
+        ));
+        assert_eq!(
+            edus[1],
+            Edu::Presence {
+                user_id: crate::testkit::user("lotte"),
+                presence: PresenceState::Online,
+            }
+        );
+    }
+
+    #[test]
+    fn puppets_receive_their_rooms_and_memberships() {
+        let event = |room: &str, state_key: &str| {
+            let event = serde_json::json!({
+                "type": "m.room.member",
+                "event_id": format!("$in-{}", room),
+                "room_id": format!("!{}:chir.rs", room),
+                "sender": "@alice:chir.rs",
+                "state_key": state_key,
+                "origin_server_ts": 1,
+                "content": { "membership": "join" },
+            });
+            PushedEvent::new(Raw::from_json(
+                serde_json::value::to_raw_value(&event).expect("events serialize"),
+            ))
+        };
+        let puppet = crate::testkit::user("_discord_1");
+        let events = [
+            event("known", "@alice:chir.rs"),
+            event("unknown", "@alice:chir.rs"),
+            event("joined", puppet.as_str()),
+        ];
+        let known = crate::testkit::room("known");
+        let received = puppet_events(&events, &puppet, |room_id| room_id == known);
+        let rooms = received
+            .iter()
+            .filter_map(|event| event.get_field::<String>("room_id").ok().flatten())
+            .collect::<Vec<_>>();
+        assert_eq!(rooms, ["!known:chir.rs", "!joined:chir.rs"]);
+    }
+}
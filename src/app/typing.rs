@@ -0,0 +1,58 @@
+//! Typing notifications of matrix users
+//!
+//! Matrix users that logged in with their own discord account are shown as typing in the discord
+//! channel of a portal while they type in its room. The homeserver delivers typing notifications
+//! as EDUs of appservice transactions, see `transactions`. Discord shows a typing indicator for
+//! ten seconds, so it is triggered again whenever the typing users of the room change.
+
+use std::sync::Arc;
+
+use super::App;
+use crate::features::Feature;
+use anyhow::Result;
+use matrix_sdk::{
+    room::Room,
+    ruma::events::{typing::TypingEventContent, SyncEphemeralRoomEvent},
+};
+use tracing::info;
+
+impl App {
+    /// Shows the typing users of a portal room as typing on their discord accounts
+    ///
+    /// # Errors
+    /// This function will return an error if a database query or a request to discord fails
+    pub(super) async fn handle_typing_event(
+        self: &Arc<Self>,
+        event: SyncEphemeralRoomEvent<TypingEventContent>,
+        room: Room,
+    ) -> Result<()> {
+        let portal = match self.portal_by_room(room.room_id()).await? {
+            Some(portal) => portal,
+            None => return Ok(()),
+        };
+        if !self.portal_feature(&portal, Feature::Typing) {
+            return Ok(());
+        }
+        for user in &event.content.user_ids {
+            if self.is_bridge_user(user) {
+                continue;
+            }
+            let client = match self.user_discord_http(user).await? {
+                Some(client) => client,
+                None => continue,
+            };
+            if self.dry_run {
+                info!(
+                    "[dry-run] Would show {} as typing in {}",
+                    user, portal.channel_id
+                );
+                continue;
+            }
+            client
+                .create_typing_trigger(portal.channel_id)
+                .exec()
+                .await?;
+        }
+        Ok(())
+    }
+}
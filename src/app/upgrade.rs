@@ -0,0 +1,144 @@
+//! Upgrade notices in the admin room
+//!
+//! The version of every start is recorded. On the first start after the version changed, the
+//! admin room gets a notice with the old and new version, the database migrations applied since
+//! the previous version started, and deprecated keys that are still set in the config, so that
+//! operators notice changes that need their attention.
+
+use std::sync::Arc;
+
+use super::App;
+use crate::html;
+use anyhow::Result;
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use sqlx::query;
+use tracing::{info, warn};
+
+/// Returns the version of the bridge, with the git commit if it is known
+#[must_use]
+pub fn version() -> String {
+    match option_env!("VERGEN_GIT_SHA") {
+        Some(sha) => format!(
+            "{} ({})",
+            env!("CARGO_PKG_VERSION"),
+            sha.get(..8).unwrap_or(sha)
+        ),
+        None => env!("CARGO_PKG_VERSION").to_owned(),
+    }
+}
+
+/// Builds the plain and HTML body of an upgrade notice
+fn upgrade_notice(
+    old: &str,
+    new: &str,
+    migrations: &[String],
+    deprecated: &[String],
+) -> (String, String) {
+    let mut plain = vec![format!("Upgraded the bridge from {} to {}", old, new)];
+    let mut formatted = vec![format!(
+        "<p>Upgraded the bridge from <b>{}</b> to <b>{}</b></p>",
+        html::escape(old),
+        html::escape(new)
+    )];
+    for (title, items) in [
+        ("Applied migrations", migrations),
+        ("Deprecated config keys", deprecated),
+    ] {
+        if items.is_empty() {
+            continue;
+        }
+        plain.push(format!("{}:", title));
+        plain.extend(items.iter().map(|item| format!("- {}", item)));
+        formatted.push(format!("<p>{}:</p><ul>", title));
+        formatted.extend(
+            items
+                .iter()
+                .map(|item| format!("<li>{}</li>", html::escape(item))),
+        );
+        formatted.push("</ul>".to_owned());
+    }
+    (plain.join("\n"), formatted.concat())
+}
+
+impl App {
+    /// Records the running version and announces upgrades in the admin room
+    ///
+    /// # Errors
+    /// This function will return an error if a database query or sending the notice fails
+    #[allow(clippy::panic)]
+    pub(super) async fn announce_upgrade(self: &Arc<Self>) -> Result<()> {
+        for key in &self.config.deprecated_keys {
+            warn!("Deprecated config key {} is set", key);
+        }
+        let version = version();
+        let previous =
+            query!("SELECT version FROM bridge_versions ORDER BY started_at DESC LIMIT 1")
+                .fetch_optional(&*self.db)
+                .await?
+                .map(|row| row.version);
+        if previous.as_deref() == Some(version.as_str()) {
+            return Ok(());
+        }
+        if self.dry_run {
+            info!("[dry-run] Would record the upgrade to {}", version);
+            return Ok(());
+        }
+        let migrations = query!(
+            "SELECT description FROM _sqlx_migrations WHERE installed_on >= (SELECT MAX(started_at) FROM bridge_versions) ORDER BY version"
+        )
+        .fetch_all(&*self.db)
+        .await?
+        .into_iter()
+        .map(|row| row.description)
+        .collect::<Vec<_>>();
+        query!(
+            "INSERT INTO bridge_versions (version) VALUES ($1)",
+            version.as_str()
+        )
+        .execute(&*self.db)
+        .await?;
+        let previous = match previous {
+            Some(previous) => previous,
+            // Nothing to announce on the first start
+            None => return Ok(()),
+        };
+        info!("Upgraded from {} to {}", previous, version);
+        let room = match self.config.bridge.admin_room {
+            Some(ref room_id) => self.client.get_room(room_id),
+            None => None,
+        };
+        if let Some(room) = room {
+            let (plain, formatted) = upgrade_notice(
+                &previous,
+                &version,
+                &migrations,
+                &self.config.deprecated_keys,
+            );
+            self.send_message(
+                &room,
+                RoomMessageEventContent::notice_html(plain, formatted),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_upgrade_notices() {
+        let (plain, formatted) =
+            upgrade_notice("0.1.0", "0.2.0", &["bridge versions".to_owned()], &[]);
+        assert_eq!(
+            plain,
+            "Upgraded the bridge from 0.1.0 to 0.2.0\nApplied migrations:\n- bridge versions"
+        );
+        assert_eq!(
+            formatted,
+            "<p>Upgraded the bridge from <b>0.1.0</b> to <b>0.2.0</b></p><p>Applied migrations:</p><ul><li>bridge versions</li></ul>"
+        );
+    }
+}
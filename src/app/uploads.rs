@@ -0,0 +1,186 @@
+//! Matrix attachments on discord
+//!
+//! `m.image`, `m.video`, `m.audio` and `m.file` messages are relayed to discord as an upload of
+//! the file, named after the message's body. Discord limits the size of uploads depending on the
+//! boost tier of the guild. Twilight needs the whole file in memory, so the download from the
+//! homeserver is read in chunks and aborted once it exceeds the limit. Files that are too large
+//! are linked instead.
+
+use std::sync::Arc;
+
+use super::{webhooks::OutgoingMessage, App};
+use crate::retry::{retry, Backoff};
+use anyhow::Result;
+use matrix_sdk::ruma::{
+    events::room::{
+        message::{MessageType, RoomMessageEventContent},
+        MediaSource,
+    },
+    MxcUri, OwnedMxcUri,
+};
+use tracing::info;
+use twilight_model::{
+    guild::PremiumTier,
+    http::attachment::Attachment,
+    id::{marker::ChannelMarker, Id},
+};
+
+/// A file attached to a matrix message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MatrixAttachment {
+    /// Location of the file on the homeserver
+    pub mxc: OwnedMxcUri,
+    /// Name of the file on discord
+    pub filename: String,
+}
+
+/// Returns the file of a matrix message, if it has an unencrypted one
+pub fn matrix_attachment(content: &RoomMessageEventContent) -> Option<MatrixAttachment> {
+    let (body, source) = match content.msgtype {
+        MessageType::Image(ref image) => (&image.body, &image.source),
+        MessageType::File(ref file) => (&file.body, &file.source),
+        MessageType::Video(ref video) => (&video.body, &video.source),
+        MessageType::Audio(ref audio) => (&audio.body, &audio.source),
+        _ => return None,
+    };
+    match *source {
+        MediaSource::Plain(ref mxc) => Some(MatrixAttachment {
+            mxc: mxc.clone(),
+            filename: body.clone(),
+        }),
+        MediaSource::Encrypted(_) => None,
+    }
+}
+
+/// Returns the largest upload discord accepts in a guild of a boost tier, in bytes
+const fn upload_limit(tier: PremiumTier) -> u64 {
+    match tier {
+        PremiumTier::Tier2 => 50 * 1024 * 1024,
+        PremiumTier::Tier3 => 100 * 1024 * 1024,
+        _ => 8 * 1024 * 1024,
+    }
+}
+
+impl App {
+    /// Returns the largest upload discord accepts in a channel, in bytes
+    ///
+    /// # Errors
+    /// This function will return an error if the database query or the request to discord fails
+    async fn discord_upload_limit(self: &Arc<Self>, channel_id: Id<ChannelMarker>) -> Result<u64> {
+        let portal_channel = self.portal_channel(channel_id).await?;
+        let guild_id = match self.portal_by_channel(portal_channel).await? {
+            Some(portal) => portal.guild_id,
+            None => None,
+        };
+        let guild_id = match guild_id {
+            Some(guild_id) => guild_id,
+            None => return Ok(upload_limit(PremiumTier::None)),
+        };
+        let guild = self
+            .discord()?
+            .http
+            .guild(guild_id)
+            .exec()
+            .await?
+            .model()
+            .await?;
+        Ok(upload_limit(guild.premium_tier))
+    }
+
+    /// Downloads a file from the matrix content repository unless it is larger than `limit`
+    ///
+    /// # Errors
+    /// This function will return an error if the download fails
+    async fn download_matrix_media_limited(
+        &self,
+        mxc: &MxcUri,
+        limit: u64,
+    ) -> Result<Option<Vec<u8>>> {
+        let url = self.mxc_to_http(mxc)?;
+        let mut response = retry("matrix", Backoff::default(), || async {
+            self.http.get(url.clone()).send().await?.error_for_status()
+        })
+        .await?;
+        if response.content_length().map_or(false, |len| len > limit) {
+            return Ok(None);
+        }
+        let mut data = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if (data.len() + chunk.len()) as u64 > limit {
+                return Ok(None);
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(Some(data))
+    }
+
+    /// Returns the text and files to send to discord for a message
+    ///
+    /// The attachment is buffered in memory, because twilight only uploads files from memory, but
+    /// never beyond the channel's upload limit: larger attachments are replaced by a link to the
+    /// file once the download exceeds it.
+    ///
+    /// # Errors
+    /// This function will return an error if the download or a request to discord fails
+    pub(super) async fn discord_upload(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+        message: &OutgoingMessage,
+    ) -> Result<(String, Vec<Attachment>)> {
+        let attachment = match message.attachment {
+            Some(ref attachment) => attachment,
+            None => return Ok((message.content.clone(), Vec::new())),
+        };
+        let limit = self.discord_upload_limit(channel_id).await?;
+        match self
+            .download_matrix_media_limited(&attachment.mxc, limit)
+            .await?
+        {
+            Some(data) => Ok((
+                String::new(),
+                vec![Attachment::from_bytes(attachment.filename.clone(), data, 0)],
+            )),
+            None => {
+                info!(
+                    "{} is too large for {}, sending a link instead",
+                    attachment.mxc, channel_id
+                );
+                Ok((self.mxc_to_http(&attachment.mxc)?.to_string(), Vec::new()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::events::room::message::{
+        FileMessageEventContent, TextMessageEventContent,
+    };
+
+    #[test]
+    fn only_media_messages_have_attachments() {
+        let mxc = OwnedMxcUri::from("mxc://chir.rs/abc");
+        let content = RoomMessageEventContent::new(MessageType::File(
+            FileMessageEventContent::plain("notes.txt".to_owned(), mxc.clone(), None),
+        ));
+        assert_eq!(
+            matrix_attachment(&content),
+            Some(MatrixAttachment {
+                mxc,
+                filename: "notes.txt".to_owned(),
+            })
+        );
+        let content = RoomMessageEventContent::new(MessageType::Text(
+            TextMessageEventContent::plain("hello"),
+        ));
+        assert_eq!(matrix_attachment(&content), None);
+    }
+
+    #[test]
+    fn boosted_guilds_allow_larger_uploads() {
+        assert_eq!(upload_limit(PremiumTier::None), 8 * 1024 * 1024);
+        assert_eq!(upload_limit(PremiumTier::Tier1), 8 * 1024 * 1024);
+        assert_eq!(upload_limit(PremiumTier::Tier3), 100 * 1024 * 1024);
+    }
+}
@@ -0,0 +1,122 @@
+//! Puppets for messages of other bots' webhooks
+//!
+//! Bots like Tupperbox post messages through webhooks, each with its own name and avatar. Instead
+//! of attributing all of them to one user, every combination of webhook and name gets a puppet
+//! with that name and avatar. Portals can switch this off with the `webhook_puppets` feature.
+//! Messages relayed from matrix through the portal's own webhook are never puppeted.
+
+use std::{fmt::Write, sync::Arc};
+
+use super::{
+    client::VirtualClient, media::MediaRetention, portals::Portal, profiles::avatar_url, App,
+};
+use crate::features::Feature;
+use anyhow::Result;
+use matrix_sdk::ruma::{ServerName, UserId};
+use sha2::{Digest, Sha256};
+use tracing::info;
+use twilight_model::{
+    channel::Message,
+    id::{marker::WebhookMarker, Id},
+};
+
+/// Number of bytes of the name hash in the localpart
+const NAME_KEY_BYTES: usize = 6;
+
+/// Returns the localpart of the puppet of a webhook identity
+///
+/// The name is hashed, as display names can contain characters that aren't allowed in user ids.
+fn webhook_puppet_localpart(prefix: &str, webhook_id: Id<WebhookMarker>, name: &str) -> String {
+    let mut localpart = format!("{}_discord_webhook_{}_", prefix, webhook_id);
+    for byte in &Sha256::digest(name.as_bytes())[..NAME_KEY_BYTES] {
+        // Writing to a string can't fail
+        let _ = write!(localpart, "{:02x}", byte);
+    }
+    localpart
+}
+
+impl App {
+    /// Returns the puppet a message of another bot's webhook is attributed to
+    ///
+    /// Returns `None` if the message wasn't sent through a webhook, was relayed through the
+    /// portal's own webhook, or the portal has webhook puppets disabled.
+    ///
+    /// # Errors
+    /// This function will return an error if the puppet can't be registered or its profile can't
+    /// be set
+    pub(super) async fn webhook_puppet(
+        self: &Arc<Self>,
+        message: &Message,
+        portal: &Portal,
+    ) -> Result<Option<Arc<VirtualClient>>> {
+        let webhook_id = match message.webhook_id {
+            Some(webhook_id) => webhook_id,
+            None => return Ok(None),
+        };
+        if !self.portal_feature(portal, Feature::WebhookPuppets) {
+            return Ok(None);
+        }
+        if let Some((own, _)) = self.portal_webhook(portal.channel_id).await? {
+            if own == webhook_id {
+                return Ok(None);
+            }
+        }
+        let name = &message.author.name;
+        let localpart = webhook_puppet_localpart(&self.config.bridge.prefix, webhook_id, name);
+        if let Some(client) = self.webhook_clients.get(&localpart) {
+            return Ok(Some(Arc::clone(&*client)));
+        }
+        if self.dry_run {
+            info!("[dry-run] Would create puppet {} for {:?}", localpart, name);
+            return Ok(None);
+        }
+        self.try_register_user(&localpart).await?;
+        let client = Arc::new(VirtualClient::new(
+            self.appservice.virtual_user_client(&localpart).await?,
+        ));
+        let avatar = match message.author.avatar {
+            Some(avatar) => {
+                let url = avatar_url(message.author.id, &avatar.to_string());
+                self.mirror_discord_media(&url, None, MediaRetention::Permanent)
+                    .await?
+            }
+            None => None,
+        };
+        let user_id = UserId::parse_with_server_name(
+            localpart.as_str(),
+            <&ServerName>::try_from(self.config.homeserver.domain.as_str())?,
+        )?;
+        self.pipeline
+            .run(&user_id, "profile", async {
+                let account = client.account();
+                account.set_display_name(Some(name)).await?;
+                if let Some(ref avatar) = avatar {
+                    account.set_avatar_url(Some(avatar)).await?;
+                }
+                Ok(())
+            })
+            .await?;
+        self.webhook_clients.insert(localpart, Arc::clone(&client));
+        Ok(Some(client))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_puppets_by_webhook_and_name() {
+        let localpart = webhook_puppet_localpart("", Id::new(42), "Alice");
+        assert!(localpart.starts_with("_discord_webhook_42_"));
+        assert_eq!(
+            localpart.len(),
+            "_discord_webhook_42_".len() + 2 * NAME_KEY_BYTES
+        );
+        assert_ne!(localpart, webhook_puppet_localpart("", Id::new(42), "Bob"));
+        assert_ne!(
+            localpart,
+            webhook_puppet_localpart("", Id::new(43), "Alice")
+        );
+    }
+}
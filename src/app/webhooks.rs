@@ -0,0 +1,382 @@
+//! Custom webhooks for portals
+//!
+//! On servers where the bridge bot can't manage webhooks, an existing webhook can be configured
+//! for a portal instead. Its URL is stored encrypted and messages from matrix are sent through
+//! it with the same name and avatar the sender is relayed with otherwise.
+//!
+//! Edits on matrix are applied to the relayed message on discord, through the webhook's message
+//! edit endpoint if the message was sent through a webhook.
+
+use std::sync::Arc;
+
+use super::{mappings::MessageMapping, uploads::MatrixAttachment, App};
+use crate::{formatter::escape_markdown, snowflake};
+use anyhow::{anyhow, Result};
+use matrix_sdk::{
+    room::Room,
+    ruma::{events::room::message::RoomMessageEventContent, EventId, RoomOrAliasId, UserId},
+};
+use sqlx::query;
+use tracing::info;
+use twilight_model::{
+    channel::message::AllowedMentions,
+    id::{
+        marker::{ChannelMarker, MessageMarker, WebhookMarker},
+        Id,
+    },
+};
+
+/// Prefixes of discord webhook URLs
+const WEBHOOK_PREFIXES: &[&str] = &[
+    "https://discord.com/api/webhooks/",
+    "https://discordapp.com/api/webhooks/",
+    "https://canary.discord.com/api/webhooks/",
+    "https://ptb.discord.com/api/webhooks/",
+];
+
+/// A message to relay to discord
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OutgoingMessage {
+    /// Text of the message
+    pub content: String,
+    /// The discord message it replies to, see `discord_reply_target`
+    pub reply_to: Option<Id<MessageMarker>>,
+    /// File to attach, see `matrix_attachment`
+    pub attachment: Option<MatrixAttachment>,
+}
+
+/// A message relayed to discord
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SentMessage {
+    /// The discord message
+    pub id: Id<MessageMarker>,
+    /// The webhook the message was sent through, if any
+    pub webhook_id: Option<Id<WebhookMarker>>,
+}
+
+/// Parses a discord webhook URL into the webhook id and token
+fn parse_webhook_url(url: &str) -> Option<(Id<WebhookMarker>, String)> {
+    let rest = WEBHOOK_PREFIXES
+        .iter()
+        .find_map(|prefix| url.strip_prefix(prefix))?;
+    let (id, token) = rest.split_once('/')?;
+    let token = token.trim_end_matches('/');
+    if token.is_empty() || token.contains('/') {
+        return None;
+    }
+    Some((id.parse().ok().and_then(Id::new_checked)?, token.to_owned()))
+}
+
+impl App {
+    /// Stores or removes the custom webhook of a portal
+    ///
+    /// # Errors
+    /// This function will return an error if no secret key is configured or the database query
+    /// fails
+    #[allow(clippy::panic)]
+    async fn set_portal_webhook(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+        url: Option<&str>,
+    ) -> Result<()> {
+        let sealed = match url {
+            Some(url) => Some(self.secrets()?.seal(url)?),
+            None => None,
+        };
+        query!(
+            "UPDATE portals SET webhook_url = $2 WHERE discord_channel_id = $1",
+            snowflake::to_db(channel_id),
+            sealed
+        )
+        .execute(&*self.db)
+        .await?;
+        Ok(())
+    }
+
+    /// Returns the custom webhook of a portal, if one is configured
+    ///
+    /// # Errors
+    /// This function will return an error if the database query or decrypting the URL fails
+    #[allow(clippy::panic)]
+    pub async fn portal_webhook(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+    ) -> Result<Option<(Id<WebhookMarker>, String)>> {
+        let sealed = query!(
+            "SELECT webhook_url FROM portals WHERE discord_channel_id = $1",
+            snowflake::to_db(channel_id)
+        )
+        .fetch_optional(&*self.db)
+        .await?
+        .and_then(|row| row.webhook_url);
+        let sealed = match sealed {
+            Some(sealed) => sealed,
+            None => return Ok(None),
+        };
+        let url = self.secrets()?.open(&sealed)?;
+        parse_webhook_url(&url)
+            .map(Some)
+            .ok_or_else(|| anyhow!("Stored webhook URL of {} is invalid", channel_id))
+    }
+
+    /// Sends a message from a matrix user to a discord channel
+    ///
+    /// Senders logged in with their own discord account send it as themselves. Otherwise the
+    /// message is sent through the custom webhook of the portal if one is configured, using the
+    /// sender's relay identity, with the guild's bot avatar if the sender has none, or else by the
+    /// bridge bot with the escaped name prefixed and no mentions allowed, so that the name can't
+    /// format or ping. Replies reference `reply_to`, or link to it when sent through the webhook.
+    /// `channel_id` may be a bridged thread, which is sent to through the
+    /// webhook of its parent's portal.
+    ///
+    /// # Errors
+    /// This function will return an error if transferring the attachment or the request to
+    /// discord fails
+    pub async fn send_to_discord(
+        self: &Arc<Self>,
+        channel_id: Id<ChannelMarker>,
+        sender: &UserId,
+        message: &OutgoingMessage,
+    ) -> Result<Option<SentMessage>> {
+        if let Some(client) = self.user_discord_http(sender).await? {
+            if self.dry_run {
+                info!(
+                    "[dry-run] Would send to {} as the account of {}: {}",
+                    channel_id, sender, message.content
+                );
+                return Ok(None);
+            }
+            let (content, files) = self.discord_upload(channel_id, message).await?;
+            let mut request = client
+                .create_message(channel_id)
+                .content(&content)?
+                .attachments(&files)?;
+            if let Some(reply_to) = message.reply_to {
+                request = request.reply(reply_to).fail_if_not_exists(false);
+            }
+            let sent = SentMessage {
+                id: request.exec().await?.model().await?.id,
+                webhook_id: None,
+            };
+            self.echoes.record_message(sent);
+            return Ok(Some(sent));
+        }
+        let identity = self.relay_identity(sender).await?;
+        let portal_channel = self.portal_channel(channel_id).await?;
+        let webhook = self.portal_webhook(portal_channel).await?;
+        if self.dry_run {
+            info!(
+                "[dry-run] Would send to {} as {}: {}",
+                channel_id, identity.name, message.content
+            );
+            return Ok(None);
+        }
+        let (content, files) = self.discord_upload(channel_id, message).await?;
+        let http = &self.discord()?.http;
+        let webhook_id = webhook.as_ref().map(|(webhook_id, _)| *webhook_id);
+        let sent = match webhook {
+            Some((webhook_id, token)) => {
+                let avatar = match identity.avatar.as_deref() {
+                    Some(avatar) => Some(avatar),
+                    None => self.fallback_avatar(portal_channel).await?,
+                };
+                let avatar = avatar.map(|avatar| self.mxc_to_http(avatar)).transpose()?;
+                let content = match message.reply_to {
+                    Some(reply_to) => format!(
+                        "{}\n{}",
+                        self.webhook_reply_line(channel_id, reply_to).await?,
+                        content
+                    ),
+                    None => content,
+                };
+                let mut request = http
+                    .execute_webhook(webhook_id, &token)
+                    .content(&content)?
+                    .attachments(&files)?
+                    .username(&identity.name)?;
+                if let Some(ref avatar) = avatar {
+                    request = request.avatar_url(avatar.as_str());
+                }
+                if portal_channel != channel_id {
+                    request = request.thread_id(channel_id);
+                }
+                request.wait().exec().await?.model().await?
+            }
+            None => {
+                let content = format!("**{}**: {}", escape_markdown(&identity.name), content);
+                let mut request = http
+                    .create_message(channel_id)
+                    .content(&content)?
+                    .attachments(&files)?
+                    .allowed_mentions(Some(&AllowedMentions::default()));
+                if let Some(reply_to) = message.reply_to {
+                    request = request.reply(reply_to).fail_if_not_exists(false);
+                }
+                request.exec().await?.model().await?
+            }
+        };
+        let sent = SentMessage {
+            id: sent.id,
+            webhook_id,
+        };
+        self.echoes.record_message(sent);
+        Ok(Some(sent))
+    }
+
+    /// Applies a matrix edit to the message it was relayed as
+    ///
+    /// Messages sent through a webhook are edited through it, messages sent as the sender's own
+    /// account with it, and others by the bridge bot. Returns `false` if the edited event wasn't
+    /// relayed to discord.
+    ///
+    /// # Errors
+    /// This function will return an error if the database query or the request to discord fails
+    pub async fn edit_on_discord(
+        self: &Arc<Self>,
+        event_id: &EventId,
+        sender: &UserId,
+        content: &str,
+    ) -> Result<bool> {
+        let mapping = match MessageMapping::relayed_for_event(&*self.db, event_id).await? {
+            Some(mapping) => mapping,
+            None => return Ok(false),
+        };
+        let (message_id, channel_id) = (mapping.message_id, mapping.channel_id);
+        if self.dry_run {
+            info!(
+                "[dry-run] Would edit {} in {}: {}",
+                message_id, channel_id, content
+            );
+            return Ok(true);
+        }
+        let http = &self.discord()?.http;
+        match mapping.webhook_id {
+            Some(webhook_id) => {
+                let portal_channel = self.portal_channel(channel_id).await?;
+                let token = match self.portal_webhook(portal_channel).await? {
+                    Some((id, token)) if id == webhook_id => token,
+                    _ => {
+                        return Err(anyhow!(
+                            "The webhook {} of {} was removed or replaced",
+                            webhook_id,
+                            channel_id
+                        ))
+                    }
+                };
+                let mut request = http
+                    .update_webhook_message(webhook_id, &token, message_id)
+                    .content(Some(content))?;
+                if portal_channel != channel_id {
+                    request = request.thread_id(channel_id);
+                }
+                request.exec().await?;
+            }
+            None => {
+                if let Some(client) = self
+                    .puppeted_message_http(sender, channel_id, message_id)
+                    .await?
+                {
+                    client
+                        .update_message(channel_id, message_id)
+                        .content(Some(content))?
+                        .exec()
+                        .await?;
+                    return Ok(true);
+                }
+                let identity = self.relay_identity(sender).await?;
+                let content = format!("**{}**: {}", escape_markdown(&identity.name), content);
+                http.update_message(channel_id, message_id)
+                    .content(Some(&content))?
+                    .allowed_mentions(Some(&AllowedMentions::default()))
+                    .exec()
+                    .await?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Handles the `webhook` command
+    ///
+    /// `webhook <room> <url>` configures a custom webhook for a portal and `webhook <room>
+    /// remove` goes back to the bridge-managed one.
+    ///
+    /// # Errors
+    /// This function will return an error if the reply could not be sent
+    pub(super) async fn handle_webhook_command(
+        self: &Arc<Self>,
+        sender: &UserId,
+        args: &[&str],
+        room: &Room,
+    ) -> Result<()> {
+        let reply = if sender == self.config.bridge.admin {
+            match self.webhook_command_reply(args).await {
+                Ok(reply) => reply,
+                Err(e) => format!("Failed to update the webhook: {}", e),
+            }
+        } else {
+            "Only the bridge admin can configure webhooks".to_owned()
+        };
+        self.send_message(room, RoomMessageEventContent::text_plain(reply))
+            .await?;
+        Ok(())
+    }
+
+    /// Runs the `webhook` command and returns the reply
+    ///
+    /// # Errors
+    /// This function will return an error if the portal can't be updated
+    async fn webhook_command_reply(self: &Arc<Self>, args: &[&str]) -> Result<String> {
+        let (target, url) = match args {
+            ["webhook", target, "remove"] => (target, None),
+            ["webhook", target, url] => (target, Some(*url)),
+            _ => return Ok("Usage: webhook <room> <webhook url>|remove".to_owned()),
+        };
+        if url.map_or(false, |url| parse_webhook_url(url).is_none()) {
+            return Ok("That is not a discord webhook URL".to_owned());
+        }
+        let target = <&RoomOrAliasId>::try_from(*target)
+            .map_err(|_| anyhow!("{} is not a room id or alias", target))?;
+        let portal = self
+            .portal_by_room_or_alias(target)
+            .await?
+            .ok_or_else(|| anyhow!("{} is not a portal", target))?;
+        if self.dry_run {
+            info!("[dry-run] Would update the webhook of {}", portal.room_id);
+            return Ok(format!("Would update the webhook of {}", target));
+        }
+        self.set_portal_webhook(portal.channel_id, url).await?;
+        Ok(match url {
+            Some(_) => format!("{} now sends messages through the custom webhook", target),
+            None => format!("Removed the custom webhook of {}", target),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_webhook_urls() {
+        assert_eq!(
+            parse_webhook_url("https://discord.com/api/webhooks/123/abc-DEF_9"),
+            Some((Id::new(123), "abc-DEF_9".to_owned()))
+        );
+        assert_eq!(
+            parse_webhook_url("https://discordapp.com/api/webhooks/123/abc/"),
+            Some((Id::new(123), "abc".to_owned()))
+        );
+        assert_eq!(
+            parse_webhook_url("https://discord.com/api/webhooks/123"),
+            None
+        );
+        assert_eq!(
+            parse_webhook_url("https://example.com/api/webhooks/1/a"),
+            None
+        );
+        assert_eq!(
+            parse_webhook_url("https://discord.com/api/webhooks/x/a"),
+            None
+        );
+    }
+}
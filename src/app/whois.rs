@@ -0,0 +1,198 @@
+//! Identity lookup across both sides of the bridge
+
+use std::sync::Arc;
+
+use super::{commands::parse_user, App};
+use anyhow::Result;
+use matrix_sdk::{
+    room::Room,
+    ruma::{
+        api::client::profile::get_profile, events::room::message::RoomMessageEventContent,
+        OwnedUserId, UserId,
+    },
+};
+use tracing::debug;
+use twilight_model::id::{marker::UserMarker, Id};
+
+/// Identity of a user on both sides of the bridge
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Identity {
+    /// Matrix user, either the real user or the puppet
+    matrix_user: Option<OwnedUserId>,
+    /// Whether `matrix_user` is a puppet of `discord_user`
+    is_puppet: bool,
+    /// Discord user
+    discord_user: Option<Id<UserMarker>>,
+}
+
+impl App {
+    /// Resolves a whois argument into both identities of a user
+    ///
+    /// # Errors
+    /// This function will return an error if a database query fails
+    async fn resolve_identity(self: &Arc<Self>, arg: &str) -> Result<Option<Identity>> {
+        if let Ok(user_id) = UserId::parse(arg) {
+            if let Some(discord_user) = self.puppet_discord_id(&user_id) {
+                return Ok(Some(Identity {
+                    matrix_user: Some(user_id),
+                    is_puppet: true,
+                    discord_user: Some(discord_user),
+                }));
+            }
+            let discord_user = self.linked_discord_user(&user_id).await?;
+            return Ok(Some(Identity {
+                matrix_user: Some(user_id),
+                is_puppet: false,
+                discord_user,
+            }));
+        }
+        let discord_user = match parse_user(arg) {
+            Some(discord_user) => discord_user,
+            None => return Ok(None),
+        };
+        let matrix_user = self.linked_matrix_user(discord_user).await?;
+        Ok(Some(Identity {
+            is_puppet: matrix_user.is_none(),
+            matrix_user: match matrix_user {
+                Some(matrix_user) => Some(matrix_user),
+                None => Some(self.puppet_user_id(discord_user)?),
+            },
+            discord_user: Some(discord_user),
+        }))
+    }
+
+    /// Returns the display name of a matrix user
+    async fn matrix_displayname(self: &Arc<Self>, user_id: &UserId) -> Option<String> {
+        let client = self.client(None).await.ok()?;
+        match client
+            .send(get_profile::v3::Request::new(user_id), None)
+            .await
+        {
+            Ok(profile) => profile.displayname,
+            Err(e) => {
+                debug!("Failed to fetch profile of {}: {:?}", user_id, e);
+                None
+            }
+        }
+    }
+
+    /// Returns the name of a discord user
+    async fn discord_username(self: &Arc<Self>, user_id: Id<UserMarker>) -> Option<String> {
+        let user = self
+            .discord()
+            .ok()?
+            .http
+            .user(user_id)
+            .exec()
+            .await
+            .ok()?
+            .model()
+            .await
+            .ok()?;
+        Some(format!("{}#{:04}", user.name, user.discriminator))
+    }
+
+    /// Returns the portal rooms a matrix user is a member of
+    ///
+    /// # Errors
+    /// This function will return an error if the portals or the room members can't be read
+    async fn shared_portals(self: &Arc<Self>, user_id: &UserId) -> Result<Vec<String>> {
+        let mut rooms = Vec::new();
+        for portal in self.all_portals().await? {
+            let room = match self.client.get_joined_room(&portal.room_id) {
+                Some(room) => room,
+                None => continue,
+            };
+            if room.get_member_no_sync(user_id).await?.is_some() {
+                rooms.push(
+                    portal
+                        .alias
+                        .map_or_else(|| portal.room_id.to_string(), |alias| alias.to_string()),
+                );
+            }
+        }
+        Ok(rooms)
+    }
+
+    /// Handles the `whois` command
+    ///
+    /// Admins can look up everyone, other users only themselves.
+    ///
+    /// # Errors
+    /// This function will return an error if a lookup fails or the reply could not be sent
+    pub(super) async fn handle_whois_command(
+        self: &Arc<Self>,
+        sender: &UserId,
+        args: &[&str],
+        room: &Room,
+    ) -> Result<()> {
+        let identity = match args {
+            ["whois", arg] => self.resolve_identity(arg).await?,
+            _ => {
+                let content =
+                    RoomMessageEventContent::text_plain("Usage: whois <mxid or discord id>");
+                self.send_message(room, content).await?;
+                return Ok(());
+            }
+        };
+        let identity = match identity {
+            Some(identity) => identity,
+            None => {
+                let content =
+                    RoomMessageEventContent::text_plain("Not a matrix user id or discord user id");
+                self.send_message(room, content).await?;
+                return Ok(());
+            }
+        };
+        let is_self = identity.matrix_user.as_deref() == Some(sender);
+        if sender != self.config.bridge.admin && !is_self {
+            let content = RoomMessageEventContent::text_plain(
+                "Only the bridge admin can look up other users",
+            );
+            self.send_message(room, content).await?;
+            return Ok(());
+        }
+
+        let mut lines = Vec::new();
+        if let Some(ref matrix_user) = identity.matrix_user {
+            let kind = if identity.is_puppet { "puppet" } else { "user" };
+            lines.push(format!("Matrix {}: {}", kind, matrix_user));
+            if let Some(displayname) = self.matrix_displayname(matrix_user).await {
+                lines.push(format!("Display name: {}", displayname));
+            }
+        }
+        match identity.discord_user {
+            Some(discord_user) => {
+                let name = self
+                    .discord_username(discord_user)
+                    .await
+                    .unwrap_or_else(|| "unknown".to_owned());
+                lines.push(format!("Discord user: {} ({})", name, discord_user));
+            }
+            None => lines.push("Discord user: not linked".to_owned()),
+        }
+        if let Some(matrix_user) = identity
+            .matrix_user
+            .as_ref()
+            .filter(|_| !identity.is_puppet)
+        {
+            let status = if self.is_logged_in(matrix_user).await? {
+                "logged in"
+            } else {
+                "not logged in"
+            };
+            lines.push(format!("Login status: {}", status));
+        }
+        if let Some(ref matrix_user) = identity.matrix_user {
+            let rooms = self.shared_portals(matrix_user).await?;
+            if rooms.is_empty() {
+                lines.push("Bridged rooms: none".to_owned());
+            } else {
+                lines.push(format!("Bridged rooms: {}", rooms.join(", ")));
+            }
+        }
+        self.send_message(room, RoomMessageEventContent::text_plain(lines.join("\n")))
+            .await?;
+        Ok(())
+    }
+}
@@ -7,10 +7,15 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use anyhow::Result;
+use crate::{features::Features, locale::Locale};
+use anyhow::{bail, Result};
 use educe::Educe;
-use matrix_sdk::ruma::OwnedUserId;
+use matrix_sdk::ruma::{OwnedMxcUri, OwnedRoomId, OwnedUserId};
 use serde::{Deserialize, Serialize};
+use twilight_model::id::{
+    marker::{ChannelMarker, GuildMarker},
+    Id,
+};
 use url::Url;
 
 /// Configuration file
@@ -20,6 +25,34 @@ pub struct File {
     pub homeserver: Homeserver,
     /// Bridge configuration
     pub bridge: Bridge,
+    /// Discord configuration
+    #[serde(default)]
+    pub discord: Discord,
+    /// Optional bridge features
+    #[serde(default)]
+    pub features: Features,
+    /// Deprecated keys set in the file, with notes on what replaces them
+    #[serde(skip)]
+    pub deprecated_keys: Vec<String>,
+}
+
+/// Deprecated config keys as dotted paths, with notes on what replaces them
+///
+/// Keys stay listed until their field is removed, so that operators are told before an upgrade
+/// breaks their configuration.
+pub const DEPRECATED_KEYS: &[(&str, &str)] = &[];
+
+/// Returns the deprecated keys set in a configuration
+fn find_deprecated(config: &serde_yaml::Value, deprecated: &[(&str, &str)]) -> Vec<String> {
+    deprecated
+        .iter()
+        .filter(|(path, _)| {
+            path.split('.')
+                .try_fold(config, |value, key| value.get(key))
+                .is_some()
+        })
+        .map(|(path, note)| format!("{} ({})", path, note))
+        .collect()
 }
 
 impl File {
@@ -28,8 +61,26 @@ impl File {
     /// # Errors
     /// This function returns an error if accessing the disk fails or the file is invalid
     pub fn read_from_file(f: impl AsRef<Path>) -> Result<Self> {
-        let file = fs::File::open(f)?;
-        Ok(serde_yaml::from_reader(file)?)
+        let text = fs::read_to_string(f)?;
+        let mut config: Self = serde_yaml::from_str(&text)?;
+        config.deprecated_keys = find_deprecated(&serde_yaml::from_str(&text)?, DEPRECATED_KEYS);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks values that are valid YAML but can't be used
+    ///
+    /// # Errors
+    /// This function returns an error naming the first invalid value
+    pub fn validate(&self) -> Result<()> {
+        if self.discord.outage_queue_limit < MIN_OUTAGE_QUEUE_LIMIT {
+            bail!(
+                "discord.outage_queue_limit must be at least {}, got {}",
+                MIN_OUTAGE_QUEUE_LIMIT,
+                self.discord.outage_queue_limit
+            );
+        }
+        Ok(())
     }
 }
 
@@ -84,13 +135,17 @@ pub struct DBOptions {
     /// Extra float digits
     #[serde(skip_serializing_if = "Option::is_none")]
     pub extra_float_digits: Option<i8>,
+    /// Queries taking longer than this are logged as slow, in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slow_query_ms: Option<u64>,
     /// Additional options
     #[serde(default)]
     #[serde(skip_serializing_if = "BTreeMap::is_empty")]
     pub options: BTreeMap<String, String>,
 }
 /// Bridge Configuration
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Educe, Deserialize, Serialize)]
+#[educe(Debug)]
 pub struct Bridge {
     /// Addresses to listen on
     pub listen_address: Vec<IpAddr>,
@@ -106,4 +161,333 @@ pub struct Bridge {
     pub db: DBOptions,
     /// Admin username
     pub admin: OwnedUserId,
+    /// Room the bridge posts operational alerts to, like degraded portals
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin_room: Option<OwnedRoomId>,
+    /// Room listing the public portals, kept up to date by the bridge
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub catalog_room: Option<OwnedRoomId>,
+    /// Maximum number of concurrent requests to the homeserver
+    #[serde(default = "default_homeserver_parallelism")]
+    pub homeserver_parallelism: usize,
+    /// Maximum number of rooms and channels whose events are handled concurrently
+    #[serde(default = "default_event_parallelism")]
+    pub event_parallelism: usize,
+    /// Seconds to wait on shutdown for queued events to be handled
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    /// Whether the bridge may act on behalf of local users
+    ///
+    /// This adds a non-exclusive namespace for all local users to the registration.
+    #[serde(default)]
+    pub double_puppet: bool,
+    /// Whether double-puppeted discord administrators and moderators get elevated power levels
+    /// in portal rooms
+    #[serde(default)]
+    pub moderator_power: bool,
+    /// Number of months message mappings are kept, forever if unset
+    ///
+    /// Mappings of pinned messages are never pruned.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_retention_months: Option<u32>,
+    /// Whether tracking parameters are removed from links in bridged messages
+    #[serde(default)]
+    pub strip_tracking_params: bool,
+    /// Whether channel constraints like slowmode are appended to portal room topics
+    #[serde(default)]
+    pub topic_metadata: bool,
+    /// Whether the discord roles of puppets are published as state events in portal rooms
+    #[serde(default)]
+    pub member_roles: bool,
+    /// Display name of puppets
+    ///
+    /// `{username}` is replaced by the discord username and `{displayname}` by the nickname in the
+    /// guild of the portal, or the username outside of guilds and for members without one.
+    #[serde(default = "default_displayname_template")]
+    pub displayname_template: String,
+    /// Which discord members get puppets in portal rooms
+    #[serde(default)]
+    pub member_sync: MemberSync,
+    /// Direction in which kicks and bans of puppets are bridged
+    #[serde(default)]
+    pub moderation_sync: ModerationSync,
+    /// Locale used in portals that don't have one set
+    #[serde(default)]
+    pub default_locale: Locale,
+    /// Key used to encrypt secrets like custom webhook URLs in the database
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    #[educe(Debug(ignore))]
+    pub secret_key: Option<String>,
+    /// Synapse admin token used to delete media the bridge uploaded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    #[educe(Debug(ignore))]
+    pub media_admin_token: Option<String>,
+    /// Webhooks notified about moderation events
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub event_webhooks: Vec<EventWebhook>,
+    /// Periodic check of mappings against matrix and discord, disabled if unset
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub integrity_check: Option<IntegrityCheck>,
+}
+
+/// Periodic integrity check configuration
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct IntegrityCheck {
+    /// Number of entries of each kind that are checked per run
+    #[serde(default = "default_integrity_sample_size")]
+    pub sample_size: u32,
+    /// Whether dangling message mappings and account links are removed
+    #[serde(default)]
+    pub repair: bool,
+}
+
+/// Default for [`IntegrityCheck::sample_size`]
+const fn default_integrity_sample_size() -> u32 {
+    50
+}
+
+/// Outbound webhook notified about moderation events
+#[derive(Clone, Educe, Deserialize, Serialize)]
+#[educe(Debug)]
+pub struct EventWebhook {
+    /// URL the events are posted to
+    pub url: Url,
+    /// Secret the request bodies are signed with
+    #[educe(Debug(ignore))]
+    pub secret: String,
+    /// Events posted to the webhook, all if empty
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<ModerationEvent>,
+}
+
+/// Moderation event that can be posted to webhooks
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationEvent {
+    /// A bridged message was deleted on discord
+    MessageDeleted,
+    /// A user was banned on discord or in a portal room
+    UserBanned,
+    /// A portal room was created
+    PortalCreated,
+}
+
+impl ModerationEvent {
+    /// Returns the name of the event in payloads and configuration
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::MessageDeleted => "message_deleted",
+            Self::UserBanned => "user_banned",
+            Self::PortalCreated => "portal_created",
+        }
+    }
+}
+
+/// Selection of the discord members that are joined into portal rooms
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MemberSync {
+    /// Join members once they are active in a channel
+    Lazy,
+    /// Join every member that can view a channel
+    Full,
+}
+
+impl Default for MemberSync {
+    fn default() -> Self {
+        Self::Lazy
+    }
+}
+
+/// Direction in which kicks and bans are bridged
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationSync {
+    /// Kicks and bans are not bridged
+    Off,
+    /// Discord kicks and bans apply to the puppets in portal rooms
+    FromDiscord,
+    /// Matrix bans of puppets also ban their users on discord
+    Both,
+}
+
+impl Default for ModerationSync {
+    fn default() -> Self {
+        Self::Off
+    }
+}
+
+impl ModerationSync {
+    /// Returns whether discord kicks and bans are applied in portal rooms
+    #[must_use]
+    pub const fn syncs_from_discord(self) -> bool {
+        !matches!(self, Self::Off)
+    }
+
+    /// Returns whether matrix bans are applied on discord
+    #[must_use]
+    pub const fn syncs_to_discord(self) -> bool {
+        matches!(self, Self::Both)
+    }
+}
+
+/// Default for [`Bridge::displayname_template`]
+fn default_displayname_template() -> String {
+    "{displayname} (Discord)".to_owned()
+}
+
+/// Default for [`Bridge::homeserver_parallelism`]
+const fn default_homeserver_parallelism() -> usize {
+    16
+}
+
+/// Default for [`Bridge::event_parallelism`]
+const fn default_event_parallelism() -> usize {
+    16
+}
+
+/// Default for [`Bridge::shutdown_timeout_secs`]
+const fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+/// Discord configuration
+#[derive(Clone, Educe, Deserialize, Serialize)]
+#[educe(Debug, Default)]
+pub struct Discord {
+    /// Token of the bridge bot
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[educe(Debug(ignore))]
+    pub bot_token: Option<String>,
+    /// Where the bridge's slash commands are registered
+    #[serde(default)]
+    pub command_scope: CommandScope,
+    /// Channel knocks on portals are announced in
+    ///
+    /// If unset, the owner of the portal's guild is notified by direct message.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub knock_channel: Option<Id<ChannelMarker>>,
+    /// Maximum number of messages queued per channel while discord is unreachable
+    ///
+    /// At least 2, so that a summary of dropped messages and the newest message fit.
+    #[serde(default = "default_outage_queue_limit")]
+    #[educe(Default = 500)]
+    pub outage_queue_limit: usize,
+    /// What happens to queued messages beyond the limit
+    #[serde(default)]
+    pub outage_overflow: OverflowPolicy,
+    /// Identity of the bridge bot in the portals of a guild
+    #[serde(default)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub guild_identities: BTreeMap<Id<GuildMarker>, BotIdentity>,
+    /// OAuth2 login of matrix users, only token logins are possible if unset
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub oauth: Option<OAuth>,
+}
+
+/// OAuth2 application used to log matrix users into discord
+///
+/// The client id is the application id of the bridge bot.
+#[derive(Clone, Educe, Deserialize, Serialize)]
+#[educe(Debug)]
+pub struct OAuth {
+    /// Client secret of the bot's application
+    #[educe(Debug(ignore))]
+    pub client_secret: String,
+    /// Public URL of the bridge's `/oauth/callback` route, registered as a redirect in the
+    /// discord developer portal
+    pub redirect_url: Url,
+}
+
+/// Name and avatar of the bridge bot in the portals of a guild
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct BotIdentity {
+    /// Display name of the bot in portal rooms and its nickname in the guild
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Avatar of the bot in portal rooms, also used for relayed messages of users without one
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avatar: Option<OwnedMxcUri>,
+}
+
+/// Smallest valid [`Discord::outage_queue_limit`]
+const MIN_OUTAGE_QUEUE_LIMIT: usize = 2;
+
+/// Default for [`Discord::outage_queue_limit`]
+const fn default_outage_queue_limit() -> usize {
+    500
+}
+
+/// Handling of messages beyond the outage queue limit
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued messages
+    DropOldest,
+    /// Replace the oldest queued messages with a note saying how many were dropped
+    Summarize,
+}
+
+impl Default for OverflowPolicy {
+    fn default() -> Self {
+        Self::DropOldest
+    }
+}
+
+/// Scope of slash command registrations
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandScope {
+    /// Register commands globally
+    Global,
+    /// Register commands in every guild, which takes effect immediately
+    Guild,
+    /// Don't register slash commands
+    Disabled,
+}
+
+impl Default for CommandScope {
+    fn default() -> Self {
+        Self::Global
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_deprecated_keys() {
+        let config = serde_yaml::from_str("bridge:\n  port: 58913\n").expect("valid YAML");
+        let deprecated = [
+            ("bridge.port", "use bridge.bridge_url"),
+            ("bridge.prefix", "unused"),
+            ("discord", "unused"),
+        ];
+        assert_eq!(
+            find_deprecated(&config, &deprecated),
+            ["bridge.port (use bridge.bridge_url)"]
+        );
+    }
+
+    #[test]
+    fn rejects_outage_queues_without_room_for_a_summary() {
+        let mut config = crate::testkit::config();
+        assert!(config.validate().is_ok());
+        config.discord.outage_queue_limit = 1;
+        assert!(config.validate().is_err());
+    }
 }
@@ -0,0 +1,140 @@
+//! Database query tracing
+//!
+//! sqlx logs every statement with its row counts and duration, and statements slower than
+//! `db.slow_query_ms` at warning level. [`QueryMetricsLayer`] picks these events up and exports
+//! the number of queries, their total duration and the number of slow queries per table.
+
+use std::fmt;
+
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::metrics::METRICS;
+
+/// Threshold for slow queries if none is configured, in milliseconds
+pub const DEFAULT_SLOW_QUERY_MS: u64 = 1000;
+
+/// Log target of sqlx statement logging
+const QUERY_TARGET: &str = "sqlx::query";
+
+/// Returns the table a query operates on
+///
+/// This is the first table after `FROM`, `INTO`, `UPDATE` or `JOIN`.
+#[must_use]
+pub fn query_table(sql: &str) -> Option<&str> {
+    let mut words = sql.split_whitespace();
+    while let Some(word) = words.next() {
+        if ["FROM", "INTO", "UPDATE", "JOIN"]
+            .iter()
+            .any(|keyword| word.eq_ignore_ascii_case(keyword))
+        {
+            let table = words
+                .next()?
+                .trim_matches(|c: char| !(c.is_ascii_alphanumeric() || c == '_'));
+            if !table.is_empty() {
+                return Some(table);
+            }
+        }
+    }
+    None
+}
+
+/// Returns the duration logged by sqlx in microseconds
+fn elapsed_us(message: &str) -> Option<i64> {
+    let elapsed = message
+        .split("elapsed: ")
+        .nth(1)?
+        .split_whitespace()
+        .next()?;
+    let unit_start = elapsed.find(|c: char| !(c.is_ascii_digit() || c == '.'))?;
+    let value = elapsed[..unit_start].parse::<f64>().ok()?;
+    let factor = match &elapsed[unit_start..] {
+        "ns" => 0.001,
+        "µs" => 1.0,
+        "ms" => 1000.0,
+        "s" => 1_000_000.0,
+        _ => return None,
+    };
+    #[allow(clippy::cast_possible_truncation)]
+    Some((value * factor) as i64)
+}
+
+/// Collects the fields of a statement log event
+#[derive(Default)]
+struct QueryVisitor {
+    /// Target of the log record
+    target: String,
+    /// Logged message
+    message: String,
+}
+
+impl Visit for QueryVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "log.target" {
+            self.target = value.to_owned();
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{:?}", value);
+        }
+    }
+}
+
+/// Layer exporting metrics about database queries
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QueryMetricsLayer;
+
+impl<S: Subscriber> Layer<S> for QueryMetricsLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut visitor = QueryVisitor::default();
+        event.record(&mut visitor);
+        if visitor.target != QUERY_TARGET {
+            return;
+        }
+        let labels = [("table", query_table(&visitor.message).unwrap_or("unknown"))];
+        METRICS.inc("bridge_db_queries_count", &labels);
+        if let Some(elapsed) = elapsed_us(&visitor.message) {
+            METRICS.add("bridge_db_queries_duration_us_sum", &labels, elapsed);
+        }
+        if *event.metadata().level() == Level::WARN {
+            METRICS.inc("bridge_db_slow_queries", &labels);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_tables() {
+        assert_eq!(
+            query_table("SELECT matrix_room_id FROM portals WHERE x = $1"),
+            Some("portals")
+        );
+        assert_eq!(
+            query_table("INSERT INTO message_map (a) VALUES ($1)"),
+            Some("message_map")
+        );
+        assert_eq!(
+            query_table("update catalog_entries\n  SET entry = $2"),
+            Some("catalog_entries")
+        );
+        assert_eq!(query_table("SELECT 1"), None);
+    }
+
+    #[test]
+    fn parses_elapsed_time() {
+        let message =
+            "SELECT seq, dropped FROM …; rows affected: 0, rows returned: 2, elapsed: 1.250ms";
+        assert_eq!(elapsed_us(message), Some(1250));
+        assert_eq!(elapsed_us("elapsed: 2.000s\n\nSELECT"), Some(2_000_000));
+        assert_eq!(elapsed_us("elapsed: 12.000µs"), Some(12));
+        assert_eq!(elapsed_us("no timing"), None);
+    }
+}
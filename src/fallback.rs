@@ -0,0 +1,381 @@
+//! Plain-text fallbacks for formatted matrix messages
+//!
+//! Clients without HTML support and double bridges to plain-text networks like IRC only see the
+//! `body` of a message. It is generated from the formatted body so both always say the same:
+//! quotes get `> ` prefixes, spoilers are wrapped in `[spoiler]` markers, links show their target
+//! and images are listed with their download URLs at the end. The quote of the replied message
+//! at the start of a rich reply is removed before it is relayed to discord, which shows replies
+//! on its own.
+
+/// Kind of an open list
+enum List {
+    /// Unordered list
+    Unordered,
+    /// Ordered list with the number of the next item
+    Ordered(u64),
+}
+
+/// Builds the plain text line by line
+#[derive(Default)]
+struct Writer {
+    /// Text written so far
+    out: String,
+    /// Nesting depth of quotes
+    quote_depth: usize,
+    /// Whether something was written on the current line
+    mid_line: bool,
+}
+
+impl Writer {
+    /// Writes the quote prefix if at the start of a line
+    fn prefix(&mut self) {
+        if !self.mid_line {
+            for _ in 0..self.quote_depth {
+                self.out.push_str("> ");
+            }
+            self.mid_line = true;
+        }
+    }
+
+    /// Writes text, keeping line breaks only if `preformatted` is set
+    fn text(&mut self, text: &str, preformatted: bool) {
+        for c in text.chars() {
+            match c {
+                '\n' if preformatted => self.newline(),
+                '\n' | '\r' | '\t' => {
+                    if self.mid_line && !self.out.ends_with(' ') {
+                        self.out.push(' ');
+                    }
+                }
+                c => {
+                    self.prefix();
+                    self.out.push(c);
+                }
+            }
+        }
+    }
+
+    /// Ends the current line
+    fn newline(&mut self) {
+        self.out.push('\n');
+        self.mid_line = false;
+    }
+
+    /// Starts a new line unless the current one is empty
+    fn block(&mut self) {
+        if self.mid_line {
+            self.newline();
+        }
+    }
+}
+
+/// Replaces HTML character references in text
+pub(crate) fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find(';') {
+            Some(end) if end <= 10 => end,
+            _ => {
+                result.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let decoded = match &rest[1..end] {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            entity => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .map_or_else(
+                    || entity.strip_prefix('#').and_then(|n| n.parse().ok()),
+                    |n| u32::from_str_radix(n, 16).ok(),
+                )
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                result.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                result.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Returns the value of an attribute of a tag
+pub(crate) fn attribute(tag: &str, name: &str) -> Option<String> {
+    let start = tag.find(&format!(" {}=\"", name))? + name.len() + 3;
+    let end = tag[start..].find('"')? + start;
+    Some(unescape(&tag[start..end]))
+}
+
+/// Generates the plain-text fallback of a formatted body
+///
+/// `media_url` turns the source of an image, usually an MXC URI, into a URL that plain-text
+/// clients can open.
+#[must_use]
+pub fn plain_text(html: &str, media_url: impl Fn(&str) -> String) -> String {
+    let mut writer = Writer::default();
+    let mut lists = Vec::new();
+    let mut links = Vec::new();
+    let mut spans = Vec::new();
+    let mut media = Vec::new();
+    let mut preformatted = 0_usize;
+    let mut skipped = 0_usize;
+    let mut first_cell = true;
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        if skipped == 0 {
+            writer.text(&unescape(&rest[..start]), preformatted > 0);
+        }
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => {
+                rest = &rest[start..];
+                break;
+            }
+        };
+        let tag = &rest[start + 1..end];
+        rest = &rest[end + 1..];
+        let closing = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        if name == "mx-reply" {
+            // The reply fallback of the formatted body is not part of the message
+            skipped = if closing {
+                skipped.saturating_sub(1)
+            } else {
+                skipped + 1
+            };
+            continue;
+        }
+        if skipped > 0 {
+            continue;
+        }
+        match (name.as_str(), closing) {
+            ("br", _) => writer.newline(),
+            ("hr", _) => {
+                writer.block();
+                writer.text("---", false);
+                writer.newline();
+            }
+            ("blockquote", false) => {
+                writer.block();
+                writer.quote_depth += 1;
+            }
+            ("blockquote", true) => {
+                writer.block();
+                writer.quote_depth = writer.quote_depth.saturating_sub(1);
+            }
+            ("ul", false) => {
+                writer.block();
+                lists.push(List::Unordered);
+            }
+            ("ol", false) => {
+                writer.block();
+                let start = attribute(tag, "start").and_then(|start| start.parse().ok());
+                lists.push(List::Ordered(start.unwrap_or(1)));
+            }
+            ("ul" | "ol", true) => {
+                writer.block();
+                lists.pop();
+            }
+            ("li", false) => {
+                writer.block();
+                let marker = match lists.last_mut() {
+                    Some(List::Ordered(next)) => {
+                        *next += 1;
+                        format!("{}. ", *next - 1)
+                    }
+                    _ => "- ".to_owned(),
+                };
+                writer.text(&"  ".repeat(lists.len().saturating_sub(1)), false);
+                writer.prefix();
+                writer.out.push_str(&marker);
+            }
+            ("pre", false) => {
+                writer.block();
+                preformatted += 1;
+            }
+            ("pre", true) => {
+                writer.block();
+                preformatted = preformatted.saturating_sub(1);
+            }
+            ("code", _) if preformatted == 0 => writer.text("`", false),
+            ("a", false) => links.push((writer.out.len(), attribute(tag, "href"))),
+            ("a", true) => {
+                if let Some((start, Some(href))) = links.pop() {
+                    let text = writer.out.get(start..).unwrap_or_default().trim();
+                    let mail = href.strip_prefix("mailto:");
+                    if text != href && mail != Some(text) && !href.is_empty() {
+                        writer.text(&format!(" ({})", href), false);
+                    }
+                }
+            }
+            ("span", false) => {
+                let spoiler = attribute(tag, "data-mx-spoiler");
+                if let Some(ref reason) = spoiler {
+                    if reason.is_empty() {
+                        writer.text("[spoiler]", false);
+                    } else {
+                        writer.text(&format!("[spoiler: {}]", reason), false);
+                    }
+                }
+                spans.push(spoiler.is_some());
+            }
+            ("span", true) => {
+                if spans.pop() == Some(true) {
+                    writer.text("[/spoiler]", false);
+                }
+            }
+            ("img", _) => {
+                let alt = attribute(tag, "alt")
+                    .or_else(|| attribute(tag, "title"))
+                    .filter(|alt| !alt.is_empty())
+                    .unwrap_or_else(|| "image".to_owned());
+                writer.text(&format!("[{}]", alt), false);
+                if let Some(src) = attribute(tag, "src") {
+                    media.push((alt, media_url(&src)));
+                }
+            }
+            ("tr", _) => {
+                writer.block();
+                first_cell = true;
+            }
+            ("td" | "th", false) => {
+                if !first_cell {
+                    writer.text(" | ", false);
+                }
+                first_cell = false;
+            }
+            (
+                "p" | "div" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "table" | "details"
+                | "summary" | "caption",
+                _,
+            ) => writer.block(),
+            _ => {}
+        }
+    }
+    if skipped == 0 {
+        writer.text(&unescape(rest), preformatted > 0);
+    }
+    let mut text = writer
+        .out
+        .lines()
+        .map(str::trim_end)
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim_matches('\n')
+        .to_owned();
+    if !media.is_empty() {
+        text.push_str("\n\n");
+        text.push_str(&attachment_list(&media));
+    }
+    text
+}
+
+/// Lists attachments with their URLs, one per line
+#[must_use]
+pub fn attachment_list(attachments: &[(String, String)]) -> String {
+    attachments
+        .iter()
+        .map(|(name, url)| format!("- {}: {}", name, url))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Removes the quote of the replied message from the plain-text body of a rich reply
+#[must_use]
+pub fn strip_reply_fallback(body: &str) -> &str {
+    if !body.starts_with("> ") {
+        return body;
+    }
+    let mut rest = body;
+    while rest.starts_with('>') {
+        rest = rest.split_once('\n').map_or("", |(_, rest)| rest);
+    }
+    rest.strip_prefix('\n').unwrap_or(rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plain(html: &str) -> String {
+        plain_text(html, |src| src.replace("mxc://", "https://media/"))
+    }
+
+    #[test]
+    fn quotes_are_prefixed() {
+        assert_eq!(
+            plain("<blockquote><p>Hello<br>World</p></blockquote><p>Reply &amp; more</p>"),
+            "> Hello\n> World\nReply & more"
+        );
+        assert_eq!(
+            plain("<blockquote>a<blockquote>b</blockquote></blockquote>c"),
+            "> a\n> > b\nc"
+        );
+        assert_eq!(
+            plain("<mx-reply><blockquote>quoted</blockquote></mx-reply>answer"),
+            "answer"
+        );
+    }
+
+    #[test]
+    fn spoilers_and_links_are_marked() {
+        assert_eq!(
+            plain(r#"The <span data-mx-spoiler="">butler</span> did it"#),
+            "The [spoiler]butler[/spoiler] did it"
+        );
+        assert_eq!(
+            plain(r#"<span data-mx-spoiler="plot">twist</span>"#),
+            "[spoiler: plot]twist[/spoiler]"
+        );
+        assert_eq!(
+            plain(
+                r#"<a href="https://example.com">site</a> <a href="https://x.y">https://x.y</a>"#
+            ),
+            "site (https://example.com) https://x.y"
+        );
+    }
+
+    #[test]
+    fn lists_and_images() {
+        assert_eq!(
+            plain(r#"<ol start="3"><li>a</li><li>b</li></ol><ul><li>c</li></ul>"#),
+            "3. a\n4. b\n- c"
+        );
+        assert_eq!(
+            plain(r#"look <img src="mxc://chir.rs/abc" alt="cat">"#),
+            "look [cat]\n\n- cat: https://media/chir.rs/abc"
+        );
+        assert_eq!(plain("<pre><code>a\n  b</code></pre>"), "a\n  b");
+        assert_eq!(plain("<code>x</code> &lt;3 &#x1F408;"), "`x` <3 🐈");
+    }
+
+    #[test]
+    fn reply_fallbacks_are_stripped() {
+        assert_eq!(
+            strip_reply_fallback("> <@alice:chir.rs> Hi\n> there\n\nHello"),
+            "Hello"
+        );
+        assert_eq!(strip_reply_fallback("Hello\n> quote"), "Hello\n> quote");
+    }
+}
@@ -0,0 +1,240 @@
+//! Bridge feature flags
+//!
+//! Optional parts of the bridge can be switched off in the `features` section of the config.
+//! Portals can override the configured value of each feature, for example to disable reactions
+//! in a single busy channel, or to enable typing notifications only where they are wanted.
+
+use std::fmt;
+
+use anyhow::{anyhow, Error, Result};
+use educe::Educe;
+use serde::{Deserialize, Serialize};
+
+/// Feature that can be switched on or off
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Feature {
+    /// Reactions
+    Reactions,
+    /// Message edits
+    Edits,
+    /// Presence of discord users
+    Presence,
+    /// Typing notifications
+    Typing,
+    /// Read receipts
+    Receipts,
+    /// Backfill of message history
+    Backfill,
+    /// Link embeds
+    Embeds,
+    /// Stickers
+    Stickers,
+    /// Separate puppets for the identities of other bots' webhooks
+    WebhookPuppets,
+}
+
+impl Feature {
+    /// All features
+    pub const ALL: [Self; 9] = [
+        Self::Reactions,
+        Self::Edits,
+        Self::Presence,
+        Self::Typing,
+        Self::Receipts,
+        Self::Backfill,
+        Self::Embeds,
+        Self::Stickers,
+        Self::WebhookPuppets,
+    ];
+
+    /// Returns the name of the feature in the config and commands
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Reactions => "reactions",
+            Self::Edits => "edits",
+            Self::Presence => "presence",
+            Self::Typing => "typing",
+            Self::Receipts => "receipts",
+            Self::Backfill => "backfill",
+            Self::Embeds => "embeds",
+            Self::Stickers => "stickers",
+            Self::WebhookPuppets => "webhook_puppets",
+        }
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl TryFrom<&str> for Feature {
+    type Error = Error;
+
+    fn try_from(name: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|feature| feature.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| {
+                let names = Self::ALL.map(Self::name);
+                anyhow!("Unknown feature {:?}, known are {}", name, names.join(", "))
+            })
+    }
+}
+
+impl TryFrom<String> for Feature {
+    type Error = Error;
+
+    fn try_from(name: String) -> Result<Self> {
+        Self::try_from(name.as_str())
+    }
+}
+
+impl From<Feature> for String {
+    fn from(feature: Feature) -> Self {
+        feature.name().to_owned()
+    }
+}
+
+/// Configured features, all enabled by default
+#[derive(Clone, Copy, Debug, Educe, Deserialize, Serialize, PartialEq, Eq)]
+#[educe(Default)]
+#[serde(default)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Features {
+    /// Whether reactions are bridged
+    #[educe(Default = true)]
+    pub reactions: bool,
+    /// Whether message edits are bridged
+    #[educe(Default = true)]
+    pub edits: bool,
+    /// Whether presence is bridged
+    #[educe(Default = true)]
+    pub presence: bool,
+    /// Whether typing notifications are bridged
+    #[educe(Default = true)]
+    pub typing: bool,
+    /// Whether read receipts are bridged
+    #[educe(Default = true)]
+    pub receipts: bool,
+    /// Whether message history is backfilled
+    #[educe(Default = true)]
+    pub backfill: bool,
+    /// Whether link embeds are bridged
+    #[educe(Default = true)]
+    pub embeds: bool,
+    /// Whether stickers are bridged
+    #[educe(Default = true)]
+    pub stickers: bool,
+    /// Whether messages of other bots' webhooks get a puppet per webhook and name
+    #[educe(Default = true)]
+    pub webhook_puppets: bool,
+}
+
+impl Features {
+    /// Returns whether a feature is enabled in the config
+    #[must_use]
+    pub const fn enabled(&self, feature: Feature) -> bool {
+        match feature {
+            Feature::Reactions => self.reactions,
+            Feature::Edits => self.edits,
+            Feature::Presence => self.presence,
+            Feature::Typing => self.typing,
+            Feature::Receipts => self.receipts,
+            Feature::Backfill => self.backfill,
+            Feature::Embeds => self.embeds,
+            Feature::Stickers => self.stickers,
+            Feature::WebhookPuppets => self.webhook_puppets,
+        }
+    }
+}
+
+/// Features a portal enables or disables regardless of the config
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FeatureOverrides {
+    /// Features enabled in the portal
+    pub enabled: Vec<Feature>,
+    /// Features disabled in the portal
+    pub disabled: Vec<Feature>,
+}
+
+impl FeatureOverrides {
+    /// Reads overrides from their database form
+    ///
+    /// Unknown feature names are skipped, so that overrides written by newer versions don't
+    /// prevent the portal from being loaded.
+    #[must_use]
+    pub fn from_names(enabled: &[String], disabled: &[String]) -> Self {
+        let parse = |names: &[String]| {
+            names
+                .iter()
+                .filter_map(|name| Feature::try_from(name.as_str()).ok())
+                .collect()
+        };
+        Self {
+            enabled: parse(enabled),
+            disabled: parse(disabled),
+        }
+    }
+
+    /// Returns the override of a feature, if any
+    #[must_use]
+    pub fn get(&self, feature: Feature) -> Option<bool> {
+        if self.disabled.contains(&feature) {
+            Some(false)
+        } else if self.enabled.contains(&feature) {
+            Some(true)
+        } else {
+            None
+        }
+    }
+
+    /// Sets or clears the override of a feature
+    pub fn set(&mut self, feature: Feature, enabled: Option<bool>) {
+        self.enabled.retain(|f| *f != feature);
+        self.disabled.retain(|f| *f != feature);
+        match enabled {
+            Some(true) => self.enabled.push(feature),
+            Some(false) => self.disabled.push(feature),
+            None => {}
+        }
+    }
+
+    /// Returns whether a feature is enabled, taking the config into account
+    #[must_use]
+    pub fn resolve(&self, features: &Features, feature: Feature) -> bool {
+        self.get(feature)
+            .unwrap_or_else(|| features.enabled(feature))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_take_precedence() {
+        let features = Features {
+            typing: false,
+            ..Features::default()
+        };
+        let mut overrides =
+            FeatureOverrides::from_names(&["typing".to_owned()], &["future".to_owned()]);
+        assert!(overrides.resolve(&features, Feature::Typing));
+        assert!(overrides.resolve(&features, Feature::Reactions));
+        overrides.set(Feature::Reactions, Some(false));
+        assert!(!overrides.resolve(&features, Feature::Reactions));
+        overrides.set(Feature::Typing, None);
+        assert!(!overrides.resolve(&features, Feature::Typing));
+        assert_eq!(overrides.disabled, [Feature::Reactions]);
+    }
+
+    #[test]
+    fn parses_names() {
+        assert_eq!(Feature::try_from("Edits").ok(), Some(Feature::Edits));
+        assert!(Feature::try_from("polls").is_err());
+    }
+}
@@ -0,0 +1,905 @@
+//! Conversion between discord markdown and matrix HTML
+//!
+//! Discord messages are sent to matrix with a `formatted_body` generated from their markdown:
+//! bold, italics, underlines, strikethrough, spoilers, inline code, code blocks, block quotes and
+//! masked links. Messages without any formatting are sent as plain text. In the other direction,
+//! the formatted body of a matrix message is turned back into discord markdown, with the text
+//! escaped so that it isn't formatted by accident. Elements discord has no markdown for, like
+//! headings and lists, are approximated.
+//!
+//! Discord mentions and custom emojis are shown as the [`Pill`] they were resolved to, and
+//! matrix.to links resolved to a [`Mention`] become discord mentions again. Resolving needs the
+//! database, see `app::mentions`.
+
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    fallback::{attribute, unescape},
+    html,
+};
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use twilight_model::id::{
+    marker::{ChannelMarker, EmojiMarker, RoleMarker, UserMarker},
+    Id,
+};
+
+/// A discord mention
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Mention {
+    /// Mention of a user, `<@id>`
+    User(Id<UserMarker>),
+    /// Mention of a channel, `<#id>`
+    Channel(Id<ChannelMarker>),
+    /// Mention of a role, `<@&id>`
+    Role(Id<RoleMarker>),
+    /// Custom emoji, `<:name:id>` or `<a:name:id>` if animated
+    Emoji {
+        /// The emoji
+        id: Id<EmojiMarker>,
+        /// Name of the emoji
+        name: String,
+        /// Whether the emoji is animated
+        animated: bool,
+    },
+}
+
+impl fmt::Display for Mention {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::User(id) => write!(f, "<@{}>", id),
+            Self::Channel(id) => write!(f, "<#{}>", id),
+            Self::Role(id) => write!(f, "<@&{}>", id),
+            Self::Emoji {
+                id,
+                ref name,
+                animated,
+            } => write!(f, "<{}:{}:{}>", if animated { "a" } else { "" }, name, id),
+        }
+    }
+}
+
+/// How a discord mention is shown on matrix
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pill {
+    /// Text in the plain body
+    pub text: String,
+    /// HTML in the formatted body
+    pub html: String,
+}
+
+/// Resolved discord mentions
+pub type Pills = HashMap<Mention, Pill>;
+
+/// Inline delimiters, the tags they stand for and whether they only apply at word boundaries
+///
+/// Longer delimiters come first so that `**` isn't read as two `*`.
+const DELIMITERS: &[(&str, &str, &str, bool)] = &[
+    ("||", "<span data-mx-spoiler>", "</span>", false),
+    ("**", "<strong>", "</strong>", false),
+    ("__", "<u>", "</u>", false),
+    ("~~", "<del>", "</del>", false),
+    ("*", "<em>", "</em>", false),
+    ("_", "<em>", "</em>", true),
+];
+
+/// Characters that are escaped in text sent to discord
+const MARKDOWN_SPECIAL: &[char] = &['\\', '*', '_', '~', '`', '|', '[', ']'];
+
+/// Writes a character of text as HTML
+fn push_text(html: &mut String, c: char) {
+    match c {
+        '\n' => html.push_str("<br>"),
+        c => html.push_str(&html::escape(c.encode_utf8(&mut [0; 4]))),
+    }
+}
+
+/// Splits `text` at the end of a span enclosed by `delimiter`
+///
+/// Returns the content of the span and the text after it. A closing delimiter that is part of a
+/// longer run of the same character is the end of the run, so `***x***` is `**` around `*x*`.
+fn delimited<'a>(text: &'a str, delimiter: &str, word_bounded: bool) -> Option<(&'a str, &'a str)> {
+    let inner = text.strip_prefix(delimiter)?;
+    let run_char = delimiter.chars().next()?;
+    let mut offset = 0;
+    loop {
+        let found = inner[offset..].find(delimiter)? + offset;
+        let run = inner[found..]
+            .chars()
+            .take_while(|&c| c == run_char)
+            .count();
+        let extra = run - delimiter.len();
+        let content = &inner[..found + extra];
+        let after = &inner[found + run..];
+        let nested = extra == 0 || content.starts_with(&inner[found..found + extra]);
+        let bounded = !word_bounded || !after.starts_with(char::is_alphanumeric);
+        if !content.trim().is_empty() && nested && bounded {
+            return Some((content, after));
+        }
+        offset = found + run;
+    }
+}
+
+/// Parses a snowflake
+fn snowflake<T>(text: &str) -> Option<Id<T>> {
+    text.parse().ok().and_then(Id::new_checked)
+}
+
+/// Splits `text` at the end of a discord mention
+fn mention(text: &str) -> Option<(Mention, &str)> {
+    let (token, rest) = text.strip_prefix('<')?.split_once('>')?;
+    let mention = if let Some(id) = token.strip_prefix("@&") {
+        Mention::Role(snowflake(id)?)
+    } else if let Some(id) = token.strip_prefix("@!").or_else(|| token.strip_prefix('@')) {
+        Mention::User(snowflake(id)?)
+    } else if let Some(id) = token.strip_prefix('#') {
+        Mention::Channel(snowflake(id)?)
+    } else {
+        let (animated, emoji) = match token.strip_prefix("a:") {
+            Some(emoji) => (true, emoji),
+            None => (false, token.strip_prefix(':')?),
+        };
+        let (name, id) = emoji.split_once(':').filter(|(name, _)| !name.is_empty())?;
+        Mention::Emoji {
+            id: snowflake(id)?,
+            name: name.to_owned(),
+            animated,
+        }
+    };
+    Some((mention, rest))
+}
+
+/// Returns the mentions in discord markdown, without duplicates
+#[must_use]
+pub fn mentions(markdown: &str) -> Vec<Mention> {
+    let mut mentions = Vec::new();
+    let mut rest = markdown;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start..];
+        match mention(rest) {
+            Some((mention, after)) => {
+                if !mentions.contains(&mention) {
+                    mentions.push(mention);
+                }
+                rest = after;
+            }
+            None => rest = &rest[1..],
+        }
+    }
+    mentions
+}
+
+/// Replaces the resolved mentions in discord markdown by the text of their pills
+fn plain_body(markdown: &str, pills: &Pills) -> String {
+    let mut body = String::with_capacity(markdown.len());
+    let mut rest = markdown;
+    while let Some(start) = rest.find('<') {
+        body.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match mention(rest).and_then(|(mention, after)| Some((pills.get(&mention)?, after))) {
+            Some((pill, after)) => {
+                body.push_str(&pill.text);
+                rest = after;
+            }
+            None => {
+                body.push('<');
+                rest = &rest[1..];
+            }
+        }
+    }
+    body.push_str(rest);
+    body
+}
+
+/// Returns the user, room or alias a matrix.to link points to
+///
+/// Links to events return `None`, they don't stand for their room.
+#[must_use]
+pub fn matrix_link_target(href: &str) -> Option<String> {
+    let target = href
+        .strip_prefix("https://matrix.to/#/")?
+        .split('?')
+        .next()
+        .unwrap_or_default();
+    if target.is_empty() || target.contains('/') {
+        return None;
+    }
+    let mut decoded = String::with_capacity(target.len());
+    let mut rest = target;
+    while let Some(start) = rest.find('%') {
+        decoded.push_str(&rest[..start]);
+        let byte = rest
+            .get(start + 1..start + 3)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .filter(u8::is_ascii);
+        match byte {
+            Some(byte) => {
+                decoded.push(char::from(byte));
+                rest = &rest[start + 3..];
+            }
+            None => {
+                decoded.push('%');
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    Some(decoded)
+}
+
+/// Returns the targets of the matrix.to links in a formatted body, without duplicates
+#[must_use]
+pub fn matrix_links(html: &str) -> Vec<String> {
+    let mut targets = Vec::new();
+    for link in html.split(" href=\"").skip(1) {
+        let href = unescape(link.split('"').next().unwrap_or_default());
+        if let Some(target) = matrix_link_target(&href) {
+            if !targets.contains(&target) {
+                targets.push(target);
+            }
+        }
+    }
+    targets
+}
+
+/// Splits `text` at the end of an inline code span
+fn code_span(text: &str) -> Option<(&str, &str)> {
+    let ticks = text.chars().take_while(|&c| c == '`').count();
+    let fence = &text[..ticks];
+    let inner = &text[ticks..];
+    let end = inner.find(fence)?;
+    let content = &inner[..end];
+    if content.is_empty() {
+        return None;
+    }
+    Some((content, &inner[end + ticks..]))
+}
+
+/// Splits `text` at the end of a masked link, returning its label and target
+fn masked_link(text: &str) -> Option<(&str, &str, &str)> {
+    let (label, rest) = text.strip_prefix('[')?.split_once("](")?;
+    let (url, rest) = rest.split_once(')')?;
+    if label.is_empty()
+        || label.contains(['[', ']'])
+        || url.contains(char::is_whitespace)
+        || !(url.starts_with("https://") || url.starts_with("http://"))
+    {
+        return None;
+    }
+    Some((label, url, rest))
+}
+
+/// Converts the inline markdown of a block to HTML
+fn inline(text: &str, pills: &Pills) -> String {
+    let mut html = String::with_capacity(text.len());
+    let mut rest = text;
+    'outer: while let Some(c) = rest.chars().next() {
+        let after = &rest[c.len_utf8()..];
+        match c {
+            '\\' => {
+                if let Some(escaped) = after.chars().next().filter(char::is_ascii_punctuation) {
+                    push_text(&mut html, escaped);
+                    rest = &after[1..];
+                    continue;
+                }
+            }
+            '`' => {
+                if let Some((code, after)) = code_span(rest) {
+                    html.push_str("<code>");
+                    html.push_str(&html::escape(code));
+                    html.push_str("</code>");
+                    rest = after;
+                    continue;
+                }
+            }
+            '<' => {
+                if let Some((pill, after)) =
+                    mention(rest).and_then(|(mention, after)| Some((pills.get(&mention)?, after)))
+                {
+                    html.push_str(&pill.html);
+                    rest = after;
+                    continue;
+                }
+            }
+            '[' => {
+                if let Some((label, url, after)) = masked_link(rest) {
+                    html.push_str("<a href=\"");
+                    html.push_str(&html::escape(url));
+                    html.push_str("\">");
+                    html.push_str(&inline(label, pills));
+                    html.push_str("</a>");
+                    rest = after;
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        let word_before = text[..text.len() - rest.len()]
+            .chars()
+            .next_back()
+            .map_or(false, char::is_alphanumeric);
+        for &(delimiter, open, close, word_bounded) in DELIMITERS {
+            if word_bounded && word_before {
+                continue;
+            }
+            if let Some((content, after)) = delimited(rest, delimiter, word_bounded) {
+                html.push_str(open);
+                html.push_str(&inline(content, pills));
+                html.push_str(close);
+                rest = after;
+                continue 'outer;
+            }
+        }
+        push_text(&mut html, c);
+        rest = after;
+    }
+    html
+}
+
+/// Converts text outside of code blocks to HTML
+///
+/// Lines starting with `> ` are quoted, and `>>> ` quotes the rest of the text.
+fn blocks(text: &str, pills: &Pills) -> String {
+    let mut lines = Vec::new();
+    let mut quote_rest = false;
+    for line in text.split('\n') {
+        if quote_rest {
+            lines.push((true, line));
+        } else if let Some(line) = line.strip_prefix(">>> ") {
+            quote_rest = true;
+            lines.push((true, line));
+        } else if let Some(line) = line.strip_prefix("> ") {
+            lines.push((true, line));
+        } else {
+            lines.push((false, line));
+        }
+    }
+    let mut html = String::with_capacity(text.len());
+    let mut start = 0;
+    while start < lines.len() {
+        let quoted = lines[start].0;
+        let end = lines[start..]
+            .iter()
+            .position(|&(q, _)| q != quoted)
+            .map_or(lines.len(), |len| start + len);
+        let group = lines[start..end]
+            .iter()
+            .map(|&(_, line)| line)
+            .collect::<Vec<_>>()
+            .join("\n");
+        if quoted {
+            html.push_str("<blockquote>");
+            html.push_str(&inline(&group, pills));
+            html.push_str("</blockquote>");
+        } else {
+            html.push_str(&inline(&group, pills));
+        }
+        start = end;
+    }
+    html
+}
+
+/// Converts the content of a code block to HTML
+///
+/// A single word on the first line of a multi-line block names its language.
+fn code_block(text: &str) -> String {
+    let (language, code) = match text.split_once('\n') {
+        Some((first, code))
+            if !first.is_empty() && !first.contains(char::is_whitespace) && !code.is_empty() =>
+        {
+            (Some(first), code)
+        }
+        _ => (None, text.strip_prefix('\n').unwrap_or(text)),
+    };
+    let mut html = String::from("<pre><code");
+    if let Some(language) = language {
+        html.push_str(" class=\"language-");
+        html.push_str(&html::escape(language));
+        html.push('"');
+    }
+    html.push('>');
+    html.push_str(&html::escape(code));
+    html.push_str("</code></pre>");
+    html
+}
+
+/// Converts discord markdown to the `formatted_body` of a matrix message
+///
+/// Mentions are replaced by their pills. Returns `None` if the text has no formatting.
+#[must_use]
+pub fn discord_to_html(markdown: &str, pills: &Pills) -> Option<String> {
+    let mut html = String::with_capacity(markdown.len());
+    let mut rest = markdown;
+    while let Some(start) = rest.find("```") {
+        let end = match rest[start + 3..].find("```") {
+            Some(end) => start + 3 + end,
+            None => break,
+        };
+        let before = &rest[..start];
+        html.push_str(&blocks(before.strip_suffix('\n').unwrap_or(before), pills));
+        html.push_str(&code_block(&rest[start + 3..end]));
+        rest = &rest[end + 3..];
+        rest = rest.strip_prefix('\n').unwrap_or(rest);
+    }
+    if !rest.is_empty() {
+        html.push_str(&blocks(rest, pills));
+    }
+    let mut plain = String::with_capacity(markdown.len());
+    for c in markdown.chars() {
+        push_text(&mut plain, c);
+    }
+    if html == plain {
+        None
+    } else {
+        Some(html)
+    }
+}
+
+/// Builds the content of a matrix message from discord markdown
+#[must_use]
+pub fn message_content(markdown: &str, pills: &Pills) -> RoomMessageEventContent {
+    let body = plain_body(markdown, pills);
+    match discord_to_html(markdown, pills) {
+        Some(html) => RoomMessageEventContent::text_html(body, html),
+        None => RoomMessageEventContent::text_plain(body),
+    }
+}
+
+/// Builds discord markdown line by line
+#[derive(Default)]
+struct Markdown {
+    /// Markdown written so far
+    out: String,
+    /// Nesting depth of quotes
+    quote_depth: usize,
+    /// Whether something was written on the current line
+    mid_line: bool,
+}
+
+impl Markdown {
+    /// Writes the quote prefix if at the start of a line
+    fn prefix(&mut self) {
+        if !self.mid_line {
+            if self.quote_depth > 0 {
+                self.out.push_str("> ");
+            }
+            self.mid_line = true;
+        }
+    }
+
+    /// Writes markdown syntax
+    fn syntax(&mut self, syntax: &str) {
+        self.prefix();
+        self.out.push_str(syntax);
+    }
+
+    /// Writes text, escaping it unless `code` is set and keeping line breaks only in code blocks
+    fn text(&mut self, text: &str, code: bool, preformatted: bool) {
+        for c in text.chars() {
+            match c {
+                '\n' if preformatted => self.newline(),
+                '\n' | '\r' | '\t' => {
+                    if self.mid_line && !self.out.ends_with(' ') {
+                        self.out.push(' ');
+                    }
+                }
+                c => {
+                    self.prefix();
+                    if !code && MARKDOWN_SPECIAL.contains(&c) {
+                        self.out.push('\\');
+                    }
+                    self.out.push(c);
+                }
+            }
+        }
+    }
+
+    /// Ends the current line
+    fn newline(&mut self) {
+        self.out.push('\n');
+        self.mid_line = false;
+    }
+
+    /// Starts a new line unless the current one is empty
+    fn block(&mut self) {
+        if self.mid_line {
+            self.newline();
+        }
+    }
+}
+
+/// Escapes text so that discord shows it on one line without formatting it
+#[must_use]
+pub fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\n' | '\r' | '\t' => escaped.push(' '),
+            c => {
+                if MARKDOWN_SPECIAL.contains(&c) {
+                    escaped.push('\\');
+                }
+                escaped.push(c);
+            }
+        }
+    }
+    escaped
+}
+
+/// Converts the formatted body of a matrix message to discord markdown
+///
+/// `media_url` turns the source of an image, usually an MXC URI, into a URL discord can show.
+/// Links to the targets in `mentions` become the discord mention instead.
+#[must_use]
+pub fn html_to_discord(
+    html: &str,
+    media_url: impl Fn(&str) -> String,
+    mentions: &HashMap<String, Mention>,
+) -> String {
+    let mut writer = Markdown::default();
+    let mut lists = Vec::new();
+    let mut links = Vec::new();
+    let mut spans = Vec::new();
+    let mut preformatted = 0_usize;
+    let mut fence_open = false;
+    let mut code = 0_usize;
+    let mut skipped = 0_usize;
+    let mut first_cell = true;
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        if skipped == 0 {
+            let text = unescape(&rest[..start]);
+            if fence_open && !text.is_empty() {
+                writer.newline();
+                fence_open = false;
+            }
+            writer.text(&text, code > 0 || preformatted > 0, preformatted > 0);
+        }
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => {
+                rest = &rest[start..];
+                break;
+            }
+        };
+        let tag = &rest[start + 1..end];
+        rest = &rest[end + 1..];
+        let closing = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        if name == "mx-reply" {
+            // The reply fallback of the formatted body is not part of the message
+            skipped = if closing {
+                skipped.saturating_sub(1)
+            } else {
+                skipped + 1
+            };
+            continue;
+        }
+        if skipped > 0 {
+            continue;
+        }
+        match (name.as_str(), closing) {
+            ("br", _) => writer.newline(),
+            ("hr", _) => {
+                writer.block();
+                writer.syntax("---");
+                writer.newline();
+            }
+            ("strong" | "b", _) => writer.syntax("**"),
+            ("em" | "i", _) => writer.syntax("*"),
+            ("u", _) => writer.syntax("__"),
+            ("del" | "strike" | "s", _) => writer.syntax("~~"),
+            ("h1" | "h2" | "h3" | "h4" | "h5" | "h6", false) => {
+                writer.block();
+                writer.syntax("**");
+            }
+            ("h1" | "h2" | "h3" | "h4" | "h5" | "h6", true) => {
+                writer.syntax("**");
+                writer.block();
+            }
+            ("blockquote", false) => {
+                writer.block();
+                writer.quote_depth += 1;
+            }
+            ("blockquote", true) => {
+                writer.block();
+                writer.quote_depth = writer.quote_depth.saturating_sub(1);
+            }
+            ("ul", false) => {
+                writer.block();
+                lists.push(None);
+            }
+            ("ol", false) => {
+                writer.block();
+                let start = attribute(tag, "start").and_then(|start| start.parse().ok());
+                lists.push(Some(start.unwrap_or(1_u64)));
+            }
+            ("ul" | "ol", true) => {
+                writer.block();
+                lists.pop();
+            }
+            ("li", false) => {
+                writer.block();
+                writer.syntax(&"  ".repeat(lists.len().saturating_sub(1)));
+                match lists.last_mut() {
+                    Some(Some(next)) => {
+                        writer.syntax(&format!("{}. ", next));
+                        *next += 1;
+                    }
+                    _ => writer.syntax("• "),
+                }
+            }
+            ("pre", false) => {
+                writer.block();
+                writer.syntax("```");
+                fence_open = true;
+                preformatted += 1;
+            }
+            ("pre", true) => {
+                if fence_open {
+                    writer.newline();
+                    fence_open = false;
+                }
+                writer.block();
+                writer.syntax("```");
+                writer.newline();
+                preformatted = preformatted.saturating_sub(1);
+            }
+            ("code", false) if preformatted > 0 => {
+                if let Some(class) = attribute(tag, "class") {
+                    if let Some(language) = class.strip_prefix("language-") {
+                        writer.syntax(language);
+                    }
+                }
+            }
+            ("code", _) if preformatted > 0 => {}
+            ("code", false) => {
+                writer.syntax("`");
+                code += 1;
+            }
+            ("code", true) => {
+                writer.syntax("`");
+                code = code.saturating_sub(1);
+            }
+            ("a", false) => links.push((writer.out.len(), attribute(tag, "href"))),
+            ("a", true) => {
+                if let Some((start, Some(href))) = links.pop() {
+                    let label = writer.out.get(start..).unwrap_or_default().to_owned();
+                    let mention =
+                        matrix_link_target(&href).and_then(|target| mentions.get(&target));
+                    if let Some(mention) = mention {
+                        writer.out.truncate(start);
+                        writer.syntax(&mention.to_string());
+                    } else if !href.is_empty() {
+                        writer.out.truncate(start);
+                        if label.replace('\\', "") == href {
+                            // Discord links URLs on its own
+                            writer.syntax(&href);
+                        } else {
+                            writer.syntax(&format!("[{}]({})", label, href));
+                        }
+                    }
+                }
+            }
+            ("span", false) => {
+                let spoiler = attribute(tag, "data-mx-spoiler").is_some()
+                    || tag.split_whitespace().any(|part| part == "data-mx-spoiler");
+                if spoiler {
+                    writer.syntax("||");
+                }
+                spans.push(spoiler);
+            }
+            ("span", true) => {
+                if spans.pop() == Some(true) {
+                    writer.syntax("||");
+                }
+            }
+            ("img", _) => {
+                let alt = attribute(tag, "alt")
+                    .or_else(|| attribute(tag, "title"))
+                    .filter(|alt| !alt.is_empty())
+                    .unwrap_or_else(|| "image".to_owned());
+                match attribute(tag, "src") {
+                    Some(src) => writer.syntax(&format!("[{}]({})", alt, media_url(&src))),
+                    None => writer.text(&alt, false, false),
+                }
+            }
+            ("tr", _) => {
+                writer.block();
+                first_cell = true;
+            }
+            ("td" | "th", false) => {
+                if !first_cell {
+                    writer.syntax(" | ");
+                }
+                first_cell = false;
+            }
+            ("p" | "div" | "table" | "details" | "summary" | "caption", _) => writer.block(),
+            _ => {}
+        }
+    }
+    if skipped == 0 {
+        writer.text(
+            &unescape(rest),
+            code > 0 || preformatted > 0,
+            preformatted > 0,
+        );
+    }
+    writer
+        .out
+        .lines()
+        .map(str::trim_end)
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim_matches('\n')
+        .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::events::room::message::MessageType;
+
+    fn html(markdown: &str) -> Option<String> {
+        discord_to_html(markdown, &Pills::new())
+    }
+
+    fn markdown(html: &str) -> String {
+        html_to_discord(
+            html,
+            |src| src.replace("mxc://", "https://media/"),
+            &HashMap::new(),
+        )
+    }
+
+    #[test]
+    fn plain_text_has_no_formatting() {
+        assert_eq!(html("just text, 2 * 3 = 6"), None);
+        assert_eq!(html("snake_case_name"), None);
+    }
+
+    #[test]
+    fn inline_markdown_becomes_html() {
+        assert_eq!(
+            html("**bold** *it* __under__ ~~gone~~ ||secret||").as_deref(),
+            Some(
+                "<strong>bold</strong> <em>it</em> <u>under</u> <del>gone</del> \
+                 <span data-mx-spoiler>secret</span>"
+            )
+        );
+        assert_eq!(
+            html("***both*** and `<code> **not bold**`").as_deref(),
+            Some("<strong><em>both</em></strong> and <code>&lt;code&gt; **not bold**</code>")
+        );
+        assert_eq!(
+            html("see [the docs](https://example.com/docs) \\*literal\\*").as_deref(),
+            Some("see <a href=\"https://example.com/docs\">the docs</a> *literal*")
+        );
+    }
+
+    #[test]
+    fn blocks_become_html() {
+        assert_eq!(
+            html("> quoted\n> **twice**\nanswer").as_deref(),
+            Some("<blockquote>quoted<br><strong>twice</strong></blockquote>answer")
+        );
+        assert_eq!(
+            html("code:\n```rust\nfn main() {}\n```\ndone").as_deref(),
+            Some("code:<pre><code class=\"language-rust\">fn main() {}\n</code></pre>done")
+        );
+        assert_eq!(
+            html(">>> all\nof this").as_deref(),
+            Some("<blockquote>all<br>of this</blockquote>")
+        );
+    }
+
+    #[test]
+    fn html_becomes_markdown() {
+        assert_eq!(
+            markdown(
+                "<strong>bold</strong> <em>it</em> <del>gone</del> \
+                 <span data-mx-spoiler=\"\">secret</span> <code>a*b</code>"
+            ),
+            "**bold** *it* ~~gone~~ ||secret|| `a*b`"
+        );
+        assert_eq!(
+            markdown("<a href=\"https://example.com/\">site</a> 2*3 <a href=\"https://x.y/\">https://x.y/</a>"),
+            "[site](https://example.com/) 2\\*3 https://x.y/"
+        );
+        assert_eq!(
+            markdown("<mx-reply><blockquote>old</blockquote></mx-reply><blockquote>quote</blockquote>reply"),
+            "> quote\nreply"
+        );
+        assert_eq!(
+            markdown("<pre><code class=\"language-rust\">fn main() {}\n</code></pre>"),
+            "```rust\nfn main() {}\n```"
+        );
+        assert_eq!(
+            markdown("<ol><li>one</li><li>two</li></ol><img src=\"mxc://chir.rs/a\" alt=\"cat\">"),
+            "1. one\n2. two\n[cat](https://media/chir.rs/a)"
+        );
+    }
+
+    #[test]
+    fn formatting_round_trips() {
+        for text in [
+            "**bold** and *italics*",
+            "||spoiler|| with `code`",
+            "> quote\nanswer",
+            "[link](https://example.com/)",
+        ] {
+            let formatted = html(text).expect("text is formatted");
+            assert_eq!(markdown(&formatted), text);
+        }
+    }
+
+    #[test]
+    fn mentions_become_pills() {
+        let user = Mention::User(Id::new(1));
+        let role = Mention::Role(Id::new(2));
+        assert_eq!(
+            mentions("<@1> <@!1> <@&2> <#3> <@x>"),
+            [user.clone(), role, Mention::Channel(Id::new(3))]
+        );
+        let emoji = Mention::Emoji {
+            id: Id::new(4),
+            name: "party".to_owned(),
+            animated: true,
+        };
+        assert_eq!(mentions("<a:party:4> <::5>"), [emoji.clone()]);
+        assert_eq!(emoji.to_string(), "<a:party:4>");
+        let mut pills = Pills::new();
+        pills.insert(
+            user,
+            Pill {
+                text: "Alice".to_owned(),
+                html: "<a href=\"https://matrix.to/#/@alice:chir.rs\">Alice</a>".to_owned(),
+            },
+        );
+        let content = message_content("hi <@!1> and <@&2>", &pills);
+        assert_eq!(content.body(), "hi Alice and <@&2>");
+        let formatted = match content.msgtype {
+            MessageType::Text(text) => text.formatted,
+            _ => None,
+        }
+        .expect("content is formatted");
+        assert_eq!(
+            formatted.body,
+            "hi <a href=\"https://matrix.to/#/@alice:chir.rs\">Alice</a> and &lt;@&amp;2&gt;"
+        );
+    }
+
+    #[test]
+    fn pills_become_mentions() {
+        assert_eq!(
+            matrix_link_target("https://matrix.to/#/%40alice%3Achir.rs?via=chir.rs").as_deref(),
+            Some("@alice:chir.rs")
+        );
+        assert_eq!(
+            matrix_link_target("https://matrix.to/#/!room:chir.rs/$event:chir.rs"),
+            None
+        );
+        let html = "<a href=\"https://matrix.to/#/@alice:chir.rs\">Alice</a> in \
+                    <a href=\"https://matrix.to/#/%23general:chir.rs\">#general</a>";
+        assert_eq!(matrix_links(html), ["@alice:chir.rs", "#general:chir.rs"]);
+        let mut mentions = HashMap::new();
+        mentions.insert("@alice:chir.rs".to_owned(), Mention::User(Id::new(1)));
+        mentions.insert("#general:chir.rs".to_owned(), Mention::Channel(Id::new(3)));
+        assert_eq!(
+            html_to_discord(html, str::to_owned, &mentions),
+            "<@1> in <#3>"
+        );
+    }
+
+    #[test]
+    fn escapes_names() {
+        assert_eq!(
+            escape_markdown("**Lotte**\n_the_ `fox`"),
+            "\\*\\*Lotte\\*\\* \\_the\\_ \\`fox\\`"
+        );
+    }
+}
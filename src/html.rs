@@ -0,0 +1,227 @@
+//! HTML helpers for formatted matrix messages
+//!
+//! Every `formatted_body` the bridge produces goes through [`sanitize`], which only keeps the
+//! tags and attributes the matrix specification suggests for clients. Scripts, event handlers
+//! and links with unexpected schemes like `data:` or `javascript:` are removed.
+
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+};
+
+use ammonia::Builder;
+use url::Url;
+
+/// Tags allowed in formatted bodies
+const ALLOWED_TAGS: &[&str] = &[
+    "font",
+    "del",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "blockquote",
+    "p",
+    "a",
+    "ul",
+    "ol",
+    "sup",
+    "sub",
+    "li",
+    "b",
+    "i",
+    "u",
+    "strong",
+    "em",
+    "strike",
+    "code",
+    "hr",
+    "br",
+    "div",
+    "table",
+    "thead",
+    "tbody",
+    "tr",
+    "th",
+    "td",
+    "caption",
+    "pre",
+    "span",
+    "img",
+    "details",
+    "summary",
+];
+
+/// Attributes allowed per tag
+const ALLOWED_ATTRIBUTES: &[(&str, &[&str])] = &[
+    ("font", &["data-mx-bg-color", "data-mx-color", "color"]),
+    (
+        "span",
+        &["data-mx-bg-color", "data-mx-color", "data-mx-spoiler"],
+    ),
+    ("a", &["name", "target", "href"]),
+    ("img", &["width", "height", "alt", "title", "src"]),
+    ("ol", &["start"]),
+    ("code", &["class"]),
+];
+
+/// URL schemes allowed in links and images
+const ALLOWED_SCHEMES: &[&str] = &["http", "https", "ftp", "mailto", "magnet", "mxc"];
+
+/// Query parameters that only serve to track who clicked a link
+const TRACKING_PARAMS: &[&str] = &[
+    "fbclid", "gclid", "dclid", "msclkid", "mc_cid", "mc_eid", "igshid", "yclid", "_hsenc", "_hsmi",
+];
+
+/// Escapes text for use in HTML element content and attribute values
+#[must_use]
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Returns whether a query parameter is used for tracking
+fn is_tracking_param(name: &str) -> bool {
+    name.starts_with("utm_") || TRACKING_PARAMS.contains(&name)
+}
+
+/// Removes tracking parameters from a URL
+///
+/// Strings that aren't URLs are returned unchanged.
+#[must_use]
+pub fn strip_tracking_params(url: &str) -> Cow<'_, str> {
+    let mut parsed = match Url::parse(url) {
+        Ok(parsed) => parsed,
+        Err(_) => return Cow::Borrowed(url),
+    };
+    if !parsed
+        .query_pairs()
+        .any(|(name, _)| is_tracking_param(&name))
+    {
+        return Cow::Borrowed(url);
+    }
+    let kept = parsed
+        .query_pairs()
+        .filter(|(name, _)| !is_tracking_param(name))
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect::<Vec<_>>();
+    if kept.is_empty() {
+        parsed.set_query(None);
+    } else {
+        parsed.query_pairs_mut().clear().extend_pairs(kept);
+    }
+    Cow::Owned(parsed.into())
+}
+
+/// Removes tracking parameters from all links in plain text
+#[must_use]
+pub fn strip_tracking_in_text(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("http") {
+        result.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find(char::is_whitespace)
+            .map_or(rest.len(), |end| start + end);
+        result.push_str(&strip_tracking_params(&rest[start..end]));
+        rest = &rest[end..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Removes everything but the allowed tags and attributes from HTML
+///
+/// Images are only kept if they point to the matrix content repository, and code classes only if
+/// they name a language. If `strip_tracking` is set, tracking parameters are removed from links.
+#[must_use]
+pub fn sanitize(html: &str, strip_tracking: bool) -> String {
+    let tag_attributes = ALLOWED_ATTRIBUTES
+        .iter()
+        .map(|(tag, attributes)| (*tag, attributes.iter().copied().collect::<HashSet<_>>()))
+        .collect::<HashMap<_, _>>();
+    Builder::empty()
+        .tags(ALLOWED_TAGS.iter().copied().collect())
+        .tag_attributes(tag_attributes)
+        .url_schemes(ALLOWED_SCHEMES.iter().copied().collect())
+        .link_rel(None)
+        .attribute_filter(
+            move |element, attribute, value| match (element, attribute) {
+                ("img", "src") if !value.starts_with("mxc://") => None,
+                ("code", "class") if !value.starts_with("language-") => None,
+                ("a", "href") if strip_tracking => Some(strip_tracking_params(value)),
+                _ => Some(Cow::Borrowed(value)),
+            },
+        )
+        .clean(html)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_special_characters() {
+        assert_eq!(
+            escape(r#"<a href="x">Tom & 'Jerry'</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; &#39;Jerry&#39;&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn sanitizer_removes_dangerous_content() {
+        assert_eq!(
+            sanitize(
+                r#"<b onclick="alert(1)">hi</b><script>alert(2)</script>"#,
+                false
+            ),
+            "<b>hi</b>"
+        );
+        assert_eq!(
+            sanitize(r#"<a href="javascript:alert(1)">x</a>"#, false),
+            "<a>x</a>"
+        );
+        assert_eq!(
+            sanitize(r#"<img src="data:image/png;base64,AAAA">"#, false),
+            "<img>"
+        );
+        assert_eq!(
+            sanitize(r#"<img src="mxc://chir.rs/abc" alt="cat">"#, false),
+            r#"<img src="mxc://chir.rs/abc" alt="cat">"#
+        );
+    }
+
+    #[test]
+    fn strips_tracking_params() {
+        assert_eq!(
+            strip_tracking_params("https://example.com/?utm_source=x&id=1&fbclid=y"),
+            "https://example.com/?id=1"
+        );
+        assert_eq!(
+            strip_tracking_params("https://example.com/?utm_source=x"),
+            "https://example.com/"
+        );
+        assert_eq!(strip_tracking_params("not a url"), "not a url");
+        assert_eq!(
+            strip_tracking_in_text("see https://example.com/?gclid=1 now"),
+            "see https://example.com/ now"
+        );
+        assert_eq!(
+            sanitize(r#"<a href="https://example.com/?utm_medium=x">x</a>"#, true),
+            r#"<a href="https://example.com/">x</a>"#
+        );
+    }
+}
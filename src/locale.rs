@@ -0,0 +1,184 @@
+//! Locales for text generated by the bridge
+//!
+//! Portals can have a preferred locale which is used for system messages and for formatting
+//! times, so that multilingual communities get consistent output in each portal. Portals without
+//! a locale use the configured default.
+
+use std::fmt;
+
+use crate::time::civil_from_days;
+use anyhow::{anyhow, Error, Result};
+use serde::{Deserialize, Serialize};
+
+/// Locale of bridge-generated text
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Locale {
+    /// English with ISO 8601 dates
+    English,
+    /// American English
+    EnglishUs,
+    /// German
+    German,
+    /// French
+    French,
+}
+
+impl Default for Locale {
+    fn default() -> Self {
+        Self::English
+    }
+}
+
+/// Messages the bridge sends on its own
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SystemMessage {
+    /// The discord channel was archived or made read-only
+    ChannelArchived,
+    /// The discord channel was reactivated
+    ChannelReactivated,
+}
+
+impl Locale {
+    /// Returns the IETF language tag of the locale
+    #[must_use]
+    pub const fn tag(self) -> &'static str {
+        match self {
+            Self::English => "en",
+            Self::EnglishUs => "en-US",
+            Self::German => "de",
+            Self::French => "fr",
+        }
+    }
+
+    /// Returns the text of a system message
+    #[must_use]
+    pub const fn system_message(self, message: SystemMessage) -> &'static str {
+        match (self, message) {
+            (Self::English | Self::EnglishUs, SystemMessage::ChannelArchived) => "This channel was archived or made read-only on discord. The room is read-only until it is reactivated.",
+            (Self::English | Self::EnglishUs, SystemMessage::ChannelReactivated) => "This channel was reactivated on discord.",
+            (Self::German, SystemMessage::ChannelArchived) => "Dieser Kanal wurde auf Discord archiviert oder schreibgeschützt. Der Raum ist schreibgeschützt, bis der Kanal reaktiviert wird.",
+            (Self::German, SystemMessage::ChannelReactivated) => "Dieser Kanal wurde auf Discord reaktiviert.",
+            (Self::French, SystemMessage::ChannelArchived) => "Ce salon a été archivé ou mis en lecture seule sur discord. Le salon reste en lecture seule jusqu’à sa réactivation.",
+            (Self::French, SystemMessage::ChannelReactivated) => "Ce salon a été réactivé sur discord.",
+        }
+    }
+
+    /// Formats a timestamp in milliseconds since the unix epoch
+    ///
+    /// Times are always given in UTC.
+    #[must_use]
+    pub fn format_timestamp(self, timestamp: u64) -> String {
+        let seconds = timestamp / 1000;
+        let (year, month, day) = civil_from_days(seconds / 86_400);
+        let hour = seconds % 86_400 / 3600;
+        let minute = seconds % 3600 / 60;
+        match self {
+            Self::English => format!(
+                "{:04}-{:02}-{:02} {:02}:{:02} UTC",
+                year, month, day, hour, minute
+            ),
+            Self::EnglishUs => format!(
+                "{:02}/{:02}/{:04} {}:{:02} {} UTC",
+                month,
+                day,
+                year,
+                (hour + 11) % 12 + 1,
+                minute,
+                if hour < 12 { "AM" } else { "PM" }
+            ),
+            Self::German => format!(
+                "{:02}.{:02}.{:04}, {:02}:{:02} UTC",
+                day, month, year, hour, minute
+            ),
+            Self::French => format!(
+                "{:02}/{:02}/{:04} {:02}:{:02} UTC",
+                day, month, year, hour, minute
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Locale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.tag())
+    }
+}
+
+impl TryFrom<&str> for Locale {
+    type Error = Error;
+
+    /// Parses a language tag like `de`, `en-US` or `fr_CA`
+    ///
+    /// Regions other than the united states are ignored.
+    fn try_from(tag: &str) -> Result<Self> {
+        let mut parts = tag.split(['-', '_']);
+        let language = parts.next().unwrap_or_default().to_ascii_lowercase();
+        let region = parts.next().map(str::to_ascii_uppercase);
+        match (language.as_str(), region.as_deref()) {
+            ("en", Some("US")) => Ok(Self::EnglishUs),
+            ("en", _) => Ok(Self::English),
+            ("de", _) => Ok(Self::German),
+            ("fr", _) => Ok(Self::French),
+            _ => Err(anyhow!(
+                "Unsupported locale {:?}, supported are en, en-US, de and fr",
+                tag
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Locale {
+    type Error = Error;
+
+    fn try_from(tag: String) -> Result<Self> {
+        Self::try_from(tag.as_str())
+    }
+}
+
+impl From<Locale> for String {
+    fn from(locale: Locale) -> Self {
+        locale.tag().to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_language_tags() {
+        assert_eq!(Locale::try_from("en").ok(), Some(Locale::English));
+        assert_eq!(Locale::try_from("en_us").ok(), Some(Locale::EnglishUs));
+        assert_eq!(Locale::try_from("en-GB").ok(), Some(Locale::English));
+        assert_eq!(Locale::try_from("de-AT").ok(), Some(Locale::German));
+        assert_eq!(Locale::try_from("FR").ok(), Some(Locale::French));
+        assert!(Locale::try_from("tlh").is_err());
+        assert!(Locale::try_from("").is_err());
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        let timestamp = 1_656_338_700_000;
+        assert_eq!(
+            Locale::English.format_timestamp(timestamp),
+            "2022-06-27 14:05 UTC"
+        );
+        assert_eq!(
+            Locale::EnglishUs.format_timestamp(timestamp),
+            "06/27/2022 2:05 PM UTC"
+        );
+        assert_eq!(
+            Locale::German.format_timestamp(timestamp),
+            "27.06.2022, 14:05 UTC"
+        );
+        assert_eq!(
+            Locale::French.format_timestamp(timestamp),
+            "27/06/2022 14:05 UTC"
+        );
+        assert_eq!(
+            Locale::EnglishUs.format_timestamp(0),
+            "01/01/1970 12:00 AM UTC"
+        );
+    }
+}
@@ -1,10 +1,13 @@
 //! Discord-Matrix bridge
 
-use std::path::PathBuf;
+use std::{io, path::PathBuf, sync::Arc};
 
-use anyhow::Result;
-use app::App;
-use clap::{Parser, Subcommand};
+use anyhow::{anyhow, Result};
+use app::{api_tokens::Scope, App};
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use matrix_sdk::ruma::{OwnedRoomOrAliasId, RoomOrAliasId};
+use twilight_model::id::Id;
 
 pub mod config;
 pub use config::File as ConfigFile;
@@ -15,7 +18,21 @@ use tracing_subscriber::{
 };
 
 pub mod app;
+pub mod db_trace;
+pub mod fallback;
+pub mod features;
+pub mod formatter;
+pub mod html;
+pub mod locale;
+pub mod metrics;
 pub mod registration;
+pub mod retry;
+pub mod secrets;
+pub mod setup;
+pub mod snowflake;
+#[cfg(any(test, feature = "testkit"))]
+pub mod testkit;
+pub mod time;
 /// Application service to connect discord to matrix
 #[derive(Clone, Debug, Parser)]
 #[clap(author, version, about, long_about = None)]
@@ -32,12 +49,72 @@ pub struct Args {
 }
 
 /// Subcommand list
-#[derive(Copy, Clone, Debug, Subcommand)]
+#[derive(Clone, Debug, Subcommand)]
 pub enum Command {
     /// Generate a registration file
     GenerateRegistration,
+    /// Interactively write the configuration and registration files
+    Setup,
     /// Start the server
-    Start,
+    Start {
+        /// Log all messages that would be sent to Matrix or Discord instead of sending them
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Export the bridged history of a portal into a static archive
+    Export {
+        /// Room ID or alias of the portal
+        #[clap(long)]
+        room: OwnedRoomOrAliasId,
+        /// Directory to write the archive to
+        #[clap(long)]
+        output: PathBuf,
+    },
+    /// Send a one-off message as the bridge bot, for example from a cron job
+    Send {
+        /// Room ID or alias of the portal to send a notice to
+        #[clap(long, required_unless_present = "channel", conflicts_with = "channel")]
+        room: Option<OwnedRoomOrAliasId>,
+        /// Discord channel to send a message to
+        #[clap(long)]
+        channel: Option<u64>,
+        /// Text of the message
+        #[clap(long)]
+        message: String,
+    },
+    /// Manage API tokens of integrations
+    ApiToken {
+        /// Action to perform
+        #[clap(subcommand)]
+        action: ApiTokenAction,
+    },
+    /// Print shell completions
+    Completions {
+        /// Shell to generate completions for
+        #[clap(arg_enum)]
+        shell: Shell,
+    },
+    /// Print the man page
+    Manpage,
+}
+
+/// Actions on API tokens
+#[derive(Clone, Debug, Subcommand)]
+pub enum ApiTokenAction {
+    /// Create a token and print it
+    Create {
+        /// Name of the integration using the token
+        name: String,
+        /// Scope of the token: read-only, manage-portals or admin
+        scope: Scope,
+    },
+    /// Revoke a token
+    Revoke {
+        /// Name of the token
+        name: String,
+    },
+    /// List all tokens
+    List,
 }
 
 /// Sets up sentry
@@ -45,6 +122,7 @@ fn setup_sentry() -> Result<ClientInitGuard> {
     tracing_subscriber::Registry::default()
         .with(tracing_subscriber::fmt::layer().with_filter(EnvFilter::from_default_env()))
         .with(sentry::integrations::tracing::layer())
+        .with(db_trace::QueryMetricsLayer)
         .try_init()?;
 
     let client_options = sentry::ClientOptions {
@@ -66,21 +144,117 @@ async fn run_app(config: &ConfigFile, args: &Args) -> Result<()> {
     Ok(())
 }
 
+/// Sends a one-off message to a portal room or discord channel
+///
+/// # Errors
+/// This function will return an error if the target is invalid or sending fails
+async fn send_cmd(
+    app: &Arc<App>,
+    room: Option<&RoomOrAliasId>,
+    channel: Option<u64>,
+    message: &str,
+) -> Result<()> {
+    match (room, channel.and_then(Id::new_checked)) {
+        (Some(room), _) => app.send_to_portal_room(room, message).await,
+        (None, Some(channel_id)) => app.send_to_channel(channel_id, message).await,
+        (None, None) => Err(anyhow!("Invalid channel id")),
+    }
+}
+
+/// Runs an API token action
+///
+/// # Errors
+/// This function will return an error if the database query fails
+async fn api_token_cmd(app: &Arc<App>, action: &ApiTokenAction) -> Result<()> {
+    match *action {
+        ApiTokenAction::Create { ref name, scope } => {
+            println!("{}", app.create_api_token(name, scope).await?);
+        }
+        ApiTokenAction::Revoke { ref name } => {
+            if !app.revoke_api_token(name).await? {
+                println!("There is no active token named {}", name);
+            }
+        }
+        ApiTokenAction::List => {
+            for token in app.api_tokens().await? {
+                println!(
+                    "{}\t{}{}",
+                    token.name,
+                    token.scope.as_str(),
+                    if token.revoked { "\trevoked" } else { "" }
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Prints shell completions or the man page
+///
+/// # Errors
+/// This function will return an error if writing to stdout fails
+fn docs_cmd(command: &Command) -> Result<()> {
+    match *command {
+        Command::Completions { shell } => {
+            clap_complete::generate(
+                shell,
+                &mut Args::command(),
+                env!("CARGO_PKG_NAME"),
+                &mut io::stdout(),
+            );
+        }
+        Command::Manpage => clap_mangen::Man::new(Args::command()).render(&mut io::stdout())?,
+        _ => {}
+    }
+    Ok(())
+}
+
 /// Main program entrypoint
 #[tokio::main]
 async fn main() -> Result<()> {
     /// The actual main function
     async fn main() -> Result<()> {
         let args = Args::parse();
+        match args.subcommand {
+            Command::Setup => return setup::setup_cmd(&args).await,
+            Command::Completions { .. } | Command::Manpage => return docs_cmd(&args.subcommand),
+            _ => {}
+        }
         let config = ConfigFile::read_from_file(&args.config)?;
 
         match args.subcommand {
+            Command::Setup | Command::Completions { .. } | Command::Manpage => {}
             Command::GenerateRegistration => {
                 registration::generate_registration_cmd(&config, &args)?;
             }
-            Command::Start => {
+            Command::Start { .. } => {
                 run_app(&config, &args).await?;
             }
+            Command::Export {
+                ref room,
+                ref output,
+            } => {
+                App::new(&config, &args)
+                    .await?
+                    .export_portal(room, output)
+                    .await?;
+            }
+            Command::Send {
+                ref room,
+                channel,
+                ref message,
+            } => {
+                send_cmd(
+                    &App::new(&config, &args).await?,
+                    room.as_deref(),
+                    channel,
+                    message,
+                )
+                .await?;
+            }
+            Command::ApiToken { ref action } => {
+                api_token_cmd(&App::new(&config, &args).await?, action).await?;
+            }
         }
 
         Ok(())
@@ -0,0 +1,111 @@
+//! Bridge metrics
+//!
+//! Metrics are kept in a global registry and can be rendered in the prometheus text format.
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicI64, Ordering},
+};
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+
+/// Global metrics registry
+pub static METRICS: Lazy<Registry> = Lazy::new(Registry::default);
+
+/// Registry of metric series
+#[derive(Debug, Default)]
+pub struct Registry {
+    /// Current value of every series, keyed by the series name including labels
+    series: DashMap<String, AtomicI64>,
+}
+
+/// Formats a series name with its labels
+fn series_name(name: &str, labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return name.to_owned();
+    }
+    let mut series = format!("{}{{", name);
+    for (i, (key, value)) in labels.iter().enumerate() {
+        if i != 0 {
+            series.push(',');
+        }
+        let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+        // Writing to a string never fails
+        let _ = write!(series, "{}=\"{}\"", key, value);
+    }
+    series.push('}');
+    series
+}
+
+impl Registry {
+    /// Sets a gauge to a value
+    pub fn set(&self, name: &str, labels: &[(&str, &str)], value: i64) {
+        self.series
+            .entry(series_name(name, labels))
+            .or_default()
+            .store(value, Ordering::Relaxed);
+    }
+
+    /// Adds a value to a counter or gauge
+    pub fn add(&self, name: &str, labels: &[(&str, &str)], value: i64) {
+        self.series
+            .entry(series_name(name, labels))
+            .or_default()
+            .fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Increments a counter by one
+    pub fn inc(&self, name: &str, labels: &[(&str, &str)]) {
+        self.add(name, labels, 1);
+    }
+
+    /// Returns the current value of a series
+    #[must_use]
+    pub fn get(&self, name: &str, labels: &[(&str, &str)]) -> Option<i64> {
+        self.series
+            .get(&series_name(name, labels))
+            .map(|v| v.load(Ordering::Relaxed))
+    }
+
+    /// Renders all metrics in the prometheus text format
+    #[must_use]
+    pub fn render(&self) -> String {
+        let mut lines = self
+            .series
+            .iter()
+            .map(|entry| {
+                format!(
+                    "{} {}\n",
+                    entry.key(),
+                    entry.value().load(Ordering::Relaxed)
+                )
+            })
+            .collect::<Vec<_>>();
+        lines.sort_unstable();
+        lines.concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn series_name_escapes_labels() {
+        assert_eq!(series_name("up", &[]), "up");
+        assert_eq!(
+            series_name("requests", &[("target", "a\"b"), ("kind", "c")]),
+            "requests{target=\"a\\\"b\",kind=\"c\"}"
+        );
+    }
+
+    #[test]
+    fn render_is_sorted() {
+        let registry = Registry::default();
+        registry.inc("b", &[]);
+        registry.set("a", &[("x", "y")], 5);
+        registry.add("b", &[], 2);
+        assert_eq!(registry.render(), "a{x=\"y\"} 5\nb 3\n");
+    }
+}
@@ -16,7 +16,7 @@ fn generate_token<R: Rng + CryptoRng>(r: &mut R) -> String {
 }
 
 /// Generate a registration
-fn generate_registration(config: &ConfigFile) -> Registration {
+pub(crate) fn generate_registration(config: &ConfigFile) -> Registration {
     let mut namespaces = Namespaces::new();
 
     namespaces.users = vec![
@@ -35,6 +35,12 @@ fn generate_registration(config: &ConfigFile) -> Registration {
             ),
         ),
     ];
+    if config.bridge.double_puppet {
+        namespaces.users.push(Namespace::new(
+            false,
+            format!("@.*:{}", config.homeserver.domain),
+        ));
+    }
     namespaces.aliases = vec![Namespace::new(
         true,
         format!(
@@ -70,16 +76,9 @@ pub fn generate_registration_cmd(config: &ConfigFile, args: &crate::Args) -> Res
 
 #[cfg(test)]
 mod tests {
-    use std::{
-        net::{IpAddr, Ipv4Addr},
-        str::FromStr,
-    };
-
-    use matrix_sdk::ruma::user_id;
     use rand::thread_rng;
-    use url::Url;
 
-    use crate::config::{self, DBOptions};
+    use crate::testkit;
 
     use super::*;
 
@@ -90,23 +89,30 @@ mod tests {
     }
 
     #[test]
-    #[allow(clippy::expect_used)]
     fn generate_registration_smoketest() {
-        let config = ConfigFile {
-            homeserver: config::Homeserver {
-                address: Url::from_str("https://matrix.chir.rs/").expect("valid URL"),
-                domain: "chir.rs".to_owned(),
-                mscs: vec![],
-            },
-            bridge: config::Bridge {
-                listen_address: vec![IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))],
-                port: 58913,
-                bridge_url: Url::from_str("http://localhost:58913/").expect("valid URL"),
-                prefix: "".to_owned(),
-                db: DBOptions::default(),
-                admin: user_id!("@lotte:chir.rs").to_owned(),
-            },
-        };
-        drop(generate_registration(&config));
+        drop(generate_registration(&testkit::config()));
+    }
+
+    #[test]
+    fn double_puppet_namespace() {
+        let config = testkit::ConfigBuilder::new()
+            .prefix("dev")
+            .double_puppet(true)
+            .build();
+        let registration = generate_registration(&config);
+        let users = registration
+            .namespaces
+            .users
+            .iter()
+            .map(|namespace| (namespace.exclusive, namespace.regex.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            users,
+            [
+                (true, "@dev_discord_.*:chir.rs"),
+                (true, "@dev_discordbot:chir.rs"),
+                (false, "@.*:chir.rs")
+            ]
+        );
     }
 }
@@ -0,0 +1,321 @@
+//! Retries with exponential backoff and circuit breakers
+//!
+//! Every retried operation belongs to a target (for example `matrix` or `discord`). Each target
+//! has a circuit breaker that opens after repeated failed operations, so that an unavailable
+//! service isn't hammered with requests.
+
+use std::{
+    fmt::Debug,
+    future::Future,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use rand::{thread_rng, Rng};
+use tokio::time::sleep;
+use tracing::warn;
+
+use crate::metrics::METRICS;
+
+/// Circuit breakers by target
+static BREAKERS: Lazy<DashMap<String, Arc<CircuitBreaker>>> = Lazy::new(DashMap::new);
+
+/// Exponential backoff policy
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    /// Delay before the first retry
+    pub initial: Duration,
+    /// Upper bound for the delay
+    pub max: Duration,
+    /// Number of attempts before giving up
+    pub max_attempts: u32,
+    /// Whether to randomize the delay
+    pub jitter: bool,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_millis(500), Duration::from_secs(30))
+    }
+}
+
+impl Backoff {
+    /// Creates a new backoff policy with 5 attempts
+    #[must_use]
+    pub const fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            max_attempts: 5,
+            jitter: true,
+        }
+    }
+
+    /// Sets the number of attempts before giving up
+    #[must_use]
+    pub const fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Disables randomization of the delay
+    #[must_use]
+    pub const fn without_jitter(mut self) -> Self {
+        self.jitter = false;
+        self
+    }
+
+    /// Returns the delay after the given failed attempt, starting at 0
+    ///
+    /// With jitter enabled, the delay is picked uniformly between half and the full delay.
+    #[must_use]
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .initial
+            .checked_mul(2_u32.saturating_pow(attempt))
+            .map_or(self.max, |delay| delay.min(self.max));
+        if self.jitter && !delay.is_zero() {
+            thread_rng().gen_range(delay / 2..=delay)
+        } else {
+            delay
+        }
+    }
+}
+
+/// State of a circuit breaker
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Requests are allowed
+    Closed,
+    /// A single request is allowed to probe whether the target recovered
+    HalfOpen,
+    /// Requests are rejected
+    Open,
+}
+
+impl BreakerState {
+    /// Numeric representation used in metrics
+    const fn metric_value(self) -> i64 {
+        match self {
+            Self::Closed => 0,
+            Self::HalfOpen => 1,
+            Self::Open => 2,
+        }
+    }
+}
+
+/// Mutable state of a circuit breaker
+#[derive(Clone, Copy, Debug)]
+enum Inner {
+    /// Closed with a number of consecutive failures
+    Closed(u32),
+    /// Open until the given instant
+    Open(Instant),
+    /// Waiting for the result of the probe request
+    HalfOpen,
+}
+
+/// Circuit breaker for a single target
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    /// Name of the target
+    target: String,
+    /// Number of consecutive failures that open the breaker
+    threshold: u32,
+    /// Time the breaker stays open
+    cooldown: Duration,
+    /// Current state
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    /// Creates a new closed circuit breaker
+    #[must_use]
+    pub fn new(target: impl Into<String>, threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            target: target.into(),
+            threshold,
+            cooldown,
+            inner: Mutex::new(Inner::Closed(0)),
+        }
+    }
+
+    /// Returns the circuit breaker for a target, creating it if necessary
+    #[must_use]
+    pub fn for_target(target: &str) -> Arc<Self> {
+        Arc::clone(
+            &*BREAKERS
+                .entry(target.to_owned())
+                .or_insert_with(|| Arc::new(Self::new(target, 5, Duration::from_secs(30)))),
+        )
+    }
+
+    /// Returns the current state
+    #[must_use]
+    pub fn state(&self) -> BreakerState {
+        match *self.inner.lock().unwrap_or_else(PoisonError::into_inner) {
+            Inner::Closed(_) => BreakerState::Closed,
+            Inner::Open(until) if until <= Instant::now() => BreakerState::HalfOpen,
+            Inner::Open(_) => BreakerState::Open,
+            Inner::HalfOpen => BreakerState::HalfOpen,
+        }
+    }
+
+    /// Checks whether a request may be made
+    ///
+    /// After the cooldown, a single probe request is allowed through.
+    #[must_use]
+    pub fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        match *inner {
+            Inner::Closed(_) => true,
+            Inner::Open(until) if until <= Instant::now() => {
+                *inner = Inner::HalfOpen;
+                drop(inner);
+                self.report(BreakerState::HalfOpen);
+                true
+            }
+            Inner::Open(_) | Inner::HalfOpen => false,
+        }
+    }
+
+    /// Records a successful request
+    pub fn record_success(&self) {
+        *self.inner.lock().unwrap_or_else(PoisonError::into_inner) = Inner::Closed(0);
+        self.report(BreakerState::Closed);
+    }
+
+    /// Records a failed request
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = match *inner {
+            Inner::Closed(failures) if failures + 1 < self.threshold => {
+                *inner = Inner::Closed(failures + 1);
+                BreakerState::Closed
+            }
+            _ => {
+                *inner = Inner::Open(Instant::now() + self.cooldown);
+                BreakerState::Open
+            }
+        };
+        drop(inner);
+        if state == BreakerState::Open {
+            warn!("Circuit breaker for {} opened", self.target);
+        }
+        self.report(state);
+    }
+
+    /// Exports the breaker state to the metrics registry
+    fn report(&self, state: BreakerState) {
+        METRICS.set(
+            "bridge_circuit_breaker_state",
+            &[("target", &self.target)],
+            state.metric_value(),
+        );
+    }
+}
+
+/// Runs an operation, retrying it with backoff on failure
+///
+/// The circuit breaker of the target is checked before the operation is started and is updated
+/// with the final outcome.
+///
+/// # Errors
+/// This function returns an error if the circuit breaker is open or the last attempt failed
+pub async fn retry<T, E, F, Fut>(target: &str, backoff: Backoff, mut f: F) -> Result<T>
+where
+    F: FnMut() -> Fut + Send,
+    Fut: Future<Output = Result<T, E>> + Send,
+    E: Into<anyhow::Error> + Debug + Send,
+{
+    let breaker = CircuitBreaker::for_target(target);
+    if !breaker.allow() {
+        METRICS.inc("bridge_circuit_breaker_rejections", &[("target", target)]);
+        return Err(anyhow!("Circuit breaker for {} is open", target));
+    }
+    let mut attempt = 0;
+    loop {
+        match f().await {
+            Ok(v) => {
+                breaker.record_success();
+                return Ok(v);
+            }
+            Err(e) if attempt + 1 >= backoff.max_attempts => {
+                breaker.record_failure();
+                return Err(e.into());
+            }
+            Err(e) => {
+                let delay = backoff.delay(attempt);
+                warn!(
+                    "Request to {} failed ({:?}), retrying in {:?}",
+                    target, e, delay
+                );
+                METRICS.inc("bridge_retries", &[("target", target)]);
+                sleep(delay).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_until_max() {
+        let backoff = Backoff::new(Duration::from_secs(2), Duration::from_secs(8)).without_jitter();
+        assert_eq!(backoff.delay(0), Duration::from_secs(2));
+        assert_eq!(backoff.delay(1), Duration::from_secs(4));
+        assert_eq!(backoff.delay(2), Duration::from_secs(8));
+        assert_eq!(backoff.delay(3), Duration::from_secs(8));
+        assert_eq!(backoff.delay(100), Duration::from_secs(8));
+    }
+
+    #[test]
+    fn backoff_jitter_is_bounded() {
+        let backoff = Backoff::new(Duration::from_secs(2), Duration::from_secs(8));
+        for _ in 0..100 {
+            let delay = backoff.delay(1);
+            assert!(delay >= Duration::from_secs(2) && delay <= Duration::from_secs(4));
+        }
+    }
+
+    #[test]
+    fn breaker_opens_after_threshold() {
+        let breaker = CircuitBreaker::new("test-open", 2, Duration::from_secs(60));
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn breaker_probes_after_cooldown() {
+        let breaker = CircuitBreaker::new("test-probe", 1, Duration::ZERO);
+        breaker.record_failure();
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+        breaker.record_success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.allow());
+    }
+
+    #[tokio::test]
+    async fn retry_gives_up_after_max_attempts() {
+        let backoff = Backoff::new(Duration::ZERO, Duration::ZERO).with_max_attempts(3);
+        let mut attempts = 0;
+        let result: Result<()> = retry("test-retry", backoff, || {
+            attempts += 1;
+            async { Err(anyhow!("failure")) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+    }
+}
@@ -0,0 +1,142 @@
+//! Encryption of secrets stored in the database
+//!
+//! Secrets like custom webhook URLs grant access to discord on their own, so they are encrypted
+//! with ChaCha20-Poly1305 under a key derived from `bridge.secret_key` before they are stored.
+//! A database dump alone is not enough to use them.
+
+use std::fmt::Write;
+
+use anyhow::{anyhow, Result};
+use chacha20poly1305::{
+    aead::{Aead, NewAead},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use rand::{thread_rng, RngCore};
+use sha2::{Digest, Sha256};
+
+/// Length of a nonce in bytes
+const NONCE_LEN: usize = 12;
+
+/// Encrypts and decrypts secrets
+#[derive(Clone)]
+pub struct SecretBox {
+    /// Cipher keyed with the derived key
+    cipher: ChaCha20Poly1305,
+}
+
+impl std::fmt::Debug for SecretBox {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecretBox").finish_non_exhaustive()
+    }
+}
+
+/// Encodes bytes as lowercase hex
+fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        // Writing to a string never fails
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+/// Decodes hex into bytes
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return Err(anyhow!("Odd number of hex digits"));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| anyhow!("Invalid hex digit"))
+        })
+        .collect()
+}
+
+impl SecretBox {
+    /// Creates a secret box from the configured secret key
+    ///
+    /// # Errors
+    /// This function will return an error if the secret key is empty
+    pub fn new(secret_key: &str) -> Result<Self> {
+        if secret_key.is_empty() {
+            return Err(anyhow!("secret_key must not be empty"));
+        }
+        let key = Sha256::digest(secret_key.as_bytes());
+        Ok(Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+        })
+    }
+
+    /// Encrypts a secret, returning the hex encoded nonce and ciphertext
+    ///
+    /// # Errors
+    /// This function will return an error if encryption fails
+    pub fn seal(&self, plaintext: &str) -> Result<String> {
+        let mut nonce = [0; NONCE_LEN];
+        thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes())
+            .map_err(|_| anyhow!("Failed to encrypt secret"))?;
+        Ok(format!("{}{}", to_hex(&nonce), to_hex(&ciphertext)))
+    }
+
+    /// Decrypts a secret sealed with [`Self::seal`]
+    ///
+    /// # Errors
+    /// This function will return an error if the secret was tampered with or sealed with a
+    /// different key
+    pub fn open(&self, sealed: &str) -> Result<String> {
+        let sealed = from_hex(sealed)?;
+        if sealed.len() < NONCE_LEN {
+            return Err(anyhow!("Sealed secret is too short"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("Failed to decrypt secret, was secret_key changed?"))?;
+        Ok(String::from_utf8(plaintext)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_roundtrip() {
+        assert_eq!(to_hex(&[0, 15, 255]), "000fff");
+        assert_eq!(from_hex("000fff").ok(), Some(vec![0, 15, 255]));
+        assert!(from_hex("0g").is_err());
+        assert!(from_hex("abc").is_err());
+    }
+
+    #[test]
+    fn sealed_secrets_roundtrip() {
+        let secrets = SecretBox::new("key").ok();
+        let sealed = secrets
+            .as_ref()
+            .and_then(|secrets| secrets.seal("hunter2").ok());
+        assert!(sealed
+            .as_deref()
+            .map_or(false, |sealed| !sealed.contains("hunter2")));
+        let opened = secrets
+            .as_ref()
+            .zip(sealed.as_deref())
+            .and_then(|(secrets, sealed)| secrets.open(sealed).ok());
+        assert_eq!(opened.as_deref(), Some("hunter2"));
+    }
+
+    #[test]
+    fn other_keys_cannot_open() {
+        let sealed = SecretBox::new("key").and_then(|secrets| secrets.seal("hunter2"));
+        let opened = SecretBox::new("other")
+            .and_then(|secrets| secrets.open(sealed.as_deref().unwrap_or_default()));
+        assert!(opened.is_err());
+        assert!(SecretBox::new("").is_err());
+    }
+}
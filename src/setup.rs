@@ -0,0 +1,328 @@
+//! Interactive setup for new operators
+//!
+//! `setup` asks for the homeserver, database and discord settings, writes the configuration and
+//! registration files and can check that everything is reachable before the first start.
+
+use std::{
+    io::{self, BufRead, Write},
+    net::{IpAddr, Ipv4Addr},
+    str::FromStr,
+};
+
+use crate::{
+    app::App,
+    config::{self, Bridge, DBOptions, Discord, Homeserver},
+    features::Features,
+    locale::Locale,
+    registration, Args, ConfigFile,
+};
+use anyhow::{anyhow, Result};
+use matrix_sdk::ruma::OwnedUserId;
+use sqlx::PgPool;
+use url::Url;
+
+/// Default port of the bridge
+const DEFAULT_PORT: u16 = 58913;
+
+/// Steps for creating the discord bot, printed before asking for its token
+const DISCORD_HINTS: &str = "\
+To create the discord bot:
+  1. Open https://discord.com/developers/applications and create a new application
+  2. Add a bot user on the \"Bot\" page and copy its token
+  3. Enable the \"Server Members\" and \"Message Content\" privileged intents
+  4. Invite the bot with the \"bot\" and \"applications.commands\" scopes";
+
+/// Returns the answer to a question, or the default if the answer is empty
+fn answer_or_default(answer: &str, default: &str) -> String {
+    let answer = answer.trim();
+    if answer.is_empty() {
+        default.to_owned()
+    } else {
+        answer.to_owned()
+    }
+}
+
+/// Parses the answer to a yes/no question
+fn parse_yes_no(answer: &str, default: bool) -> Option<bool> {
+    match answer.trim().to_ascii_lowercase().as_str() {
+        "" => Some(default),
+        "y" | "yes" => Some(true),
+        "n" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+/// Returns `None` for empty strings
+fn non_empty(value: String) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+/// Reads answers from the terminal
+struct Prompt<R> {
+    /// Input the answers are read from
+    input: R,
+}
+
+impl<R: BufRead> Prompt<R> {
+    /// Prints a prompt and reads one line
+    ///
+    /// # Errors
+    /// This function will return an error if reading from the terminal fails or it was closed
+    fn read(&mut self, prompt: &str) -> Result<String> {
+        print!("{}", prompt);
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        if self.input.read_line(&mut answer)? == 0 {
+            return Err(anyhow!("Setup aborted"));
+        }
+        Ok(answer)
+    }
+
+    /// Asks a question, returning the default for empty answers
+    ///
+    /// # Errors
+    /// This function will return an error if reading from the terminal fails
+    fn ask(&mut self, question: &str, default: &str) -> Result<String> {
+        let answer = if default.is_empty() {
+            self.read(&format!("{}: ", question))?
+        } else {
+            self.read(&format!("{} [{}]: ", question, default))?
+        };
+        Ok(answer_or_default(&answer, default))
+    }
+
+    /// Asks a question until the answer can be parsed
+    ///
+    /// # Errors
+    /// This function will return an error if reading from the terminal fails
+    fn ask_parsed<T: FromStr>(&mut self, question: &str, default: &str) -> Result<T>
+    where
+        T::Err: std::fmt::Display,
+    {
+        loop {
+            match self.ask(question, default)?.parse() {
+                Ok(value) => return Ok(value),
+                Err(e) => println!("Invalid answer: {}", e),
+            }
+        }
+    }
+
+    /// Asks a yes/no question
+    ///
+    /// # Errors
+    /// This function will return an error if reading from the terminal fails
+    fn confirm(&mut self, question: &str, default: bool) -> Result<bool> {
+        let hint = if default { "Y/n" } else { "y/N" };
+        loop {
+            let answer = self.read(&format!("{} [{}]: ", question, hint))?;
+            match parse_yes_no(&answer, default) {
+                Some(answer) => return Ok(answer),
+                None => println!("Please answer yes or no"),
+            }
+        }
+    }
+}
+
+/// Asks for all settings needed to run the bridge
+///
+/// # Errors
+/// This function will return an error if reading from the terminal fails
+fn ask_config<R: BufRead>(prompt: &mut Prompt<R>) -> Result<ConfigFile> {
+    println!("Homeserver");
+    let address: Url = prompt.ask_parsed("Homeserver URL", "https://matrix.example.com")?;
+    let domain = prompt.ask(
+        "Server name (the part after the colon in user ids)",
+        address.host_str().unwrap_or_default(),
+    )?;
+    let admin: OwnedUserId = prompt.ask_parsed("Bridge admin", &format!("@admin:{}", domain))?;
+
+    println!("\nBridge");
+    let port = prompt.ask_parsed("Port to listen on", &DEFAULT_PORT.to_string())?;
+    let bridge_url = prompt.ask_parsed(
+        "URL the homeserver reaches the bridge at",
+        &format!("http://localhost:{}/", port),
+    )?;
+    let prefix = prompt.ask("Prefix for bridged users and rooms (may be empty)", "")?;
+
+    println!("\nDatabase");
+    let db = if prompt.confirm("Connect over a unix socket?", true)? {
+        DBOptions {
+            socket: Some(prompt.ask_parsed("Socket directory", "/run/postgresql")?),
+            ..DBOptions::default()
+        }
+    } else {
+        DBOptions {
+            host: Some(prompt.ask("Host", "localhost")?),
+            port: Some(prompt.ask_parsed("Port", "5432")?),
+            password: non_empty(prompt.ask("Password", "")?),
+            ..DBOptions::default()
+        }
+    };
+    let db = DBOptions {
+        user: Some(prompt.ask("User", "discord_bridge")?),
+        database: Some(prompt.ask("Database", "discord_bridge")?),
+        ..db
+    };
+
+    println!("\nDiscord\n{}", DISCORD_HINTS);
+    let bot_token = non_empty(prompt.ask("Bot token (leave empty to set it later)", "")?);
+
+    Ok(ConfigFile {
+        homeserver: Homeserver {
+            address,
+            domain,
+            mscs: Vec::new(),
+        },
+        bridge: Bridge {
+            listen_address: vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)],
+            port,
+            bridge_url,
+            prefix,
+            db,
+            admin,
+            admin_room: None,
+            catalog_room: None,
+            homeserver_parallelism: 16,
+            event_parallelism: 16,
+            shutdown_timeout_secs: 30,
+            double_puppet: false,
+            moderator_power: false,
+            message_retention_months: None,
+            strip_tracking_params: false,
+            topic_metadata: false,
+            member_roles: false,
+            displayname_template: "{displayname} (Discord)".to_owned(),
+            member_sync: config::MemberSync::Lazy,
+            moderation_sync: config::ModerationSync::Off,
+            default_locale: Locale::English,
+            secret_key: None,
+            media_admin_token: None,
+            event_webhooks: Vec::new(),
+            integrity_check: None,
+        },
+        discord: Discord {
+            bot_token,
+            ..Discord::default()
+        },
+        features: Features::default(),
+        deprecated_keys: Vec::new(),
+    })
+}
+
+/// Checks that the homeserver, the database and discord are reachable
+///
+/// Failures are printed instead of being returned, so that all checks run.
+async fn test_connectivity(config: &ConfigFile) {
+    let http = reqwest::Client::new();
+
+    let versions = config.homeserver.address.join("_matrix/client/versions");
+    let homeserver = match versions {
+        Ok(url) => http
+            .get(url)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map(drop)
+            .map_err(anyhow::Error::from),
+        Err(e) => Err(e.into()),
+    };
+    report("Homeserver", &homeserver);
+
+    let database = PgPool::connect_with(App::get_connect_options(config))
+        .await
+        .map(drop)
+        .map_err(anyhow::Error::from);
+    report("Database", &database);
+
+    if let Some(ref token) = config.discord.bot_token {
+        let discord = http
+            .get("https://discord.com/api/v10/users/@me")
+            .header(reqwest::header::AUTHORIZATION, format!("Bot {}", token))
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map(drop)
+            .map_err(anyhow::Error::from);
+        report("Discord", &discord);
+    }
+}
+
+/// Prints the result of a connectivity check
+fn report(name: &str, result: &Result<()>) {
+    match result {
+        Ok(()) => println!("{}: ok", name),
+        Err(e) => println!("{}: failed ({})", name, e),
+    }
+}
+
+/// Command for the interactive setup
+///
+/// # Errors
+/// This function will return an error if reading from the terminal or writing the files fails
+pub async fn setup_cmd(args: &Args) -> Result<()> {
+    let stdin = io::stdin();
+    let mut prompt = Prompt {
+        input: stdin.lock(),
+    };
+    if args.config.exists()
+        && !prompt.confirm(
+            &format!("{} already exists, overwrite it?", args.config.display()),
+            false,
+        )?
+    {
+        return Ok(());
+    }
+    let config = ask_config(&mut prompt)?;
+    let test = prompt.confirm("\nTest connectivity now?", true)?;
+    drop(prompt);
+
+    std::fs::write(&args.config, serde_yaml::to_string(&config)?)?;
+    println!("Wrote {}", args.config.display());
+    registration::generate_registration_cmd(&config, args)?;
+    println!(
+        "Wrote {}, add it to the app_service_config_files of your homeserver",
+        args.registration.display()
+    );
+
+    if test {
+        test_connectivity(&config).await;
+    }
+    println!(
+        "Start the bridge with: discord-matrix-bridge -c {} -r {} start",
+        args.config.display(),
+        args.registration.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_answers_use_defaults() {
+        assert_eq!(answer_or_default("  \n", "5432"), "5432");
+        assert_eq!(answer_or_default("6543\n", "5432"), "6543");
+    }
+
+    #[test]
+    fn parses_yes_no() {
+        assert_eq!(parse_yes_no("", true), Some(true));
+        assert_eq!(parse_yes_no("Yes\n", false), Some(true));
+        assert_eq!(parse_yes_no("n", true), Some(false));
+        assert_eq!(parse_yes_no("maybe", true), None);
+    }
+
+    #[test]
+    fn asks_until_valid() {
+        let mut prompt = Prompt {
+            input: "abc\n\n".as_bytes(),
+        };
+        assert_eq!(prompt.ask_parsed::<u16>("Port", "5432").ok(), Some(5432));
+        assert!(prompt.ask("Host", "").is_err());
+    }
+}
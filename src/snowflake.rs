@@ -0,0 +1,83 @@
+//! Discord snowflake helpers
+//!
+//! Snowflakes encode their creation time in milliseconds since the discord epoch in the upper
+//! 42 bits, which gives every discord object a timestamp without an extra request.
+
+use anyhow::{anyhow, Result};
+use twilight_model::id::Id;
+
+/// Start of the discord epoch in milliseconds since the unix epoch
+pub const DISCORD_EPOCH_MS: u64 = 1_420_070_400_000;
+
+/// Number of bits below the timestamp of a snowflake
+const TIMESTAMP_SHIFT: u32 = 22;
+
+/// Returns the creation time of a snowflake in milliseconds since the unix epoch
+#[must_use]
+pub const fn timestamp_ms<T>(id: Id<T>) -> u64 {
+    (id.get() >> TIMESTAMP_SHIFT) + DISCORD_EPOCH_MS
+}
+
+/// Returns the smallest snowflake created at the given time
+///
+/// This is useful as a bound when paginating by time. Returns `None` for times that can't be
+/// represented, which are those before the discord epoch, exactly at it, or too far in the future.
+#[must_use]
+pub fn first_at<T>(timestamp: u64) -> Option<Id<T>> {
+    let elapsed = timestamp.checked_sub(DISCORD_EPOCH_MS)?;
+    if elapsed >> (u64::BITS - TIMESTAMP_SHIFT) != 0 {
+        return None;
+    }
+    Id::new_checked(elapsed << TIMESTAMP_SHIFT)
+}
+
+/// Converts a snowflake into its database representation
+///
+/// Snowflakes are stored as `BIGINT`, which is signed. The bit pattern is preserved.
+#[must_use]
+#[allow(clippy::cast_possible_wrap)]
+pub const fn to_db<T>(id: Id<T>) -> i64 {
+    id.get() as i64
+}
+
+/// Converts a database value back into a snowflake
+///
+/// # Errors
+/// This function returns an error if the stored value is zero
+#[allow(clippy::cast_sign_loss)]
+pub fn from_db<T>(id: i64) -> Result<Id<T>> {
+    Id::new_checked(id as u64).ok_or_else(|| anyhow!("Invalid snowflake in database: {}", id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use twilight_model::id::marker::MessageMarker;
+
+    #[test]
+    fn decodes_timestamps() {
+        let id = Id::<MessageMarker>::new(175_928_847_299_117_063);
+        assert_eq!(timestamp_ms(id), 1_462_015_105_796);
+        assert_eq!(timestamp_ms(Id::<MessageMarker>::new(1)), DISCORD_EPOCH_MS);
+        assert_eq!(
+            first_at::<MessageMarker>(timestamp_ms(id)).map(timestamp_ms),
+            Some(timestamp_ms(id))
+        );
+    }
+
+    #[test]
+    fn rejects_unrepresentable_times() {
+        assert_eq!(first_at::<MessageMarker>(0), None);
+        assert_eq!(first_at::<MessageMarker>(DISCORD_EPOCH_MS), None);
+        assert_eq!(first_at::<MessageMarker>(u64::MAX), None);
+        assert!(first_at::<MessageMarker>(DISCORD_EPOCH_MS + 1).is_some());
+    }
+
+    #[test]
+    fn database_roundtrip() {
+        let id = Id::<MessageMarker>::new(u64::MAX);
+        assert_eq!(to_db(id), -1);
+        assert_eq!(from_db::<MessageMarker>(-1).ok(), Some(id));
+        assert!(from_db::<MessageMarker>(0).is_err());
+    }
+}
@@ -0,0 +1,338 @@
+//! Fixtures for tests
+//!
+//! Builders for configurations, registrations and portals, and fake discord and matrix events,
+//! so tests don't have to spell out every field. Available to the crate's own tests and, with the
+//! `testkit` feature, to integration tests.
+
+#![allow(clippy::expect_used)]
+
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    str::FromStr,
+};
+
+use crate::{
+    app::{portals::Portal, room_metadata::MetadataSync},
+    config::{Bridge, DBOptions, Discord, Homeserver, MemberSync, ModerationSync},
+    features::{FeatureOverrides, Features},
+    locale::Locale,
+    ConfigFile,
+};
+use matrix_sdk::ruma::{
+    api::appservice::Registration,
+    events::room::{member::SyncRoomMemberEvent, message::SyncRoomMessageEvent},
+    OwnedRoomId, OwnedUserId, RoomId, UserId,
+};
+use serde_json::json;
+use twilight_gateway::Event;
+use twilight_model::{
+    gateway::payload::incoming::{BanAdd, MessageDelete},
+    id::Id,
+    user::User,
+};
+use url::Url;
+
+/// Domain of the fake homeserver
+pub const DOMAIN: &str = "chir.rs";
+
+/// Builder for [`ConfigFile`]
+///
+/// Starts out with a minimal configuration for a homeserver on [`DOMAIN`] and all optional
+/// features disabled.
+#[derive(Clone, Debug)]
+pub struct ConfigBuilder {
+    /// Configuration built so far
+    config: ConfigFile,
+}
+
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConfigBuilder {
+    /// Creates a builder with the default test configuration
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            config: ConfigFile {
+                homeserver: Homeserver {
+                    address: Url::from_str("https://matrix.chir.rs/").expect("valid URL"),
+                    domain: DOMAIN.to_owned(),
+                    mscs: Vec::new(),
+                },
+                bridge: Bridge {
+                    listen_address: vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)],
+                    port: 58913,
+                    bridge_url: Url::from_str("http://localhost:58913/").expect("valid URL"),
+                    prefix: String::new(),
+                    db: DBOptions::default(),
+                    admin: user("lotte"),
+                    admin_room: None,
+                    catalog_room: None,
+                    homeserver_parallelism: 16,
+                    event_parallelism: 16,
+                    shutdown_timeout_secs: 30,
+                    double_puppet: false,
+                    moderator_power: false,
+                    message_retention_months: None,
+                    strip_tracking_params: false,
+                    topic_metadata: false,
+                    member_roles: false,
+                    displayname_template: "{displayname} (Discord)".to_owned(),
+                    member_sync: MemberSync::Lazy,
+                    moderation_sync: ModerationSync::Off,
+                    default_locale: Locale::English,
+                    secret_key: None,
+                    media_admin_token: None,
+                    event_webhooks: Vec::new(),
+                    integrity_check: None,
+                },
+                discord: Discord::default(),
+                features: Features::default(),
+                deprecated_keys: Vec::new(),
+            },
+        }
+    }
+
+    /// Sets the prefix of bridge users and aliases
+    #[must_use]
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.config.bridge.prefix = prefix.to_owned();
+        self
+    }
+
+    /// Sets the bridge admin
+    #[must_use]
+    pub fn admin(mut self, admin: OwnedUserId) -> Self {
+        self.config.bridge.admin = admin;
+        self
+    }
+
+    /// Enables double puppeting
+    #[must_use]
+    pub const fn double_puppet(mut self, double_puppet: bool) -> Self {
+        self.config.bridge.double_puppet = double_puppet;
+        self
+    }
+
+    /// Sets the MSCs the homeserver supports
+    #[must_use]
+    pub fn mscs(mut self, mscs: &[u16]) -> Self {
+        self.config.homeserver.mscs = mscs.to_vec();
+        self
+    }
+
+    /// Sets the token of the discord bot
+    #[must_use]
+    pub fn bot_token(mut self, token: &str) -> Self {
+        self.config.discord.bot_token = Some(token.to_owned());
+        self
+    }
+
+    /// Sets the configured features
+    #[must_use]
+    pub const fn features(mut self, features: Features) -> Self {
+        self.config.features = features;
+        self
+    }
+
+    /// Changes any other part of the configuration
+    #[must_use]
+    pub fn with(mut self, f: impl FnOnce(&mut ConfigFile)) -> Self {
+        f(&mut self.config);
+        self
+    }
+
+    /// Returns the configuration
+    #[must_use]
+    pub fn build(self) -> ConfigFile {
+        self.config
+    }
+}
+
+/// Returns the default test configuration
+#[must_use]
+pub fn config() -> ConfigFile {
+    ConfigBuilder::new().build()
+}
+
+/// Generates a registration for a configuration, with random tokens
+#[must_use]
+pub fn registration(config: &ConfigFile) -> Registration {
+    crate::registration::generate_registration(config)
+}
+
+/// Returns a user on [`DOMAIN`]
+#[must_use]
+pub fn user(localpart: &str) -> OwnedUserId {
+    UserId::parse(format!("@{}:{}", localpart, DOMAIN)).expect("valid user id")
+}
+
+/// Returns a room on [`DOMAIN`]
+#[must_use]
+pub fn room(id: &str) -> OwnedRoomId {
+    RoomId::parse(format!("!{}:{}", id, DOMAIN)).expect("valid room id")
+}
+
+/// Returns a portal of a guild channel without alias, locale or feature overrides
+#[must_use]
+pub fn portal(channel_id: u64, room_id: &str) -> Portal {
+    Portal {
+        channel_id: Id::new(channel_id),
+        room_id: room(room_id),
+        alias: None,
+        guild_id: Some(Id::new(1)),
+        read_only: false,
+        locale: None,
+        features: FeatureOverrides::default(),
+        metadata_sync: MetadataSync::FromDiscord,
+    }
+}
+
+/// Returns a discord user
+#[must_use]
+pub fn discord_user(id: u64, name: &str) -> User {
+    serde_json::from_value(json!({
+        "id": id.to_string(),
+        "username": name,
+        "discriminator": "0001",
+        "avatar": null,
+    }))
+    .expect("valid user")
+}
+
+/// Returns a discord ban event
+#[must_use]
+pub fn discord_ban(guild_id: u64, user_id: u64) -> Event {
+    Event::BanAdd(BanAdd {
+        guild_id: Id::new(guild_id),
+        user: discord_user(user_id, "banned"),
+    })
+}
+
+/// Returns a discord message deletion event
+#[must_use]
+pub fn discord_message_delete(guild_id: u64, channel_id: u64, message_id: u64) -> Event {
+    Event::MessageDelete(MessageDelete {
+        channel_id: Id::new(channel_id),
+        guild_id: Some(Id::new(guild_id)),
+        id: Id::new(message_id),
+    })
+}
+
+/// Returns the gateway payload of a new discord message, as the bridge receives it
+///
+/// `reply_to` is the message the message replies to, if any.
+#[must_use]
+pub fn discord_message_payload(
+    guild_id: u64,
+    channel_id: u64,
+    message_id: u64,
+    author: &User,
+    content: &str,
+    reply_to: Option<u64>,
+) -> String {
+    let reference = reply_to.map(|message_id| {
+        json!({
+            "channel_id": channel_id.to_string(),
+            "guild_id": guild_id.to_string(),
+            "message_id": message_id.to_string(),
+        })
+    });
+    json!({
+        "op": 0,
+        "s": 1,
+        "t": "MESSAGE_CREATE",
+        "d": {
+            "attachments": [],
+            "author": author,
+            "channel_id": channel_id.to_string(),
+            "content": content,
+            "edited_timestamp": null,
+            "embeds": [],
+            "guild_id": guild_id.to_string(),
+            "id": message_id.to_string(),
+            "mention_everyone": false,
+            "mention_roles": [],
+            "mentions": [],
+            "message_reference": reference,
+            "pinned": false,
+            "timestamp": "2022-07-06T12:00:00.000000+00:00",
+            "tts": false,
+            "type": if reply_to.is_some() { 19 } else { 0 },
+        },
+    })
+    .to_string()
+}
+
+/// Returns a text message event in a room
+#[must_use]
+pub fn room_message(sender: &UserId, event_id: &str, body: &str) -> SyncRoomMessageEvent {
+    serde_json::from_value(json!({
+        "type": "m.room.message",
+        "event_id": format!("${}", event_id),
+        "sender": sender,
+        "origin_server_ts": 1_656_338_700_000_u64,
+        "content": { "msgtype": "m.text", "body": body },
+    }))
+    .expect("valid message event")
+}
+
+/// Returns a rich reply to an event in a room, with the quote of the replied message
+#[must_use]
+pub fn room_reply(
+    sender: &UserId,
+    event_id: &str,
+    body: &str,
+    in_reply_to: &str,
+) -> SyncRoomMessageEvent {
+    serde_json::from_value(json!({
+        "type": "m.room.message",
+        "event_id": format!("${}", event_id),
+        "sender": sender,
+        "origin_server_ts": 1_656_338_700_000_u64,
+        "content": {
+            "msgtype": "m.text",
+            "body": format!("> <@alice:{}> question\n\n{}", DOMAIN, body),
+            "m.relates_to": { "m.in_reply_to": { "event_id": in_reply_to } },
+        },
+    }))
+    .expect("valid reply event")
+}
+
+/// Returns a membership event of a user
+///
+/// `membership` is the new membership, like `join` or `ban`.
+#[must_use]
+pub fn room_member(sender: &UserId, user_id: &UserId, membership: &str) -> SyncRoomMemberEvent {
+    serde_json::from_value(json!({
+        "type": "m.room.member",
+        "event_id": format!("${}_{}", membership, user_id.localpart()),
+        "sender": sender,
+        "state_key": user_id,
+        "origin_server_ts": 1_656_338_700_000_u64,
+        "content": { "membership": membership },
+    }))
+    .expect("valid member event")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix_sdk::ruma::events::{room::member::MembershipState, SyncStateEvent};
+
+    #[test]
+    fn builds_events() {
+        let event = room_member(&user("lotte"), &user("spammer"), "ban");
+        assert!(matches!(
+            event,
+            SyncStateEvent::Original(ref event)
+                if event.content.membership == MembershipState::Ban
+                    && event.state_key == user("spammer")
+        ));
+        let event = room_message(&user("lotte"), "a", "hi");
+        assert_eq!(event.sender().as_str(), "@lotte:chir.rs");
+    }
+}
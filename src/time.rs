@@ -0,0 +1,191 @@
+//! Timestamps and clock handling
+//!
+//! Times are handled as milliseconds since the unix epoch throughout the bridge and formatted the
+//! same way wherever they are shown. Discord and the homeserver stamp events with their own
+//! clocks; the offset of each against the bridge's clock is tracked so that drift between them is
+//! noticed before it reorders backfilled history or breaks time-based deduplication.
+
+use std::{
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Largest tolerated difference between the homeserver and discord clocks in milliseconds
+///
+/// Offsets are measured on event arrival, so they include delivery latency as well.
+pub const SKEW_TOLERANCE_MS: i64 = 30_000;
+
+/// Returns the current time in milliseconds since the unix epoch
+///
+/// Returns 0 if the system clock is set before the epoch.
+#[must_use]
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| {
+            u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX)
+        })
+}
+
+/// Converts days since the unix epoch into a `(year, month, day)` date
+///
+/// This is the `civil_from_days` algorithm by Howard Hinnant, restricted to dates after the epoch.
+#[must_use]
+pub const fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+/// Formats a timestamp in milliseconds as an ISO 8601 date and time in UTC
+///
+/// This is the machine readable form used in logs and exports, like `2022-06-27T14:05:00.000Z`.
+#[must_use]
+pub fn format_iso8601(timestamp: u64) -> String {
+    let seconds = timestamp / 1000;
+    let (year, month, day) = civil_from_days(seconds / 86_400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds % 86_400 / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+        timestamp % 1000
+    )
+}
+
+/// Returns how far a remote timestamp is ahead of the local clock in milliseconds
+#[must_use]
+#[allow(clippy::cast_possible_wrap)]
+pub const fn offset_ms(remote: u64, local: u64) -> i64 {
+    if remote >= local {
+        let diff = remote - local;
+        if diff > i64::MAX as u64 {
+            i64::MAX
+        } else {
+            diff as i64
+        }
+    } else {
+        let diff = local - remote;
+        if diff > i64::MAX as u64 {
+            -i64::MAX
+        } else {
+            -(diff as i64)
+        }
+    }
+}
+
+/// A clock whose offset is tracked
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Clock {
+    /// The homeserver, observed through `origin_server_ts`
+    Homeserver,
+    /// Discord, observed through snowflake timestamps
+    Discord,
+}
+
+/// Change of the skew between the homeserver and discord clocks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SkewChange {
+    /// The skew exceeded the tolerance, positive if the homeserver is ahead of discord
+    Exceeded(i64),
+    /// The skew is within the tolerance again
+    Recovered,
+}
+
+/// Last observed offsets of both clocks
+#[derive(Debug, Default)]
+struct Offsets {
+    /// Offset of the homeserver clock
+    homeserver: Option<i64>,
+    /// Offset of the discord clock
+    discord: Option<i64>,
+    /// Whether the skew is currently beyond the tolerance
+    skewed: bool,
+}
+
+/// Detects clock skew between the homeserver and discord
+#[derive(Debug, Default)]
+pub struct ClockSkew {
+    /// Last observed offsets
+    offsets: Mutex<Offsets>,
+}
+
+impl ClockSkew {
+    /// Records a timestamp of one clock observed at local time `now`
+    ///
+    /// Returns a change if the skew just exceeded the tolerance or went back within it, so that
+    /// it is only reported once.
+    pub fn observe(&self, clock: Clock, timestamp: u64, now: u64) -> Option<SkewChange> {
+        let mut offsets = self.offsets.lock().ok()?;
+        let offset = offset_ms(timestamp, now);
+        match clock {
+            Clock::Homeserver => offsets.homeserver = Some(offset),
+            Clock::Discord => offsets.discord = Some(offset),
+        }
+        let skew = offsets.homeserver?.saturating_sub(offsets.discord?);
+        let skewed = skew.saturating_abs() > SKEW_TOLERANCE_MS;
+        if skewed == offsets.skewed {
+            return None;
+        }
+        offsets.skewed = skewed;
+        Some(if skewed {
+            SkewChange::Exceeded(skew)
+        } else {
+            SkewChange::Recovered
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_iso8601() {
+        assert_eq!(format_iso8601(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            format_iso8601(1_656_338_700_042),
+            "2022-06-27T14:05:00.042Z"
+        );
+        assert_eq!(format_iso8601(951_782_400_000), "2000-02-29T00:00:00.000Z");
+    }
+
+    #[test]
+    fn offsets_saturate() {
+        assert_eq!(offset_ms(1500, 1000), 500);
+        assert_eq!(offset_ms(1000, 1500), -500);
+        assert_eq!(offset_ms(u64::MAX, 0), i64::MAX);
+        assert_eq!(offset_ms(0, u64::MAX), -i64::MAX);
+    }
+
+    #[test]
+    fn reports_skew_once() {
+        let skew = ClockSkew::default();
+        assert_eq!(skew.observe(Clock::Homeserver, 100_000, 100_000), None);
+        assert_eq!(
+            skew.observe(Clock::Discord, 160_000, 100_000),
+            Some(SkewChange::Exceeded(-60_000))
+        );
+        assert_eq!(skew.observe(Clock::Discord, 161_000, 101_000), None);
+        assert_eq!(
+            skew.observe(Clock::Discord, 101_000, 100_000),
+            Some(SkewChange::Recovered)
+        );
+        assert_eq!(skew.observe(Clock::Homeserver, 100_000, 100_000), None);
+    }
+}